@@ -0,0 +1,149 @@
+//! Measures how fast random rays, and camera rays one by one and in packets, are traced
+//! against the random scene with a triangle mesh, or a stress scene of millions of primitives.
+//! Run with `cargo run --release --example traversal [BVH width] [sphereflake|menger|soup]`.
+
+use rand::prelude::*;
+use rand_xorshift::XorShiftRng;
+use rt::{
+    sampler::{Sampler, SamplerKind},
+    scene::Scene,
+    world::bvh::MAX_PACKET_SIZE,
+    Ray,
+};
+use std::time::{Duration, Instant};
+use ultraviolet::{Vec2, Vec3};
+
+const RAYS: usize = 2_000_000;
+
+fn main() {
+    let mut rng = XorShiftRng::seed_from_u64(0);
+    // Random rays are aimed into a box around the interesting part of the scene
+    let (mut scene, min, max) = match std::env::args().nth(2).as_deref() {
+        // About 5.4, 1.9 and 4 million primitives
+        Some("sphereflake") => (
+            Scene::sphere_flake(7),
+            -Vec3::broadcast(2.),
+            Vec3::broadcast(2.),
+        ),
+        Some("menger") => (Scene::menger_sponge(4), -Vec3::one(), Vec3::one()),
+        Some("soup") => (
+            Scene::triangle_soup(&mut rng, 4_000_000),
+            -Vec3::one(),
+            Vec3::one(),
+        ),
+        Some(name) => panic!("Unknown scene {}", name),
+        None => (
+            random_with_mesh(&mut rng),
+            Vec3::new(-11., 0., -11.),
+            Vec3::new(11., 1., 11.),
+        ),
+    };
+    if let Some(width) = std::env::args().nth(1) {
+        scene.bvh.width = width.parse().expect("BVH width is a number");
+    }
+
+    let started = Instant::now();
+    let world = scene.world(0).expect("Generated scenes are valid");
+    println!(
+        "{} objects, BVH built in {:.3} s",
+        scene.objects.len(),
+        started.elapsed().as_secs_f64()
+    );
+
+    // Rays from around the camera position towards random points in the scene
+    let look_from = Vec3::from(scene.camera.look_from);
+    let rays: Vec<Ray> = (0..RAYS)
+        .map(|_| {
+            let origin = look_from + Vec3::from(rng.gen::<[f32; 3]>()) - Vec3::broadcast(0.5);
+            let target = Vec3::new(
+                rng.gen_range(min.x..max.x),
+                rng.gen_range(min.y..max.y),
+                rng.gen_range(min.z..max.z),
+            );
+            Ray::new(origin, target - origin, rng.gen())
+        })
+        .collect();
+
+    let started = Instant::now();
+    let hits = rays
+        .iter()
+        .filter(|r| world.traverse(r, 0.001).is_some())
+        .count();
+    report("random", hits, started.elapsed());
+
+    // Samples of the same pixel, as the renderer traces them
+    let camera = scene
+        .camera(16. / 9., 0)
+        .expect("Random scene has no camera path");
+    let rays: Vec<Ray> = (0..RAYS)
+        .map(|i| {
+            let pixel = i / MAX_PACKET_SIZE;
+            let xy = Vec2::new((pixel % 1280) as f32, (pixel / 1280 % 720) as f32);
+            let pixel_size = Vec2::one() / Vec2::new(1279., 719.);
+            let mut sampler = Sampler::new(&mut rng, SamplerKind::Random, 1);
+            camera.get_ray(&mut sampler, xy * pixel_size, pixel_size)
+        })
+        .collect();
+
+    let started = Instant::now();
+    let hits = rays
+        .iter()
+        .filter(|r| world.traverse(r, 0.001).is_some())
+        .count();
+    report("camera", hits, started.elapsed());
+
+    let started = Instant::now();
+    let hits: usize = rays
+        .chunks(MAX_PACKET_SIZE)
+        .map(|packet| {
+            world
+                .traverse_packet(packet, 0.001)
+                .iter()
+                .filter(|hit| hit.is_some())
+                .count()
+        })
+        .sum();
+    report("camera packet", hits, started.elapsed());
+}
+
+/// The random scene with a bumpy triangle mesh over the ground, for a more realistic
+/// primitive count
+fn random_with_mesh(rng: &mut impl Rng) -> Scene {
+    let mut scene = Scene::random(rng);
+    const GRID: u32 = 256;
+    let positions: Vec<[f32; 3]> = (0..=GRID)
+        .flat_map(|z| (0..=GRID).map(move |x| (x, z)))
+        .map(|(x, z)| {
+            let (x, z) = (
+                x as f32 / GRID as f32 * 24. - 12.,
+                z as f32 / GRID as f32 * 24. - 12.,
+            );
+            [x, 0.05 * (x * 3.).sin() * (z * 2.).cos(), z]
+        })
+        .collect();
+    let indices: Vec<[u32; 3]> = (0..GRID)
+        .flat_map(|z| (0..GRID).map(move |x| z * (GRID + 1) + x))
+        .flat_map(|i| {
+            vec![
+                [i, i + GRID + 1, i + 1],
+                [i + 1, i + GRID + 1, i + GRID + 2],
+            ]
+        })
+        .collect();
+    let material = scene.materials.len() - 1;
+    scene
+        .add_mesh(&positions, &indices, material)
+        .expect("Mesh indices are valid");
+    scene
+}
+
+fn report(name: &str, hits: usize, elapsed: Duration) {
+    println!(
+        "{:>13}: {} rays, {} hits in {:.3} s, {:.2} M rays/s",
+        name,
+        RAYS,
+        hits,
+        elapsed.as_secs_f64(),
+        RAYS as f64 / elapsed.as_secs_f64() / 1e6
+    );
+}
@@ -0,0 +1,130 @@
+#ifndef RT_H
+#define RT_H
+
+/* Generated with cbindgen from src/capi.rs, do not edit by hand */
+
+#include <stdarg.h>
+#include <stdbool.h>
+#include <stddef.h>
+#include <stdint.h>
+#include <stdlib.h>
+
+#define RT_OK 0
+
+#define RT_ERROR_INVALID_ARGUMENT -1
+
+#define RT_ERROR_RENDER_FAILED -2
+
+typedef enum RtMaterialKind {
+  RtLambertian,
+  RtMetal,
+  RtDielectric,
+} RtMaterialKind;
+
+/**
+ * Opaque scene handle
+ */
+typedef struct RtScene RtScene;
+
+typedef struct RtMaterial {
+  enum RtMaterialKind kind;
+  /**
+   * Used by Lambertian and metal materials
+   */
+  float albedo[3];
+  /**
+   * Used by metal materials
+   */
+  float fuzz;
+  /**
+   * Used by dielectric materials
+   */
+  float refraction;
+} RtMaterial;
+
+/**
+ * Called with the number of finished and total tiles
+ */
+typedef void (*RtProgressCallback)(uint32_t, uint32_t, void*);
+
+#ifdef __cplusplus
+extern "C" {
+#endif // __cplusplus
+
+/**
+ * Create an empty scene with a camera at (0, 0, 1) looking at the origin
+ */
+struct RtScene *rt_scene_new(void);
+
+/**
+ * Create the random spheres scene
+ */
+struct RtScene *rt_scene_new_random(uint64_t seed);
+
+/**
+ * # Safety
+ *
+ * `scene` must come from [`rt_scene_new`] or [`rt_scene_new_random`], or be null.
+ */
+void rt_scene_free(struct RtScene *scene);
+
+/**
+ * # Safety
+ *
+ * `scene` must be a valid scene, `center` must point to 3 floats and `material` to a material.
+ */
+int rt_scene_add_sphere(struct RtScene *scene,
+                        const float *center,
+                        float radius,
+                        const struct RtMaterial *material);
+
+/**
+ * Add a triangle mesh with `vertex_count` xyz positions and `triangle_count` index triplets
+ *
+ * # Safety
+ *
+ * `scene` must be a valid scene, and the arrays must have as many elements as declared.
+ */
+int rt_scene_add_mesh(struct RtScene *scene,
+                      const float *positions,
+                      size_t vertex_count,
+                      const uint32_t *indices,
+                      size_t triangle_count,
+                      const struct RtMaterial *material);
+
+/**
+ * # Safety
+ *
+ * `scene` must be a valid scene and the vectors must point to 3 floats each.
+ */
+int rt_scene_set_camera(struct RtScene *scene,
+                        const float *look_from,
+                        const float *look_at,
+                        const float *up,
+                        float vertical_fov_degrees,
+                        float aperture,
+                        float focus_distance);
+
+/**
+ * Render the scene into `out` as `width * height` 8bpp RGB pixels, top row first.
+ * Uses `nthreads` threads, or all cores when it is 0. `progress` is called from the calling
+ * thread while rendering.
+ *
+ * # Safety
+ *
+ * `scene` must be a valid scene and `out` must be valid for writes of `width * height * 3` bytes.
+ */
+int rt_render(const struct RtScene *scene,
+              uint32_t width,
+              uint32_t height,
+              uint32_t samples_per_pixel,
+              uint32_t nthreads,
+              uint8_t *out,
+              RtProgressCallback progress,
+              void *user_data);
+
+#ifdef __cplusplus
+}  // extern "C"
+#endif  // __cplusplus
+
+#endif  /* RT_H */
@@ -0,0 +1,223 @@
+//! Baking maps of how meshes are shaped and surrounded into textures, by their texture
+//! coordinates
+
+use crate::{
+    ray::RayKind,
+    sampling::{cosine_hemisphere, Onb},
+    scene::{Scene, SurfaceSpec},
+    world::{bvh::MAX_PACKET_SIZE, World},
+    Ray,
+};
+use anyhow::{anyhow, Result};
+use rand::prelude::*;
+use ultraviolet::{Vec2, Vec3};
+
+#[derive(Clone, Copy, Debug)]
+pub struct BakeOptions {
+    pub width: usize,
+    pub height: usize,
+    /// Per texel for ambient occlusion and bent normals
+    pub rays: u32,
+    /// Rays start this far above the surface, so that they don't hit it where it is flat
+    pub cage_distance: f32,
+    /// Objects further away from the surface don't occlude it
+    pub occlusion_distance: f32,
+    /// Distance over which the change of the normal is measured for curvature
+    pub curvature_radius: f32,
+}
+
+/// Maps baked for one texel
+#[derive(Clone, Copy, Debug)]
+pub struct BakedTexel {
+    /// Fraction of cosine weighted directions from which light reaches the surface, from 0 to 1
+    pub occlusion: f32,
+    /// Unit vector in world space along the average unoccluded direction
+    pub bent_normal: Vec3,
+    /// Sine of how much the normal turns away from the texel within the curvature radius,
+    /// positive where the surface is convex and negative where it is concave
+    pub curvature: f32,
+}
+
+impl BakedTexel {
+    /// For texels which no triangle covers
+    pub const EMPTY: Self = Self {
+        occlusion: 1.,
+        bent_normal: Vec3::new(0., 0., 0.),
+        curvature: 0.,
+    };
+}
+
+/// Point on a mesh which a texel covers
+#[derive(Clone, Copy)]
+struct Texel {
+    position: Vec3,
+    normal: Vec3,
+}
+
+/// Mesh rasterized into texels, in the world that it occludes
+pub struct Baker {
+    world: World,
+    time: f32,
+    options: BakeOptions,
+    /// Rows from the top, where texture coordinates have `v` growing upwards
+    texels: Vec<Option<Texel>>,
+}
+
+impl Baker {
+    /// The mesh is made of the triangles in `scene` with `material`. Where their texture
+    /// coordinates overlap, the last triangle is baked.
+    pub fn new(scene: &Scene, material: usize, options: BakeOptions) -> Result<Self> {
+        let (width, height) = (options.width, options.height);
+        if width == 0 || height == 0 {
+            return Err(anyhow!("Baked maps have no texels"));
+        }
+        let mut texels = vec![None; width * height];
+        let mut triangles = 0;
+        for object in scene.objects.iter().filter(|o| o.material == material) {
+            let (vertices, uvs) = match scene.surfaces.get(object.surface) {
+                Some(SurfaceSpec::Triangle { vertices, uvs }) => (*vertices, *uvs),
+                _ => continue,
+            };
+            let position = Vec3::from(object.position);
+            let vertices = vertices.map(|v| Vec3::from(v) + position);
+            let normal = (vertices[1] - vertices[0]).cross(vertices[2] - vertices[0]);
+            if normal.mag_sq() == 0. {
+                continue;
+            }
+            let normal = normal.normalized();
+            // To texel coordinates with y down
+            let points = uvs.map(|[u, v]| Vec2::new(u * width as f32, (1. - v) * height as f32));
+            rasterize(points, width, height, |x, y, b| {
+                texels[y * width + x] = Some(Texel {
+                    position: vertices[0] * b[0] + vertices[1] * b[1] + vertices[2] * b[2],
+                    normal,
+                });
+            });
+            triangles += 1;
+        }
+        if triangles == 0 {
+            return Err(anyhow!("No triangles with material {}", material));
+        }
+        Ok(Self {
+            world: scene.world(0)?,
+            time: scene.shutter(0).start,
+            options,
+            texels,
+        })
+    }
+
+    pub fn width(&self) -> usize {
+        self.options.width
+    }
+
+    pub fn height(&self) -> usize {
+        self.options.height
+    }
+
+    /// Bake the texels of row `y`, counting from the top
+    pub fn bake_row<R: Rng>(&self, rng: &mut R, y: usize) -> Vec<BakedTexel> {
+        let width = self.options.width;
+        self.texels[y * width..][..width]
+            .iter()
+            .map(|texel| match texel {
+                Some(texel) => self.bake_texel(rng, texel),
+                None => BakedTexel::EMPTY,
+            })
+            .collect()
+    }
+
+    fn bake_texel<R: Rng>(&self, rng: &mut R, texel: &Texel) -> BakedTexel {
+        let options = &self.options;
+        let frame = Onb::from_normal(texel.normal);
+        let origin = texel.position + texel.normal * options.cage_distance;
+
+        let (mut unoccluded, mut bent_normal) = (0, Vec3::zero());
+        // Rays from the same point are coherent enough to trace as packets
+        let mut left = options.rays as usize;
+        while left > 0 {
+            let rays: Vec<Ray> = (0..left.min(MAX_PACKET_SIZE))
+                .map(|_| {
+                    let direction =
+                        frame.to_world(cosine_hemisphere(Vec2::new(rng.gen(), rng.gen())));
+                    Ray::new(origin, direction, self.time).with_kind(RayKind::Shadow)
+                })
+                .collect();
+            left -= rays.len();
+            let occluded = self
+                .world
+                .occluded_packet(&rays, 0.0..options.occlusion_distance);
+            for (r, occluded) in rays.iter().zip(occluded) {
+                if !occluded {
+                    unoccluded += 1;
+                    bent_normal += r.direction();
+                }
+            }
+        }
+
+        // Probe the surface around the texel from above, along and across the tangent
+        let (mut curvature, mut probes) = (0., 0);
+        for tangent in [
+            frame.tangent,
+            frame.bitangent,
+            -frame.tangent,
+            -frame.bitangent,
+        ] {
+            let r = Ray::new(
+                origin + tangent * options.curvature_radius,
+                -texel.normal,
+                self.time,
+            );
+            if let Some(intersection) = self.world.traverse(&r, 0.) {
+                // Facing the probe, which is on the same side as the normal of the texel
+                curvature += (intersection.hit.normal - texel.normal).dot(tangent);
+                probes += 1;
+            }
+        }
+
+        BakedTexel {
+            occlusion: unoccluded as f32 / options.rays.max(1) as f32,
+            bent_normal: if unoccluded > 0 {
+                bent_normal.normalized()
+            } else {
+                texel.normal
+            },
+            curvature: if probes > 0 {
+                curvature / probes as f32
+            } else {
+                0.
+            },
+        }
+    }
+}
+
+/// Call `texel` with the position and barycentric coordinates of the center of each texel
+/// inside of the triangle with corners at `points` in texel coordinates
+fn rasterize(
+    points: [Vec2; 3],
+    width: usize,
+    height: usize,
+    mut texel: impl FnMut(usize, usize, [f32; 3]),
+) {
+    let [a, b, c] = points;
+    let area = (b - a).x * (c - a).y - (c - a).x * (b - a).y;
+    if area == 0. {
+        return;
+    }
+    let min = a.min_by_component(b).min_by_component(c);
+    let max = a.max_by_component(b).max_by_component(c);
+    let range = |min: f32, max: f32, len: usize| {
+        (min.floor().max(0.) as usize)..(max.ceil().max(0.) as usize).min(len)
+    };
+    for y in range(min.y, max.y, height) {
+        for x in range(min.x, max.x, width) {
+            let p = Vec2::new(x as f32 + 0.5, y as f32 + 0.5);
+            let edge = |from: Vec2, to: Vec2| {
+                ((to - from).x * (p - from).y - (p - from).x * (to - from).y) / area
+            };
+            let weights = [edge(b, c), edge(c, a), edge(a, b)];
+            if weights.iter().all(|&w| w >= 0.) {
+                texel(x, y, weights);
+            }
+        }
+    }
+}
@@ -0,0 +1,228 @@
+//! Standard benchmark, which renders built-in scenes with fixed seeds and settings so that its
+//! reports can be compared between machines and versions of the renderer. The rates of each
+//! kind of ray are over the whole time spent rendering, so they add up to the total rate.
+//! With `--numa`, the scenes are rendered again with the threads spread over the NUMA nodes,
+//! and the report has the speedup from that.
+
+use crate::builtin::Seeded;
+#[cfg(feature = "numa")]
+use crate::numa::Placement;
+use anyhow::{anyhow, Result};
+use rt::{
+    render::{self, CancellationToken, Frame, RayCounts, Renderer},
+    scene::{Irradiance, LightSpec, Scene},
+};
+use std::time::{Duration, Instant};
+
+const WIDTH: usize = 320;
+const HEIGHT: usize = 180;
+const SAMPLES_PER_PIXEL: u32 = 16;
+const SEED: u64 = 1;
+/// Names of the scenes and how they are chosen like with `--builtin`
+const SCENES: [(&str, &str); 3] = [
+    ("random", "random"),
+    ("sphereflake", "sphereflake"),
+    ("menger", "menger"),
+];
+
+struct Measurement {
+    name: &'static str,
+    /// Time spent loading the scene and building its hierarchy
+    build: Duration,
+    render: Duration,
+    rays: RayCounts,
+}
+
+/// Millions of rays per second
+fn mrays(rays: u64, time: Duration) -> f64 {
+    rays as f64 / time.as_secs_f64().max(f64::EPSILON) / 1e6
+}
+
+/// Built-in scene lit by a sun, so that it has shadow rays to trace
+fn scene(builtin: &str) -> Result<Scene> {
+    let mut scene = builtin.parse::<Seeded>()?.scene(SEED);
+    scene.lights.push(LightSpec::Sun {
+        direction: [0.4, 1., 0.3],
+        angular_radius_degrees: 0.27,
+        color: [1.; 3],
+        temperature_kelvin: None,
+        irradiance: Irradiance::WattsPerSquareMeter(2.),
+        caustics: true,
+    });
+    scene.resolve_materials()?;
+    Ok(scene)
+}
+
+/// Threads which are pinned to NUMA nodes, or nothing without the `numa` feature
+#[cfg(feature = "numa")]
+type Pinning<'a> = Option<&'a Placement>;
+#[cfg(not(feature = "numa"))]
+type Pinning<'a> = Option<&'a ()>;
+
+/// Render on `nthreads` threads, which are pinned by `pinning` before they allocate anything
+fn render(renderer: &Renderer, nthreads: usize, pinning: Pinning<'_>) -> Result<()> {
+    let frame = Frame::new(renderer, &(), CancellationToken::new());
+    crossbeam_utils::thread::scope(|s| {
+        let threads: Vec<_> = (0..nthreads)
+            .map(|thread| {
+                let frame = &frame;
+                s.spawn(move |_| {
+                    #[cfg(feature = "numa")]
+                    if let Some(placement) = pinning {
+                        if let Err(e) = placement.pin(thread) {
+                            eprintln!("Cannot pin rendering thread {}: {}", thread, e);
+                        }
+                    }
+                    #[cfg(not(feature = "numa"))]
+                    let _ = (thread, pinning);
+                    frame.work(renderer)
+                })
+            })
+            .collect();
+        threads.into_iter().try_for_each(|thread| {
+            thread
+                .join()
+                .unwrap_or_else(|_| Err(anyhow!("Rendering thread panicked")))
+        })
+    })
+    .map_err(|_| anyhow!("A rendering thread encountered an irrecoverable error"))?
+}
+
+/// Render every benchmark scene, with the total of them last
+fn measure(json: bool, nthreads: usize, pinning: Pinning<'_>) -> Result<Vec<Measurement>> {
+    let mut results = Vec::new();
+    for &(name, builtin) in &SCENES {
+        if !json {
+            eprint!("Rendering {:<16}\r", name);
+        }
+        let started = Instant::now();
+        let renderer = Renderer::new(&scene(builtin)?, 0, WIDTH, HEIGHT, SAMPLES_PER_PIXEL)?;
+        let build = started.elapsed();
+        let (rays, started) = (render::ray_counts(), Instant::now());
+        render(&renderer, nthreads, pinning)?;
+        results.push(Measurement {
+            name,
+            build,
+            render: started.elapsed(),
+            rays: render::ray_counts() - rays,
+        });
+    }
+    if !json {
+        eprint!("{:26}\r", "");
+    }
+    if results.iter().any(|result| result.rays.total() == 0) {
+        return Err(anyhow!("A benchmark scene traced no rays"));
+    }
+    let total = Measurement {
+        name: "total",
+        build: results.iter().map(|result| result.build).sum(),
+        render: results.iter().map(|result| result.render).sum(),
+        rays: results
+            .iter()
+            .fold(RayCounts::default(), |sum, result| RayCounts {
+                primary: sum.primary + result.rays.primary,
+                secondary: sum.secondary + result.rays.secondary,
+                shadow: sum.shadow + result.rays.shadow,
+            }),
+    };
+    results.push(total);
+    Ok(results)
+}
+
+/// Render the benchmark scenes on `nthreads` threads and print how fast the rays were traced.
+/// With `numa`, they are rendered again with the threads spread over the NUMA nodes, and the
+/// speedup of the total rate from that is printed too.
+pub fn run(json: bool, nthreads: usize, numa: bool) -> Result<()> {
+    let results = measure(json, nthreads, None)?;
+    // Speedup from pinning, and the number of nodes
+    let numa: Option<(f64, usize)> = if numa {
+        #[cfg(feature = "numa")]
+        {
+            let placement = Placement::detect()?;
+            let pinned = measure(json, nthreads, Some(&placement))?;
+            let rate = |results: &[Measurement]| {
+                let total = results.last().expect("There is a total");
+                mrays(total.rays.total(), total.render)
+            };
+            Some((rate(&pinned) / rate(&results), placement.nodes()))
+        }
+        #[cfg(not(feature = "numa"))]
+        return Err(anyhow!("NUMA placement needs the numa feature"));
+    } else {
+        None
+    };
+    let (results, total) = results.split_at(results.len() - 1);
+    let total = &total[0];
+
+    if json {
+        let scenes: Vec<String> = results.iter().map(measurement_json).collect();
+        let numa = match numa {
+            Some((speedup, nodes)) => {
+                format!(",\"numa_nodes\":{},\"numa_speedup\":{}", nodes, speedup)
+            }
+            None => String::new(),
+        };
+        println!(
+            "{{\"version\":\"{}\",\"threads\":{},\"width\":{},\"height\":{},\
+             \"samples_per_pixel\":{},\"scenes\":[{}],\"total\":{}{}}}",
+            env!("CARGO_PKG_VERSION"),
+            nthreads,
+            WIDTH,
+            HEIGHT,
+            SAMPLES_PER_PIXEL,
+            scenes.join(","),
+            measurement_json(total),
+            numa,
+        );
+        return Ok(());
+    }
+    println!(
+        "rt {} on {} threads, {}x{} pixels with {} samples per pixel",
+        env!("CARGO_PKG_VERSION"),
+        nthreads,
+        WIDTH,
+        HEIGHT,
+        SAMPLES_PER_PIXEL
+    );
+    println!(
+        "{:<12} {:>8} {:>9} {:>9} {:>9} {:>9} {:>9}",
+        "", "build s", "render s", "primary", "secondary", "shadow", "Mrays/s"
+    );
+    for result in results.iter().chain(Some(total)) {
+        println!(
+            "{:<12} {:>8.3} {:>9.3} {:>9.3} {:>9.3} {:>9.3} {:>9.3}",
+            result.name,
+            result.build.as_secs_f64(),
+            result.render.as_secs_f64(),
+            mrays(result.rays.primary, result.render),
+            mrays(result.rays.secondary, result.render),
+            mrays(result.rays.shadow, result.render),
+            mrays(result.rays.total(), result.render),
+        );
+    }
+    if let Some((speedup, nodes)) = numa {
+        println!(
+            "Spreading threads over {} NUMA nodes traced rays {:.3} times as fast",
+            nodes, speedup
+        );
+    }
+    Ok(())
+}
+
+fn measurement_json(result: &Measurement) -> String {
+    format!(
+        "{{\"name\":\"{}\",\"build_secs\":{},\"render_secs\":{},\"primary_rays\":{},\
+         \"secondary_rays\":{},\"shadow_rays\":{},\"primary_mrays_per_sec\":{},\
+         \"secondary_mrays_per_sec\":{},\"shadow_mrays_per_sec\":{},\"mrays_per_sec\":{}}}",
+        result.name,
+        result.build.as_secs_f64(),
+        result.render.as_secs_f64(),
+        result.rays.primary,
+        result.rays.secondary,
+        result.rays.shadow,
+        mrays(result.rays.primary, result.render),
+        mrays(result.rays.secondary, result.render),
+        mrays(result.rays.shadow, result.render),
+        mrays(result.rays.total(), result.render),
+    )
+}
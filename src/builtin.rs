@@ -0,0 +1,82 @@
+//! Procedurally generated scenes, chosen like `random:grid_size=9,glass=0` or
+//! `sphereflake:depth=6`
+
+use anyhow::{anyhow, Result};
+use rand::SeedableRng;
+use rand_xorshift::XorShiftRng;
+use rt::scene::{RandomOptions, Scene};
+use std::str::FromStr;
+
+pub enum Builtin {
+    /// See [`Scene::random_with`]
+    Random(RandomOptions),
+    /// See [`Scene::sphere_flake`]
+    SphereFlake { depth: u32 },
+    /// See [`Scene::menger_sponge`]
+    MengerSponge { level: u32 },
+    /// See [`Scene::triangle_soup`]
+    TriangleSoup { count: usize },
+}
+
+/// Built-in scene with the seed of its random numbers, if it was given
+#[derive(Default)]
+pub struct Seeded(pub Builtin, pub Option<u64>);
+
+impl Default for Builtin {
+    fn default() -> Self {
+        Self::Random(RandomOptions::default())
+    }
+}
+
+impl FromStr for Seeded {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let (name, params) = s.split_once(':').unwrap_or((s, ""));
+        let mut builtin = match name {
+            "random" => Builtin::default(),
+            "sphereflake" => Builtin::SphereFlake { depth: 4 },
+            "menger" => Builtin::MengerSponge { level: 3 },
+            "soup" => Builtin::TriangleSoup { count: 1_000_000 },
+            _ => return Err(anyhow!("Unknown built-in scene {}", name)),
+        };
+        let mut seed = None;
+        for param in params.split(',').filter(|param| !param.is_empty()) {
+            let (key, value) = param
+                .split_once('=')
+                .ok_or_else(|| anyhow!("Parameter {} is not like key=value", param))?;
+            match (&mut builtin, key) {
+                (_, "seed") => seed = Some(value.parse()?),
+                (Builtin::Random(options), "grid_size") => options.grid_size = value.parse()?,
+                (Builtin::Random(options), "diffuse") => options.diffuse_weight = value.parse()?,
+                (Builtin::Random(options), "metal") => options.metal_weight = value.parse()?,
+                (Builtin::Random(options), "glass") => options.glass_weight = value.parse()?,
+                (Builtin::Random(options), "radius") => {
+                    options.radius = match value.split_once(':') {
+                        Some((min, max)) => (min.parse()?, max.parse()?),
+                        None => (value.parse()?, value.parse()?),
+                    }
+                }
+                (Builtin::Random(options), "motion") => options.motion = value.parse()?,
+                (Builtin::SphereFlake { depth }, "depth") => *depth = value.parse()?,
+                (Builtin::MengerSponge { level }, "level") => *level = value.parse()?,
+                (Builtin::TriangleSoup { count }, "count") => *count = value.parse()?,
+                _ => return Err(anyhow!("Unknown parameter {} of the {} scene", key, name)),
+            }
+        }
+        Ok(Self(builtin, seed))
+    }
+}
+
+impl Seeded {
+    /// Generate the scene, with random numbers from `seed` unless another one was given
+    pub fn scene(&self, seed: u64) -> Scene {
+        let mut rng = XorShiftRng::seed_from_u64(self.1.unwrap_or(seed));
+        match self.0 {
+            Builtin::Random(ref options) => Scene::random_with(&mut rng, options),
+            Builtin::SphereFlake { depth } => Scene::sphere_flake(depth),
+            Builtin::MengerSponge { level } => Scene::menger_sponge(level),
+            Builtin::TriangleSoup { count } => Scene::triangle_soup(&mut rng, count),
+        }
+    }
+}
@@ -1,18 +1,16 @@
-use crate::Ray;
+use crate::{
+    color::COLOR_CHANNELS,
+    filter::{FilterSampler, PixelFilter},
+    image::Image,
+    ray::Differentials,
+    sampler::Sampler,
+    sampling::{concentric_disc, Distribution2D},
+    Ray,
+};
 use rand::prelude::*;
 use std::ops::Range;
 use ultraviolet::{Vec2, Vec3};
 
-fn random_in_disc(rng: &mut impl Rng) -> Vec2 {
-    loop {
-        let v = Vec2::from(rng.gen::<[f32; 2]>()) * 2. - Vec2::one();
-        let len = v.mag_sq();
-        if len < 1. {
-            return v;
-        }
-    }
-}
-
 pub struct Camera {
     origin: Vec3,
     lower_left_corner: Vec3,
@@ -22,9 +20,28 @@ pub struct Camera {
     v: Vec3,
     lens_radius: f32,
     shutter_time: Range<f32>,
+    /// Fraction of the shutter time that each row is exposed for, when rows are exposed one
+    /// after another
+    rolling_shutter: Option<f32>,
+    /// Over the square around the lens, in proportion to how much light passes the aperture,
+    /// instead of a round aperture
+    aperture: Option<Distribution2D>,
+    /// Offset of the second aperture which clips the first towards the edges of the image, in
+    /// lens radii at the corners
+    cat_eye: f32,
+    /// Point and normal of the plane in focus when the lens is tilted, instead of the viewport
+    focus_plane: Option<(Vec3, Vec3)>,
+    /// Ratio of the height to the width of the aperture as seen in the image
+    squeeze: f32,
+    /// Lateral and longitudinal chromatic aberration, of blue relative to green and green
+    /// relative to red
+    aberration: Vec2,
+    /// Of positions in pixels
+    filter: FilterSampler,
 }
 
 impl Camera {
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
         origin: Vec3,
         look_at: Vec3,
@@ -63,18 +80,263 @@ impl Camera {
             v,
             lens_radius: aperture / 2.,
             shutter_time,
+            rolling_shutter: None,
+            aperture: None,
+            cat_eye: 0.,
+            focus_plane: None,
+            squeeze: 1.,
+            aberration: Vec2::zero(),
+            filter: FilterSampler::new(PixelFilter::Box),
+        }
+    }
+
+    /// Place samples in pixels in proportion to `filter`, which can reach into the neighboring
+    /// pixels
+    pub fn with_filter(self, filter: PixelFilter) -> Self {
+        Self {
+            filter: FilterSampler::new(filter),
+            ..self
+        }
+    }
+
+    /// Focus colors differently like a lens with chromatic aberration, which fringes edges.
+    /// `lateral` magnifies blue that much more than green and green than red, towards the edges
+    /// of the image, and `longitudinal` focuses them that much farther, relative to the focus
+    /// distance. Every sample is then of one color, so the image is noisier.
+    pub fn with_chromatic_aberration(self, lateral: f32, longitudinal: f32) -> Self {
+        Self {
+            aberration: Vec2::new(lateral, longitudinal),
+            ..self
+        }
+    }
+
+    /// Narrow the aperture horizontally by `squeeze` like an anamorphic lens, which squeezes
+    /// the image horizontally onto the sensor to be stretched back, making out of focus
+    /// highlights oval
+    pub fn with_anamorphic_squeeze(self, squeeze: f32) -> Self {
+        Self {
+            squeeze: squeeze.max(1.),
+            ..self
+        }
+    }
+
+    /// Shift the lens parallel to the image by `shift` times the width and height of the image,
+    /// which moves the view without turning the camera, so vertical lines stay parallel when
+    /// looking up at buildings
+    pub fn with_shift(self, shift: Vec2) -> Self {
+        Self {
+            lower_left_corner: self.lower_left_corner
+                + shift.x * self.horizontal
+                + shift.y * self.vertical,
+            ..self
+        }
+    }
+
+    /// Tilt the plane in focus around the point in focus at the center of the lens, by `degrees`
+    /// around the vertical and horizontal axes of the image, like tilting the lens of a view
+    /// camera. Positive angles move the plane away from the camera towards the right and the
+    /// top. Tilts near 90 degrees make the plane in focus pass through the camera.
+    pub fn with_tilt(self, degrees: Vec2) -> Self {
+        if degrees == Vec2::zero() {
+            return self;
+        }
+        let w = self.u.cross(self.v);
+        // The viewport is at the focus distance
+        let center = self.origin + w * (self.lower_left_corner - self.origin).dot(w);
+        let slope = Vec2::new(degrees.x.to_radians().tan(), degrees.y.to_radians().tan());
+        let normal = (w + self.u * slope.x + self.v * slope.y).normalized();
+        Self {
+            focus_plane: Some((center, normal)),
+            ..self
+        }
+    }
+
+    /// Let light through the square around the lens in proportion to the first channel of
+    /// `image`, with the top row up, which shapes out of focus highlights like it. `image` must
+    /// not be empty.
+    pub fn with_aperture(self, image: &Image) -> Self {
+        let weights = image.pixels.iter().map(|pixel| pixel.x).collect();
+        Self {
+            aperture: Some(Distribution2D::new(weights, image.width, image.height)),
+            ..self
         }
     }
 
-    pub fn get_ray(&self, rng: &mut impl Rng, uv: Vec2) -> Ray {
-        let rd = self.lens_radius * random_in_disc(rng);
+    /// Clip the aperture towards the edges of the image by another one, like the barrel of a
+    /// real lens, which darkens the edges and gives out of focus highlights there the shape of
+    /// a cat's eye. `strength` is how far the other aperture is offset in the corners, in
+    /// radii of the lens.
+    pub fn with_cat_eye(self, strength: f32) -> Self {
+        Self {
+            cat_eye: strength.max(0.),
+            ..self
+        }
+    }
+
+    /// Expose rows from the top down like the rolling shutter of a CMOS sensor, each for
+    /// `exposure` of the shutter time, from 0 to 1, which skews fast objects
+    pub fn with_rolling_shutter(self, exposure: f32) -> Self {
+        Self {
+            rolling_shutter: Some(exposure.clamp(0., 1.)),
+            ..self
+        }
+    }
+
+    /// Times during which the row at height `v` in the viewport is exposed
+    fn exposure_time(&self, v: f32) -> Range<f32> {
+        let Range { start, end } = self.shutter_time;
+        match self.rolling_shutter {
+            Some(exposure) => {
+                let duration = end - start;
+                let start = start + (1. - v).clamp(0., 1.) * (1. - exposure) * duration;
+                start..start + exposure * duration
+            }
+            None => start..end,
+        }
+    }
+
+    /// Point on the lens chosen with `u` in the unit square, in lens radii from its center
+    fn lens_point(&self, u: Vec2) -> Vec2 {
+        match &self.aperture {
+            Some(aperture) => {
+                let (p, _) = aperture.sample(u);
+                Vec2::new(p.x * 2. - 1., 1. - p.y * 2.)
+            }
+            None => concentric_disc(u),
+        }
+    }
+
+    /// Ray through a random point of the pixel whose lower left corner is at `uv`, when the
+    /// viewport goes from zero to one and a pixel is `pixel_size` in size, with differentials
+    /// through the same point of the next pixels
+    pub fn get_ray(&self, sampler: &mut Sampler<impl Rng>, uv: Vec2, pixel_size: Vec2) -> Ray {
+        self.sample_ray(sampler, uv, pixel_size).0
+    }
+
+    /// Ray like [`Camera::get_ray`] and the fraction of its light in each channel which reaches
+    /// the image, which is zero where cat-eye vignetting blocks it
+    pub fn sample_ray(
+        &self,
+        sampler: &mut Sampler<impl Rng>,
+        uv: Vec2,
+        pixel_size: Vec2,
+    ) -> (Ray, Vec3) {
+        let uv = uv + (Vec2::broadcast(0.5) + self.filter.sample(sampler.next_2d())) * pixel_size;
+        self.ray_through(sampler, uv, pixel_size)
+    }
+
+    /// Ray like [`Camera::sample_ray`] through exactly `uv` in the viewport instead of a point
+    /// chosen by the filter
+    pub fn ray_through(
+        &self,
+        sampler: &mut Sampler<impl Rng>,
+        uv: Vec2,
+        pixel_size: Vec2,
+    ) -> (Ray, Vec3) {
+        // With chromatic aberration, a ray is of one channel, which carries the light of all
+        let (channel, mut weight) = if self.aberration == Vec2::zero() {
+            (0., Vec3::one())
+        } else {
+            let channel = sampler.gen_range(0..COLOR_CHANNELS);
+            let mut weight = Vec3::zero();
+            weight[channel] = COLOR_CHANNELS as f32;
+            // Red, green and blue
+            (channel as f32 - 1., weight)
+        };
+        let magnification = 1. + self.aberration.x * channel;
+        let focus = 1. + self.aberration.y * channel;
+        let lens = self.lens_point(sampler.next_2d());
+        // The other aperture is offset from the center of the image outwards
+        let clip = lens - self.cat_eye * (uv * 2. - Vec2::one()) / 2f32.sqrt();
+        if self.cat_eye > 0. && clip.mag_sq() > 1. {
+            weight = Vec3::zero();
+        }
+        let rd = self.lens_radius * Vec2::new(lens.x / self.squeeze, lens.y);
         let offset = self.u * rd.x + self.v * rd.y;
-        Ray::new(
-            self.origin + offset,
-            self.lower_left_corner + uv.x * self.horizontal + uv.y * self.vertical
-                - self.origin
-                - offset,
-            rng.gen_range(self.shutter_time.clone()),
+        let origin = self.origin + offset;
+        let direction = |uv: Vec2| {
+            let uv = Vec2::broadcast(0.5) + (uv - Vec2::broadcast(0.5)) * magnification;
+            let target = self.lower_left_corner + uv.x * self.horizontal + uv.y * self.vertical;
+            match self.focus_plane {
+                Some((center, normal)) => {
+                    let pinhole = target - self.origin;
+                    let facing = pinhole.dot(normal);
+                    // To where the ray through the center of the lens meets the tilted plane,
+                    // or parallel to it when the plane is behind, like focusing at infinity
+                    if facing < 0. {
+                        pinhole * ((center - self.origin).dot(normal) / facing * focus) - offset
+                    } else {
+                        pinhole
+                    }
+                }
+                None => (target - self.origin) * focus - offset,
+            }
+        };
+        // Rows exposed for no time at all are seen at an instant
+        let time = self.exposure_time(uv.y);
+        let time = if time.start < time.end {
+            sampler.gen_range(time)
+        } else {
+            time.start
+        };
+        let ray = Ray::new(origin, direction(uv), time).with_differentials(Some(Differentials {
+            origins: [origin; 2],
+            directions: [
+                direction(uv + Vec2::new(pixel_size.x, 0.)).normalized(),
+                direction(uv + Vec2::new(0., pixel_size.y)).normalized(),
+            ],
+        }));
+        (ray, weight)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sampler::SamplerKind;
+    use rand_xorshift::XorShiftRng;
+
+    fn camera(shutter_time: Range<f32>) -> Camera {
+        Camera::new(
+            Vec3::zero(),
+            -Vec3::unit_z(),
+            Vec3::unit_y(),
+            60.,
+            1.,
+            0.,
+            1.,
+            shutter_time,
         )
     }
+
+    /// Times of rays through the middle of rows from the bottom to the top of the viewport
+    fn row_times(camera: &Camera) -> Vec<f32> {
+        let mut rng = XorShiftRng::seed_from_u64(0);
+        let mut sampler = Sampler::new(&mut rng, SamplerKind::Random, 1);
+        (0..8)
+            .map(|row| {
+                let uv = Vec2::new(0.5, (row as f32 + 0.5) / 8.);
+                camera
+                    .ray_through(&mut sampler, uv, Vec2::broadcast(0.1))
+                    .0
+                    .time()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn rows_without_exposure_are_seen_at_an_instant() {
+        let times = row_times(&camera(0.0..2.0).with_rolling_shutter(0.));
+        // The top row is exposed first and the bottom one last
+        for (row, &time) in times.iter().enumerate() {
+            let expected = 2. * (1. - (row as f32 + 0.5) / 8.);
+            assert!(
+                (time - expected).abs() < 1e-5,
+                "{} is not {}",
+                time,
+                expected
+            );
+        }
+        assert!(row_times(&camera(1.0..1.0)).iter().all(|&time| time == 1.));
+    }
 }
@@ -0,0 +1,270 @@
+//! Camera moves read from the animation of a camera node in a glTF file, such as one exported
+//! from Blender
+
+use anyhow::{anyhow, Context, Result};
+use gltf::{
+    animation::{util::ReadOutputs, Interpolation},
+    buffer::Source,
+    camera::Projection,
+    Gltf,
+};
+use std::{fs, path::Path};
+use ultraviolet::{Mat4, Rotor3, Vec3};
+
+/// Keyframes of one property of a node, with translations and scales padded to four values
+struct Track {
+    /// Ascending, in seconds
+    times: Vec<f32>,
+    /// One per time, or an in-tangent, a value and an out-tangent per time with cubic splines
+    values: Vec<[f32; 4]>,
+    interpolation: Interpolation,
+}
+
+impl Track {
+    /// Value at `time`, which is held before the first and after the last keyframe
+    fn sample(&self, time: f32, rotation: bool) -> [f32; 4] {
+        let stride = match self.interpolation {
+            Interpolation::CubicSpline => 3,
+            _ => 1,
+        };
+        let value = |i: usize| self.values[i * stride + stride / 2];
+        let next = self.times.partition_point(|&t| t <= time);
+        if next == 0 {
+            return value(0);
+        }
+        if next == self.times.len() {
+            return value(next - 1);
+        }
+        let (i, dt) = (next - 1, self.times[next] - self.times[next - 1]);
+        let t = (time - self.times[i]) / dt;
+        let [a, b] = [value(i), value(next)];
+        match self.interpolation {
+            Interpolation::Step => a,
+            Interpolation::Linear if rotation => slerp(a, b, t),
+            Interpolation::Linear => lerp(a, b, t),
+            Interpolation::CubicSpline => {
+                // Hermite spline with the out-tangent of the first and the in-tangent of the
+                // second keyframe
+                let (out, into) = (self.values[i * 3 + 2], self.values[next * 3]);
+                let (t2, t3) = (t * t, t * t * t);
+                let weights = [
+                    2. * t3 - 3. * t2 + 1.,
+                    (t3 - 2. * t2 + t) * dt,
+                    -2. * t3 + 3. * t2,
+                    (t3 - t2) * dt,
+                ];
+                let mut value = [0.; 4];
+                for (c, v) in value.iter_mut().enumerate() {
+                    *v = weights[0] * a[c]
+                        + weights[1] * out[c]
+                        + weights[2] * b[c]
+                        + weights[3] * into[c];
+                }
+                if rotation {
+                    normalized(value)
+                } else {
+                    value
+                }
+            }
+        }
+    }
+}
+
+fn lerp(a: [f32; 4], b: [f32; 4], t: f32) -> [f32; 4] {
+    let mut value = a;
+    for (v, b) in value.iter_mut().zip(b) {
+        *v += (b - *v) * t;
+    }
+    value
+}
+
+fn normalized(q: [f32; 4]) -> [f32; 4] {
+    let length = q.iter().map(|c| c * c).sum::<f32>().sqrt();
+    q.map(|c| c / length)
+}
+
+/// Spherical interpolation of quaternions along the shorter arc
+fn slerp(a: [f32; 4], b: [f32; 4], t: f32) -> [f32; 4] {
+    let dot: f32 = a.iter().zip(&b).map(|(a, b)| a * b).sum();
+    let (b, dot) = if dot < 0. {
+        (b.map(|c| -c), -dot)
+    } else {
+        (b, dot)
+    };
+    if dot > 0.9995 {
+        return normalized(lerp(a, b, t));
+    }
+    let angle = dot.acos();
+    let (wa, wb) = (
+        ((1. - t) * angle).sin() / angle.sin(),
+        (t * angle).sin() / angle.sin(),
+    );
+    let mut value = [0.; 4];
+    for (c, v) in value.iter_mut().enumerate() {
+        *v = wa * a[c] + wb * b[c];
+    }
+    value
+}
+
+/// Transform of a node relative to its parent, of which any part may be animated
+struct PathNode {
+    translation: [f32; 3],
+    rotation: [f32; 4],
+    scale: [f32; 3],
+    tracks: [Option<Track>; 3],
+}
+
+impl PathNode {
+    fn transform(&self, time: f32) -> Mat4 {
+        let sample = |i: usize, rotation| {
+            self.tracks[i]
+                .as_ref()
+                .map(|track| track.sample(time, rotation))
+        };
+        let [t, s] = [(0, self.translation), (2, self.scale)].map(|(i, rest)| {
+            sample(i, false).map_or(Vec3::from(rest), |v| Vec3::new(v[0], v[1], v[2]))
+        });
+        let r = Rotor3::from_quaternion_array(sample(1, true).unwrap_or(self.rotation));
+        Mat4::from_translation(t)
+            * r.into_matrix().into_homogeneous()
+            * Mat4::from_nonuniform_scale(s)
+    }
+}
+
+/// Animated camera node and its ancestors
+pub struct CameraPath {
+    /// From the root to the camera
+    nodes: Vec<PathNode>,
+    /// In radians, if the camera has a perspective projection
+    vertical_fov: Option<f32>,
+}
+
+impl CameraPath {
+    /// Read the camera node called `node`, or the first camera node if there is no name, from
+    /// a .gltf or .glb file. Buffers must be embedded in .glb files or be separate files.
+    pub fn open(path: &Path, node: Option<&str>) -> Result<Self> {
+        let bytes = fs::read(path).with_context(|| format!("Cannot read {}", path.display()))?;
+        Self::parse(&bytes, path.parent().unwrap_or_else(|| Path::new("")), node)
+            .with_context(|| format!("Cannot parse {}", path.display()))
+    }
+
+    fn parse(bytes: &[u8], dir: &Path, node: Option<&str>) -> Result<Self> {
+        let gltf = Gltf::from_slice(bytes)?;
+        let buffers = gltf
+            .buffers()
+            .map(|buffer| match buffer.source() {
+                Source::Bin => gltf
+                    .blob
+                    .clone()
+                    .ok_or_else(|| anyhow!("Binary chunk is missing")),
+                Source::Uri(uri) if uri.starts_with("data:") => {
+                    Err(anyhow!("Embedded buffers are not supported"))
+                }
+                Source::Uri(uri) => {
+                    let path = dir.join(uri);
+                    fs::read(&path).with_context(|| format!("Cannot read {}", path.display()))
+                }
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        let camera = gltf
+            .nodes()
+            .filter(|n| n.camera().is_some())
+            .find(|n| node.is_none() || n.name() == node)
+            .ok_or_else(|| match node {
+                Some(name) => anyhow!("There is no camera node called {}", name),
+                None => anyhow!("There is no camera node"),
+            })?;
+        let mut parents = vec![None; gltf.nodes().len()];
+        for parent in gltf.nodes() {
+            for child in parent.children() {
+                parents[child.index()] = Some(parent.index());
+            }
+        }
+        let mut chain = vec![camera.index()];
+        while let Some(parent) = parents[*chain.last().unwrap()] {
+            if chain.contains(&parent) {
+                return Err(anyhow!("Node hierarchy has a cycle"));
+            }
+            chain.push(parent);
+        }
+        chain.reverse();
+
+        let mut nodes: Vec<PathNode> = chain
+            .iter()
+            .map(|&index| {
+                let (translation, rotation, scale) = gltf
+                    .nodes()
+                    .nth(index)
+                    .expect("Node is in the document")
+                    .transform()
+                    .decomposed();
+                PathNode {
+                    translation,
+                    rotation,
+                    scale,
+                    tracks: [None, None, None],
+                }
+            })
+            .collect();
+        for channel in gltf.animations().flat_map(|animation| animation.channels()) {
+            let target = channel.target().node().index();
+            let node = match chain.iter().position(|&i| i == target) {
+                Some(node) => node,
+                None => continue,
+            };
+            let reader = channel.reader(|buffer| buffers.get(buffer.index()).map(Vec::as_slice));
+            let times: Vec<f32> = reader
+                .read_inputs()
+                .ok_or_else(|| anyhow!("Animation has no keyframe times"))?
+                .collect();
+            let pad = |[x, y, z]: [f32; 3]| [x, y, z, 0.];
+            let (property, values): (usize, Vec<_>) = match reader
+                .read_outputs()
+                .ok_or_else(|| anyhow!("Animation has no values"))?
+            {
+                ReadOutputs::Translations(values) => (0, values.map(pad).collect()),
+                ReadOutputs::Rotations(values) => (1, values.into_f32().collect()),
+                ReadOutputs::Scales(values) => (2, values.map(pad).collect()),
+                ReadOutputs::MorphTargetWeights(_) => continue,
+            };
+            let interpolation = channel.sampler().interpolation();
+            let stride = if interpolation == Interpolation::CubicSpline {
+                3
+            } else {
+                1
+            };
+            if times.is_empty() || values.len() != times.len() * stride {
+                return Err(anyhow!("Animation has mismatched keyframes"));
+            }
+            nodes[node].tracks[property] = Some(Track {
+                times,
+                values,
+                interpolation,
+            });
+        }
+
+        Ok(Self {
+            nodes,
+            vertical_fov: camera
+                .camera()
+                .and_then(|camera| match camera.projection() {
+                    Projection::Perspective(perspective) => Some(perspective.yfov()),
+                    Projection::Orthographic(_) => None,
+                }),
+        })
+    }
+
+    /// Transform from the space of the camera, which looks along -z with y up, to the world
+    /// `time` seconds into the animation
+    pub fn transform(&self, time: f32) -> Mat4 {
+        self.nodes.iter().fold(Mat4::identity(), |parent, node| {
+            parent * node.transform(time)
+        })
+    }
+
+    /// In radians, if the camera has a perspective projection
+    pub fn vertical_fov(&self) -> Option<f32> {
+        self.vertical_fov
+    }
+}
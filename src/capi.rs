@@ -0,0 +1,265 @@
+//! Stable C ABI for embedding the renderer, see `include/rt.h`.
+//!
+//! The header is generated with `cbindgen --config cbindgen.toml --output include/rt.h`.
+
+use crate::{
+    render::{CancellationToken, Frame, Renderer},
+    scene::{CameraSpec, Keyframes, MaterialSpec, ObjectSpec, Scene, SurfaceSpec},
+    world::Visibility,
+};
+use rand::prelude::*;
+use rand_xorshift::XorShiftRng;
+use std::{
+    convert::TryFrom,
+    os::raw::{c_int, c_void},
+    sync::mpsc::{self, RecvTimeoutError},
+    time::Duration,
+};
+
+pub const RT_OK: c_int = 0;
+pub const RT_ERROR_INVALID_ARGUMENT: c_int = -1;
+pub const RT_ERROR_RENDER_FAILED: c_int = -2;
+
+/// Opaque scene handle
+pub struct RtScene(Scene);
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub enum RtMaterialKind {
+    RtLambertian,
+    RtMetal,
+    RtDielectric,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct RtMaterial {
+    pub kind: RtMaterialKind,
+    /// Used by Lambertian and metal materials
+    pub albedo: [f32; 3],
+    /// Used by metal materials
+    pub fuzz: f32,
+    /// Used by dielectric materials
+    pub refraction: f32,
+}
+
+impl From<RtMaterial> for MaterialSpec {
+    fn from(material: RtMaterial) -> Self {
+        match material.kind {
+            RtMaterialKind::RtLambertian => Self::Lambertian {
+                albedo: material.albedo,
+            },
+            RtMaterialKind::RtMetal => Self::Metal {
+                albedo: material.albedo,
+                fuzz: material.fuzz,
+            },
+            RtMaterialKind::RtDielectric => Self::Dielectric {
+                refraction: material.refraction,
+                roughness: 0.,
+                volume: None,
+            },
+        }
+    }
+}
+
+/// Called with the number of finished and total tiles
+pub type RtProgressCallback = Option<extern "C" fn(u32, u32, *mut c_void)>;
+
+/// Create an empty scene with a camera at (0, 0, 1) looking at the origin
+#[no_mangle]
+pub extern "C" fn rt_scene_new() -> *mut RtScene {
+    Box::into_raw(Box::new(RtScene(Scene::new(CameraSpec::default()))))
+}
+
+/// Create the random spheres scene
+#[no_mangle]
+pub extern "C" fn rt_scene_new_random(seed: u64) -> *mut RtScene {
+    Box::into_raw(Box::new(RtScene(Scene::random(
+        &mut XorShiftRng::seed_from_u64(seed),
+    ))))
+}
+
+/// # Safety
+///
+/// `scene` must come from [`rt_scene_new`] or [`rt_scene_new_random`], or be null.
+#[no_mangle]
+pub unsafe extern "C" fn rt_scene_free(scene: *mut RtScene) {
+    if !scene.is_null() {
+        drop(Box::from_raw(scene));
+    }
+}
+
+/// # Safety
+///
+/// `scene` must be a valid scene, `center` must point to 3 floats and `material` to a material.
+#[no_mangle]
+pub unsafe extern "C" fn rt_scene_add_sphere(
+    scene: *mut RtScene,
+    center: *const f32,
+    radius: f32,
+    material: *const RtMaterial,
+) -> c_int {
+    let (scene, material) = match (scene.as_mut(), material.as_ref()) {
+        (Some(scene), Some(material)) if !center.is_null() => (scene, material),
+        _ => return RT_ERROR_INVALID_ARGUMENT,
+    };
+    let surface = scene.0.add_surface(SurfaceSpec::Sphere { radius });
+    let material = scene.0.add_material((*material).into());
+    scene.0.objects.push(ObjectSpec {
+        name: None,
+        surface,
+        material,
+        position: *(center as *const [f32; 3]),
+        velocity: [0.; 3],
+        visibility: Visibility::default(),
+        priority: 0,
+    });
+    RT_OK
+}
+
+/// Add a triangle mesh with `vertex_count` xyz positions and `triangle_count` index triplets
+///
+/// # Safety
+///
+/// `scene` must be a valid scene, and the arrays must have as many elements as declared.
+#[no_mangle]
+pub unsafe extern "C" fn rt_scene_add_mesh(
+    scene: *mut RtScene,
+    positions: *const f32,
+    vertex_count: usize,
+    indices: *const u32,
+    triangle_count: usize,
+    material: *const RtMaterial,
+) -> c_int {
+    let (scene, material) = match (scene.as_mut(), material.as_ref()) {
+        (Some(scene), Some(material)) if !positions.is_null() && !indices.is_null() => {
+            (scene, material)
+        }
+        _ => return RT_ERROR_INVALID_ARGUMENT,
+    };
+    let positions = std::slice::from_raw_parts(positions as *const [f32; 3], vertex_count);
+    let indices = std::slice::from_raw_parts(indices as *const [u32; 3], triangle_count);
+    let material = scene.0.add_material((*material).into());
+    match scene.0.add_mesh(positions, indices, material) {
+        Ok(()) => RT_OK,
+        Err(_) => RT_ERROR_INVALID_ARGUMENT,
+    }
+}
+
+/// # Safety
+///
+/// `scene` must be a valid scene and the vectors must point to 3 floats each.
+#[no_mangle]
+pub unsafe extern "C" fn rt_scene_set_camera(
+    scene: *mut RtScene,
+    look_from: *const f32,
+    look_at: *const f32,
+    up: *const f32,
+    vertical_fov_degrees: f32,
+    aperture: f32,
+    focus_distance: f32,
+) -> c_int {
+    let scene = match scene.as_mut() {
+        Some(scene) if !look_from.is_null() && !look_at.is_null() && !up.is_null() => scene,
+        _ => return RT_ERROR_INVALID_ARGUMENT,
+    };
+    let vec3 = |v: *const f32| *(v as *const [f32; 3]);
+    // A path, keyframes or a focus object would override what is set here
+    scene.0.camera = CameraSpec {
+        look_from: vec3(look_from),
+        look_at: vec3(look_at),
+        up: vec3(up),
+        vertical_fov_degrees,
+        aperture,
+        focus_distance,
+        path: None,
+        focus_distance_keys: Keyframes::default(),
+        aperture_keys: Keyframes::default(),
+        focus_object: None,
+        ..std::mem::take(&mut scene.0.camera)
+    };
+    RT_OK
+}
+
+/// Render the scene into `out` as `width * height` 8bpp RGB pixels, top row first.
+/// Uses `nthreads` threads, or all cores when it is 0. `progress` is called from the calling
+/// thread while rendering.
+///
+/// # Safety
+///
+/// `scene` must be a valid scene and `out` must be valid for writes of `width * height * 3` bytes.
+#[no_mangle]
+pub unsafe extern "C" fn rt_render(
+    scene: *const RtScene,
+    width: u32,
+    height: u32,
+    samples_per_pixel: u32,
+    nthreads: u32,
+    out: *mut u8,
+    progress: RtProgressCallback,
+    user_data: *mut c_void,
+) -> c_int {
+    let scene = match scene.as_ref() {
+        Some(scene) if !out.is_null() && width > 0 && height > 0 => scene,
+        _ => return RT_ERROR_INVALID_ARGUMENT,
+    };
+    let nthreads = match nthreads {
+        0 => num_cpus::get(),
+        n => n as usize,
+    };
+
+    let mut renderer = match Renderer::new(
+        &scene.0,
+        0,
+        width as usize,
+        height as usize,
+        samples_per_pixel,
+    ) {
+        Ok(renderer) => renderer,
+        Err(_) => return RT_ERROR_INVALID_ARGUMENT,
+    };
+    if renderer.train_guide(nthreads).is_err() {
+        return RT_ERROR_RENDER_FAILED;
+    }
+    let frame = Frame::new(&renderer, &(), CancellationToken::new());
+    let report = || {
+        if let Some(progress) = progress {
+            let tiles = |n| u32::try_from(n).unwrap_or(u32::MAX);
+            progress(
+                tiles(frame.tiles_done()),
+                tiles(frame.tiles_total()),
+                user_data,
+            );
+        }
+    };
+
+    let result = crossbeam_utils::thread::scope(|s| {
+        // Each thread holds a sender until it returns or unwinds, so the channel disconnects
+        // once all of them are gone, whether or not the frame got finished
+        let (alive, exited) = mpsc::channel::<()>();
+        let threads: Vec<_> = (0..nthreads)
+            .map(|_| {
+                let (frame, renderer, alive) = (&frame, &renderer, alive.clone());
+                s.spawn(move |_| {
+                    let _alive = alive;
+                    frame.work(renderer)
+                })
+            })
+            .collect();
+        drop(alive);
+        while let Err(RecvTimeoutError::Timeout) = exited.recv_timeout(Duration::from_millis(50)) {
+            report();
+        }
+        threads
+            .into_iter()
+            .all(|thread| matches!(thread.join(), Ok(Ok(()))))
+    });
+    if !matches!(result, Ok(true)) || !frame.finished() {
+        return RT_ERROR_RENDER_FAILED;
+    }
+    report();
+
+    let image = frame.into_image();
+    std::ptr::copy_nonoverlapping(image.as_ptr(), out, image.len());
+    RT_OK
+}
@@ -1,5 +1,9 @@
+use crate::lut::Lut;
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
 use ultraviolet::Vec3;
 
+#[derive(Clone, Copy)]
 pub struct Color(Vec3);
 
 impl From<Vec3> for Color {
@@ -24,12 +28,229 @@ impl Color {
     }
 }
 
+/// Relative luminance of linear sRGB
+pub fn luminance(color: Vec3) -> f32 {
+    color.dot(Vec3::new(0.2126, 0.7152, 0.0722))
+}
+
+/// RGB primaries and white point in which colors are rendered
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ColorSpace {
+    /// Linear Rec. 709, the primaries of sRGB
+    #[default]
+    LinearSrgb,
+    /// The AP1 primaries of ACES with its D60 white point, whose wider gamut makes products of
+    /// colors closer to those of spectra
+    AcesCg,
+}
+
+/// Linear sRGB to ACEScg, with Bradford adaptation from D65 to D60
+const SRGB_TO_ACESCG: [[f32; 3]; 3] = [
+    [0.613_097_4, 0.339_523_1, 0.047_379_5],
+    [0.070_193_7, 0.916_353_9, 0.013_452_4],
+    [0.020_615_6, 0.109_569_8, 0.869_815_1],
+];
+
+const ACESCG_TO_SRGB: [[f32; 3]; 3] = [
+    [1.705_051, -0.621_792, -0.083_259],
+    [-0.130_256, 1.140_805, -0.010_548],
+    [-0.024_003, -0.128_969, 1.152_972],
+];
+
+fn transform(matrix: &[[f32; 3]; 3], color: Vec3) -> Vec3 {
+    let [x, y, z] = matrix.map(|row| Vec3::from(row).dot(color));
+    Vec3::new(x, y, z)
+}
+
+impl ColorSpace {
+    /// From linear sRGB, such as that of images read from files
+    pub fn from_srgb(self, color: Vec3) -> Vec3 {
+        match self {
+            Self::LinearSrgb => color,
+            Self::AcesCg => transform(&SRGB_TO_ACESCG, color),
+        }
+    }
+
+    /// To linear sRGB, for displaying. Colors outside of the sRGB gamut have negative
+    /// components.
+    pub fn to_srgb(self, color: Vec3) -> Vec3 {
+        match self {
+            Self::LinearSrgb => color,
+            Self::AcesCg => transform(&ACESCG_TO_SRGB, color),
+        }
+    }
+
+    /// Relative luminance of a color in this space
+    pub fn luminance(self, color: Vec3) -> f32 {
+        match self {
+            Self::LinearSrgb => luminance(color),
+            Self::AcesCg => color.dot(Vec3::new(0.272_228_7, 0.674_081_8, 0.053_689_5)),
+        }
+    }
+
+    /// CIE xy chromaticities of the red, green and blue primaries and the white point
+    pub fn chromaticities(self) -> [[f32; 2]; 4] {
+        match self {
+            Self::LinearSrgb => [[0.64, 0.33], [0.3, 0.6], [0.15, 0.06], [0.3127, 0.329]],
+            Self::AcesCg => [
+                [0.713, 0.293],
+                [0.165, 0.83],
+                [0.128, 0.044],
+                [0.32168, 0.33767],
+            ],
+        }
+    }
+}
+
+/// Color of the light radiated by a black body at a temperature in kelvin, in linear sRGB with a
+/// luminance of 1. Temperatures far from white are clipped to the gamut.
+pub fn blackbody(kelvin: f32) -> Vec3 {
+    // Piecewise Gaussian fit of the CIE 1931 color matching functions, from Wyman et al.,
+    // "Simple Analytic Approximations to the CIE XYZ Color Matching Functions"
+    let g = |x: f64, mu: f64, sigma_low: f64, sigma_high: f64| {
+        let sigma = if x < mu { sigma_low } else { sigma_high };
+        (-0.5 * ((x - mu) / sigma).powi(2)).exp()
+    };
+    let kelvin = f64::from(kelvin.max(1.));
+    let mut xyz = [0f64; 3];
+    for nm in (360..=830).step_by(5) {
+        let nm = f64::from(nm);
+        // Planck's law without its constant factor, which normalizing removes
+        let meters = nm * 1e-9;
+        let radiance = 1. / (meters.powi(5) * ((1.438_776_9e-2 / (meters * kelvin)).exp() - 1.));
+        xyz[0] += radiance
+            * (1.056 * g(nm, 599.8, 37.9, 31.0) + 0.362 * g(nm, 442.0, 16.0, 26.7)
+                - 0.065 * g(nm, 501.1, 20.4, 26.2));
+        xyz[1] += radiance * (0.821 * g(nm, 568.8, 46.9, 40.5) + 0.286 * g(nm, 530.9, 16.3, 31.1));
+        xyz[2] += radiance * (1.217 * g(nm, 437.0, 11.8, 36.0) + 0.681 * g(nm, 459.0, 26.0, 13.8));
+    }
+    if xyz[1] <= 0. {
+        return Vec3::one();
+    }
+    let [x, y, z] = xyz.map(|c| (c / xyz[1]) as f32);
+    let rgb = Vec3::new(
+        3.240_454 * x - 1.537_139 * y - 0.498_531 * z,
+        -0.969_266 * x + 1.876_011 * y + 0.041_556 * z,
+        0.055_643 * x - 0.204_026 * y + 1.057_225 * z,
+    )
+    .max_by_component(Vec3::zero());
+    rgb / luminance(rgb)
+}
+
+/// Bits of the nearest IEEE 754 half precision float to `value`, rounding ties to even.
+/// Values beyond the largest half float, 65504, become infinite.
+pub fn f32_to_f16(value: f32) -> u16 {
+    let bits = value.to_bits();
+    let sign = (bits >> 16 & 0x8000) as u16;
+    let exponent = (bits >> 23 & 0xff) as i32;
+    let mantissa = bits & 0x7f_ffff;
+    if exponent == 0xff {
+        // Infinity, or NaN which stays NaN with a mantissa bit set
+        return sign | 0x7c00 | if mantissa != 0 { 0x200 } else { 0 };
+    }
+    let exponent = exponent - 127 + 15;
+    if exponent >= 0x1f {
+        return sign | 0x7c00;
+    }
+    // Subnormal half floats have their implicit leading bit in the mantissa
+    let (mantissa, shift, exponent) = if exponent > 0 {
+        (mantissa, 13, exponent as u32)
+    } else if exponent >= -10 {
+        (mantissa | 0x80_0000, (14 - exponent) as u32, 0)
+    } else {
+        return sign;
+    };
+    let truncated = mantissa >> shift;
+    let rest = mantissa & ((1 << shift) - 1);
+    let halfway = 1 << (shift - 1);
+    let round_up = rest > halfway || rest == halfway && truncated & 1 == 1;
+    // Rounding up can carry into the exponent, up to infinity
+    sign | ((exponent << 10) + truncated + u32::from(round_up)) as u16
+}
+
+/// Value of the half precision float with `bits`
+pub fn f16_to_f32(bits: u16) -> f32 {
+    let sign = u32::from(bits & 0x8000) << 16;
+    let exponent = u32::from(bits >> 10 & 0x1f);
+    let mantissa = u32::from(bits & 0x3ff);
+    match exponent {
+        0 => {
+            let magnitude = mantissa as f32 / (1 << 24) as f32;
+            f32::from_bits(sign | magnitude.to_bits())
+        }
+        0x1f => f32::from_bits(sign | 0x7f80_0000 | mantissa << 13),
+        _ => f32::from_bits(sign | (exponent + 127 - 15) << 23 | mantissa << 13),
+    }
+}
+
+/// Color in half precision, for storing images in half the memory
+pub fn to_half(color: Vec3) -> [u16; 3] {
+    [color.x, color.y, color.z].map(f32_to_f16)
+}
+
+pub fn from_half(color: [u16; 3]) -> Vec3 {
+    let [r, g, b] = color.map(f16_to_f32);
+    Vec3::new(r, g, b)
+}
+
 pub const COLOR_CHANNELS: usize = 3;
 pub type OutputColor = [u8; COLOR_CHANNELS];
 
 impl From<Color> for OutputColor {
     fn from(color: Color) -> Self {
-        let c = Vec3::from(color.sqrt().clamp(0., 0.999)) * 256.;
-        [c.x as u8, c.y as u8, c.z as u8]
+        quantize(color.sqrt())
     }
 }
+
+fn quantize(color: Color) -> OutputColor {
+    let c = Vec3::from(color.clamp(0., 0.999)) * 256.;
+    [c.x as u8, c.y as u8, c.z as u8]
+}
+
+/// How the colors of the image are encoded for viewing
+#[derive(Clone)]
+pub struct Display {
+    space: ColorSpace,
+    lut: Option<Arc<Lut>>,
+}
+
+impl Display {
+    /// Converted from `space` to sRGB with a gamma of 2
+    pub fn new(space: ColorSpace) -> Self {
+        Self { space, lut: None }
+    }
+
+    /// Transformed by `lut` from the working space instead, such as with the display and view
+    /// transform of an OpenColorIO config
+    pub fn with_lut(self, lut: Lut) -> Self {
+        Self {
+            lut: Some(Arc::new(lut)),
+            ..self
+        }
+    }
+
+    pub fn encode(&self, color: Vec3) -> OutputColor {
+        match &self.lut {
+            Some(lut) => quantize(Color::from(lut.apply(color))),
+            None => OutputColor::from(Color::from(self.space.to_srgb(color))),
+        }
+    }
+}
+
+/// Divide linear RGB `sums` of samples by the number of `samples` of each pixel. Pixels without
+/// samples are black.
+pub fn average(sums: &[f32], samples: &[u32]) -> Vec<f32> {
+    sums.chunks_exact(COLOR_CHANNELS)
+        .zip(samples)
+        .flat_map(|(sum, &n)| sum.iter().map(move |&sum| sum / n.max(1) as f32))
+        .collect()
+}
+
+/// Average linear RGB `sums` of samples like [`average`] and encode them to 8bpp RGB for
+/// `display`
+pub fn resolve(sums: &[f32], samples: &[u32], display: &Display) -> Vec<u8> {
+    average(sums, samples)
+        .chunks_exact(COLOR_CHANNELS)
+        .flat_map(|c| display.encode(Vec3::new(c[0], c[1], c[2])))
+        .collect()
+}
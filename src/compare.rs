@@ -0,0 +1,436 @@
+//! Measuring how much two images of the same size differ
+
+use rt::{color::ColorSpace, image::Image};
+use ultraviolet::Vec3;
+
+/// Side of the square windows over which [`ssim`] compares images
+const SSIM_WINDOW: usize = 8;
+
+/// Colors of differences from none to as large as shown, between which the heatmap is
+/// interpolated
+const HEAT_COLORS: [[f32; 3]; 5] = [
+    [0., 0., 0.],
+    [0., 0., 160.],
+    [200., 0., 40.],
+    [255., 200., 0.],
+    [255., 255., 255.],
+];
+
+/// Root mean square of the differences between the channels of linear RGB images
+pub fn rmse(a: &Image, b: &Image) -> f32 {
+    let sum: f32 = a
+        .pixels
+        .iter()
+        .zip(&b.pixels)
+        .map(|(&a, &b)| (a - b).mag_sq())
+        .sum();
+    (sum / (a.pixels.len() * 3).max(1) as f32).sqrt()
+}
+
+/// Luminance with a gamma of 2, like the images are displayed
+fn lightness(color: Vec3) -> f32 {
+    ColorSpace::LinearSrgb.luminance(color).max(0.).sqrt()
+}
+
+/// Mean structural similarity of the lightness of the images, over windows which overlap by
+/// half. 1 means that the images are the same.
+pub fn ssim(a: &Image, b: &Image) -> f32 {
+    const C1: f32 = 0.01 * 0.01;
+    const C2: f32 = 0.03 * 0.03;
+    let window = SSIM_WINDOW.min(a.width).min(a.height);
+    if window == 0 {
+        return 1.;
+    }
+    let (mut sum, mut windows) = (0., 0);
+    for y in (0..=a.height - window).step_by((window / 2).max(1)) {
+        for x in (0..=a.width - window).step_by((window / 2).max(1)) {
+            let pairs = (y..y + window).flat_map(|y| {
+                (x..x + window).map(move |x| (lightness(a.pixel(x, y)), lightness(b.pixel(x, y))))
+            });
+            let n = (window * window) as f32;
+            let (mut mean_a, mut mean_b) = (0., 0.);
+            for (a, b) in pairs.clone() {
+                mean_a += a / n;
+                mean_b += b / n;
+            }
+            let (mut var_a, mut var_b, mut covariance) = (0., 0., 0.);
+            for (a, b) in pairs {
+                var_a += (a - mean_a) * (a - mean_a) / n;
+                var_b += (b - mean_b) * (b - mean_b) / n;
+                covariance += (a - mean_a) * (b - mean_b) / n;
+            }
+            sum += (2. * mean_a * mean_b + C1) * (2. * covariance + C2)
+                / ((mean_a * mean_a + mean_b * mean_b + C1) * (var_a + var_b + C2));
+            windows += 1;
+        }
+    }
+    sum / windows as f32
+}
+
+/// 8bpp RGB heatmap of the absolute differences of the luminances of the images multiplied
+/// by `scale`, from black for none to white for 1 and above
+pub fn heatmap(a: &Image, b: &Image, scale: f32) -> Vec<u8> {
+    let space = ColorSpace::LinearSrgb;
+    a.pixels
+        .iter()
+        .zip(&b.pixels)
+        .flat_map(|(&a, &b)| {
+            let difference = (space.luminance(a) - space.luminance(b)).abs() * scale;
+            let t = difference.clamp(0., 1.) * (HEAT_COLORS.len() - 1) as f32;
+            let i = (t as usize).min(HEAT_COLORS.len() - 2);
+            let (from, to, t) = (HEAT_COLORS[i], HEAT_COLORS[i + 1], t - i as f32);
+            [0, 1, 2].map(|c| (from[c] + (to[c] - from[c]) * t) as u8)
+        })
+        .collect()
+}
+
+/// Linear sRGB to CIE XYZ, row by row
+const RGB_TO_XYZ: [[f32; 3]; 3] = [
+    [0.412_456_4, 0.357_576_1, 0.180_437_5],
+    [0.212_672_9, 0.715_152_2, 0.072_175],
+    [0.019_333_9, 0.119_192, 0.950_304_1],
+];
+const XYZ_TO_RGB: [[f32; 3]; 3] = [
+    [3.240_454_2, -1.537_138_5, -0.498_531_4],
+    [-0.969_266, 1.876_010_8, 0.041_556],
+    [0.055_643_4, -0.204_025_9, 1.057_225_2],
+];
+
+fn transform(matrix: &[[f32; 3]; 3], v: Vec3) -> Vec3 {
+    let [x, y, z] = matrix.map(|row| Vec3::from(row).dot(v));
+    Vec3::new(x, y, z)
+}
+
+/// Of white in linear sRGB
+fn white() -> Vec3 {
+    transform(&RGB_TO_XYZ, Vec3::one())
+}
+
+/// Linear sRGB to the opponent space of FLIP, which has lightness and two chromatic channels
+fn to_ycxcz(color: Vec3) -> Vec3 {
+    let xyz = transform(&RGB_TO_XYZ, color) / white();
+    Vec3::new(
+        116. * xyz.y - 16.,
+        500. * (xyz.x - xyz.y),
+        200. * (xyz.y - xyz.z),
+    )
+}
+
+fn from_ycxcz(ycxcz: Vec3) -> Vec3 {
+    let y = (ycxcz.x + 16.) / 116.;
+    let xyz = Vec3::new(ycxcz.y / 500. + y, y, y - ycxcz.z / 200.) * white();
+    transform(&XYZ_TO_RGB, xyz)
+}
+
+/// Linear sRGB to CIELAB with the lightness scaling the chromatic channels, as by the Hunt
+/// effect
+fn to_hunt_lab(color: Vec3) -> Vec3 {
+    let f = |t: f32| {
+        const DELTA: f32 = 6. / 29.;
+        if t > DELTA * DELTA * DELTA {
+            t.cbrt()
+        } else {
+            t / (3. * DELTA * DELTA) + 4. / 29.
+        }
+    };
+    let xyz = transform(&RGB_TO_XYZ, color) / white();
+    let l = 116. * f(xyz.y) - 16.;
+    Vec3::new(
+        l,
+        0.01 * l * 500. * (f(xyz.x) - f(xyz.y)),
+        0.01 * l * 200. * (f(xyz.y) - f(xyz.z)),
+    )
+}
+
+/// Color difference between Hunt adjusted CIELAB colors
+fn hyab(a: Vec3, b: Vec3) -> f32 {
+    (a.x - b.x).abs() + ((a.y - b.y).powi(2) + (a.z - b.z).powi(2)).sqrt()
+}
+
+/// Convolve `width` wide `image` with the separable kernel `horizontal` by `vertical`, both of
+/// odd length, clamping at the edges
+fn convolve(image: &[f32], width: usize, horizontal: &[f32], vertical: &[f32]) -> Vec<f32> {
+    let height = image.len() / width;
+    let pass = |image: &[f32], kernel: &[f32], dx: isize, dy: isize| -> Vec<f32> {
+        let radius = (kernel.len() / 2) as isize;
+        (0..image.len())
+            .map(|i| {
+                let (x, y) = ((i % width) as isize, (i / width) as isize);
+                kernel
+                    .iter()
+                    .enumerate()
+                    .map(|(k, weight)| {
+                        let offset = k as isize - radius;
+                        let x = (x + offset * dx).clamp(0, width as isize - 1) as usize;
+                        let y = (y + offset * dy).clamp(0, height as isize - 1) as usize;
+                        weight * image[y * width + x]
+                    })
+                    .sum()
+            })
+            .collect()
+    };
+    pass(&pass(image, horizontal, 1, 0), vertical, 0, 1)
+}
+
+/// Mean of the FLIP perceptual error between images of displayed colors by Andersson et al.,
+/// when viewed at `pixels_per_degree`, from 0 for none to 1
+pub fn flip(a: &Image, b: &Image, pixels_per_degree: f32) -> f32 {
+    const QC: f32 = 0.7;
+    const QF: f32 = 0.5;
+    const PC: f32 = 0.4;
+    const PT: f32 = 0.95;
+    // Width of edges in degrees
+    const FEATURE_WIDTH: f32 = 0.082;
+    // Contrast sensitivity of each channel, as sums of Gaussians (a1, b1) and (a2, b2)
+    const CSF: [[(f32, f32); 2]; 3] = [
+        [(1., 0.0047), (0., 1e-5)],
+        [(1., 0.0053), (0., 1e-5)],
+        [(34.1, 0.04), (13.5, 0.025)],
+    ];
+
+    let width = a.width;
+    if a.pixels.is_empty() {
+        return 0.;
+    }
+    let ycxcz = |image: &Image| -> [Vec<f32>; 3] {
+        let colors: Vec<Vec3> = image
+            .pixels
+            .iter()
+            .map(|&c| to_ycxcz(c.clamped(Vec3::zero(), Vec3::one())))
+            .collect();
+        [0, 1, 2].map(|c| colors.iter().map(|v| v[c]).collect())
+    };
+    let (a, b) = (ycxcz(a), ycxcz(b));
+
+    // Filter the colors by the contrast sensitivity of the eye at the viewing distance
+    let radius = (3. * (0.04 / (2. * std::f32::consts::PI.powi(2))).sqrt() * pixels_per_degree)
+        .ceil() as isize;
+    let gaussian = |a: f32, b: f32| -> Vec<f32> {
+        (-radius..=radius)
+            .map(|x| {
+                let x = x as f32 / pixels_per_degree;
+                a.sqrt()
+                    * (std::f32::consts::PI / b).sqrt().sqrt()
+                    * (-std::f32::consts::PI.powi(2) * x * x / b).exp()
+            })
+            .collect()
+    };
+    let filtered = |channels: &[Vec<f32>; 3]| -> Vec<Vec3> {
+        let [y, cx, cz] = [0, 1, 2].map(|c| {
+            let mut sum = vec![0.; channels[c].len()];
+            let mut total = 0.;
+            for &(weight, spread) in CSF[c].iter().filter(|&&(weight, _)| weight > 0.) {
+                // The square of the kernel is the term of the two-dimensional kernel
+                let kernel = gaussian(weight, spread);
+                total += kernel.iter().sum::<f32>().powi(2);
+                for (sum, v) in sum
+                    .iter_mut()
+                    .zip(convolve(&channels[c], width, &kernel, &kernel))
+                {
+                    *sum += v;
+                }
+            }
+            sum.into_iter().map(|v| v / total).collect::<Vec<f32>>()
+        });
+        (0..y.len())
+            .map(|i| {
+                let rgb = from_ycxcz(Vec3::new(y[i], cx[i], cz[i]));
+                to_hunt_lab(rgb.clamped(Vec3::zero(), Vec3::one()))
+            })
+            .collect()
+    };
+    let (lab_a, lab_b) = (filtered(&a), filtered(&b));
+    let cmax = hyab(to_hunt_lab(Vec3::unit_y()), to_hunt_lab(Vec3::unit_z())).powf(QC);
+
+    // Edges and points in the lightness, from the first and second derivatives of a Gaussian
+    let deviation = 0.5 * FEATURE_WIDTH / pixels_per_degree;
+    let feature_radius = (3. * deviation * pixels_per_degree).ceil() as isize;
+    let features = |y: &[f32], second: bool| -> Vec<f32> {
+        let mut kernel: Vec<f32> = (-feature_radius..=feature_radius)
+            .flat_map(|y| (-feature_radius..=feature_radius).map(move |x| (x, y)))
+            .map(|(x, y)| {
+                let (x, y) = (x as f32 / pixels_per_degree, y as f32 / pixels_per_degree);
+                let g = (-(x * x + y * y) / (2. * deviation * deviation)).exp();
+                if second {
+                    (x * x / (deviation * deviation) - 1.) * g
+                } else {
+                    -x * g
+                }
+            })
+            .collect();
+        let positive: f32 = kernel.iter().filter(|&&w| w > 0.).sum();
+        let negative: f32 = -kernel.iter().filter(|&&w| w < 0.).sum::<f32>();
+        for w in &mut kernel {
+            *w /= if *w > 0. { positive } else { negative };
+        }
+        let side = (2 * feature_radius + 1) as usize;
+        let height = y.len() / width;
+        let at = |x: isize, y_: isize| {
+            y[y_.clamp(0, height as isize - 1) as usize * width
+                + x.clamp(0, width as isize - 1) as usize]
+        };
+        (0..y.len())
+            .map(|i| {
+                let (x, y) = ((i % width) as isize, (i / width) as isize);
+                let (mut gx, mut gy) = (0., 0.);
+                for (k, w) in kernel.iter().enumerate() {
+                    let (kx, ky) = (
+                        (k % side) as isize - feature_radius,
+                        (k / side) as isize - feature_radius,
+                    );
+                    gx += w * at(x + kx, y + ky);
+                    // The vertical kernel is the transpose of the horizontal one
+                    gy += w * at(x + ky, y + kx);
+                }
+                (gx * gx + gy * gy).sqrt()
+            })
+            .collect()
+    };
+    let lightness = |channels: &[Vec<f32>; 3]| -> Vec<f32> {
+        channels[0].iter().map(|y| (y + 16.) / 116.).collect()
+    };
+    let (y_a, y_b) = (lightness(&a), lightness(&b));
+    let [edges_a, points_a, edges_b, points_b] = [
+        features(&y_a, false),
+        features(&y_a, true),
+        features(&y_b, false),
+        features(&y_b, true),
+    ];
+
+    let errors = (0..y_a.len()).map(|i| {
+        let color = hyab(lab_a[i], lab_b[i]).powf(QC);
+        let color = if color < PC * cmax {
+            PT / (PC * cmax) * color
+        } else {
+            PT + (color - PC * cmax) / (cmax - PC * cmax) * (1. - PT)
+        };
+        let feature = (edges_a[i] - edges_b[i])
+            .abs()
+            .max((points_a[i] - points_b[i]).abs());
+        let feature = (feature / std::f32::consts::SQRT_2).powf(QF);
+        color.powf(1. - feature)
+    });
+    errors.sum::<f32>() / y_a.len() as f32
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::builtin::Seeded;
+    use rt::{
+        render::{CancellationToken, Frame, Renderer},
+        write_png,
+    };
+    use std::{
+        fs::File,
+        io::BufWriter,
+        path::{Path, PathBuf},
+    };
+
+    /// Of the golden images, which are small to render quickly in debug builds
+    const GOLDEN_WIDTH: usize = 96;
+    const GOLDEN_HEIGHT: usize = 54;
+    const GOLDEN_SAMPLES: u32 = 16;
+    /// Largest mean FLIP error of a render from its golden image, which allows for differences
+    /// in floating point results between machines but not for visible changes
+    const GOLDEN_MAX_FLIP: f32 = 0.02;
+
+    /// Horizontal gradient with a brighter square in the middle of `size` pixels
+    fn gradient(size: usize) -> Image {
+        let (width, height) = (64, 32);
+        let pixels = (0..width * height)
+            .map(|i| {
+                let (x, y) = (i % width, i / width);
+                let square = (x as isize - 32).unsigned_abs() < size / 2
+                    && (y as isize - 16).unsigned_abs() < size / 2;
+                if square {
+                    Vec3::new(0.9, 0.6, 0.2)
+                } else {
+                    Vec3::broadcast(x as f32 / width as f32 * 0.5)
+                }
+            })
+            .collect();
+        Image {
+            width,
+            height,
+            pixels,
+        }
+    }
+
+    #[test]
+    fn flip_of_identical_images_is_zero() {
+        let image = gradient(8);
+        assert_eq!(flip(&image, &image, 67.), 0.);
+    }
+
+    #[test]
+    fn flip_grows_with_difference() {
+        let reference = gradient(0);
+        let small = flip(&reference, &gradient(4), 67.);
+        let large = flip(&reference, &gradient(16), 67.);
+        assert!(small > 0., "FLIP of a difference is {}", small);
+        assert!(large > small, "FLIP {} is not more than {}", large, small);
+        assert!(large <= 1.);
+    }
+
+    /// Render the built-in scene `builtin` like `--builtin` and compare it to its image in
+    /// `tests/golden`, which is written instead when `RT_BLESS` is set
+    fn assert_golden(builtin: &str) {
+        let scene = builtin.parse::<Seeded>().unwrap().scene(0);
+        let renderer = Renderer::new(&scene, 0, GOLDEN_WIDTH, GOLDEN_HEIGHT, GOLDEN_SAMPLES)
+            .expect("Built-in scenes are valid");
+        let frame = Frame::new(&renderer, &(), CancellationToken::new());
+        frame.work(&renderer).unwrap();
+        let image = frame.into_image();
+
+        let name = builtin.replace([':', '=', ','], "_");
+        let golden = PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+            .join("tests/golden")
+            .join(format!("{}.png", name));
+        let write = |path: &Path| {
+            let writer = BufWriter::new(File::create(path).unwrap());
+            write_png(writer, GOLDEN_WIDTH, GOLDEN_HEIGHT, &image).unwrap();
+        };
+        if std::env::var_os("RT_BLESS").is_some() {
+            std::fs::create_dir_all(golden.parent().unwrap()).unwrap();
+            write(&golden);
+            return;
+        }
+        assert!(
+            golden.exists(),
+            "{} has no golden image {}, render it with RT_BLESS=1",
+            builtin,
+            golden.display()
+        );
+        // Read back like the golden image, which undoes the gamma of the display
+        let rendered = std::env::temp_dir().join(format!("rt_golden_{}.png", name));
+        write(&rendered);
+        let (a, b) = (
+            Image::open(&golden).unwrap(),
+            Image::open(&rendered).unwrap(),
+        );
+        let error = flip(&a, &b, 67.);
+        assert!(
+            error <= GOLDEN_MAX_FLIP,
+            "{} differs from {} by FLIP {}",
+            builtin,
+            golden.display(),
+            error
+        );
+    }
+
+    #[test]
+    fn golden_random() {
+        assert_golden("random:grid_size=5");
+    }
+
+    #[test]
+    fn golden_menger() {
+        assert_golden("menger:level=2");
+    }
+
+    #[test]
+    fn golden_sphereflake() {
+        assert_golden("sphereflake:depth=2");
+    }
+}
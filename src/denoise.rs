@@ -0,0 +1,241 @@
+//! Reconstructing images rendered with few samples per pixel by regression on the features of
+//! the surfaces seen in each pixel, after NFOR from Bitterli et al., "Nonlinearly Weighted
+//! First-order Regression for Denoising Monte Carlo Renderings"
+//!
+//! Around each pixel, the color is fitted as a linear function of the albedo, normal and depth
+//! of its neighbors. Neighbors are weighted by how similar the colors of the patches around
+//! them are, relative to their variance, so that the fit stays local where the features don't
+//! tell edges apart.
+
+use crate::{color::COLOR_CHANNELS, render::Pass};
+use anyhow::{anyhow, Result};
+use ultraviolet::Vec3;
+
+/// Passes that a [`Denoiser`] needs besides the color of the image
+pub const FEATURE_PASSES: [Pass; 4] = [Pass::Albedo, Pass::Normal, Pass::Depth, Pass::Variance];
+
+/// Constant term, albedo, normal and depth
+const PARAMETERS: usize = 8;
+
+#[derive(Clone, Copy, Debug)]
+pub struct DenoiseOptions {
+    /// Pixels at most this far away horizontally and vertically are in the regression
+    pub radius: usize,
+    /// Colors of neighbors are compared over patches of pixels at most this far away
+    pub patch_radius: usize,
+    /// Higher values weight dissimilar neighbors more, blurring more
+    pub strength: f32,
+}
+
+impl Default for DenoiseOptions {
+    fn default() -> Self {
+        Self {
+            radius: 6,
+            patch_radius: 1,
+            strength: 1.0,
+        }
+    }
+}
+
+/// Surface seen in a pixel
+#[derive(Clone, Copy)]
+struct Features {
+    albedo: Vec3,
+    normal: Vec3,
+    depth: f32,
+}
+
+pub struct Denoiser<'a> {
+    width: usize,
+    height: usize,
+    color: &'a [f32],
+    variance: &'a [f32],
+    features: Vec<Features>,
+    options: DenoiseOptions,
+}
+
+impl<'a> Denoiser<'a> {
+    /// Denoise linear RGB `color` of `width` by `height` pixels, with `passes` including all of
+    /// [`FEATURE_PASSES`]
+    pub fn new(
+        width: usize,
+        height: usize,
+        color: &'a [f32],
+        passes: &'a [(Pass, Vec<f32>)],
+        options: DenoiseOptions,
+    ) -> Result<Self> {
+        let pass = |pass: Pass| {
+            passes
+                .iter()
+                .find(|(p, _)| *p == pass)
+                .map(|(_, data)| data.as_slice())
+                .filter(|data| data.len() == width * height * pass.channels())
+                .ok_or_else(|| anyhow!("Denoising needs the {} pass", pass.name()))
+        };
+        if color.len() != width * height * COLOR_CHANNELS {
+            return Err(anyhow!("Image to denoise has wrong size {}", color.len()));
+        }
+        let (albedo, normal, depth) =
+            (pass(Pass::Albedo)?, pass(Pass::Normal)?, pass(Pass::Depth)?);
+        let vec3 =
+            |data: &[f32], i: usize| Vec3::new(data[i * 3], data[i * 3 + 1], data[i * 3 + 2]);
+        Ok(Self {
+            width,
+            height,
+            color,
+            variance: pass(Pass::Variance)?,
+            features: (0..width * height)
+                .map(|i| Features {
+                    albedo: vec3(albedo, i),
+                    normal: vec3(normal, i),
+                    depth: depth[i],
+                })
+                .collect(),
+            options,
+        })
+    }
+
+    pub fn height(&self) -> usize {
+        self.height
+    }
+
+    fn color(&self, x: usize, y: usize) -> Vec3 {
+        let i = (y * self.width + x) * COLOR_CHANNELS;
+        Vec3::new(self.color[i], self.color[i + 1], self.color[i + 2])
+    }
+
+    fn variance(&self, x: usize, y: usize) -> Vec3 {
+        let i = (y * self.width + x) * COLOR_CHANNELS;
+        Vec3::new(self.variance[i], self.variance[i + 1], self.variance[i + 2])
+    }
+
+    /// Denoise row `y`, counting from the top, as linear RGB
+    pub fn denoise_row(&self, y: usize) -> Vec<f32> {
+        (0..self.width)
+            .flat_map(|x| {
+                let c = self.denoise_pixel(x, y);
+                [c.x, c.y, c.z]
+            })
+            .collect()
+    }
+
+    /// Distance between the colors of the patches around pixels `p` and `q` relative to their
+    /// variance, which is zero or less where they differ no more than their noise
+    fn patch_distance(&self, p: (usize, usize), q: (usize, usize)) -> f32 {
+        let (r, k) = (self.options.patch_radius as isize, self.options.strength);
+        let (mut sum, mut n) = (0., 0);
+        for dy in -r..=r {
+            for dx in -r..=r {
+                let offset = |(x, y): (usize, usize)| {
+                    let x = (x as isize + dx).clamp(0, self.width as isize - 1) as usize;
+                    let y = (y as isize + dy).clamp(0, self.height as isize - 1) as usize;
+                    (x, y)
+                };
+                let (p, q) = (offset(p), offset(q));
+                let (var_p, var_q) = (self.variance(p.0, p.1), self.variance(q.0, q.1));
+                let difference = self.color(p.0, p.1) - self.color(q.0, q.1);
+                let d = (difference * difference - (var_p + var_p.min_by_component(var_q)))
+                    / (Vec3::broadcast(1e-10) + (var_p + var_q) * k * k);
+                sum += d.x + d.y + d.z;
+                n += COLOR_CHANNELS;
+            }
+        }
+        sum / n as f32
+    }
+
+    fn denoise_pixel(&self, x: usize, y: usize) -> Vec3 {
+        let center = self.features[y * self.width + x];
+        let r = self.options.radius;
+        // Normal equations of the weighted least squares fit, with a column for each channel
+        let mut a = [[0f32; PARAMETERS]; PARAMETERS];
+        let mut b = [Vec3::zero(); PARAMETERS];
+        let mut total = 0.;
+        for qy in y.saturating_sub(r)..(y + r + 1).min(self.height) {
+            for qx in x.saturating_sub(r)..(x + r + 1).min(self.width) {
+                let color = self.color(qx, qy);
+                if !(color.x + color.y + color.z).is_finite() {
+                    continue;
+                }
+                let weight = (-self.patch_distance((x, y), (qx, qy)).max(0.)).exp();
+                let q = self.features[qy * self.width + qx];
+                let (albedo, normal) = (q.albedo - center.albedo, q.normal - center.normal);
+                let row = [
+                    1.,
+                    albedo.x,
+                    albedo.y,
+                    albedo.z,
+                    normal.x,
+                    normal.y,
+                    normal.z,
+                    depth_feature(center.depth, q.depth),
+                ];
+                for (i, &xi) in row.iter().enumerate() {
+                    for (j, &xj) in row.iter().enumerate().take(i + 1) {
+                        a[i][j] += weight * xi * xj;
+                    }
+                    b[i] += color * (weight * xi);
+                }
+                total += weight;
+            }
+        }
+        if total <= 0. {
+            return self.color(x, y);
+        }
+        // Ridge regression keeps the fit stable where features barely change
+        for (i, row) in a.iter_mut().enumerate().skip(1) {
+            row[i] += 1e-3 * total;
+        }
+        match solve(&a, &b) {
+            Some([intercept, ..]) if (intercept.x + intercept.y + intercept.z).is_finite() => {
+                intercept.max_by_component(Vec3::zero())
+            }
+            _ => b[0] / total,
+        }
+    }
+}
+
+/// Depth of a neighbor relative to the depth at the center, which doesn't depend on the scale
+/// of the scene and is bounded for the background
+fn depth_feature(center: f32, depth: f32) -> f32 {
+    match (center.is_finite(), depth.is_finite()) {
+        (true, true) => (center / depth.max(f32::MIN_POSITIVE)).min(2.) - 1.,
+        (true, false) => -1.,
+        (false, true) => 1.,
+        (false, false) => 0.,
+    }
+}
+
+/// Solve `a x = b` for symmetric positive definite `a`, given as its lower triangle, by
+/// Cholesky decomposition
+fn solve(
+    a: &[[f32; PARAMETERS]; PARAMETERS],
+    b: &[Vec3; PARAMETERS],
+) -> Option<[Vec3; PARAMETERS]> {
+    let mut l = [[0f32; PARAMETERS]; PARAMETERS];
+    for i in 0..PARAMETERS {
+        for j in 0..=i {
+            let sum: f32 = (0..j).map(|k| l[i][k] * l[j][k]).sum();
+            if i == j {
+                let d = a[i][i] - sum;
+                if d <= 0. {
+                    return None;
+                }
+                l[i][i] = d.sqrt();
+            } else {
+                l[i][j] = (a[i][j] - sum) / l[j][j];
+            }
+        }
+    }
+    // Forward substitution for l y = b, then back substitution for l^T x = y
+    let mut y = [Vec3::zero(); PARAMETERS];
+    for i in 0..PARAMETERS {
+        let sum = (0..i).fold(Vec3::zero(), |sum, k| sum + y[k] * l[i][k]);
+        y[i] = (b[i] - sum) / l[i][i];
+    }
+    let mut x = [Vec3::zero(); PARAMETERS];
+    for i in (0..PARAMETERS).rev() {
+        let sum = (i + 1..PARAMETERS).fold(Vec3::zero(), |sum, k| sum + x[k] * l[k][i]);
+        x[i] = (y[i] - sum) / l[i][i];
+    }
+    Some(x)
+}
@@ -0,0 +1,89 @@
+//! Images which help with choosing the exposure of a render
+
+use rt::color::{ColorSpace, COLOR_CHANNELS};
+use ultraviolet::Vec3;
+
+pub const HISTOGRAM_WIDTH: usize = 256;
+pub const HISTOGRAM_HEIGHT: usize = 128;
+
+/// Luminance of middle gray, on which the exposure of a scene is usually based
+const MIDDLE_GRAY: f32 = 0.18;
+
+/// Range of the histogram in stops, where 0 is a luminance of 1 which displays as white
+const HISTOGRAM_STOPS: (f32, f32) = (-12., 4.);
+
+/// Colors for luminances up to a number of stops above or below middle gray
+const FALSE_COLORS: [(f32, [u8; 3]); 7] = [
+    (-6., [48, 0, 64]),
+    (-4., [0, 0, 200]),
+    (-2., [0, 140, 200]),
+    (-0.5, [90, 90, 90]),
+    (0.5, [0, 190, 0]),
+    (1.5, [170, 170, 170]),
+    (2.4, [255, 210, 0]),
+];
+/// Color for luminances above all of [`FALSE_COLORS`], near white
+const WHITE_COLOR: [u8; 3] = [255, 120, 0];
+/// Stripes over pixels with a channel that is clipped
+const ZEBRA_COLOR: [u8; 3] = [255, 0, 0];
+
+fn pixels(linear: &[f32]) -> impl Iterator<Item = Vec3> + '_ {
+    linear
+        .chunks_exact(COLOR_CHANNELS)
+        .map(|c| Vec3::new(c[0], c[1], c[2]))
+}
+
+/// Histogram of the luminance of linear RGB `linear` in `space` in stops, as 8bpp RGB of
+/// [`HISTOGRAM_WIDTH`] by [`HISTOGRAM_HEIGHT`] pixels. Clipped luminances are on a red
+/// background, and a green line marks middle gray.
+pub fn histogram(linear: &[f32], space: ColorSpace) -> Vec<u8> {
+    let (min, max) = HISTOGRAM_STOPS;
+    let column = |stops: f32| {
+        let x = (stops - min) / (max - min) * HISTOGRAM_WIDTH as f32;
+        (x.max(0.) as usize).min(HISTOGRAM_WIDTH - 1)
+    };
+    let mut counts = [0u32; HISTOGRAM_WIDTH];
+    for color in pixels(linear) {
+        counts[column(space.luminance(color).log2())] += 1;
+    }
+    let highest = counts.iter().copied().max().unwrap_or(0).max(1) as f32;
+    let (white, gray) = (column(0.), column(MIDDLE_GRAY.log2()));
+
+    let mut image = Vec::with_capacity(HISTOGRAM_WIDTH * HISTOGRAM_HEIGHT * COLOR_CHANNELS);
+    for y in 0..HISTOGRAM_HEIGHT {
+        let height = (HISTOGRAM_HEIGHT - y) as f32 / HISTOGRAM_HEIGHT as f32;
+        for (x, &count) in counts.iter().enumerate() {
+            image.extend_from_slice(&if count as f32 / highest >= height {
+                [220, 220, 220]
+            } else if x == gray {
+                [0, 190, 0]
+            } else if x >= white {
+                [90, 20, 20]
+            } else {
+                [32, 32, 32]
+            });
+        }
+    }
+    image
+}
+
+/// Linear RGB `linear` in `space` of an image `width` pixels wide as 8bpp RGB, colored by how
+/// many stops from middle gray the luminance of each pixel is, with stripes where a channel
+/// clips when shown in sRGB
+pub fn false_color(linear: &[f32], width: usize, space: ColorSpace) -> Vec<u8> {
+    pixels(linear)
+        .enumerate()
+        .flat_map(|(i, color)| {
+            let (x, y) = (i % width, i / width);
+            let clipped = space.to_srgb(color).component_max() >= 1.;
+            if clipped && (x + y) / 4 % 2 == 0 {
+                return ZEBRA_COLOR;
+            }
+            let stops = (space.luminance(color) / MIDDLE_GRAY).log2();
+            FALSE_COLORS
+                .iter()
+                .find(|&&(limit, _)| stops < limit)
+                .map_or(WHITE_COLOR, |&(_, color)| color)
+        })
+        .collect()
+}
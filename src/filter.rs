@@ -0,0 +1,182 @@
+//! Reconstruction filters of pixels, which weight the samples of a pixel by how far from its
+//! center they are. Samples are placed in proportion to the filter instead of being weighted,
+//! which is filter importance sampling, so that every sample counts the same and each pixel
+//! still only needs its own samples. Alternatively, samples are splatted with their weights
+//! into every pixel under the filter, see [`SplatImage`].
+
+use crate::{color::COLOR_CHANNELS, sampling::Distribution1D};
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
+use std::{f32::consts::TAU, str::FromStr};
+use ultraviolet::{Vec2, Vec3};
+
+/// Pieces of the tabulated distributions of filters
+const TABLE_SIZE: usize = 64;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum PixelFilter {
+    /// Uniform over the pixel, which is the sharpest and aliases the most
+    #[default]
+    Box,
+    /// Falling linearly to zero a pixel away from the center
+    Tent,
+    /// Gaussian with a standard deviation of half a pixel, cut off 1.5 pixels away
+    Gaussian,
+    /// Blackman-Harris window two pixels in radius, which is smooth like a Gaussian but keeps
+    /// more detail
+    BlackmanHarris,
+}
+
+impl FromStr for PixelFilter {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "box" => Ok(Self::Box),
+            "tent" => Ok(Self::Tent),
+            "gaussian" => Ok(Self::Gaussian),
+            "blackman-harris" => Ok(Self::BlackmanHarris),
+            _ => Err(anyhow!("Unknown pixel filter {}", s)),
+        }
+    }
+}
+
+impl PixelFilter {
+    /// Distance from the center of a pixel, in pixels, beyond which the filter is zero
+    pub fn radius(self) -> f32 {
+        match self {
+            Self::Box => 0.5,
+            Self::Tent => 1.,
+            Self::Gaussian => 1.5,
+            Self::BlackmanHarris => 2.,
+        }
+    }
+
+    /// Value at `x` pixels from the center along either axis, as the filters are separable
+    fn evaluate(self, x: f32) -> f32 {
+        let radius = self.radius();
+        if x.abs() > radius {
+            return 0.;
+        }
+        match self {
+            Self::Box => 1.,
+            Self::Tent => radius - x.abs(),
+            Self::Gaussian => {
+                let gaussian = |x: f32| (-2. * x.powi(2)).exp();
+                (gaussian(x) - gaussian(radius)).max(0.)
+            }
+            Self::BlackmanHarris => {
+                let t = TAU * (x + radius) / (2. * radius);
+                0.35875 - 0.48829 * t.cos() + 0.14128 * (2. * t).cos() - 0.01168 * (3. * t).cos()
+            }
+        }
+    }
+}
+
+/// Offsets of samples from the centers of pixels, distributed like a [`PixelFilter`]
+pub struct FilterSampler {
+    radius: f32,
+    /// Over the width of the filter, unless it is a box
+    distribution: Option<Distribution1D>,
+}
+
+impl FilterSampler {
+    pub fn new(filter: PixelFilter) -> Self {
+        let radius = filter.radius();
+        let distribution = (filter != PixelFilter::Box).then(|| {
+            Distribution1D::new(
+                (0..TABLE_SIZE)
+                    .map(|i| {
+                        let x = ((i as f32 + 0.5) / TABLE_SIZE as f32 * 2. - 1.) * radius;
+                        filter.evaluate(x)
+                    })
+                    .collect(),
+            )
+        });
+        Self {
+            radius,
+            distribution,
+        }
+    }
+
+    /// Offset in pixels chosen with `u` in the unit square
+    pub fn sample(&self, u: Vec2) -> Vec2 {
+        match &self.distribution {
+            Some(distribution) => {
+                let x = distribution.sample(u.x).0;
+                let y = distribution.sample(u.y).0;
+                (Vec2::new(x, y) * 2. - Vec2::one()) * self.radius
+            }
+            None => u - Vec2::broadcast(0.5),
+        }
+    }
+}
+
+/// Image which samples are splatted into at any position, adding them to every pixel under
+/// the filter around the position weighted by the filter. Pixels are the weighted averages of
+/// the samples, so each one has a sum of colors and a sum of weights.
+#[derive(Clone)]
+pub struct SplatImage {
+    width: usize,
+    height: usize,
+    filter: PixelFilter,
+    color: Vec<Vec3>,
+    weight: Vec<f32>,
+}
+
+impl SplatImage {
+    pub fn new(width: usize, height: usize, filter: PixelFilter) -> Self {
+        Self {
+            width,
+            height,
+            filter,
+            color: vec![Vec3::zero(); width * height],
+            weight: vec![0.; width * height],
+        }
+    }
+
+    /// Add a sample of `color` at `position`, in pixels from the top left corner of the image
+    pub fn splat(&mut self, position: Vec2, color: Vec3) {
+        let radius = self.filter.radius();
+        let range = |center: f32, size: usize| {
+            let start = (center - radius).floor().max(0.) as usize;
+            let end = ((center + radius).ceil().max(0.) as usize).min(size);
+            start..end
+        };
+        for y in range(position.y, self.height) {
+            let weight_y = self.filter.evaluate(y as f32 + 0.5 - position.y);
+            for x in range(position.x, self.width) {
+                let weight = weight_y * self.filter.evaluate(x as f32 + 0.5 - position.x);
+                if weight != 0. {
+                    let i = y * self.width + x;
+                    self.color[i] += color * weight;
+                    self.weight[i] += weight;
+                }
+            }
+        }
+    }
+
+    /// Add the samples of `other`, which is the same size
+    pub fn merge(&mut self, other: &Self) {
+        for (sum, color) in self.color.iter_mut().zip(&other.color) {
+            *sum += *color;
+        }
+        for (sum, weight) in self.weight.iter_mut().zip(&other.weight) {
+            *sum += weight;
+        }
+    }
+
+    /// Linear RGB, which is black where no samples were splatted
+    pub fn resolve(&self) -> Vec<f32> {
+        let mut linear = Vec::with_capacity(self.color.len() * COLOR_CHANNELS);
+        for (&color, &weight) in self.color.iter().zip(&self.weight) {
+            let color = if weight > 0. {
+                color / weight
+            } else {
+                Vec3::zero()
+            };
+            linear.extend_from_slice(&[color.x, color.y, color.z]);
+        }
+        linear
+    }
+}
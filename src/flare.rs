@@ -0,0 +1,289 @@
+//! Flares of light from bright highlights, added to rendered images before they are tone
+//! mapped: horizontal streaks like those of anamorphic lenses, and the ghosts and diffraction
+//! spikes of lenses in general
+
+use crate::color::COLOR_CHANNELS;
+use serde::{Deserialize, Serialize};
+use std::f32::consts::PI;
+use ultraviolet::{Vec2, Vec3};
+
+/// Light brighter than white in the image, after exposure, streaks
+const THRESHOLD: f32 = 1.;
+
+/// Tints of successive ghosts, from the coatings of the lens elements reflecting them
+const GHOST_TINTS: [[f32; 3]; 3] = [[1., 0.7, 0.4], [0.5, 1., 0.6], [0.7, 0.5, 1.]];
+
+/// Highlights are blurred into ghosts over a square this many times smaller than the image
+const GHOST_BLUR: usize = 100;
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Streaks {
+    /// Fraction of the light above white which is spread into a streak
+    #[serde(default = "Streaks::default_intensity")]
+    pub intensity: f32,
+    /// Distance in which a streak fades to about a third, in widths of the image
+    #[serde(default = "Streaks::default_length")]
+    pub length: f32,
+    /// Tint of the streaks, which are blue with the coatings of most anamorphic lenses
+    #[serde(default = "Streaks::default_color")]
+    pub color: [f32; 3],
+}
+
+impl Streaks {
+    fn default_intensity() -> f32 {
+        0.1
+    }
+
+    fn default_length() -> f32 {
+        0.2
+    }
+
+    fn default_color() -> [f32; 3] {
+        [0.3, 0.5, 1.]
+    }
+
+    /// Add the streaks to `linear`, which is `width` by `height` pixels of linear RGB
+    pub fn apply(&self, linear: &mut [f32], width: usize, height: usize) {
+        if width == 0 || self.length <= 0. {
+            return;
+        }
+        let decay = (-1. / (self.length * width as f32)).exp();
+        // The streaks have as much light as they take from the highlights times the intensity
+        let scale = self.intensity * (1. - decay) / (1. + decay);
+        let color = Vec3::from(self.color);
+        for row in linear.chunks_exact_mut(width * COLOR_CHANNELS).take(height) {
+            let excess: Vec<Vec3> = row
+                .chunks_exact(COLOR_CHANNELS)
+                .map(|c| {
+                    (Vec3::new(c[0], c[1], c[2]) - Vec3::broadcast(THRESHOLD))
+                        .max_by_component(Vec3::zero())
+                        .component_max()
+                        * color
+                })
+                .collect();
+            // Exponential falloff to the right and then to the left of each highlight
+            let mut streak = vec![Vec3::zero(); width];
+            let mut light = Vec3::zero();
+            for (s, &e) in streak.iter_mut().zip(&excess) {
+                light = light * decay + e;
+                *s = light;
+            }
+            light = Vec3::zero();
+            for (s, &e) in streak.iter_mut().zip(&excess).rev() {
+                light = light * decay + e;
+                // The highlight itself was counted on the way right
+                *s += light - e;
+            }
+            for (c, s) in row.chunks_exact_mut(COLOR_CHANNELS).zip(streak) {
+                let s = s * scale;
+                c[0] += s.x;
+                c[1] += s.y;
+                c[2] += s.z;
+            }
+        }
+    }
+}
+
+impl Default for Streaks {
+    fn default() -> Self {
+        Self {
+            intensity: Self::default_intensity(),
+            length: Self::default_length(),
+            color: Self::default_color(),
+        }
+    }
+}
+
+/// Ghosts, which are reflections of highlights between the elements of a lens mirrored through
+/// the center of the image, and diffraction spikes, which are rays around highlights from the
+/// edges of the aperture blades
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub struct LensFlare {
+    /// Light brighter than this flares
+    #[serde(default = "LensFlare::default_threshold")]
+    pub threshold: f32,
+    /// Number of ghosts of each highlight
+    #[serde(default = "LensFlare::default_ghosts")]
+    pub ghosts: u32,
+    /// Fraction of the light above the threshold in each ghost
+    #[serde(default = "LensFlare::default_ghost_intensity")]
+    pub ghost_intensity: f32,
+    /// Number of spikes around each highlight, which is twice the number of blades for an
+    /// aperture with an odd number of them and as many for an even number
+    #[serde(default = "LensFlare::default_spikes")]
+    pub spikes: u32,
+    /// Length of the spikes in widths of the image
+    #[serde(default = "LensFlare::default_spike_length")]
+    pub spike_length: f32,
+    /// Fraction of the light above the threshold in all of the spikes together
+    #[serde(default = "LensFlare::default_spike_intensity")]
+    pub spike_intensity: f32,
+}
+
+impl LensFlare {
+    fn default_threshold() -> f32 {
+        THRESHOLD
+    }
+
+    fn default_ghosts() -> u32 {
+        3
+    }
+
+    fn default_ghost_intensity() -> f32 {
+        0.02
+    }
+
+    fn default_spikes() -> u32 {
+        6
+    }
+
+    fn default_spike_length() -> f32 {
+        0.05
+    }
+
+    fn default_spike_intensity() -> f32 {
+        0.1
+    }
+
+    /// Scale of ghost `i` around the center of the image, which is negative for the ones on
+    /// the opposite side of the highlight
+    fn ghost_scale(i: u32) -> f32 {
+        let scale = 0.4 + 0.35 * i as f32;
+        if i.is_multiple_of(2) {
+            -scale
+        } else {
+            scale
+        }
+    }
+
+    /// Add the flares to `linear`, which is `width` by `height` pixels of linear RGB. The time
+    /// taken grows with the number of pixels brighter than the threshold.
+    pub fn apply(&self, linear: &mut [f32], width: usize, height: usize) {
+        if width == 0 || height == 0 {
+            return;
+        }
+        // Light above the threshold, in the color of the pixel
+        let bright: Vec<Vec3> = linear
+            .chunks_exact(COLOR_CHANNELS)
+            .map(|c| {
+                let color = Vec3::new(c[0], c[1], c[2]);
+                let max = color.component_max();
+                if max > self.threshold {
+                    color * (1. - self.threshold / max)
+                } else {
+                    Vec3::zero()
+                }
+            })
+            .collect();
+        let mut flare = vec![Vec3::zero(); width * height];
+
+        // Ghosts are out of focus, so each pixel gathers the blurred highlights which its ghosts
+        // are scaled from
+        let blurred = box_blur(&bright, width, height, width / GHOST_BLUR);
+        let center = Vec2::new(width as f32, height as f32) / 2.;
+        for (i, tint) in (0..self.ghosts).zip(GHOST_TINTS.iter().cycle()) {
+            let scale = Self::ghost_scale(i);
+            // Ghosts are spread over an area which grows with the square of the scale
+            let weight = self.ghost_intensity / scale.powi(2) * Vec3::from(*tint);
+            for y in 0..height {
+                for x in 0..width {
+                    let p = Vec2::new(x as f32 + 0.5, y as f32 + 0.5);
+                    let source = center + (p - center) / scale;
+                    if source.x >= 0.
+                        && source.y >= 0.
+                        && (source.x as usize) < width
+                        && (source.y as usize) < height
+                    {
+                        let s = blurred[source.y as usize * width + source.x as usize];
+                        flare[y * width + x] += s * weight;
+                    }
+                }
+            }
+        }
+
+        // Each highlight scatters its spikes, fading out quadratically
+        let length = (self.spike_length * width as f32) as usize;
+        if self.spikes > 0 && length > 0 {
+            let falloff = |t: usize| (1. - t as f32 / length as f32).powi(2);
+            let total: f32 = (1..=length).map(falloff).sum::<f32>() * self.spikes as f32;
+            let directions: Vec<Vec2> = (0..self.spikes)
+                .map(|i| {
+                    let angle = PI / 4. + 2. * PI * i as f32 / self.spikes as f32;
+                    Vec2::new(angle.cos(), angle.sin())
+                })
+                .collect();
+            for (i, &light) in bright.iter().enumerate() {
+                if light == Vec3::zero() {
+                    continue;
+                }
+                let p = Vec2::new((i % width) as f32 + 0.5, (i / width) as f32 + 0.5);
+                let light = light * (self.spike_intensity / total);
+                for &direction in &directions {
+                    for t in 1..=length {
+                        let q = p + direction * t as f32;
+                        if q.x < 0. || q.y < 0. {
+                            break;
+                        }
+                        let (x, y) = (q.x as usize, q.y as usize);
+                        if x >= width || y >= height {
+                            break;
+                        }
+                        flare[y * width + x] += light * falloff(t);
+                    }
+                }
+            }
+        }
+
+        for (c, f) in linear.chunks_exact_mut(COLOR_CHANNELS).zip(flare) {
+            c[0] += f.x;
+            c[1] += f.y;
+            c[2] += f.z;
+        }
+    }
+}
+
+/// Average of the pixels at most `radius` away horizontally and vertically from each pixel of
+/// `image`, which is `width` by `height`
+fn box_blur(image: &[Vec3], width: usize, height: usize, radius: usize) -> Vec<Vec3> {
+    if radius == 0 {
+        return image.to_vec();
+    }
+    // Running sums along lines of `len` pixels which are `stride` apart
+    let blur_line = |src: &[Vec3], dst: &mut [Vec3], start: usize, stride: usize, len: usize| {
+        let at = |i: usize| src[start + i * stride];
+        let mut sum = (0..=radius.min(len - 1))
+            .map(at)
+            .fold(Vec3::zero(), |a, b| a + b);
+        for i in 0..len {
+            dst[start + i * stride] = sum / (2 * radius + 1) as f32;
+            if i + radius + 1 < len {
+                sum += at(i + radius + 1);
+            }
+            if i >= radius {
+                sum -= at(i - radius);
+            }
+        }
+    };
+    let mut rows = vec![Vec3::zero(); image.len()];
+    for y in 0..height {
+        blur_line(image, &mut rows, y * width, 1, width);
+    }
+    let mut blurred = vec![Vec3::zero(); image.len()];
+    for x in 0..width {
+        blur_line(&rows, &mut blurred, x, width, height);
+    }
+    blurred
+}
+
+impl Default for LensFlare {
+    fn default() -> Self {
+        Self {
+            threshold: Self::default_threshold(),
+            ghosts: Self::default_ghosts(),
+            ghost_intensity: Self::default_ghost_intensity(),
+            spikes: Self::default_spikes(),
+            spike_length: Self::default_spike_length(),
+            spike_intensity: Self::default_spike_intensity(),
+        }
+    }
+}
@@ -0,0 +1,60 @@
+//! Fog blended into rendered images by the distance to the surfaces seen by the camera, for
+//! atmosphere without tracing rays through a participating medium. Unlike a medium, the fog
+//! doesn't scatter light into shadows or around lights.
+
+use crate::color::COLOR_CHANNELS;
+use serde::{Deserialize, Serialize};
+use ultraviolet::Vec3;
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub struct DepthFog {
+    /// Fraction of light lost per unit of distance, which hides half of the light in about
+    /// 0.7 / density
+    #[serde(default = "DepthFog::default_density")]
+    pub density: f32,
+    /// Distance from the camera where the fog begins
+    #[serde(default)]
+    pub start: f32,
+    /// Linear color of the fog in the image, after exposure
+    #[serde(default = "DepthFog::default_color")]
+    pub color: [f32; 3],
+}
+
+impl DepthFog {
+    fn default_density() -> f32 {
+        0.05
+    }
+
+    fn default_color() -> [f32; 3] {
+        [0.7, 0.75, 0.8]
+    }
+
+    /// Fraction of the light of a surface at `distance` replaced by fog, which is one where
+    /// nothing was hit
+    pub fn factor(&self, distance: f32) -> f32 {
+        if distance.is_infinite() {
+            return 1.;
+        }
+        1. - (-self.density.max(0.) * (distance - self.start).max(0.)).exp()
+    }
+
+    /// Blend the fog into `linear`, which is linear RGB, by `factors` of each pixel, see
+    /// [`DepthFog::factor`]
+    pub fn apply(&self, linear: &mut [f32], factors: &[f32]) {
+        let color = Vec3::from(self.color);
+        for (c, &f) in linear.chunks_exact_mut(COLOR_CHANNELS).zip(factors) {
+            let blended = Vec3::new(c[0], c[1], c[2]) * (1. - f) + color * f;
+            c.copy_from_slice(blended.as_slice());
+        }
+    }
+}
+
+impl Default for DepthFog {
+    fn default() -> Self {
+        Self {
+            density: Self::default_density(),
+            start: 0.,
+            color: Self::default_color(),
+        }
+    }
+}
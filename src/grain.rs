@@ -0,0 +1,88 @@
+//! Noise of a camera sensor or grain of film, added to rendered images to match footage
+//!
+//! The light of a pixel is counted as photons, so its shot noise has a variance equal to their
+//! number, on top of the constant read noise of the sensor. Raising the ISO amplifies fewer
+//! photons to the same brightness, which makes the image noisier.
+
+use crate::{
+    color::{ColorSpace, COLOR_CHANNELS},
+    sampling::standard_normal,
+};
+use rand::prelude::*;
+use rand_xorshift::XorShiftRng;
+use serde::{Deserialize, Serialize};
+use ultraviolet::{Vec2, Vec3};
+
+/// ISO at which white is [`Grain::photons`]
+const BASE_ISO: f32 = 100.;
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Grain {
+    /// Sensitivity of the sensor or film, which scales the noise by its square root
+    #[serde(default = "Grain::default_iso")]
+    pub iso: f32,
+    /// Number of photons which make a pixel white at ISO 100
+    #[serde(default = "Grain::default_photons")]
+    pub photons: f32,
+    /// Standard deviation of the noise of reading a pixel, in photons
+    #[serde(default = "Grain::default_read_noise")]
+    pub read_noise: f32,
+    /// Vary only the brightness of pixels, like the grain of black and white film, instead of
+    /// each channel like the noise of a sensor
+    #[serde(default)]
+    pub monochrome: bool,
+}
+
+impl Grain {
+    fn default_iso() -> f32 {
+        800.
+    }
+
+    fn default_photons() -> f32 {
+        20000.
+    }
+
+    fn default_read_noise() -> f32 {
+        3.
+    }
+
+    /// Add the noise to `linear`, which is linear RGB in `space`. The noise is the same for
+    /// the same `seed`, so it changes between frames only when the seed does. Pixels are
+    /// clipped at black like by a sensor.
+    pub fn apply(&self, linear: &mut [f32], space: ColorSpace, seed: u64) {
+        let mut rng = XorShiftRng::seed_from_u64(seed);
+        let photons = self.photons * BASE_ISO / self.iso.max(f32::EPSILON);
+        // Of light counted as photons, relative to white
+        let deviation =
+            |light: f32| (light.max(0.) * photons + self.read_noise.powi(2)).sqrt() / photons;
+        for c in linear.chunks_exact_mut(COLOR_CHANNELS) {
+            let color = Vec3::new(c[0], c[1], c[2]);
+            let normal = |rng: &mut XorShiftRng| standard_normal(Vec2::new(rng.gen(), rng.gen()));
+            let noisy = if self.monochrome {
+                let noise = deviation(space.luminance(color)) * normal(&mut rng).x;
+                color + Vec3::broadcast(noise)
+            } else {
+                let (xy, z) = (normal(&mut rng), normal(&mut rng).x);
+                color
+                    + Vec3::new(
+                        deviation(color.x) * xy.x,
+                        deviation(color.y) * xy.y,
+                        deviation(color.z) * z,
+                    )
+            }
+            .max_by_component(Vec3::zero());
+            c.copy_from_slice(noisy.as_slice());
+        }
+    }
+}
+
+impl Default for Grain {
+    fn default() -> Self {
+        Self {
+            iso: Self::default_iso(),
+            photons: Self::default_photons(),
+            read_noise: Self::default_read_noise(),
+            monochrome: false,
+        }
+    }
+}
@@ -0,0 +1,399 @@
+//! Path guiding, which learns where light arrives from at diffuse surfaces while rendering
+//! and samples bounces towards it, from Müller et al., "Practical Path Guiding for Efficient
+//! Light-Transport Simulation".
+//!
+//! Light is recorded in an SD-tree, a binary tree over space whose leaves have quadtrees over
+//! directions. Directions map to the unit square by cylindrical coordinates, which preserves
+//! area, and the quadtrees are refined where more light was recorded.
+
+use crate::{
+    sampling::{cosine_hemisphere, cosine_hemisphere_pdf, Onb},
+    world::aabb::Aabb,
+};
+use serde::{Deserialize, Serialize};
+use std::{
+    f32::consts::{PI, TAU},
+    sync::atomic::{AtomicU32, Ordering},
+};
+use ultraviolet::{Vec2, Vec3};
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub struct GuidingOptions {
+    /// Iterations of learning before rendering, with 1 sample per pixel in the first one and
+    /// twice as many in each of the next
+    #[serde(default = "GuidingOptions::default_training_iterations")]
+    pub training_iterations: u32,
+    /// Fraction of bounces sampled like the material instead of by the guide, which keeps
+    /// sampling robust where the guide hasn't learned enough
+    #[serde(default = "GuidingOptions::default_bsdf_fraction")]
+    pub bsdf_fraction: f32,
+}
+
+impl GuidingOptions {
+    fn default_training_iterations() -> u32 {
+        4
+    }
+
+    fn default_bsdf_fraction() -> f32 {
+        0.5
+    }
+}
+
+impl Default for GuidingOptions {
+    fn default() -> Self {
+        Self {
+            training_iterations: Self::default_training_iterations(),
+            bsdf_fraction: Self::default_bsdf_fraction(),
+        }
+    }
+}
+
+/// Leaves of the spatial tree split in two when they have recorded more than this many times
+/// the square root of the samples per pixel of the iteration
+const SPATIAL_THRESHOLD: f32 = 12000.;
+/// Quadtree nodes split in four when they have more than this fraction of the recorded light
+const DIRECTIONAL_THRESHOLD: f32 = 0.01;
+const MAX_DIRECTIONAL_DEPTH: u32 = 20;
+
+/// Float which threads can add to
+struct AtomicF32(AtomicU32);
+
+impl AtomicF32 {
+    fn new(value: f32) -> Self {
+        Self(AtomicU32::new(value.to_bits()))
+    }
+
+    fn get(&self) -> f32 {
+        f32::from_bits(self.0.load(Ordering::Relaxed))
+    }
+
+    fn add(&self, value: f32) {
+        let mut old = self.0.load(Ordering::Relaxed);
+        loop {
+            let new = (f32::from_bits(old) + value).to_bits();
+            match self
+                .0
+                .compare_exchange_weak(old, new, Ordering::Relaxed, Ordering::Relaxed)
+            {
+                Ok(_) => return,
+                Err(current) => old = current,
+            }
+        }
+    }
+}
+
+/// Node of a quadtree over the unit square, with quadrant `i` at `(i & 1, i >> 1)` halves
+#[derive(Clone, Copy, Default)]
+struct QuadNode {
+    /// Light recorded in each quadrant
+    sums: [f32; 4],
+    /// Index of the node of each quadrant, or 0 for leaves since the root is never a child
+    children: [u32; 4],
+}
+
+/// Quadrant of `p` in the unit square, and `p` in the unit square of the quadrant
+fn quadrant(p: Vec2) -> (usize, Vec2) {
+    let (x, y) = (p.x >= 0.5, p.y >= 0.5);
+    let offset = Vec2::new(x as u32 as f32, y as u32 as f32) * 0.5;
+    (x as usize | (y as usize) << 1, (p - offset) * 2.)
+}
+
+/// Quadtree of the light arriving at a region of space
+struct DTree {
+    /// Light recorded in the previous iteration, which directions are sampled from
+    sampling: Vec<QuadNode>,
+    /// Topology refined from the sampling tree, recording light in the current iteration
+    building: Vec<([AtomicF32; 4], [u32; 4])>,
+    samples: AtomicU32,
+}
+
+impl DTree {
+    fn new() -> Self {
+        Self::with_topology(vec![QuadNode::default()], 0)
+    }
+
+    fn with_topology(building: Vec<QuadNode>, samples: u32) -> Self {
+        Self {
+            sampling: vec![QuadNode::default()],
+            building: building
+                .iter()
+                .map(|node| (node.sums.map(AtomicF32::new), node.children))
+                .collect(),
+            samples: AtomicU32::new(samples),
+        }
+    }
+
+    fn built(&self) -> Vec<QuadNode> {
+        self.building
+            .iter()
+            .map(|(sums, children)| QuadNode {
+                sums: [0, 1, 2, 3].map(|i| sums[i].get()),
+                children: *children,
+            })
+            .collect()
+    }
+
+    fn record(&self, p: Vec2, value: f32) {
+        self.samples.fetch_add(1, Ordering::Relaxed);
+        let (mut node, mut p) = (0, p);
+        loop {
+            let (i, inner) = quadrant(p);
+            let (sums, children) = &self.building[node];
+            sums[i].add(value);
+            match children[i] {
+                0 => return,
+                child => (node, p) = (child as usize, inner),
+            }
+        }
+    }
+
+    /// Light recorded in the last iteration, or zero if there is nothing to sample by
+    fn energy(&self) -> f32 {
+        self.sampling[0].sums.iter().sum()
+    }
+
+    /// Point in the unit square in proportion to the light recorded in the last iteration,
+    /// with `u` in the unit square. The tree must have energy.
+    fn sample(&self, mut u: Vec2) -> Vec2 {
+        let (mut node, mut origin, mut size) = (0, Vec2::zero(), 1.);
+        loop {
+            let QuadNode { sums, children } = self.sampling[node];
+            // Choose the half along x, and then the quadrant in it along y
+            let pick = |u: &mut f32, low: f32, high: f32| {
+                let p = if low + high > 0. {
+                    low / (low + high)
+                } else {
+                    0.5
+                };
+                if *u < p {
+                    *u /= p;
+                    0
+                } else {
+                    *u = ((*u - p) / (1. - p)).min(1. - f32::EPSILON);
+                    1
+                }
+            };
+            let x = pick(&mut u.x, sums[0] + sums[2], sums[1] + sums[3]);
+            let y = pick(&mut u.y, sums[x], sums[x + 2]);
+            let i = x | y << 1;
+            size *= 0.5;
+            origin += Vec2::new(x as f32, y as f32) * size;
+            match children[i] {
+                0 => return origin + u * size,
+                child => node = child as usize,
+            }
+        }
+    }
+
+    /// Density of [`DTree::sample`] choosing `p` over the unit square
+    fn pdf(&self, p: Vec2) -> f32 {
+        let (mut node, mut p, mut pdf) = (0, p, 1.);
+        loop {
+            let QuadNode { sums, children } = self.sampling[node];
+            let total: f32 = sums.iter().sum();
+            if total <= 0. {
+                return 0.;
+            }
+            let (i, inner) = quadrant(p);
+            pdf *= 4. * sums[i] / total;
+            match children[i] {
+                0 => return pdf,
+                child => (node, p) = (child as usize, inner),
+            }
+        }
+    }
+
+    /// Sample from the recorded light, and record in a tree refined where it was bright
+    fn refine(&mut self) {
+        let built = self.built();
+        let total: f32 = built[0].sums.iter().sum();
+        let mut refined = vec![QuadNode::default()];
+        if total > 0. {
+            // Nodes of the refined tree with their node in the built tree, if it has one, and
+            // the light in their quadrants
+            let mut stack = vec![(0, Some(0), built[0].sums, 1)];
+            while let Some((node, old, sums, depth)) = stack.pop() {
+                for (i, &sum) in sums.iter().enumerate() {
+                    if sum / total <= DIRECTIONAL_THRESHOLD || depth >= MAX_DIRECTIONAL_DEPTH {
+                        continue;
+                    }
+                    let child = refined.len();
+                    refined[node].children[i] = child as u32;
+                    refined.push(QuadNode::default());
+                    // Leaves of the built tree spread their light over the new quadrants
+                    let old_child = old.map(|old: usize| built[old].children[i] as usize);
+                    let (old_child, child_sums) = match old_child {
+                        Some(c) if c != 0 => (Some(c), built[c].sums),
+                        _ => (None, [sum / 4.; 4]),
+                    };
+                    stack.push((child, old_child, child_sums, depth + 1));
+                }
+            }
+        }
+        *self = Self {
+            sampling: built,
+            ..Self::with_topology(refined, 0)
+        };
+    }
+}
+
+impl Clone for DTree {
+    fn clone(&self) -> Self {
+        Self {
+            sampling: self.sampling.clone(),
+            ..Self::with_topology(self.built(), self.samples.load(Ordering::Relaxed))
+        }
+    }
+}
+
+/// Node of the binary tree over space, which splits the cube of its parent in half along the
+/// next axis
+#[derive(Clone, Copy)]
+enum SpatialNode {
+    Inner([usize; 2]),
+    /// Index of the quadtree of the region
+    Leaf(usize),
+}
+
+/// Where light arrives from at diffuse surfaces, learned while rendering
+pub struct Guide {
+    options: GuidingOptions,
+    /// Cube around the scene
+    min: Vec3,
+    size: f32,
+    nodes: Vec<SpatialNode>,
+    dtrees: Vec<DTree>,
+    iteration: u32,
+    learning: bool,
+}
+
+impl Guide {
+    /// Guide for a scene inside of `bounds`, which learns until [`Guide::finish_learning`]
+    pub fn new(bounds: Aabb, options: GuidingOptions) -> Self {
+        let bounds = if bounds.min.x <= bounds.max.x {
+            bounds
+        } else {
+            Aabb::new(Vec3::zero()..Vec3::one())
+        };
+        let size = (bounds.max - bounds.min)
+            .component_max()
+            .max(f32::MIN_POSITIVE);
+        Self {
+            options,
+            min: bounds.min,
+            size,
+            nodes: vec![SpatialNode::Leaf(0)],
+            dtrees: vec![DTree::new()],
+            iteration: 0,
+            learning: true,
+        }
+    }
+
+    pub fn options(&self) -> &GuidingOptions {
+        &self.options
+    }
+
+    pub fn is_learning(&self) -> bool {
+        self.learning
+    }
+
+    /// Stop recording light, after which sampling uses what was learned in the last iteration
+    pub fn finish_learning(&mut self) {
+        self.learning = false;
+    }
+
+    fn dtree(&self, position: Vec3) -> &DTree {
+        let mut p = ((position - self.min) / self.size).clamped(Vec3::zero(), Vec3::one());
+        let (mut node, mut axis) = (0, 0);
+        loop {
+            match self.nodes[node] {
+                SpatialNode::Inner(children) => {
+                    let c = &mut p.as_mut_slice()[axis];
+                    let upper = *c >= 0.5;
+                    *c = *c * 2. - upper as u32 as f32;
+                    node = children[upper as usize];
+                    axis = (axis + 1) % 3;
+                }
+                SpatialNode::Leaf(dtree) => return &self.dtrees[dtree],
+            }
+        }
+    }
+
+    /// Direction from `position` on a diffuse surface with shading frame `frame`, either like
+    /// a Lambertian surface or towards the light learned so far, with `u` in the unit square
+    /// and `choice` in `[0, 1)`
+    pub fn sample(&self, position: Vec3, frame: &Onb, u: Vec2, choice: f32) -> Vec3 {
+        let dtree = self.dtree(position);
+        if choice < self.options.bsdf_fraction || dtree.energy() <= 0. {
+            frame.to_world(cosine_hemisphere(u))
+        } else {
+            from_square(dtree.sample(u))
+        }
+    }
+
+    /// Density of [`Guide::sample`] choosing `direction` at a surface with normal `normal`
+    pub fn pdf(&self, position: Vec3, normal: Vec3, direction: Vec3) -> f32 {
+        let bsdf = cosine_hemisphere_pdf(direction.dot(normal));
+        let dtree = self.dtree(position);
+        if dtree.energy() <= 0. {
+            return bsdf;
+        }
+        let guide = dtree.pdf(to_square(direction)) / (4. * PI);
+        let fraction = self.options.bsdf_fraction;
+        fraction * bsdf + (1. - fraction) * guide
+    }
+
+    /// Record the luminance of light arriving at `position` from `direction` divided by the
+    /// density of sampling the direction
+    pub fn record(&self, position: Vec3, direction: Vec3, value: f32) {
+        if self.learning && value.is_finite() && value >= 0. {
+            self.dtree(position).record(to_square(direction), value);
+        }
+    }
+
+    /// Start learning again after an iteration of recording light, which can be sampled next
+    pub fn refine(&mut self) {
+        let threshold = SPATIAL_THRESHOLD * ((1u64 << self.iteration) as f32).sqrt();
+        // Split regions which had many samples, assuming that they had them evenly
+        let mut stack = vec![0];
+        while let Some(node) = stack.pop() {
+            match self.nodes[node] {
+                SpatialNode::Inner(children) => stack.extend(children),
+                SpatialNode::Leaf(dtree) => {
+                    let samples = self.dtrees[dtree].samples.load(Ordering::Relaxed);
+                    if samples as f32 <= threshold {
+                        continue;
+                    }
+                    self.dtrees[dtree]
+                        .samples
+                        .store(samples / 2, Ordering::Relaxed);
+                    let clone = self.dtrees[dtree].clone();
+                    self.dtrees.push(clone);
+                    let children = [self.nodes.len(), self.nodes.len() + 1];
+                    self.nodes.push(SpatialNode::Leaf(dtree));
+                    self.nodes.push(SpatialNode::Leaf(self.dtrees.len() - 1));
+                    self.nodes[node] = SpatialNode::Inner(children);
+                    stack.extend(children);
+                }
+            }
+        }
+        for dtree in &mut self.dtrees {
+            dtree.refine();
+        }
+        self.iteration += 1;
+    }
+}
+
+fn to_square(direction: Vec3) -> Vec2 {
+    Vec2::new(
+        (direction.z.clamp(-1., 1.) + 1.) * 0.5,
+        (direction.y.atan2(direction.x) / TAU).rem_euclid(1.),
+    )
+}
+
+fn from_square(p: Vec2) -> Vec3 {
+    let cos_theta = p.x * 2. - 1.;
+    let sin_theta = (1. - cos_theta * cos_theta).max(0.).sqrt();
+    let phi = p.y * TAU;
+    Vec3::new(sin_theta * phi.cos(), sin_theta * phi.sin(), cos_theta)
+}
@@ -0,0 +1,147 @@
+use anyhow::Result;
+use rt::{render::Frame, world::bvh::BvhStats};
+use std::{
+    io::{prelude::*, BufReader},
+    net::{TcpListener, TcpStream},
+    sync::atomic::{AtomicBool, Ordering},
+    time::{Duration, Instant},
+};
+
+const INDEX_HTML: &str = r#"<!DOCTYPE html>
+<html>
+<head>
+<meta charset="utf-8">
+<title>rt</title>
+<style>
+body { background: #222; color: #ddd; font-family: monospace; }
+img { max-width: 100%; image-rendering: pixelated; }
+</style>
+</head>
+<body>
+<img id="image" src="/image.png">
+<pre id="stats"></pre>
+<script>
+async function update() {
+    const stats = await (await fetch("/stats")).json();
+    document.getElementById("stats").textContent =
+        `${stats.progress.toFixed(1)} % done, ${stats.elapsed_secs.toFixed(0)} s elapsed\n` +
+        `${stats.width}x${stats.height}, ${stats.samples_per_pixel} spp, ` +
+        `${(stats.samples_per_sec / 1e6).toFixed(2)} M samples/s\n` +
+        `BVH built in ${(stats.bvh_build_secs * 1e3).toFixed(1)} ms, ` +
+        `SAH cost ${stats.bvh_sah_cost.toFixed(2)}`;
+    document.getElementById("image").src = "/image.png?" + Date.now();
+    if (!stats.done) {
+        setTimeout(update, 2000);
+    }
+}
+update();
+</script>
+</body>
+</html>
+"#;
+
+/// Render state that is exposed to HTTP clients
+pub struct Monitor<'a> {
+    pub frame: &'a Frame<'a>,
+    pub samples_per_pixel: u32,
+    pub started: Instant,
+    pub bvh: BvhStats,
+    /// NUMA nodes which the rendering threads are pinned to, or 0 if they aren't
+    pub numa_nodes: usize,
+}
+
+impl Monitor<'_> {
+    fn progress(&self) -> f32 {
+        self.frame.tiles_done() as f32 / self.frame.tiles_total() as f32
+    }
+
+    fn stats_json(&self, done: bool) -> String {
+        let elapsed = self.started.elapsed().as_secs_f32();
+        let (width, height) = (self.frame.width(), self.frame.height());
+        let pixels_done = self.progress() * (width * height) as f32;
+        format!(
+            "{{\"progress\":{},\"elapsed_secs\":{},\"width\":{},\"height\":{},\
+             \"samples_per_pixel\":{},\"samples_per_sec\":{},\"bvh_build_secs\":{},\
+             \"bvh_sah_cost\":{},\"numa_nodes\":{},\"done\":{}}}",
+            self.progress() * 100.,
+            elapsed,
+            width,
+            height,
+            self.samples_per_pixel,
+            pixels_done * self.samples_per_pixel as f32 / elapsed.max(f32::EPSILON),
+            self.bvh.build_time.as_secs_f32(),
+            self.bvh.sah_cost,
+            self.numa_nodes,
+            done,
+        )
+    }
+}
+
+/// Serve the monitoring page until `done` is set
+pub fn serve(listener: &TcpListener, monitor: &Monitor, done: &AtomicBool) -> Result<()> {
+    listener.set_nonblocking(true)?;
+    // Keep serving the final image for a moment so that open pages can catch up
+    let mut linger = None;
+    while linger.is_none_or(|t: Instant| t.elapsed() < Duration::from_secs(3)) {
+        if linger.is_none() && done.load(Ordering::Relaxed) {
+            linger = Some(Instant::now());
+        }
+        match listener.accept() {
+            Ok((stream, _)) => {
+                if let Err(e) = respond(stream, monitor, done.load(Ordering::Relaxed)) {
+                    eprintln!("HTTP: {}", e);
+                }
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => {
+                std::thread::sleep(Duration::from_millis(50));
+            }
+            Err(e) => return Err(e.into()),
+        }
+    }
+    Ok(())
+}
+
+fn respond(stream: TcpStream, monitor: &Monitor, done: bool) -> Result<()> {
+    stream.set_nonblocking(false)?;
+    stream.set_read_timeout(Some(Duration::from_secs(5)))?;
+    let mut reader = BufReader::new(stream);
+
+    // Only the request line matters, but consume headers to be polite
+    let mut request_line = String::new();
+    reader.read_line(&mut request_line)?;
+    let mut line = String::new();
+    while reader.read_line(&mut line)? > 2 {
+        line.clear();
+    }
+
+    let path = request_line.split_whitespace().nth(1).unwrap_or("/");
+    let path = path.split('?').next().unwrap_or(path);
+    let (status, content_type, body) = match path {
+        "/" => ("200 OK", "text/html", INDEX_HTML.as_bytes().to_vec()),
+        "/stats" => (
+            "200 OK",
+            "application/json",
+            monitor.stats_json(done).into_bytes(),
+        ),
+        "/image.png" => {
+            let image = monitor.frame.image();
+            let mut png_data = Vec::new();
+            let (width, height) = (monitor.frame.width(), monitor.frame.height());
+            rt::write_png(&mut png_data, width, height, &image)?;
+            ("200 OK", "image/png", png_data)
+        }
+        _ => ("404 Not Found", "text/plain", b"Not found".to_vec()),
+    };
+
+    let mut stream = reader.into_inner();
+    write!(
+        stream,
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\n\
+         Cache-Control: no-store\r\nConnection: close\r\n\r\n",
+        status,
+        content_type,
+        body.len()
+    )?;
+    stream.write_all(&body)?;
+    Ok(())
+}
@@ -0,0 +1,136 @@
+//! Reading IES LM-63 photometric files, which describe how the luminous intensity of a light
+//! fixture varies by direction
+
+use anyhow::{anyhow, Context, Result};
+use std::{fs, path::Path};
+
+/// Luminous intensity of a fixture by direction, with type C photometry. Vertical angles are
+/// measured from straight down and horizontal angles around the vertical axis.
+#[derive(Debug)]
+pub struct IesProfile {
+    /// Ascending, in degrees
+    vertical_angles: Vec<f32>,
+    /// Ascending, in degrees
+    horizontal_angles: Vec<f32>,
+    /// In candela, every vertical angle for each horizontal angle
+    candela: Vec<f32>,
+}
+
+impl IesProfile {
+    pub fn open(path: &Path) -> Result<Self> {
+        let text = fs::read(path).with_context(|| format!("Cannot read {}", path.display()))?;
+        Self::parse(&String::from_utf8_lossy(&text))
+            .with_context(|| format!("Cannot parse {}", path.display()))
+    }
+
+    pub fn parse(text: &str) -> Result<Self> {
+        // Keywords come before the tilt, and the rest is whitespace separated numbers
+        let mut lines = text.lines();
+        let tilt = lines
+            .by_ref()
+            .find(|line| line.trim_start().starts_with("TILT="))
+            .ok_or_else(|| anyhow!("No TILT line"))?;
+        let mut numbers = lines.flat_map(str::split_whitespace).map(|number| {
+            number
+                .parse::<f32>()
+                .map_err(|_| anyhow!("Invalid number {}", number))
+        });
+        let mut next = || {
+            numbers
+                .next()
+                .unwrap_or_else(|| Err(anyhow!("File ends early")))
+        };
+        if tilt.trim() == "TILT=INCLUDE" {
+            // Lamp to luminaire geometry and the angles and factors of the tilt, which only
+            // matter for fixtures that are tilted when installed
+            next()?;
+            let pairs = next()? as usize;
+            for _ in 0..pairs * 2 {
+                next()?;
+            }
+        } else if tilt.trim() != "TILT=NONE" {
+            return Err(anyhow!("Tilt files are not supported"));
+        }
+
+        let [_lamps, _lumens_per_lamp, multiplier, vertical, horizontal, photometric_type] =
+            [(); 6].map(|_| next());
+        let (vertical, horizontal) = (vertical? as usize, horizontal? as usize);
+        if photometric_type? != 1. {
+            return Err(anyhow!("Only type C photometry is supported"));
+        }
+        // Units and dimensions of the luminous opening
+        for _ in 0..4 {
+            next()?;
+        }
+        let ballast_factor = next()?;
+        // Unused factor and input watts
+        next()?;
+        next()?;
+        let multiplier = multiplier? * ballast_factor;
+
+        let mut read = |n: usize| (0..n).map(|_| next()).collect::<Result<Vec<_>>>();
+        let vertical_angles = read(vertical)?;
+        let horizontal_angles = read(horizontal)?;
+        let candela = read(vertical * horizontal)?
+            .into_iter()
+            .map(|value| value * multiplier)
+            .collect();
+        let ascending = |angles: &[f32]| angles.windows(2).all(|pair| pair[0] < pair[1]);
+        if vertical == 0 || horizontal == 0 {
+            return Err(anyhow!("No angles"));
+        }
+        if !ascending(&vertical_angles) || !ascending(&horizontal_angles) {
+            return Err(anyhow!("Angles are not in ascending order"));
+        }
+        Ok(Self {
+            vertical_angles,
+            horizontal_angles,
+            candela,
+        })
+    }
+
+    pub fn max_candela(&self) -> f32 {
+        self.candela.iter().copied().fold(0., f32::max)
+    }
+
+    /// Intensity in candela at angles in degrees, interpolated between the measured ones
+    pub fn candela(&self, vertical: f32, horizontal: f32) -> f32 {
+        // The last horizontal angle tells how the fixture is symmetric
+        let horizontal = horizontal.rem_euclid(360.);
+        let horizontal = match self.horizontal_angles.last() {
+            Some(&last) if last <= 90. => 90. - (horizontal % 180. - 90.).abs(),
+            Some(&last) if last <= 180. => 180. - (horizontal - 180.).abs(),
+            _ => horizontal,
+        };
+        let (v, v_t) = match interval(&self.vertical_angles, vertical) {
+            Some(interval) => interval,
+            None => return 0.,
+        };
+        let (h, h_t) = interval(&self.horizontal_angles, horizontal).unwrap_or((0, 0.));
+        let rows = self.vertical_angles.len();
+        let at = |h: usize, v: usize| {
+            let h = h.min(self.horizontal_angles.len() - 1);
+            self.candela[h * rows + v.min(rows - 1)]
+        };
+        let lerp = |a: f32, b: f32, t: f32| a + (b - a) * t;
+        lerp(
+            lerp(at(h, v), at(h, v + 1), v_t),
+            lerp(at(h + 1, v), at(h + 1, v + 1), v_t),
+            h_t,
+        )
+    }
+}
+
+/// Index of the last of `angles` at most `angle` and how far `angle` is towards the next one,
+/// or `None` if `angle` is outside of them
+fn interval(angles: &[f32], angle: f32) -> Option<(usize, f32)> {
+    let (&first, &last) = (angles.first()?, angles.last()?);
+    if angle < first || angle > last {
+        return None;
+    }
+    let i = angles.partition_point(|&a| a <= angle).max(1) - 1;
+    match angles.get(i + 1) {
+        Some(&next) => Some((i, (angle - angles[i]) / (next - angles[i]))),
+        None => Some((i, 0.)),
+    }
+}
@@ -0,0 +1,279 @@
+//! Reading images, such as environment maps and textures
+
+use anyhow::{anyhow, Context, Result};
+use std::{
+    fs::File,
+    io::{BufRead, BufReader, Read},
+    path::Path,
+};
+use ultraviolet::{Vec2, Vec3};
+
+/// Linear RGB image, top row first
+pub struct Image {
+    pub width: usize,
+    pub height: usize,
+    pub pixels: Vec<Vec3>,
+}
+
+impl Image {
+    /// Read a Radiance HDR file, an 8-bit PNG file, or an OpenEXR file with the `exr` feature,
+    /// by the extension of `path`
+    pub fn open(path: &Path) -> Result<Self> {
+        let extension = path
+            .extension()
+            .and_then(|extension| extension.to_str())
+            .map(str::to_ascii_lowercase);
+        let image = match extension.as_deref() {
+            Some("hdr") => read_hdr(BufReader::new(File::open(path)?)),
+            Some("png") => read_png(File::open(path)?),
+            #[cfg(feature = "exr")]
+            Some("exr") => read_exr(path),
+            _ => Err(anyhow!("Unsupported image format")),
+        };
+        image.with_context(|| format!("Cannot read {}", path.display()))
+    }
+
+    pub fn pixel(&self, x: usize, y: usize) -> Vec3 {
+        self.pixels[y * self.width + x]
+    }
+
+    /// Nearest pixel to texture coordinates `uv`, which repeat over the unit square with `v`
+    /// growing upwards
+    pub fn sample(&self, uv: Vec2) -> Vec3 {
+        let (u, v) = (uv.x.rem_euclid(1.), 1. - uv.y.rem_euclid(1.));
+        let x = ((u * self.width as f32) as usize).min(self.width - 1);
+        let y = ((v * self.height as f32) as usize).min(self.height - 1);
+        self.pixel(x, y)
+    }
+
+    /// Mean of the pixels
+    pub fn average(&self) -> Vec3 {
+        self.pixels
+            .iter()
+            .fold(Vec3::zero(), |sum, &pixel| sum + pixel)
+            / self.pixels.len() as f32
+    }
+}
+
+/// Image read a row at a time, top row first, so that it doesn't have to fit in memory at once.
+/// Interlaced PNG and OpenEXR files are read whole and then handed out by row.
+pub struct Rows {
+    pub width: usize,
+    pub height: usize,
+    rows: Box<dyn Iterator<Item = Result<Vec<Vec3>>>>,
+}
+
+impl Rows {
+    /// Open an image like [`Image::open`]
+    pub fn open(path: &Path) -> Result<Self> {
+        let extension = path
+            .extension()
+            .and_then(|extension| extension.to_str())
+            .map(str::to_ascii_lowercase);
+        let rows = match extension.as_deref() {
+            Some("hdr") => hdr_rows(BufReader::new(File::open(path)?)),
+            Some("png") => png_rows(File::open(path)?),
+            _ => Image::open(path).map(Self::from),
+        };
+        rows.with_context(|| format!("Cannot read {}", path.display()))
+    }
+}
+
+impl From<Image> for Rows {
+    fn from(image: Image) -> Self {
+        let Image {
+            width,
+            height,
+            pixels,
+        } = image;
+        let rows = (0..height).map(move |y| Ok(pixels[y * width..][..width].to_vec()));
+        Self {
+            width,
+            height,
+            rows: Box::new(rows),
+        }
+    }
+}
+
+impl Iterator for Rows {
+    type Item = Result<Vec<Vec3>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.rows.next()
+    }
+}
+
+/// Read an 8-bit PNG image, undoing the gamma of 2 that rendered images are written with
+fn read_png(read: impl Read) -> Result<Image> {
+    let mut decoder = png::Decoder::new(read);
+    decoder.set_transformations(png::Transformations::EXPAND | png::Transformations::STRIP_16);
+    let (info, mut reader) = decoder.read_info()?;
+    let mut buffer = vec![0; info.buffer_size()];
+    reader.next_frame(&mut buffer)?;
+    Ok(Image {
+        width: info.width as usize,
+        height: info.height as usize,
+        pixels: png_pixels(&buffer, info.color_type.samples()),
+    })
+}
+
+/// Rows of an 8-bit PNG image like [`read_png`]
+fn png_rows(read: impl Read + 'static) -> Result<Rows> {
+    let mut decoder = png::Decoder::new(read);
+    decoder.set_transformations(png::Transformations::EXPAND | png::Transformations::STRIP_16);
+    let (info, mut reader) = decoder.read_info()?;
+    let (width, height) = (info.width as usize, info.height as usize);
+    let channels = info.color_type.samples();
+    if reader.info().interlaced {
+        let mut buffer = vec![0; info.buffer_size()];
+        reader.next_frame(&mut buffer)?;
+        return Ok(Image {
+            width,
+            height,
+            pixels: png_pixels(&buffer, channels),
+        }
+        .into());
+    }
+    let rows = (0..height).map(move |_| match reader.next_row()? {
+        Some(row) => Ok(png_pixels(row, channels)),
+        None => Err(anyhow!("Image has too few rows")),
+    });
+    Ok(Rows {
+        width,
+        height,
+        rows: Box::new(rows),
+    })
+}
+
+/// Linear colors of 8-bit PNG pixels of `channels` channels
+fn png_pixels(bytes: &[u8], channels: usize) -> Vec<Vec3> {
+    let linear = |value: u8| (f32::from(value) / 255.).powi(2);
+    bytes
+        .chunks_exact(channels)
+        .map(|pixel| match pixel {
+            [gray] | [gray, _] => Vec3::broadcast(linear(*gray)),
+            [r, g, b, ..] => Vec3::new(linear(*r), linear(*g), linear(*b)),
+            _ => unreachable!("PNG pixels have 1 to 4 channels"),
+        })
+        .collect()
+}
+
+/// Read a Radiance RGBE image with the standard orientation
+fn read_hdr(read: impl BufRead + 'static) -> Result<Image> {
+    let rows = hdr_rows(read)?;
+    let (width, height) = (rows.width, rows.height);
+    let mut pixels = Vec::with_capacity(width * height);
+    for row in rows {
+        pixels.extend(row?);
+    }
+    Ok(Image {
+        width,
+        height,
+        pixels,
+    })
+}
+
+/// Rows of a Radiance RGBE image like [`read_hdr`]
+fn hdr_rows(mut read: impl BufRead + 'static) -> Result<Rows> {
+    let mut line = String::new();
+    read.read_line(&mut line)?;
+    if !line.starts_with("#?") {
+        return Err(anyhow!("Not a Radiance HDR file"));
+    }
+    loop {
+        line.clear();
+        if read.read_line(&mut line)? == 0 {
+            return Err(anyhow!("Header has no end"));
+        }
+        match line.trim() {
+            "" => break,
+            "FORMAT=32-bit_rle_xyze" => return Err(anyhow!("XYZ images are not supported")),
+            _ => {}
+        }
+    }
+    line.clear();
+    read.read_line(&mut line)?;
+    let (height, width) = match line.split_whitespace().collect::<Vec<_>>()[..] {
+        ["-Y", height, "+X", width] => (height.parse()?, width.parse()?),
+        _ => return Err(anyhow!("Unsupported resolution {}", line.trim())),
+    };
+
+    let mut scanline = vec![[0; 4]; width];
+    let rows = (0..height).map(move |_| {
+        read_scanline(&mut read, &mut scanline)?;
+        Ok(scanline.iter().map(|&rgbe| from_rgbe(rgbe)).collect())
+    });
+    Ok(Rows {
+        width,
+        height,
+        rows: Box::new(rows),
+    })
+}
+
+/// Read either a flat or a run-length encoded scanline of RGBE pixels
+fn read_scanline(read: &mut impl Read, scanline: &mut [[u8; 4]]) -> Result<()> {
+    let width = scanline.len();
+    let mut start = [0; 4];
+    read.read_exact(&mut start)?;
+    if !(8..0x8000).contains(&width) || start[..2] != [2, 2] || start[2] & 0x80 != 0 {
+        scanline[0] = start;
+        for pixel in &mut scanline[1..] {
+            read.read_exact(pixel)?;
+        }
+        return Ok(());
+    }
+    if usize::from(start[2]) << 8 | usize::from(start[3]) != width {
+        return Err(anyhow!("Scanline has wrong width"));
+    }
+
+    // Each channel is encoded separately as runs and literal bytes
+    let mut byte = [0];
+    for c in 0..4 {
+        let mut x = 0;
+        while x < width {
+            read.read_exact(&mut byte)?;
+            let (count, run) = match byte[0] {
+                count @ 129..=255 => (usize::from(count - 128), true),
+                count => (usize::from(count), false),
+            };
+            if count == 0 || x + count > width {
+                return Err(anyhow!("Scanline has invalid run length"));
+            }
+            if run {
+                read.read_exact(&mut byte)?;
+            }
+            for pixel in &mut scanline[x..x + count] {
+                if !run {
+                    read.read_exact(&mut byte)?;
+                }
+                pixel[c] = byte[0];
+            }
+            x += count;
+        }
+    }
+    Ok(())
+}
+
+/// Channels with a shared exponent
+fn from_rgbe([r, g, b, e]: [u8; 4]) -> Vec3 {
+    if e == 0 {
+        return Vec3::zero();
+    }
+    Vec3::new(r.into(), g.into(), b.into()) * 2f32.powi(i32::from(e) - 136)
+}
+
+#[cfg(feature = "exr")]
+fn read_exr(path: &Path) -> Result<Image> {
+    let image = exr::prelude::read_first_rgba_layer_from_file(
+        path,
+        |resolution, _| Image {
+            width: resolution.width(),
+            height: resolution.height(),
+            pixels: vec![Vec3::zero(); resolution.area()],
+        },
+        |image, position, (r, g, b, _): (f32, f32, f32, f32)| {
+            image.pixels[position.y() * image.width + position.x()] = Vec3::new(r, g, b);
+        },
+    )?;
+    Ok(image.layer_data.channel_data.pixels)
+}
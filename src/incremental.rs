@@ -0,0 +1,235 @@
+//! Incremental re-rendering: the previous render is kept in a directory together with its
+//! scene and the objects that were seen in each tile.
+//!
+//! When only materials change, tiles whose paths never hit, sampled light from or were shadowed
+//! by an object with a changed material are copied from the previous samples. Any change to the
+//! camera, the geometry, the render settings or a file that the scene reads renders everything
+//! again.
+
+use anyhow::{Context, Result};
+use rt::{
+    color::COLOR_CHANNELS,
+    render::{Frame, Tile},
+    scene::{ObjectSpec, Scene},
+};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::{
+    collections::HashSet,
+    fs,
+    path::{Path, PathBuf},
+    time::SystemTime,
+};
+
+const STATE_FILE: &str = "state.ron";
+const COLOR_FILE: &str = "color.f32";
+const SAMPLES_FILE: &str = "samples.u32";
+
+#[derive(Serialize, Deserialize)]
+struct State {
+    scene: Scene,
+    frame: u32,
+    width: usize,
+    height: usize,
+    samples_per_pixel: u32,
+    /// Objects seen in each tile, unknown for unfinished tiles and tiles rendered by workers
+    visible: Vec<Option<Vec<u32>>>,
+    #[serde(default)]
+    files: Vec<Stamp>,
+}
+
+/// Size and modification time of a file that a scene reads, which change when it is edited
+#[derive(PartialEq, Serialize, Deserialize)]
+struct Stamp {
+    path: PathBuf,
+    len: u64,
+    modified: Option<SystemTime>,
+}
+
+/// Render kept from an earlier run
+pub struct Previous {
+    state: State,
+    /// Sums of the samples of each pixel as little-endian linear RGB floats
+    color: Vec<u8>,
+    /// Number of samples of each pixel as little-endian integers
+    samples: Vec<u8>,
+}
+
+impl Previous {
+    /// Read the render kept in `dir`, if there is one
+    pub fn load(dir: &Path) -> Result<Option<Self>> {
+        let state_path = dir.join(STATE_FILE);
+        if !state_path.exists() {
+            return Ok(None);
+        }
+        let state: State = ron::de::from_bytes(&fs::read(&state_path)?)
+            .with_context(|| format!("Cannot parse {}", state_path.display()))?;
+        let color = fs::read(dir.join(COLOR_FILE))?;
+        let samples = fs::read(dir.join(SAMPLES_FILE))?;
+        let pixels = state.width * state.height;
+        if color.len() != pixels * COLOR_CHANNELS * 4 || samples.len() != pixels * 4 {
+            return Ok(None);
+        }
+        Ok(Some(Self {
+            state,
+            color,
+            samples,
+        }))
+    }
+
+    /// Keep a finished render in `dir` for the next run
+    pub fn save(
+        dir: &Path,
+        scene: &Scene,
+        frame: &Frame,
+        number: u32,
+        samples_per_pixel: u32,
+    ) -> Result<()> {
+        fs::create_dir_all(dir)?;
+        let state = State {
+            scene: scene.clone(),
+            frame: number,
+            width: frame.width(),
+            height: frame.height(),
+            samples_per_pixel,
+            visible: frame.visible_objects(),
+            files: stamps(scene),
+        };
+        fs::write(
+            dir.join(STATE_FILE),
+            ron::to_string(&state).expect("Scene can be serialized"),
+        )?;
+        let (color, samples) = frame.accumulation();
+        let color: Vec<u8> = color.iter().flat_map(|c| c.to_le_bytes()).collect();
+        let samples: Vec<u8> = samples.iter().flat_map(|n| n.to_le_bytes()).collect();
+        fs::write(dir.join(COLOR_FILE), color)?;
+        fs::write(dir.join(SAMPLES_FILE), samples)?;
+        Ok(())
+    }
+
+    /// Copy tiles which look the same in the new scene to `frame`, returning how many were
+    /// reused
+    pub fn reuse(
+        &self,
+        scene: &Scene,
+        frame: &Frame,
+        number: u32,
+        samples_per_pixel: u32,
+    ) -> Result<usize> {
+        let state = &self.state;
+        // Images of passes are not kept
+        if !scene.passes.is_empty() {
+            return Ok(0);
+        }
+        let changed = match changed_objects(&state.scene, scene) {
+            Some(changed) => changed,
+            None => return Ok(0),
+        };
+        if stamps(scene) != state.files {
+            return Ok(0);
+        }
+        if (
+            state.frame,
+            state.width,
+            state.height,
+            state.samples_per_pixel,
+        ) != (number, frame.width(), frame.height(), samples_per_pixel)
+            || state.visible.len() != frame.tiles_total()
+        {
+            return Ok(0);
+        }
+
+        let mut reused = 0;
+        for (i, visible) in state.visible.iter().enumerate() {
+            let visible = match visible {
+                Some(visible) if !visible.iter().any(|object| changed.contains(object)) => visible,
+                _ => continue,
+            };
+            let tile = frame.tile(i).expect("Tile counts are equal");
+            frame.reuse(i, &self.tile_data(&tile), visible.clone())?;
+            reused += 1;
+        }
+        Ok(reused)
+    }
+
+    /// Data of a tile in the format of [`rt::render::Renderer::accumulate_tile`]
+    fn tile_data(&self, tile: &Tile) -> Vec<u8> {
+        let mut data = Vec::with_capacity(tile.pixel_count() * (COLOR_CHANNELS + 1) * 4);
+        for (buffer, pixel_len) in [(&self.color, COLOR_CHANNELS * 4), (&self.samples, 4)] {
+            for y in tile.y..tile.y + tile.height {
+                let offset = (y * self.state.width + tile.x) * pixel_len;
+                data.extend_from_slice(&buffer[offset..][..tile.width * pixel_len]);
+            }
+        }
+        data
+    }
+}
+
+/// Objects whose material differs between the scenes, or `None` if anything else that
+/// affects the image differs
+fn changed_objects(old: &Scene, new: &Scene) -> Option<HashSet<u32>> {
+    // Every setting, so that new ones are compared too, without the materials and objects
+    // which are compared one by one
+    let settings = |scene: &Scene| Scene {
+        materials: Vec::new(),
+        objects: Vec::new(),
+        ..scene.clone()
+    };
+    if old.objects.len() != new.objects.len() || settings(old) != settings(new) {
+        return None;
+    }
+    // The surfaces themselves are among the settings
+    let geometry = |object: &ObjectSpec| {
+        (
+            object.surface,
+            object.position,
+            object.velocity,
+            object.visibility,
+            object.priority,
+        )
+    };
+    let mut changed = HashSet::new();
+    for (i, (a, b)) in old.objects.iter().zip(&new.objects).enumerate() {
+        if geometry(a) != geometry(b) {
+            return None;
+        }
+        if old.materials.get(a.material) != new.materials.get(b.material) {
+            changed.insert(i as u32);
+        }
+    }
+    Some(changed)
+}
+
+/// Stamps of the files that `scene` reads. Every string in the scene which names a file is
+/// taken for one, so that the files of new settings are found too.
+fn stamps(scene: &Scene) -> Vec<Stamp> {
+    fn strings<'a>(value: &'a Value, found: &mut Vec<&'a str>) {
+        match value {
+            Value::String(s) => found.push(s),
+            Value::Array(values) => values.iter().for_each(|v| strings(v, found)),
+            Value::Object(map) => {
+                for (key, v) in map {
+                    found.push(key);
+                    strings(v, found);
+                }
+            }
+            _ => (),
+        }
+    }
+    let value = serde_json::to_value(scene).expect("Scene can be serialized");
+    let mut paths = Vec::new();
+    strings(&value, &mut paths);
+    paths.sort_unstable();
+    paths.dedup();
+    paths
+        .into_iter()
+        .filter_map(|path| {
+            let metadata = fs::metadata(path).ok().filter(|m| m.is_file())?;
+            Some(Stamp {
+                path: path.into(),
+                len: metadata.len(),
+                modified: metadata.modified().ok(),
+            })
+        })
+        .collect()
+}
@@ -0,0 +1,182 @@
+//! Statistics of a scene and problems with it, found without rendering
+
+use anyhow::Result;
+use rt::{
+    scene::{LightSpec, MaterialSpec, Scene, SurfaceSpec},
+    world::{material::Material, Object},
+};
+use std::mem::size_of;
+use ultraviolet::Vec3;
+
+/// Name of the kind of a material, like in scene files
+fn material_kind(material: &MaterialSpec) -> &'static str {
+    match material {
+        MaterialSpec::Lambertian { .. } => "Lambertian",
+        MaterialSpec::Metal { .. } => "Metal",
+        MaterialSpec::AnisotropicMetal { .. } => "AnisotropicMetal",
+        MaterialSpec::Dielectric { .. } => "Dielectric",
+        MaterialSpec::Emissive { .. } => "Emissive",
+        MaterialSpec::Clearcoat { .. } => "Clearcoat",
+        MaterialSpec::Cutout { .. } => "Cutout",
+        MaterialSpec::Named(_) => "Named",
+    }
+}
+
+/// Problems which make the scene render differently than was probably intended
+fn warnings(scene: &Scene) -> Vec<String> {
+    let mut warnings = Vec::new();
+    for (i, surface) in scene.surfaces.iter().enumerate() {
+        match *surface {
+            SurfaceSpec::Sphere { radius } if radius <= 0. => {
+                warnings.push(format!("Sphere {} has a radius of {}", i, radius))
+            }
+            SurfaceSpec::Triangle { vertices, .. } => {
+                let [a, b, c] = vertices.map(Vec3::from);
+                if (b - a).cross(c - a).mag_sq() == 0. {
+                    warnings.push(format!("Triangle {} is degenerate", i));
+                }
+            }
+            _ => (),
+        }
+    }
+    let mut used = vec![false; scene.materials.len()];
+    for (i, object) in scene.objects.iter().enumerate() {
+        match used.get_mut(object.material) {
+            Some(used) => *used = true,
+            None => warnings.push(format!(
+                "Object {} refers to nonexistent material {}",
+                i, object.material
+            )),
+        }
+        if object.surface >= scene.surfaces.len() {
+            warnings.push(format!(
+                "Object {} refers to nonexistent surface {}",
+                i, object.surface
+            ));
+        }
+    }
+    for (i, _) in used.iter().enumerate().filter(|(_, &used)| !used) {
+        warnings.push(format!("Material {} is not used", i));
+    }
+    // Only emissive triangles are sampled as lights
+    let emissive_spheres = scene.objects.iter().any(|object| {
+        matches!(
+            scene.materials.get(object.material),
+            Some(MaterialSpec::Emissive { .. })
+        ) && matches!(
+            scene.surfaces.get(object.surface),
+            Some(SurfaceSpec::Sphere { .. })
+        )
+    });
+    if emissive_spheres && scene.lights.is_empty() {
+        warnings.push(String::from(
+            "Emissive spheres are only found by chance, and there are no lights",
+        ));
+    }
+    warnings
+}
+
+/// Print statistics of `scene`, building its world at `frame` for the bounds and the BVH
+pub fn print(scene: &Scene, frame: u32) -> Result<()> {
+    let spheres = scene
+        .surfaces
+        .iter()
+        .filter(|surface| matches!(surface, SurfaceSpec::Sphere { .. }))
+        .count();
+    let triangles = scene.surfaces.len() - spheres;
+    let placed = |sphere: bool| {
+        scene
+            .objects
+            .iter()
+            .filter(|object| {
+                matches!(
+                    (scene.surfaces.get(object.surface), sphere),
+                    (Some(SurfaceSpec::Sphere { .. }), true)
+                        | (Some(SurfaceSpec::Triangle { .. }), false)
+                )
+            })
+            .count()
+    };
+    println!("Objects     {}", scene.objects.len());
+    println!("  Spheres   {} objects, {} surfaces", placed(true), spheres);
+    println!(
+        "  Triangles {} objects, {} surfaces",
+        placed(false),
+        triangles
+    );
+
+    println!("Materials   {}", scene.materials.len());
+    let mut kinds: Vec<(&str, usize, usize)> = Vec::new();
+    for (i, material) in scene.materials.iter().enumerate() {
+        let objects = scene.objects.iter().filter(|o| o.material == i).count();
+        let kind = material_kind(material);
+        match kinds.iter_mut().find(|(k, _, _)| *k == kind) {
+            Some((_, materials, used)) => {
+                *materials += 1;
+                *used += objects;
+            }
+            None => kinds.push((kind, 1, objects)),
+        }
+    }
+    for (kind, materials, objects) in kinds {
+        println!(
+            "  {:<17} {} materials on {} objects",
+            kind, materials, objects
+        );
+    }
+
+    let count = |f: fn(&LightSpec) -> bool| scene.lights.iter().filter(|l| f(l)).count();
+    let emissive_triangles = scene
+        .objects
+        .iter()
+        .filter(|object| {
+            matches!(
+                scene.materials.get(object.material),
+                Some(MaterialSpec::Emissive { .. })
+            ) && matches!(
+                scene.surfaces.get(object.surface),
+                Some(SurfaceSpec::Triangle { .. })
+            )
+        })
+        .count();
+    println!(
+        "Lights      {} suns, {} points, {} spots, {} emissive triangles",
+        count(|l| matches!(l, LightSpec::Sun { .. })),
+        count(|l| matches!(l, LightSpec::Point { .. })),
+        count(|l| matches!(l, LightSpec::Spot { .. })),
+        emissive_triangles
+    );
+    println!("Cameras     {}", scene.cameras.len() + 1);
+
+    let warnings = warnings(scene);
+    // The world can't be built with invalid references
+    let valid = scene.objects.iter().all(|object| {
+        object.surface < scene.surfaces.len() && object.material < scene.materials.len()
+    });
+    if valid {
+        let world = scene.world(frame)?;
+        let bounds = world.bounds();
+        println!(
+            "Bounds      ({:.3}, {:.3}, {:.3}) to ({:.3}, {:.3}, {:.3})",
+            bounds.min.x, bounds.min.y, bounds.min.z, bounds.max.x, bounds.max.y, bounds.max.z
+        );
+        let bvh = world.bvh_stats();
+        println!(
+            "BVH         {} nodes, {} leaves, SAH cost {:.2}",
+            bvh.nodes, bvh.leaves, bvh.sah_cost
+        );
+        let bytes = world.surface_bytes()
+            + scene.materials.len() * size_of::<Material>()
+            + scene.objects.len() * size_of::<Object>()
+            + bvh.bytes;
+        println!(
+            "Memory      about {:.1} MiB without textures",
+            bytes as f32 / (1024. * 1024.)
+        );
+    }
+
+    for warning in &warnings {
+        println!("Warning: {}", warning);
+    }
+    Ok(())
+}
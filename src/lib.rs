@@ -0,0 +1,132 @@
+#[macro_use]
+pub mod profile;
+
+pub mod bake;
+pub mod camera;
+pub mod camera_path;
+#[cfg(feature = "capi")]
+pub mod capi;
+pub mod color;
+pub mod denoise;
+pub mod filter;
+pub mod flare;
+pub mod fog;
+pub mod grain;
+pub mod guiding;
+pub mod ies;
+pub mod image;
+pub mod lut;
+pub mod mlt;
+pub mod ray;
+pub mod render;
+pub mod sampler;
+pub mod sampling;
+pub mod scene;
+pub mod texture;
+#[cfg(target_arch = "wasm32")]
+mod wasm;
+pub mod world;
+
+use anyhow::Result;
+use color::ColorSpace;
+pub use ray::Ray;
+use std::{convert::TryFrom, io::Write};
+#[cfg(feature = "exr")]
+use {render::Pass, std::io::Seek};
+
+/// Write 8bpp RGB with sRGB primaries as a PNG file
+pub fn write_png(write: impl Write, width: usize, height: usize, rgb8_data: &[u8]) -> Result<()> {
+    write_png_with_metadata(write, width, height, rgb8_data, &[])
+}
+
+/// Write 8bpp RGB like [`write_png`], with a text chunk for each keyword and text in `metadata`
+pub fn write_png_with_metadata(
+    write: impl Write,
+    width: usize,
+    height: usize,
+    rgb8_data: &[u8],
+    metadata: &[(&str, &str)],
+) -> Result<()> {
+    let mut encoder = png::Encoder::new(write, u32::try_from(width)?, u32::try_from(height)?);
+    encoder.set_color(png::ColorType::RGB);
+    encoder.set_depth(png::BitDepth::Eight);
+    let mut writer = encoder.write_header()?;
+    // White point and primaries in units of 1e-5
+    let [red, green, blue, white] = ColorSpace::LinearSrgb.chromaticities();
+    let chromaticities: Vec<u8> = [white, red, green, blue]
+        .iter()
+        .flatten()
+        .flat_map(|&c| ((c * 1e5).round() as u32).to_be_bytes())
+        .collect();
+    writer.write_chunk(*b"cHRM", &chromaticities)?;
+    for (keyword, text) in metadata {
+        let chunk: Vec<u8> = [keyword.as_bytes(), &[0], text.as_bytes()].concat();
+        writer.write_chunk(*b"tEXt", &chunk)?;
+    }
+    writer.write_image_data(rgb8_data)?;
+    Ok(())
+}
+
+/// Write images of passes with [`Pass::channels`] values per pixel as an OpenEXR file, with a
+/// part named after each pass, tagged with the chromaticities of the `space` of their colors and
+/// a text attribute for each name and text in `metadata`. Channels are written in half
+/// precision if `half` is true.
+#[cfg(feature = "exr")]
+pub fn write_exr(
+    write: impl Write + Seek,
+    width: usize,
+    height: usize,
+    passes: &[(Pass, Vec<f32>)],
+    space: ColorSpace,
+    half: bool,
+    metadata: &[(&str, &str)],
+) -> Result<()> {
+    use exr::{meta::attribute::Chromaticities, prelude::*};
+
+    let layers: Vec<_> = passes
+        .iter()
+        .map(|(pass, data)| {
+            let channels = pass
+                .channel_names()
+                .iter()
+                .enumerate()
+                .map(|(c, &name)| {
+                    let samples = data.iter().skip(c).step_by(pass.channels()).copied();
+                    let samples = if half {
+                        FlatSamples::F16(
+                            samples
+                                .map(|v| f16::from_bits(color::f32_to_f16(v)))
+                                .collect(),
+                        )
+                    } else {
+                        FlatSamples::F32(samples.collect())
+                    };
+                    AnyChannel::new(name, samples)
+                })
+                .collect();
+            Layer::new(
+                (width, height),
+                LayerAttributes::named(pass.name()),
+                Encoding::FAST_LOSSLESS,
+                AnyChannels::sort(channels),
+            )
+        })
+        .collect();
+    let mut attributes = ImageAttributes::new(IntegerBounds::from_dimensions((width, height)));
+    let [red, green, blue, white] = space.chromaticities().map(|[x, y]| Vec2(x, y));
+    attributes.chromaticities = Some(Chromaticities {
+        red,
+        green,
+        blue,
+        white,
+    });
+    for &(name, text) in metadata {
+        attributes
+            .other
+            .insert(Text::from(name), AttributeValue::Text(Text::from(text)));
+    }
+    Image::from_layers(attributes, layers)
+        .write()
+        .to_buffered(write)?;
+    Ok(())
+}
@@ -0,0 +1,217 @@
+//! Reading color lookup tables in the .cube format, such as display and view transforms of
+//! OpenColorIO configs baked with `ociobakelut --format resolve_cube`. OpenColorIO configs
+//! themselves are not read, so a transform has to be baked before rendering.
+
+use anyhow::{anyhow, Context, Result};
+use std::{fs, path::Path};
+use ultraviolet::Vec3;
+
+/// Largest number of values along each axis of a 3D table, where 256 values already cover every
+/// 8-bit input and take 200 MB
+const MAX_SIZE: usize = 256;
+
+/// Largest number of values in a 1D table
+const MAX_SHAPER_SIZE: usize = 65536;
+
+/// Table of values sampled over a range of inputs for each channel
+#[derive(Debug)]
+struct Table {
+    min: Vec3,
+    max: Vec3,
+    values: Vec<Vec3>,
+}
+
+impl Table {
+    /// Position of `color` between the first and last of `size` values along each channel
+    fn position(&self, color: Vec3, size: usize) -> Vec3 {
+        let t = (color - self.min) / (self.max - self.min);
+        t.clamped(Vec3::zero(), Vec3::one()) * (size - 1) as f32
+    }
+}
+
+/// 3D lookup table, optionally after a 1D shaper table for each channel which allows it to
+/// cover a wide range of inputs, such as scene-linear colors
+#[derive(Debug)]
+pub struct Lut {
+    shaper: Option<Table>,
+    size: usize,
+    /// Red changes fastest, then green, then blue
+    cube: Table,
+}
+
+impl Lut {
+    pub fn open(path: &Path) -> Result<Self> {
+        let text = fs::read(path).with_context(|| format!("Cannot read {}", path.display()))?;
+        Self::parse(&String::from_utf8_lossy(&text))
+            .with_context(|| format!("Cannot parse {}", path.display()))
+    }
+
+    pub fn parse(text: &str) -> Result<Self> {
+        let (mut shaper_size, mut size) = (0, 0);
+        let (mut shaper_range, mut domain) = ([0., 1.], [Vec3::zero(), Vec3::one()]);
+        let mut rows = Vec::new();
+        for line in text.lines().map(str::trim) {
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let mut words = line.split_whitespace();
+            let keyword = words.next().unwrap_or_default();
+            let mut numbers = words.map(|number| {
+                number
+                    .parse::<f32>()
+                    .map_err(|_| anyhow!("Invalid number {}", number))
+            });
+            let mut next = || {
+                numbers
+                    .next()
+                    .unwrap_or_else(|| Err(anyhow!("Missing number after {}", keyword)))
+            };
+            match keyword {
+                "TITLE" => {}
+                "LUT_1D_SIZE" => shaper_size = table_size(next()?, MAX_SHAPER_SIZE)?,
+                "LUT_3D_SIZE" => size = table_size(next()?, MAX_SIZE)?,
+                "LUT_1D_INPUT_RANGE" => shaper_range = [next()?, next()?],
+                "LUT_3D_INPUT_RANGE" => {
+                    let [min, max] = [next()?, next()?];
+                    domain = [Vec3::broadcast(min), Vec3::broadcast(max)];
+                }
+                "DOMAIN_MIN" => domain[0] = Vec3::new(next()?, next()?, next()?),
+                "DOMAIN_MAX" => domain[1] = Vec3::new(next()?, next()?, next()?),
+                _ if keyword.starts_with(|c: char| c.is_ascii_alphabetic()) => {
+                    return Err(anyhow!("Unsupported keyword {}", keyword))
+                }
+                _ => {
+                    let value = keyword
+                        .parse::<f32>()
+                        .map_err(|_| anyhow!("Invalid number {}", keyword))?;
+                    rows.push(Vec3::new(value, next()?, next()?));
+                }
+            }
+        }
+
+        if size < 2 {
+            return Err(anyhow!("No 3D table"));
+        }
+        if shaper_size == 1 {
+            return Err(anyhow!("1D table has only one value"));
+        }
+        let expected = size
+            .checked_pow(3)
+            .and_then(|values| values.checked_add(shaper_size))
+            .ok_or_else(|| anyhow!("Tables are too large"))?;
+        if rows.len() != expected {
+            return Err(anyhow!(
+                "Tables have {} values instead of {}",
+                rows.len(),
+                expected
+            ));
+        }
+        // The shaper comes first, and only .cube files from Resolve have one together with a 3D
+        // table, whose domain is then the output of the shaper
+        let cube = rows.split_off(shaper_size);
+        let shaper = (shaper_size > 0).then(|| Table {
+            min: Vec3::broadcast(shaper_range[0]),
+            max: Vec3::broadcast(shaper_range[1]),
+            values: rows,
+        });
+        Ok(Self {
+            shaper,
+            size,
+            cube: Table {
+                min: domain[0],
+                max: domain[1],
+                values: cube,
+            },
+        })
+    }
+
+    /// Look up `color`, interpolating linearly between the values of the tables
+    pub fn apply(&self, color: Vec3) -> Vec3 {
+        let color = match &self.shaper {
+            Some(shaper) => {
+                let p = shaper.position(color, shaper.values.len());
+                let channel = |c: usize| {
+                    let (i, t) = split(p.as_slice()[c], shaper.values.len());
+                    let at = |i: usize| shaper.values[i].as_slice()[c];
+                    lerp(at(i), at(i + 1), t)
+                };
+                Vec3::new(channel(0), channel(1), channel(2))
+            }
+            None => color,
+        };
+
+        let p = self.cube.position(color, self.size);
+        let ((r, tr), (g, tg), (b, tb)) = (
+            split(p.x, self.size),
+            split(p.y, self.size),
+            split(p.z, self.size),
+        );
+        let at =
+            |r: usize, g: usize, b: usize| self.cube.values[(b * self.size + g) * self.size + r];
+        let plane = |b: usize| {
+            lerp(
+                lerp(at(r, g, b), at(r + 1, g, b), tr),
+                lerp(at(r, g + 1, b), at(r + 1, g + 1, b), tr),
+                tg,
+            )
+        };
+        lerp(plane(b), plane(b + 1), tb)
+    }
+}
+
+/// Number of values in a table, which is a whole number up to `max`
+fn table_size(value: f32, max: usize) -> Result<usize> {
+    if value.fract() != 0. || !(0. ..=max as f32).contains(&value) {
+        return Err(anyhow!(
+            "Table size {} is not a whole number up to {}",
+            value,
+            max
+        ));
+    }
+    Ok(value as usize)
+}
+
+/// Index of the value at or below position `p` in a table of `size` values, which is not the
+/// last one, and how far `p` is towards the next one
+fn split(p: f32, size: usize) -> (usize, f32) {
+    let i = (p as usize).min(size - 2);
+    (i, p - i as f32)
+}
+
+fn lerp<T>(a: T, b: T, t: f32) -> T
+where
+    T: std::ops::Add<Output = T>
+        + std::ops::Sub<Output = T>
+        + std::ops::Mul<f32, Output = T>
+        + Copy,
+{
+    a + (b - a) * t
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn identity() {
+        let mut text = String::from("LUT_3D_SIZE 2\n");
+        for b in 0..2 {
+            for g in 0..2 {
+                for r in 0..2 {
+                    text += &format!("{} {} {}\n", r, g, b);
+                }
+            }
+        }
+        let lut = Lut::parse(&text).unwrap();
+        let color = Vec3::new(0.25, 0.5, 0.75);
+        assert!((lut.apply(color) - color).mag() < 1e-6);
+    }
+
+    #[test]
+    fn rejects_invalid_sizes() {
+        for size in ["257", "1e30", "-8", "2.5", "NaN"] {
+            assert!(Lut::parse(&format!("LUT_3D_SIZE {}\n", size)).is_err());
+        }
+        assert!(Lut::parse("LUT_1D_SIZE 100000\nLUT_3D_SIZE 2\n").is_err());
+    }
+}
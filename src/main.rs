@@ -1,49 +1,174 @@
-mod camera;
-mod color;
-mod ray;
-mod world;
+mod bench;
+mod builtin;
+mod burn_in;
+mod compare;
+mod diagnostics;
+mod http;
+mod incremental;
+mod info;
+mod net;
+#[cfg(feature = "numa")]
+mod numa;
+mod priority;
+mod sweep;
+mod term_preview;
 
 use anyhow::{anyhow, Context, Result};
-use camera::Camera;
-use color::{Color, OutputColor, COLOR_CHANNELS};
-use parking_lot::Mutex;
+use builtin::Seeded;
+use incremental::Previous;
 use rand::prelude::*;
 use rand_xorshift::XorShiftRng;
-use ray::Ray;
+use rt::{
+    bake::{BakeOptions, BakedTexel, Baker},
+    color::{Color, ColorSpace, Display, OutputColor, COLOR_CHANNELS},
+    denoise::{DenoiseOptions, Denoiser, FEATURE_PASSES},
+    filter::{PixelFilter, SplatImage},
+    fog::DepthFog,
+    guiding::GuidingOptions,
+    image::Image,
+    mlt::{self, Mlt, MltOptions},
+    ray::Regularization,
+    render::{CancellationToken, Frame, Integrator, Pass, Renderer, TileCompleted, COMPONENTS},
+    sampler::{RngKind, SamplerKind},
+    scene::{Scene, MAIN_CAMERA},
+    texture::TextureCacheOptions,
+    write_exr, write_png, write_png_with_metadata,
+};
 use std::{
-    convert::TryFrom,
-    ffi::OsString,
-    fs::File,
-    io::{prelude::*, BufWriter},
-    time::SystemTime,
+    fs::{self, File},
+    io::BufWriter,
+    net::TcpListener,
+    path::{Path, PathBuf},
+    sync::atomic::{AtomicBool, AtomicUsize, Ordering},
+    time::{Duration, Instant, SystemTime},
 };
-use ultraviolet::{Lerp, Vec2, Vec3};
-use world::World;
+use sweep::{Override, Sweep};
+use term_preview::Protocol;
+use ultraviolet::Vec3;
 
-fn ray_color<R: Rng>(r: Ray, world: &World<R>, rng: &mut R, depth: u32) -> Vec3 {
-    if depth == 0 {
-        return Vec3::zero();
-    }
+/// Of biased renders of scenes which don't have one
+const DEFAULT_MAX_RADIANCE: f32 = 10.;
 
-    if let Some((hit, material)) = world.traverse(&r, 0.001) {
-        if let Some((att, r)) = material.scatter(rng, r, hit) {
-            att * ray_color(r, world, rng, depth - 1)
-        } else {
-            Vec3::zero()
-        }
-    } else {
-        // From 0 to 1 when down to up
-        let t = 0.5 * (r.direction().y + 1.);
-        // Blue to white gradient
-        Vec3::one().lerp(Vec3::new(0.5, 0.7, 1.), t)
+struct Options {
+    width: usize,
+    height: usize,
+    samples_per_pixel: u32,
+    nthreads: usize,
+    term_preview: Option<Protocol>,
+    term_preview_interval: Duration,
+    cancel: CancellationToken,
+    /// Directory where the previous render is kept for reusing its tiles
+    incremental: Option<PathBuf>,
+    /// Nodes which the rendering threads are spread over
+    #[cfg(feature = "numa")]
+    numa: Option<std::sync::Arc<numa::Placement>>,
+}
+
+/// Images of a rendered frame
+struct Rendered {
+    /// 8bpp RGB
+    image: Vec<u8>,
+    /// Linear RGB
+    linear: Vec<f32>,
+    passes: Vec<(Pass, Vec<f32>)>,
+    display: Display,
+}
+
+impl Rendered {
+    /// Replace the image with the linear one, after it has been changed
+    fn encode(&mut self) {
+        let display = &self.display;
+        self.image = self
+            .linear
+            .chunks_exact(COLOR_CHANNELS)
+            .flat_map(|c| display.encode(Vec3::new(c[0], c[1], c[2])))
+            .collect();
     }
 }
 
+struct Listeners {
+    http: Option<TcpListener>,
+    coordinator: Option<TcpListener>,
+}
+
+/// Names which are taken for a subcommand when they are the first argument after the global
+/// options
+const SUBCOMMANDS: [&str; 7] = [
+    "worker",
+    "bake",
+    "info",
+    "bench",
+    "diff",
+    "contact-sheet",
+    "matball",
+];
+
 fn main() -> Result<()> {
     let mut args = pico_args::Arguments::from_env();
+    // Leave a core free for other programs, unless told how many threads to use
+    let background = args.contains("--background");
+    let nthreads: usize = args
+        .opt_value_from_str(["-j", "--threads"])?
+        .unwrap_or_else(|| num_cpus::get() - usize::from(background && num_cpus::get() > 1));
+    if background {
+        priority::lower().context("Cannot lower the priority")?;
+    }
+
+    // The global options above may come before the subcommand, which is then the first of the
+    // remaining arguments
+    let mut remaining = args.finish();
+    let subcommand = match remaining.first().and_then(|arg| arg.to_str()) {
+        Some(name) if SUBCOMMANDS.contains(&name) => remaining.remove(0).into_string().ok(),
+        _ => None,
+    };
+    let mut args = pico_args::Arguments::from_vec(remaining);
+
+    if subcommand.as_deref() == Some("worker") {
+        let address: String = args.value_from_str("--connect")?;
+        let remaining = args.finish();
+        if !remaining.is_empty() {
+            return Err(anyhow!("Unknown arguments {:?}", remaining));
+        }
+        return net::work(&address, nthreads);
+    }
+    if subcommand.as_deref() == Some("bake") {
+        return bake(args, nthreads);
+    }
+    if subcommand.as_deref() == Some("info") {
+        let compact_memory = args.contains("--compact-memory");
+        let scene_path: PathBuf = args.free_from_str()?;
+        let remaining = args.finish();
+        if !remaining.is_empty() {
+            return Err(anyhow!("Unknown arguments {:?}", remaining));
+        }
+        let mut scene = Scene::open(&scene_path)?;
+        scene.resolve_materials()?;
+        scene.compact_memory |= compact_memory;
+        return info::print(&scene, 0);
+    }
+    if subcommand.as_deref() == Some("bench") {
+        let json = args.contains("--json");
+        let numa = args.contains("--numa");
+        let remaining = args.finish();
+        if !remaining.is_empty() {
+            return Err(anyhow!("Unknown arguments {:?}", remaining));
+        }
+        return bench::run(json, nthreads, numa);
+    }
+    if subcommand.as_deref() == Some("diff") {
+        return diff(args);
+    }
+    if subcommand.as_deref() == Some("contact-sheet") {
+        return contact_sheet(args, nthreads);
+    }
+    // Otherwise renders like without a subcommand
+    let material_path: Option<PathBuf> = if subcommand.as_deref() == Some("matball") {
+        Some(args.value_from_str("--material")?)
+    } else {
+        None
+    };
 
     // Image
-    const MAX_DEPTH: u32 = 64;
     let aspect_ratio: f32 = args
         .opt_value_from_fn(["-a", "--aspect-ratio"], |s| {
             let mut split = s.splitn(2, ':');
@@ -65,114 +190,1428 @@ fn main() -> Result<()> {
         .unwrap_or(720);
     let image_width: usize = (image_height as f32 * aspect_ratio) as usize;
     let samples_per_pixel: u32 = args.opt_value_from_str(["-s", "--samples"])?.unwrap_or(64);
+    let term_preview: Option<Protocol> = if args.contains("--term-preview") {
+        Some(
+            args.opt_value_from_str("--term-protocol")?
+                .unwrap_or_else(Protocol::detect),
+        )
+    } else {
+        None
+    };
+    let term_preview_interval = Duration::from_secs(
+        args.opt_value_from_str("--term-preview-interval")?
+            .unwrap_or(5),
+    );
+    let bvh_bins: Option<usize> = args.opt_value_from_str("--bvh-bins")?;
+    let bvh_max_leaf_size: Option<usize> = args.opt_value_from_str("--bvh-max-leaf")?;
+    let bvh_width: Option<usize> = args.opt_value_from_str("--bvh-width")?;
+    let bvh_cache: Option<PathBuf> = args.opt_value_from_str("--bvh-cache")?;
+    let compact_memory = args.contains("--compact-memory");
+    let sampler: Option<SamplerKind> = args.opt_value_from_str("--sampler")?;
+    let filter: Option<PixelFilter> = args.opt_value_from_str("--filter")?;
+    let light_samples: Option<u32> = args.opt_value_from_str("--light-samples")?;
+    let splat = args.contains("--splat");
+    let rng: Option<RngKind> = args.opt_value_from_str("--rng")?;
+    let integrator: Option<Integrator> = args.opt_value_from_str("--integrator")?;
+    let components = args.contains("--components");
+    let direct_indirect = args.contains("--direct-indirect");
+    let aovs = args.contains("--aovs");
+    let fog_density: Option<f32> = args.opt_value_from_str("--fog")?;
+    let fog_pass = args.contains("--fog-pass");
+    let exr_half = args.contains("--exr-half");
+    // Both a PNG and an OpenEXR file of the image, differing only in the extension
+    let dual = args.contains("--dual");
+    let diagnostics = args.contains("--diagnostics");
+    let burn_in = args.contains("--burn-in");
+    let pyramid = args.contains("--pyramid");
+    let preview_geometry = args.contains("--preview-geometry");
+    let bracket: Vec<f32> = args
+        .opt_value_from_fn("--bracket", |s| {
+            s.split(',').map(str::parse).collect::<Result<_, _>>()
+        })?
+        .unwrap_or_default();
+    // Percentages of the resolution, like 25 or 25%, of smaller copies written alongside
+    let proxies: Vec<f32> = args
+        .opt_value_from_fn("--proxy", |s| {
+            s.split(',')
+                .map(|s| s.trim_end_matches('%').parse::<f32>())
+                .collect::<Result<_, _>>()
+        })?
+        .unwrap_or_default();
+    if proxies.iter().any(|&p| !(p > 0. && p < 100.)) {
+        return Err(anyhow!("Proxies must be smaller than the image"));
+    }
+    let denoise = args.contains("--denoise");
+    let noise_threshold: Option<f32> = args.opt_value_from_str("--noise-threshold")?;
+    // A .cube file, such as an OpenColorIO display transform baked with ociobakelut, because
+    // OpenColorIO configs are not read directly
+    let display_lut: Option<PathBuf> = args.opt_value_from_str("--display-lut")?;
+    let texture_budget: Option<usize> = args.opt_value_from_str("--texture-budget")?;
+    let guiding = args.contains("--guiding");
+    let regularize = args.contains("--regularize");
+    let biased = args.contains("--biased");
+    let scene_path: Option<PathBuf> = args.opt_value_from_str("--scene")?;
+    let builtin: Option<Seeded> = args.opt_value_from_str("--builtin")?;
+    let camera: Option<String> = args.opt_value_from_str("--camera")?;
+    let incremental: Option<PathBuf> = args.opt_value_from_str("--incremental")?;
+    #[cfg(feature = "profile")]
+    let profile_path: Option<PathBuf> = args.opt_value_from_str("--profile")?;
+    #[cfg(feature = "numa")]
+    let numa = args.contains("--numa");
+    let http_address: Option<String> = args.opt_value_from_str("--http")?;
+    let listen_address: Option<String> = args.opt_value_from_str("--listen")?;
+    // Without threads of its own, only workers can render the tiles
+    if nthreads == 0 && listen_address.is_none() {
+        return Err(anyhow!(
+            "Rendering needs at least one thread, or workers with --listen"
+        ));
+    }
+
+    // Animation
+    let frame_start: Option<u32> = args.opt_value_from_str("--frame-start")?;
+    let frame_end: Option<u32> = args.opt_value_from_str("--frame-end")?;
+    let frame_step: Option<u32> = args.opt_value_from_str("--frame-step")?;
+    let animation = frame_start.is_some() || frame_end.is_some() || frame_step.is_some();
+    let frame_start = frame_start.unwrap_or(1);
+    let frames: Vec<u32> = if animation {
+        let step = frame_step.unwrap_or(1);
+        if step == 0 {
+            return Err(anyhow!("Frame step must be positive"));
+        }
+        (frame_start..=frame_end.unwrap_or(frame_start))
+            .step_by(step as usize)
+            .collect()
+    } else {
+        vec![0]
+    };
+    if animation && incremental.is_some() {
+        return Err(anyhow!(
+            "Incremental rendering of animations is not supported"
+        ));
+    }
+    let overrides: Vec<Override> = args.values_from_str("--set")?;
+    let sweep: Option<Sweep> = args.opt_value_from_str("--sweep")?;
+    // Material whose parameter is swept
+    let target: Option<usize> = args.opt_value_from_str("--target")?;
+    if sweep.is_some() && (animation || incremental.is_some()) {
+        return Err(anyhow!(
+            "Sweeps can't be animated or rendered incrementally"
+        ));
+    }
+    // Images of sweeps are numbered like frames
+    let numbered = animation || sweep.is_some();
+    let manifest = args.contains("--manifest");
+    // Every invocation of an animation has to agree on the scene, so don't default to time
+    let seed: u64 = match args.opt_value_from_str("--seed")? {
+        Some(seed) => seed,
+        None if animation => 0,
+        None => SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)?
+            .as_secs(),
+    };
+
     let mut remaining = args.finish();
-    let output_file_path = remaining.pop().unwrap_or_else(|| {
-        OsString::from(format!(
-            "{}.png",
-            humantime::format_rfc3339(SystemTime::now())
-        ))
-    });
+    let output_file_path = match remaining.pop() {
+        Some(path) => path
+            .into_string()
+            .map_err(|path| anyhow!("Output path {:?} is not valid UTF-8", path))?,
+        None => format!(
+            "{}{}.png",
+            humantime::format_rfc3339(SystemTime::now()),
+            if numbered { "_####" } else { "" }
+        ),
+    };
     if !remaining.is_empty() {
         return Err(anyhow!("Unknown arguments {:?}", remaining));
     }
-    // Ensure output file is writable before starting a long render
-    let output_file_writer =
-        BufWriter::new(File::create(output_file_path).context("Cannot create output file")?);
 
-    // World (different each time)
-    let world = World::random(&mut XorShiftRng::seed_from_u64(
-        SystemTime::now()
-            .duration_since(std::time::UNIX_EPOCH)?
-            .as_secs(),
-    ));
-
-    // Camera
-    let lookfrom = Vec3::new(13., 2., 3.);
-    let lookat = Vec3::zero();
-    let camera = Camera::new(
-        lookfrom,
-        lookat,
-        Vec3::unit_y(),
-        20.,
-        aspect_ratio,
-        0.1,
-        10.,
-        0f32..1.,
-    );
+    if manifest {
+        print_manifest(&output_file_path, seed, &frames);
+        return Ok(());
+    }
+
+    // Bind servers early so that address errors are reported right away
+    let bind = |address: Option<String>| {
+        address
+            .map(|address| {
+                TcpListener::bind(&address).with_context(|| format!("Cannot listen on {}", address))
+            })
+            .transpose()
+    };
+    let listeners = Listeners {
+        http: bind(http_address)?,
+        coordinator: bind(listen_address)?,
+    };
+    let mut options = Options {
+        width: image_width,
+        height: image_height,
+        samples_per_pixel,
+        nthreads,
+        term_preview,
+        term_preview_interval,
+        cancel: CancellationToken::new(),
+        incremental,
+        #[cfg(feature = "numa")]
+        numa: if numa {
+            let placement = numa::Placement::detect()?;
+            eprintln!("Spreading threads over {} NUMA nodes", placement.nodes());
+            Some(std::sync::Arc::new(placement))
+        } else {
+            None
+        },
+    };
+
+    // Stop at tile boundaries on the first interrupt and keep what has been rendered so far
+    let cancel = options.cancel.clone();
+    ctrlc::set_handler(move || {
+        if cancel.is_cancelled() {
+            std::process::exit(130);
+        }
+        eprintln!("\nInterrupted, finishing tiles in progress. Interrupt again to quit.");
+        cancel.cancel();
+    })
+    .context("Cannot set interrupt handler")?;
+
+    // Shown in burned in text
+    let scene_name = scene_path
+        .as_deref()
+        .or(material_path.as_deref())
+        .and_then(Path::file_stem)
+        .map_or_else(
+            || String::from("random"),
+            |stem| stem.to_string_lossy().into_owned(),
+        );
+    if builtin.is_some() && (scene_path.is_some() || material_path.is_some()) {
+        return Err(anyhow!("Give either a scene or a built-in one"));
+    }
+    let mut scene = match (scene_path, material_path) {
+        (Some(_), Some(_)) => return Err(anyhow!("Material balls have a scene of their own")),
+        (Some(path), None) => Scene::open(&path)?,
+        (None, Some(path)) => Scene::material_ball(read_ron(&path)?),
+        (None, None) => builtin.unwrap_or_default().scene(seed),
+    };
+    scene.resolve_materials()?;
+    for set in &overrides {
+        set.apply(&mut scene, &mut options.samples_per_pixel)?;
+    }
+    if let Some(bins) = bvh_bins {
+        scene.bvh.bins = bins;
+    }
+    if let Some(max_leaf_size) = bvh_max_leaf_size {
+        scene.bvh.max_leaf_size = max_leaf_size;
+    }
+    if let Some(width) = bvh_width {
+        scene.bvh.width = width;
+    }
+    scene.bvh.cache = bvh_cache;
+    scene.compact_memory |= compact_memory;
+    if let Some(sampler) = sampler {
+        scene.sampler = sampler;
+    }
+    if let Some(filter) = filter {
+        scene.filter = filter;
+    }
+    scene.splat |= splat;
+    if let Some(light_samples) = light_samples {
+        scene.light_samples = light_samples;
+    }
+    if let Some(rng) = rng {
+        scene.rng = rng;
+    }
+    if let Some(integrator) = integrator {
+        scene.integrator = integrator;
+    }
+    if noise_threshold.is_some() {
+        scene.noise_threshold = noise_threshold;
+    }
+    if display_lut.is_some() {
+        scene.display_lut = display_lut;
+    }
+    if let Some(megabytes) = texture_budget {
+        let directory = scene.texture_cache.take().and_then(|cache| cache.directory);
+        scene.texture_cache = Some(TextureCacheOptions {
+            megabytes,
+            directory,
+        });
+    }
+    if guiding && scene.guiding.is_none() {
+        scene.guiding = Some(GuidingOptions::default());
+    }
+    // Regularization is one of the speedups which bias the image
+    if (biased || regularize) && scene.regularization.is_none() {
+        scene.regularization = Some(Regularization::default());
+    }
+    if biased && scene.max_radiance.is_none() {
+        scene.max_radiance = Some(DEFAULT_MAX_RADIANCE);
+    }
+    scene.biased |= biased || regularize;
+    if components {
+        scene
+            .passes
+            .extend(COMPONENTS.iter().copied().map(Pass::Component));
+    }
+    if direct_indirect {
+        scene.passes.extend([Pass::Direct, Pass::Indirect]);
+    }
+    if let Some(density) = fog_density {
+        scene.fog = Some(DepthFog {
+            density,
+            ..scene.fog.unwrap_or_default()
+        });
+    }
+    if fog_pass {
+        if scene.fog.is_none() {
+            return Err(anyhow!("The fog pass needs fog, see --fog"));
+        }
+        scene.passes.push(Pass::Fog);
+    }
+    // OpenEXR files have all passes, starting with linear color
+    let exr = Path::new(&output_file_path)
+        .extension()
+        .is_some_and(|extension| extension.eq_ignore_ascii_case("exr"));
+    if exr_half && !exr && !dual {
+        return Err(anyhow!("--exr-half needs an OpenEXR output file"));
+    }
+    if aovs {
+        scene
+            .passes
+            .extend([Pass::Albedo, Pass::Normal, Pass::Variance]);
+        if exr {
+            scene.passes.push(Pass::Depth);
+        }
+    }
+    if preview_geometry {
+        if exr {
+            return Err(anyhow!(
+                "Geometry previews can only be written to PNG files"
+            ));
+        }
+        // Nothing is sampled, so there is nothing to guide
+        scene.guiding = None;
+    }
+    if exr && burn_in {
+        return Err(anyhow!("Text can only be burned into PNG files"));
+    }
+    if exr {
+        // Written from the accumulated samples instead
+        scene.passes.retain(|&pass| pass != Pass::Beauty);
+    } else if scene.passes.contains(&Pass::Depth) {
+        return Err(anyhow!(
+            "The depth pass can only be written to OpenEXR files"
+        ));
+    }
+    // Passes which are only rendered for the denoiser or the fog aren't written
+    let written = scene.passes.clone();
+    if denoise {
+        for pass in FEATURE_PASSES {
+            if !scene.passes.contains(&pass) {
+                scene.passes.push(pass);
+            }
+        }
+    }
+    if scene.fog.is_some() && !scene.passes.contains(&Pass::Fog) {
+        scene.passes.push(Pass::Fog);
+    }
+
+    if views(&scene, &camera)?.len() > 1 && options.incremental.is_some() {
+        return Err(anyhow!(
+            "Incremental rendering of more than one camera is not supported"
+        ));
+    }
+
+    if scene.integrator == Integrator::Pssmlt {
+        // Chains cover the whole image, so it can't be split into tiles
+        if scene.fog.is_some() {
+            return Err(anyhow!("PSSMLT renders can't be fogged"));
+        }
+        if !scene.passes.is_empty() {
+            return Err(anyhow!("Passes can't be rendered with PSSMLT"));
+        }
+        if options.incremental.is_some() || listeners.coordinator.is_some() {
+            return Err(anyhow!(
+                "PSSMLT renders can't be incremental or distributed"
+            ));
+        }
+    }
+    if scene.splat {
+        // Samples land in the pixels of other tiles
+        if scene.integrator == Integrator::Pssmlt {
+            return Err(anyhow!("PSSMLT renders can't be splatted"));
+        }
+        if scene.fog.is_some() {
+            return Err(anyhow!("Splatted renders can't be fogged"));
+        }
+        if !scene.passes.is_empty() {
+            return Err(anyhow!("Passes can't be splatted"));
+        }
+        if options.incremental.is_some() || listeners.coordinator.is_some() {
+            return Err(anyhow!(
+                "Splatted renders can't be incremental or distributed"
+            ));
+        }
+    }
+
+    if let Some(sweep) = &sweep {
+        if sweep.parameter.of_material() && target.is_none() {
+            return Err(anyhow!("Choose the material to vary with --target"));
+        }
+    }
+    // Steps of a sweep render the first frame of a variant of the scene, numbered from 1
+    let runs: Vec<(u32, Option<f32>)> = match &sweep {
+        Some(sweep) => (1..).zip(sweep.values().map(Some)).collect(),
+        None => frames.iter().map(|&frame| (frame, None)).collect(),
+    };
+
+    for &(number, value) in &runs {
+        let base_path = if numbered {
+            frame_path(&output_file_path, number)
+        } else {
+            output_file_path.clone()
+        };
+        let (frame, scene) = match (&sweep, value) {
+            (Some(sweep), Some(value)) => {
+                let mut scene = scene.clone();
+                sweep
+                    .parameter
+                    .apply(&mut scene, target, &mut options.samples_per_pixel, value)?;
+                (0, scene)
+            }
+            _ => (number, scene.clone()),
+        };
+        let views = views(&scene, &camera)?;
+        // Ensure output files are writable before starting a long render
+        let outputs = views
+            .iter()
+            .map(|(name, view)| {
+                let path = if views.len() > 1 && *name != MAIN_CAMERA {
+                    pass_path(&base_path, name)
+                } else {
+                    base_path.clone()
+                };
+                let writer =
+                    BufWriter::new(File::create(&path).context("Cannot create output file")?);
+                Ok((name, path, view, writer))
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        // The world is built once for all of the cameras
+        let mut renderer: Option<Renderer> = None;
+        for (name, path, view, output_file_writer) in outputs {
+            let renderer = match &mut renderer {
+                Some(renderer) => {
+                    renderer.set_camera(view, frame)?;
+                    renderer
+                }
+                None => renderer.insert(prepare_renderer(&scene, view, frame, &options)?),
+            };
+            if preview_geometry {
+                let started = Instant::now();
+                let image = render_geometry(renderer, nthreads)?;
+                write_png(output_file_writer, image_width, image_height, &image)
+                    .context("Failed to write output PNG file")?;
+                eprintln!(
+                    "Geometry of {} shaded in {}",
+                    name,
+                    humantime::format_duration(started.elapsed())
+                );
+                continue;
+            }
+            if pyramid {
+                render_pyramid(renderer, view, frame, &options, &listeners, &path)?;
+                if options.cancel.is_cancelled() {
+                    break;
+                }
+            }
+            let mut rendered = render_frame(renderer, view, frame, &options, &listeners)?;
+            if denoise {
+                denoise_image(&mut rendered, image_width, image_height, nthreads)?;
+            }
+            // The fog is in the scene, in front of the lens which flares
+            if let Some(fog) = &scene.fog {
+                if let Some((_, factors)) = rendered.passes.iter().find(|(p, _)| *p == Pass::Fog) {
+                    fog.apply(&mut rendered.linear, factors);
+                    rendered.encode();
+                }
+            }
+            if let Some(streaks) = &view.camera.flare_streaks {
+                streaks.apply(&mut rendered.linear, image_width, image_height);
+                rendered.encode();
+            }
+            if let Some(flare) = &view.camera.lens_flare {
+                flare.apply(&mut rendered.linear, image_width, image_height);
+                rendered.encode();
+            }
+            if let Some(grain) = &view.camera.grain {
+                // Grain changes from frame to frame like in footage
+                grain.apply(
+                    &mut rendered.linear,
+                    scene.working_space,
+                    seed.wrapping_add(u64::from(frame)),
+                );
+                rendered.encode();
+            }
+            let Rendered {
+                mut image,
+                linear,
+                mut passes,
+                display,
+            } = rendered;
+            passes.retain(|(pass, _)| written.contains(pass));
+
+            if diagnostics {
+                write_diagnostics(
+                    &path,
+                    &linear,
+                    image_width,
+                    image_height,
+                    scene.working_space,
+                )?;
+            }
+            if !bracket.is_empty() {
+                write_bracket(
+                    &path,
+                    &linear,
+                    image_width,
+                    image_height,
+                    &display,
+                    &bracket,
+                )?;
+            }
+            for &percent in &proxies {
+                write_proxy(&path, &linear, image_width, image_height, &display, percent)?;
+            }
+            if burn_in {
+                let mut text = format!(
+                    "{}  frame {}  {} spp  {}",
+                    scene_name,
+                    frame,
+                    options.samples_per_pixel,
+                    humantime::format_rfc3339_seconds(SystemTime::now())
+                );
+                if views.len() > 1 {
+                    text = format!("{}  {}", name, text);
+                }
+                burn_in::burn_in(&mut image, image_width, image_height, &text);
+            }
+            // Whether the image can be trusted as a reference
+            let metadata = [("biased", if scene.biased { "true" } else { "false" })];
+            if dual {
+                // The display image, or the linear one with the passes, of the other format
+                let other = Path::new(&path).with_extension(if exr { "png" } else { "exr" });
+                let writer =
+                    BufWriter::new(File::create(&other).context("Cannot create output file")?);
+                if exr {
+                    write_png_with_metadata(writer, image_width, image_height, &image, &metadata)
+                        .context("Failed to write output PNG file")?;
+                } else {
+                    let mut layers = vec![(Pass::Beauty, linear.clone())];
+                    layers.extend(
+                        passes
+                            .iter()
+                            .filter(|(pass, _)| *pass != Pass::Beauty)
+                            .cloned(),
+                    );
+                    write_exr(
+                        writer,
+                        image_width,
+                        image_height,
+                        &layers,
+                        scene.working_space,
+                        exr_half,
+                        &metadata,
+                    )
+                    .context("Failed to write output OpenEXR file")?;
+                }
+            }
+            if exr {
+                passes.insert(0, (Pass::Beauty, linear));
+                write_exr(
+                    output_file_writer,
+                    image_width,
+                    image_height,
+                    &passes,
+                    scene.working_space,
+                    exr_half,
+                    &metadata,
+                )
+                .context("Failed to write output OpenEXR file")?;
+            } else {
+                // Encode PNG from results
+                write_png_with_metadata(
+                    output_file_writer,
+                    image_width,
+                    image_height,
+                    &image,
+                    &metadata,
+                )
+                .context("Failed to write output PNG file")?;
+                for (pass, data) in &passes {
+                    let path = pass_path(&path, pass.name());
+                    let writer =
+                        BufWriter::new(File::create(&path).context("Cannot create output file")?);
+                    write_png_with_metadata(
+                        writer,
+                        image_width,
+                        image_height,
+                        &pass_rgb8(*pass, data, scene.working_space),
+                        &metadata,
+                    )
+                    .context("Failed to write output PNG file")?;
+                }
+            }
+            if options.cancel.is_cancelled() {
+                eprintln!("Cancelled, partial image written to {}", path);
+                break;
+            }
+        }
+        // Totals so far, so that the profile is there even if the animation is cancelled
+        #[cfg(feature = "profile")]
+        if let Some(path) = &profile_path {
+            rt::profile::write_folded(BufWriter::new(
+                File::create(path).context("Cannot create profile file")?,
+            ))
+            .context("Failed to write profile")?;
+        }
+        if options.cancel.is_cancelled() {
+            return Ok(());
+        }
+        if let (Some(sweep), Some(value)) = (&sweep, value) {
+            eprintln!("{:?} {} done.         ", sweep.parameter, value);
+        } else if animation {
+            eprintln!("Frame {} done.         ", frame);
+        }
+    }
+
+    eprintln!("Done.                  ");
+    Ok(())
+}
 
-    // Render using all cpu cores
-    let nthreads = num_cpus::get();
-    // Allocate image buffer
-    let mut pixel_data = vec![0u8; image_width * image_height * COLOR_CHANNELS];
-    // Divide buffer into chunks for threads to work on
-    const CHUNK_PIXELS: usize = 4096;
-    let chunks: Mutex<Vec<_>> = Mutex::new(
-        pixel_data
-            .chunks_mut(CHUNK_PIXELS * COLOR_CHANNELS)
-            .enumerate()
+/// Every camera of `scene` renders an image of its own, unless `camera` names one
+fn views(scene: &Scene, camera: &Option<String>) -> Result<Vec<(String, Scene)>> {
+    match camera {
+        Some(name) => Ok(vec![(name.clone(), scene.seen_by(name)?)]),
+        None => scene
+            .camera_names()?
+            .into_iter()
+            .map(|name| Ok((name.to_owned(), scene.seen_by(name)?)))
             .collect(),
+    }
+}
+
+/// Build the world of `scene` and learn its path guide through the camera of `view`
+fn prepare_renderer(
+    scene: &Scene,
+    view: &Scene,
+    frame: u32,
+    options: &Options,
+) -> Result<Renderer> {
+    let mut renderer = Renderer::new(
+        scene,
+        frame,
+        options.width,
+        options.height,
+        options.samples_per_pixel,
+    )?;
+    renderer.set_camera(view, frame)?;
+    let bvh = *renderer.bvh_stats();
+    eprintln!(
+        "BVH with {} nodes {} in {}, SAH cost {:.2}",
+        bvh.nodes,
+        if bvh.cached { "loaded" } else { "built" },
+        humantime::format_duration(bvh.build_time),
+        bvh.sah_cost
     );
+    if scene.guiding.is_some() {
+        let started = Instant::now();
+        renderer.train_guide(options.nthreads)?;
+        eprintln!(
+            "Path guide trained in {}",
+            humantime::format_duration(started.elapsed())
+        );
+    }
+    Ok(renderer)
+}
+
+/// 8bpp RGB image of how directly the surfaces seen by `renderer` face it, see
+/// [`Renderer::geometry_row`]
+fn render_geometry(renderer: &Renderer, nthreads: usize) -> Result<Vec<u8>> {
+    let next_row = AtomicUsize::new(0);
+    let mut rows: Vec<(usize, Vec<u8>)> = crossbeam_utils::thread::scope(|s| {
+        let threads: Vec<_> = (0..nthreads.max(1))
+            .map(|_| {
+                s.spawn(|_| {
+                    let mut rows = Vec::new();
+                    loop {
+                        let y = next_row.fetch_add(1, Ordering::Relaxed);
+                        if y >= renderer.height() {
+                            return rows;
+                        }
+                        let mut rng = XorShiftRng::seed_from_u64(y as u64);
+                        rows.push((y, renderer.geometry_row(&mut rng, y)));
+                    }
+                })
+            })
+            .collect();
+        threads
+            .into_iter()
+            .map(|thread| {
+                thread
+                    .join()
+                    .map_err(|_| anyhow!("Shading thread panicked"))
+            })
+            .collect::<Result<Vec<_>>>()
+            .map(|rows| rows.into_iter().flatten().collect())
+    })
+    .map_err(|_| anyhow!("A shading thread encountered an irrecoverable error"))??;
+    rows.sort_unstable_by_key(|&(y, _)| y);
+    Ok(rows.into_iter().flat_map(|(_, row)| row).collect())
+}
+
+/// Render and write the image of `scene` at 1/8, 1/4 and 1/2 of the size in `options` to
+/// PNG files next to `path`, for seeing the composition before the full image is done
+fn render_pyramid(
+    renderer: &mut Renderer,
+    scene: &Scene,
+    frame: u32,
+    options: &Options,
+    listeners: &Listeners,
+    path: &str,
+) -> Result<()> {
+    let png = Path::new(path).with_extension("png");
+    for (divisor, name) in [(8, "eighth"), (4, "quarter"), (2, "half")] {
+        let level = Options {
+            width: (options.width / divisor).max(1),
+            height: (options.height / divisor).max(1),
+            cancel: options.cancel.clone(),
+            incremental: None,
+            #[cfg(feature = "numa")]
+            numa: options.numa.clone(),
+            ..*options
+        };
+        renderer.set_resolution(level.width, level.height);
+        let rendered = render_frame(renderer, scene, frame, &level, listeners);
+        renderer.set_resolution(options.width, options.height);
+        let path = pass_path(&png.to_string_lossy(), name);
+        let writer = BufWriter::new(File::create(&path).context("Cannot create output file")?);
+        write_png(writer, level.width, level.height, &rendered?.image)
+            .context("Failed to write output PNG file")?;
+        eprintln!("Image at {} size written to {}", name, path);
+        if options.cancel.is_cancelled() {
+            break;
+        }
+    }
+    Ok(())
+}
+
+/// Render the image of `scene`, whose camera `renderer` looks through
+fn render_frame(
+    renderer: &Renderer,
+    scene: &Scene,
+    frame: u32,
+    options: &Options,
+    listeners: &Listeners,
+) -> Result<Rendered> {
+    let &Options {
+        width: image_width,
+        height: image_height,
+        samples_per_pixel,
+        ..
+    } = options;
+    let bvh = *renderer.bvh_stats();
+
+    if scene.integrator == Integrator::Pssmlt {
+        return render_mlt(renderer, options);
+    }
+    if scene.splat {
+        return render_splatted(renderer, scene.filter, options);
+    }
+
+    // Shared so that previews and remote workers can access it while rendering
+    let progress = |completed: &TileCompleted| {
+        eprint!(
+            "Tiles left {:>5}\r",
+            completed.tiles_total - completed.tiles_done
+        );
+    };
+    let image = Frame::new(renderer, &progress, options.cancel.clone());
+    if let Some(dir) = &options.incremental {
+        if let Some(previous) = Previous::load(dir)? {
+            let reused = previous.reuse(scene, &image, frame, samples_per_pixel)?;
+            eprintln!("Reused {} of {} tiles", reused, image.tiles_total());
+        }
+    }
+    let started = Instant::now();
+    let done = AtomicBool::new(false);
     // Run the rendering threads
     crossbeam_utils::thread::scope(|s| {
-        for _ in 0..nthreads {
-            s.spawn(|_| {
-                let mut rng = XorShiftRng::seed_from_u64(123);
-
-                while let Some((i, chunk)) = {
-                    let (chunk, len) = {
-                        let mut chunks = chunks.lock();
-                        (chunks.pop(), chunks.len())
-                    };
-                    eprint!("Chunks left {:>5}\r", len);
-                    chunk
-                } {
-                    let chunk_offset = CHUNK_PIXELS * i;
-                    for i in 0..chunk.len() / COLOR_CHANNELS {
-                        // Calculate pixel coordinates
-                        let pixel = chunk_offset + i;
-                        let xy = Vec2::new(
-                            (pixel % image_width) as f32,
-                            (image_height - 1 - (pixel / image_width)) as f32,
-                        );
-
-                        // Accumulate color from rays
-                        let mut color = Vec3::zero();
-                        for _ in 0..samples_per_pixel {
-                            // Ray through viewport in right handed space
-                            let random = Vec2::from(rng.gen::<[f32; 2]>());
-                            let wh = Vec2::new(image_width as f32, image_height as f32);
-                            let uv = (xy + random) / (wh - Vec2::one());
-                            color += ray_color(
-                                camera.get_ray(&mut rng, uv),
-                                &world,
-                                &mut rng,
-                                MAX_DEPTH,
-                            );
+        let renderers: Vec<_> = (0..options.nthreads)
+            .map(|thread| {
+                #[cfg(not(feature = "numa"))]
+                let _ = thread;
+                let image = &image;
+                s.spawn(move |_| {
+                    // Tile buffers are allocated by the thread, so pinning it first puts them
+                    // in the memory of its node
+                    #[cfg(feature = "numa")]
+                    if let Some(placement) = &options.numa {
+                        if let Err(e) = placement.pin(thread) {
+                            eprintln!("Cannot pin rendering thread {}: {}", thread, e);
+                        }
+                    }
+                    image.work(renderer)
+                })
+            })
+            .collect();
+
+        if let Some(listener) = &listeners.coordinator {
+            let job = ron::to_string(&net::Job {
+                scene: scene.clone(),
+                frame,
+                width: image_width,
+                height: image_height,
+                samples_per_pixel,
+            })
+            .expect("Scene can be serialized");
+            let (image, done) = (&image, &done);
+            s.spawn(move |s| -> Result<()> {
+                listener.set_nonblocking(true)?;
+                while !done.load(Ordering::Relaxed) {
+                    match listener.accept() {
+                        Ok((stream, address)) => {
+                            eprintln!("Worker {} connected", address);
+                            stream.set_nonblocking(false)?;
+                            let job = job.clone();
+                            s.spawn(move |_| {
+                                if let Err(e) = net::serve_worker(stream, &job, image) {
+                                    eprintln!("Worker {} failed: {}", address, e);
+                                }
+                            });
                         }
+                        Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => {
+                            std::thread::sleep(Duration::from_millis(50));
+                        }
+                        Err(e) => return Err(e.into()),
+                    }
+                }
+                Ok(())
+            });
+        }
 
-                        // Average samples, clamp and output to 8bpp RGB buffer
-                        chunk[i * COLOR_CHANNELS..][..COLOR_CHANNELS].copy_from_slice(
-                            &OutputColor::from(Color::from(color / samples_per_pixel as f32)),
-                        );
+        if let Some(protocol) = options.term_preview {
+            let (image, done) = (&image, &done);
+            let interval = options.term_preview_interval;
+            s.spawn(move |_| -> Result<()> {
+                let mut last = Instant::now();
+                while !done.load(Ordering::Relaxed) {
+                    std::thread::sleep(Duration::from_millis(100));
+                    if last.elapsed() >= interval {
+                        last = Instant::now();
+                        show_term_preview(protocol, image_width, image_height, &image.image())?;
                     }
                 }
+                show_term_preview(protocol, image_width, image_height, &image.image())
             });
         }
+
+        if let Some(listener) = &listeners.http {
+            let monitor = http::Monitor {
+                frame: &image,
+                samples_per_pixel,
+                started,
+                bvh,
+                #[cfg(feature = "numa")]
+                numa_nodes: options
+                    .numa
+                    .as_ref()
+                    .map_or(0, |placement| placement.nodes()),
+                #[cfg(not(feature = "numa"))]
+                numa_nodes: 0,
+            };
+            let done = &done;
+            s.spawn(move |_| {
+                if let Err(e) = http::serve(listener, &monitor, done) {
+                    eprintln!("HTTP server failed: {}", e);
+                }
+            });
+        }
+
+        let result = renderers.into_iter().try_for_each(|r| {
+            r.join()
+                .unwrap_or_else(|_| Err(anyhow!("Rendering thread panicked")))
+        });
+        // Without local threads, wait for the workers
+        while result.is_ok() && !image.stopped() {
+            std::thread::sleep(Duration::from_millis(50));
+        }
+        done.store(true, Ordering::Relaxed);
+        result
+    })
+    .map_err(|_| anyhow!("A rendering thread encountered an irrecoverable error"))??;
+
+    let failed: Vec<String> = image
+        .failed_tiles()
+        .into_iter()
+        .filter_map(|i| image.tile(i))
+        .map(|tile| format!("({}, {})", tile.x, tile.y))
+        .collect();
+    if !failed.is_empty() {
+        eprintln!(
+            "{} tiles failed to render and are magenta, at {}",
+            failed.len(),
+            failed.join(", ")
+        );
+    }
+    if let Some(dir) = &options.incremental {
+        Previous::save(dir, scene, &image, frame, samples_per_pixel)
+            .context("Cannot save render for incremental rendering")?;
+    }
+    let passes = image.pass_images();
+    Ok(Rendered {
+        linear: image.linear_image(),
+        image: image.into_image(),
+        passes,
+        display: renderer.display().clone(),
+    })
+}
+
+/// Render with as many mutations as `options` has samples, see [`rt::mlt`]
+fn render_mlt(renderer: &Renderer, options: &Options) -> Result<Rendered> {
+    let started = Instant::now();
+    let mlt = Mlt::new(renderer, MltOptions::default());
+    let mutations = options.width as u64 * options.height as u64 * options.samples_per_pixel as u64;
+    let (next_chain, chains_done) = (AtomicUsize::new(0), AtomicUsize::new(0));
+    let images: Vec<Vec<Vec3>> = crossbeam_utils::thread::scope(|s| {
+        let threads: Vec<_> = (0..options.nthreads.max(1))
+            .map(|_| {
+                s.spawn(|_| {
+                    let mut image = vec![Vec3::zero(); options.width * options.height];
+                    while !options.cancel.is_cancelled() {
+                        let chain = next_chain.fetch_add(1, Ordering::Relaxed);
+                        if chain >= mlt.chains() as usize {
+                            break;
+                        }
+                        mlt.run_chain(chain as u32, mutations, &mut image);
+                        let done = chains_done.fetch_add(1, Ordering::Relaxed) + 1;
+                        eprint!("Chains left {:>5}\r", mlt.chains() as usize - done);
+                    }
+                    image
+                })
+            })
+            .collect();
+        threads
+            .into_iter()
+            .map(|thread| {
+                thread
+                    .join()
+                    .map_err(|_| anyhow!("Rendering thread panicked"))
+            })
+            .collect::<Result<Vec<_>>>()
+    })
+    .map_err(|_| anyhow!("A rendering thread encountered an irrecoverable error"))??;
+    eprintln!(
+        "Rendered with PSSMLT in {}",
+        humantime::format_duration(started.elapsed())
+    );
+
+    let linear = mlt::resolve(images);
+    let display = renderer.display().clone();
+    Ok(Rendered {
+        image: linear
+            .chunks_exact(COLOR_CHANNELS)
+            .flat_map(|c| display.encode(Vec3::new(c[0], c[1], c[2])))
+            .collect(),
+        linear,
+        passes: Vec::new(),
+        display,
+    })
+}
+
+/// Render with every sample splatted into the pixels under `filter`, see [`SplatImage`]
+fn render_splatted(
+    renderer: &Renderer,
+    filter: PixelFilter,
+    options: &Options,
+) -> Result<Rendered> {
+    let started = Instant::now();
+    let (width, height) = (options.width, options.height);
+    let (next_row, rows_done) = (AtomicUsize::new(0), AtomicUsize::new(0));
+    // Each thread splats into an image of its own
+    let images: Vec<SplatImage> = crossbeam_utils::thread::scope(|s| {
+        let threads: Vec<_> = (0..options.nthreads.max(1))
+            .map(|_| {
+                s.spawn(|_| {
+                    let mut image = SplatImage::new(width, height, filter);
+                    while !options.cancel.is_cancelled() {
+                        let y = next_row.fetch_add(1, Ordering::Relaxed);
+                        if y >= height {
+                            break;
+                        }
+                        renderer.splat_row(y, &mut image);
+                        let done = rows_done.fetch_add(1, Ordering::Relaxed) + 1;
+                        eprint!("Rows left {:>5}\r", height - done);
+                    }
+                    image
+                })
+            })
+            .collect();
+        threads
+            .into_iter()
+            .map(|thread| {
+                thread
+                    .join()
+                    .map_err(|_| anyhow!("Rendering thread panicked"))
+            })
+            .collect::<Result<Vec<_>>>()
     })
-    .map_err(|_| anyhow!("A rendering thread encountered an irrecoverable error"))?;
+    .map_err(|_| anyhow!("A rendering thread encountered an irrecoverable error"))??;
+    eprintln!(
+        "Rendered with splatting in {}",
+        humantime::format_duration(started.elapsed())
+    );
 
-    // Encode PNG from results
-    write_png(output_file_writer, image_width, image_height, &pixel_data)
-        .context("Failed to write output PNG file")?;
+    let mut image = SplatImage::new(width, height, filter);
+    for other in &images {
+        image.merge(other);
+    }
+    let mut rendered = Rendered {
+        image: Vec::new(),
+        linear: image.resolve(),
+        passes: Vec::new(),
+        display: renderer.display().clone(),
+    };
+    rendered.encode();
+    Ok(rendered)
+}
+
+/// Replace the image with a reconstruction from its passes, see [`rt::denoise`]
+fn denoise_image(
+    rendered: &mut Rendered,
+    width: usize,
+    height: usize,
+    nthreads: usize,
+) -> Result<()> {
+    let started = Instant::now();
+    let denoiser = Denoiser::new(
+        width,
+        height,
+        &rendered.linear,
+        &rendered.passes,
+        DenoiseOptions::default(),
+    )?;
+    let next_row = AtomicUsize::new(0);
+    let mut rows: Vec<(usize, Vec<f32>)> = crossbeam_utils::thread::scope(|s| {
+        let threads: Vec<_> = (0..nthreads.max(1))
+            .map(|_| {
+                s.spawn(|_| {
+                    let mut rows = Vec::new();
+                    loop {
+                        let y = next_row.fetch_add(1, Ordering::Relaxed);
+                        if y >= denoiser.height() {
+                            return rows;
+                        }
+                        rows.push((y, denoiser.denoise_row(y)));
+                    }
+                })
+            })
+            .collect();
+        threads
+            .into_iter()
+            .map(|thread| {
+                thread
+                    .join()
+                    .map_err(|_| anyhow!("Denoising thread panicked"))
+            })
+            .collect::<Result<Vec<_>>>()
+            .map(|rows| rows.into_iter().flatten().collect())
+    })
+    .map_err(|_| anyhow!("A denoising thread encountered an irrecoverable error"))??;
+    rows.sort_unstable_by_key(|&(y, _)| y);
+    rendered.linear = rows.into_iter().flat_map(|(_, row)| row).collect();
+    rendered.encode();
+    eprintln!(
+        "Denoised in {}",
+        humantime::format_duration(started.elapsed())
+    );
+    Ok(())
+}
+
+/// Bake maps of a mesh in a scene and write them as PNG files named after the output path
+fn bake(mut args: pico_args::Arguments, nthreads: usize) -> Result<()> {
+    let scene_path: PathBuf = args.value_from_str("--scene")?;
+    let material: usize = args.value_from_str("--material")?;
+    let size: usize = args.opt_value_from_str("--size")?.unwrap_or(1024);
+    let options = BakeOptions {
+        width: size,
+        height: size,
+        rays: args.opt_value_from_str("--rays")?.unwrap_or(64),
+        cage_distance: args.opt_value_from_str("--cage-distance")?.unwrap_or(1e-3),
+        occlusion_distance: args
+            .opt_value_from_str("--occlusion-distance")?
+            .unwrap_or(f32::INFINITY),
+        curvature_radius: args
+            .opt_value_from_str("--curvature-radius")?
+            .unwrap_or(1e-2),
+    };
+    let mut remaining = args.finish();
+    let output_file_path = match remaining.pop() {
+        Some(path) => path
+            .into_string()
+            .map_err(|path| anyhow!("Output path {:?} is not valid UTF-8", path))?,
+        None => String::from("bake.png"),
+    };
+    if !remaining.is_empty() {
+        return Err(anyhow!("Unknown arguments {:?}", remaining));
+    }
+
+    let mut scene = Scene::open(&scene_path)?;
+    scene.resolve_materials()?;
+    let baker = Baker::new(&scene, material, options)?;
+    let (width, height) = (baker.width(), baker.height());
+    let next_row = AtomicUsize::new(0);
+    let mut rows: Vec<(usize, Vec<BakedTexel>)> = crossbeam_utils::thread::scope(|s| {
+        let threads: Vec<_> = (0..nthreads.max(1))
+            .map(|_| {
+                s.spawn(|_| {
+                    let mut rows = Vec::new();
+                    loop {
+                        let y = next_row.fetch_add(1, Ordering::Relaxed);
+                        if y >= height {
+                            return rows;
+                        }
+                        // Seeded by row so that the maps don't depend on the threads
+                        let mut rng = XorShiftRng::seed_from_u64(y as u64);
+                        rows.push((y, baker.bake_row(&mut rng, y)));
+                    }
+                })
+            })
+            .collect();
+        threads
+            .into_iter()
+            .map(|thread| thread.join().map_err(|_| anyhow!("Baking thread panicked")))
+            .collect::<Result<Vec<_>>>()
+            .map(|rows| rows.into_iter().flatten().collect())
+    })
+    .map_err(|_| anyhow!("A baking thread encountered an irrecoverable error"))??;
+    rows.sort_unstable_by_key(|&(y, _)| y);
+    let texels: Vec<BakedTexel> = rows.into_iter().flat_map(|(_, row)| row).collect();
+
+    // Occlusion and curvature are linear, with flat surfaces at half gray in curvature maps
+    let byte = |value: f32| (value.clamp(0., 1.) * 255.).round() as u8;
+    for name in ["ao", "bent_normal", "curvature"] {
+        let data: Vec<u8> = texels
+            .iter()
+            .flat_map(|texel| match name {
+                "ao" => [byte(texel.occlusion); 3],
+                "bent_normal" => {
+                    let c = texel.bent_normal * 0.5 + Vec3::broadcast(0.5);
+                    [byte(c.x), byte(c.y), byte(c.z)]
+                }
+                _ => [byte(0.5 + texel.curvature * 0.5); 3],
+            })
+            .collect();
+        let path = Path::new(&pass_path(&output_file_path, name)).with_extension("png");
+        let writer = BufWriter::new(File::create(&path).context("Cannot create output file")?);
+        write_png(writer, width, height, &data).context("Failed to write output PNG file")?;
+    }
+    Ok(())
+}
+
+/// Render a grid of small images of a scene or a material ball with a parameter varied
+/// between them, each labeled with its value, and write it as a PNG file
+fn contact_sheet(mut args: pico_args::Arguments, nthreads: usize) -> Result<()> {
+    let scene_path: Option<PathBuf> = args.opt_value_from_str("--scene")?;
+    let material_path: Option<PathBuf> = args.opt_value_from_str("--material")?;
+    let sweep: Sweep = args.value_from_str("--vary")?;
+    let target: Option<usize> = args.opt_value_from_str("--target")?;
+    let size: usize = args.opt_value_from_str("--size")?.unwrap_or(192);
+    let columns: Option<usize> = args.opt_value_from_str("--columns")?;
+    let mut samples_per_pixel: u32 = args.opt_value_from_str(["-s", "--samples"])?.unwrap_or(64);
+    let mut remaining = args.finish();
+    let output_file_path = match remaining.pop() {
+        Some(path) => path
+            .into_string()
+            .map_err(|path| anyhow!("Output path {:?} is not valid UTF-8", path))?,
+        None => String::from("contact_sheet.png"),
+    };
+    if !remaining.is_empty() {
+        return Err(anyhow!("Unknown arguments {:?}", remaining));
+    }
+
+    let (mut scene, target): (Scene, _) = match (scene_path, material_path) {
+        (Some(path), None) => (Scene::open(&path)?, target),
+        // The ball has the second material
+        (None, Some(path)) => (Scene::material_ball(read_ron(&path)?), target.or(Some(1))),
+        _ => return Err(anyhow!("Give either a scene or a material")),
+    };
+    scene.resolve_materials()?;
+    if sweep.parameter.of_material() && target.is_none() {
+        return Err(anyhow!("Choose the material to vary with --target"));
+    }
+    let columns = columns
+        .unwrap_or_else(|| (sweep.steps as f32).sqrt().ceil() as usize)
+        .max(1);
+    let rows = (sweep.steps as usize).div_ceil(columns);
+    let width = columns * size;
+    let listeners = Listeners {
+        http: None,
+        coordinator: None,
+    };
+    let writer =
+        BufWriter::new(File::create(&output_file_path).context("Cannot create output file")?);
+
+    let mut sheet = vec![0u8; width * rows * size * COLOR_CHANNELS];
+    for (i, value) in sweep.values().enumerate() {
+        let mut scene = scene.clone();
+        sweep
+            .parameter
+            .apply(&mut scene, target, &mut samples_per_pixel, value)?;
+        let options = Options {
+            width: size,
+            height: size,
+            samples_per_pixel,
+            nthreads,
+            term_preview: None,
+            term_preview_interval: Duration::ZERO,
+            cancel: CancellationToken::new(),
+            incremental: None,
+            #[cfg(feature = "numa")]
+            numa: None,
+        };
+        let renderer = prepare_renderer(&scene, &scene, 0, &options)?;
+        let mut image = render_frame(&renderer, &scene, 0, &options, &listeners)?.image;
+        let label = format!("{:?} {}", sweep.parameter, (value * 1000.).round() / 1000.);
+        burn_in::burn_in(&mut image, size, size, &label);
+
+        let (x, y) = (i % columns * size, i / columns * size);
+        for (row, pixels) in image.chunks_exact(size * COLOR_CHANNELS).enumerate() {
+            let offset = ((y + row) * width + x) * COLOR_CHANNELS;
+            sheet[offset..offset + pixels.len()].copy_from_slice(pixels);
+        }
+    }
+    write_png(writer, width, rows * size, &sheet).context("Failed to write output PNG file")?;
     eprintln!("Done.                  ");
     Ok(())
 }
 
-fn write_png(write: impl Write, width: usize, height: usize, rgb8_data: &[u8]) -> Result<()> {
-    let mut encoder = png::Encoder::new(write, u32::try_from(width)?, u32::try_from(height)?);
-    encoder.set_color(png::ColorType::RGB);
-    encoder.set_depth(png::BitDepth::Eight);
-    let mut writer = encoder.write_header()?;
-    writer.write_image_data(&rgb8_data)?;
+/// Print how much two images differ and write a heatmap of where they do as a PNG file
+fn diff(mut args: pico_args::Arguments) -> Result<()> {
+    let scale: f32 = args.opt_value_from_str("--scale")?.unwrap_or(8.);
+    // Of a 0.7 m wide 4K display seen from 0.7 m, as by FLIP
+    let pixels_per_degree: f32 = args.opt_value_from_str("--ppd")?.unwrap_or(67.);
+    let output_file_path: PathBuf = args
+        .opt_value_from_str(["-o", "--output"])?
+        .unwrap_or_else(|| PathBuf::from("diff.png"));
+    let a: PathBuf = args.free_from_str()?;
+    let b: PathBuf = args.free_from_str()?;
+    let remaining = args.finish();
+    if !remaining.is_empty() {
+        return Err(anyhow!("Unknown arguments {:?}", remaining));
+    }
+
+    let (a, b) = (Image::open(&a)?, Image::open(&b)?);
+    if (a.width, a.height) != (b.width, b.height) {
+        return Err(anyhow!(
+            "Images are {}x{} and {}x{} pixels",
+            a.width,
+            a.height,
+            b.width,
+            b.height
+        ));
+    }
+    println!("RMSE {:.6}", compare::rmse(&a, &b));
+    println!("SSIM {:.6}", compare::ssim(&a, &b));
+    println!("FLIP {:.6}", compare::flip(&a, &b, pixels_per_degree));
+    let writer =
+        BufWriter::new(File::create(&output_file_path).context("Cannot create output file")?);
+    write_png(writer, a.width, a.height, &compare::heatmap(&a, &b, scale))
+        .context("Failed to write output PNG file")
+}
+
+fn read_ron<T: serde::de::DeserializeOwned>(path: &Path) -> Result<T> {
+    let bytes = fs::read(path).with_context(|| format!("Cannot read {}", path.display()))?;
+    ron::de::from_bytes(&bytes).with_context(|| format!("Cannot parse {}", path.display()))
+}
+
+/// Substitute the frame number for the last run of `#` characters in `pattern`,
+/// or append it to the file stem if there is none
+fn frame_path(pattern: &str, frame: u32) -> String {
+    if let Some(end) = pattern.rfind('#') {
+        let start = pattern[..end].trim_end_matches('#').len();
+        format!(
+            "{}{:0width$}{}",
+            &pattern[..start],
+            frame,
+            &pattern[end + 1..],
+            width = end + 1 - start
+        )
+    } else {
+        let path = Path::new(pattern);
+        let stem = path.with_extension("");
+        match path.extension() {
+            Some(extension) => format!(
+                "{}_{:04}.{}",
+                stem.display(),
+                frame,
+                extension.to_string_lossy()
+            ),
+            None => format!("{}_{:04}", stem.display(), frame),
+        }
+    }
+}
+
+/// Convert the image of a pass with three channels in `space` to 8bpp sRGB for viewing
+fn pass_rgb8(pass: Pass, data: &[f32], space: ColorSpace) -> Vec<u8> {
+    data.chunks_exact(pass.channels())
+        .flat_map(|v| {
+            let v = match v {
+                &[y] => Vec3::broadcast(y),
+                v => Vec3::new(v[0], v[1], v[2]),
+            };
+            match pass {
+                // Unit vectors to colors without gamma
+                Pass::Normal => {
+                    let c = (v * 0.5 + Vec3::broadcast(0.5)) * 255.;
+                    [c.x as u8, c.y as u8, c.z as u8]
+                }
+                // Fractions as they are
+                Pass::Fog => {
+                    let c = v.clamped(Vec3::zero(), Vec3::one()) * 255.;
+                    [c.x as u8, c.y as u8, c.z as u8]
+                }
+                Pass::Variance => OutputColor::from(Color::from(v)),
+                _ => OutputColor::from(Color::from(space.to_srgb(v))),
+            }
+        })
+        .collect()
+}
+
+/// Write a histogram and a false color image of linear RGB `linear` in `space` as PNG files
+/// next to the image at `path`
+fn write_diagnostics(
+    path: &str,
+    linear: &[f32],
+    width: usize,
+    height: usize,
+    space: ColorSpace,
+) -> Result<()> {
+    let images = [
+        (
+            "histogram",
+            diagnostics::HISTOGRAM_WIDTH,
+            diagnostics::HISTOGRAM_HEIGHT,
+            diagnostics::histogram(linear, space),
+        ),
+        (
+            "false_color",
+            width,
+            height,
+            diagnostics::false_color(linear, width, space),
+        ),
+    ];
+    for (name, width, height, data) in images {
+        let path = Path::new(&pass_path(path, name)).with_extension("png");
+        let writer = BufWriter::new(File::create(&path).context("Cannot create output file")?);
+        write_png(writer, width, height, &data).context("Failed to write output PNG file")?;
+    }
+    Ok(())
+}
+
+/// Write PNG files of linear RGB `linear` at each of `exposures` in EV relative to it, next to
+/// the image at `path`
+fn write_bracket(
+    path: &str,
+    linear: &[f32],
+    width: usize,
+    height: usize,
+    display: &Display,
+    exposures: &[f32],
+) -> Result<()> {
+    for &ev in exposures {
+        let scale = ev.exp2();
+        let data: Vec<u8> = linear
+            .chunks_exact(COLOR_CHANNELS)
+            .flat_map(|c| display.encode(Vec3::new(c[0], c[1], c[2]) * scale))
+            .collect();
+        let path = Path::new(&pass_path(path, &format!("ev{:+}", ev))).with_extension("png");
+        let writer = BufWriter::new(File::create(&path).context("Cannot create output file")?);
+        write_png(writer, width, height, &data).context("Failed to write output PNG file")?;
+    }
     Ok(())
 }
+
+/// Write a PNG copy of the image at `percent` of its resolution next to `path`, where every
+/// pixel is the average of the pixels of the image that it covers
+fn write_proxy(
+    path: &str,
+    linear: &[f32],
+    width: usize,
+    height: usize,
+    display: &Display,
+    percent: f32,
+) -> Result<()> {
+    let scale = percent / 100.;
+    let proxy_width = ((width as f32 * scale).round() as usize).max(1);
+    let proxy_height = ((height as f32 * scale).round() as usize).max(1);
+    // Pixels of the image which are covered by pixel `i` of `n` of the proxy
+    let covered =
+        |i: usize, n: usize, size: usize| i * size / n..((i + 1) * size / n).max(i * size / n + 1);
+    let mut data = Vec::with_capacity(proxy_width * proxy_height * COLOR_CHANNELS);
+    for py in 0..proxy_height {
+        for px in 0..proxy_width {
+            let mut sum = Vec3::zero();
+            let mut count = 0;
+            for y in covered(py, proxy_height, height) {
+                for x in covered(px, proxy_width, width) {
+                    let i = (y * width + x) * COLOR_CHANNELS;
+                    sum += Vec3::new(linear[i], linear[i + 1], linear[i + 2]);
+                    count += 1;
+                }
+            }
+            data.extend(display.encode(sum / count as f32));
+        }
+    }
+    let path = Path::new(&pass_path(path, &format!("proxy{}", percent))).with_extension("png");
+    let writer = BufWriter::new(File::create(&path).context("Cannot create output file")?);
+    write_png(writer, proxy_width, proxy_height, &data).context("Failed to write output PNG file")
+}
+
+/// Path of the image of a pass next to the image at `path`
+fn pass_path(path: &str, name: &str) -> String {
+    let path = Path::new(path);
+    let stem = path.with_extension("");
+    match path.extension() {
+        Some(extension) => format!(
+            "{}_{}.{}",
+            stem.display(),
+            name,
+            extension.to_string_lossy()
+        ),
+        None => format!("{}_{}", stem.display(), name),
+    }
+}
+
+/// Print a JSON description of the frames in range whose output files don't exist yet. The
+/// output path is valid UTF-8, which is checked when it's parsed.
+fn print_manifest(output_file_path: &str, seed: u64, frames: &[u32]) {
+    let outstanding: Vec<serde_json::Value> = frames
+        .iter()
+        .map(|&frame| (frame, frame_path(output_file_path, frame)))
+        .filter(|(_, path)| !Path::new(path).exists())
+        .map(|(frame, path)| serde_json::json!({ "frame": frame, "output": path }))
+        .collect();
+    println!(
+        "{}",
+        serde_json::json!({ "seed": seed, "frames": outstanding })
+    );
+}
+
+fn show_term_preview(
+    protocol: Protocol,
+    width: usize,
+    height: usize,
+    rgb8_data: &[u8],
+) -> Result<()> {
+    const PREVIEW_WIDTH: usize = 320;
+    let (width, height, data) = term_preview::downscale(width, height, rgb8_data, PREVIEW_WIDTH);
+    protocol.write(std::io::stderr().lock(), width, height, &data)
+}
@@ -0,0 +1,269 @@
+//! Primary sample space Metropolis light transport, from Kelemen et al., "A Simple and Robust
+//! Mutation Strategy for the Metropolis Light Transport Algorithm".
+//!
+//! Paths are traced like by the path tracer, but all of their random numbers come from a
+//! vector which Markov chains mutate, either slightly or by starting over. Once a chain finds a
+//! path which carries light, it keeps exploring paths near it in proportion to their
+//! luminance, which finds paths that independent samples rarely do.
+
+use crate::{color::COLOR_CHANNELS, render::Renderer};
+use rand::prelude::*;
+use rand_xorshift::XorShiftRng;
+use std::f32::consts::TAU;
+use ultraviolet::Vec3;
+
+#[derive(Clone, Copy, Debug)]
+pub struct MltOptions {
+    /// Independent paths traced to estimate the brightness of the image and to start the
+    /// chains from
+    pub bootstrap_samples: u32,
+    pub chains: u32,
+    /// Standard deviation of the small mutations of each random number
+    pub sigma: f32,
+    /// Probability of starting over with new random numbers instead of a small mutation
+    pub large_step_probability: f32,
+}
+
+impl Default for MltOptions {
+    fn default() -> Self {
+        Self {
+            bootstrap_samples: 100_000,
+            chains: 1000,
+            sigma: 0.01,
+            large_step_probability: 0.3,
+        }
+    }
+}
+
+/// Number in the vector of a chain, with the iteration when it was last mutated
+#[derive(Clone, Copy, Default)]
+struct PrimarySample {
+    value: f32,
+    modified: u64,
+    /// Before the mutation of the current iteration, for rejecting it
+    backup: (f32, u64),
+}
+
+/// Random numbers of a path, which are mutated lazily when they are drawn. Paths use a
+/// different number of them depending on where they go.
+struct PrimarySampleSpace {
+    rng: XorShiftRng,
+    samples: Vec<PrimarySample>,
+    /// Of the next number to draw
+    index: usize,
+    iteration: u64,
+    large_step: bool,
+    last_large_step: u64,
+    sigma: f32,
+    large_step_probability: f32,
+}
+
+impl PrimarySampleSpace {
+    /// The first path is independent, and depends only on `seed`
+    fn new(seed: u64, options: &MltOptions) -> Self {
+        Self {
+            rng: XorShiftRng::seed_from_u64(seed),
+            samples: Vec::new(),
+            index: 0,
+            iteration: 0,
+            large_step: true,
+            last_large_step: 0,
+            sigma: options.sigma,
+            large_step_probability: options.large_step_probability,
+        }
+    }
+
+    /// Propose a mutation of the numbers, which are drawn again from the first one
+    fn start_iteration(&mut self) {
+        self.iteration += 1;
+        self.large_step = self.rng.gen::<f32>() < self.large_step_probability;
+        self.index = 0;
+    }
+
+    fn accept(&mut self) {
+        if self.large_step {
+            self.last_large_step = self.iteration;
+        }
+    }
+
+    fn reject(&mut self) {
+        for sample in &mut self.samples {
+            if sample.modified == self.iteration {
+                (sample.value, sample.modified) = sample.backup;
+            }
+        }
+        self.iteration -= 1;
+    }
+
+    /// Next number in `[0, 1)`, with the mutations since it was last drawn applied
+    fn next(&mut self) -> f32 {
+        if self.index == self.samples.len() {
+            self.samples.push(PrimarySample::default());
+        }
+        let sample = &mut self.samples[self.index];
+        self.index += 1;
+        // Numbers which weren't drawn since the last accepted large step were replaced by it
+        if sample.modified < self.last_large_step {
+            sample.value = self.rng.gen();
+            sample.modified = self.last_large_step;
+        }
+        sample.backup = (sample.value, sample.modified);
+        if self.large_step {
+            sample.value = self.rng.gen();
+        } else {
+            // Small steps accumulated since the number was last drawn add up to one with a
+            // larger deviation
+            let steps = (self.iteration - sample.modified) as f32;
+            let (u1, u2): (f32, f32) = (self.rng.gen(), self.rng.gen());
+            let normal = (-2. * (1. - u1).ln()).sqrt() * (TAU * u2).cos();
+            sample.value = (sample.value + normal * self.sigma * steps.sqrt()).rem_euclid(1.);
+            if sample.value >= 1. {
+                sample.value = 0.;
+            }
+        }
+        sample.modified = self.iteration;
+        sample.value
+    }
+}
+
+impl RngCore for PrimarySampleSpace {
+    fn next_u32(&mut self) -> u32 {
+        (f64::from(self.next()) * f64::from(u32::MAX)) as u32
+    }
+
+    fn next_u64(&mut self) -> u64 {
+        u64::from(self.next_u32()) << 32 | u64::from(self.next_u32())
+    }
+
+    fn fill_bytes(&mut self, dest: &mut [u8]) {
+        for chunk in dest.chunks_mut(4) {
+            let bytes = self.next_u32().to_le_bytes();
+            chunk.copy_from_slice(&bytes[..chunk.len()]);
+        }
+    }
+
+    fn try_fill_bytes(&mut self, dest: &mut [u8]) -> Result<(), rand::Error> {
+        self.fill_bytes(dest);
+        Ok(())
+    }
+}
+
+/// Pixel that a path went through, counted from the top left, and its color
+type PathSample = ((usize, usize), Vec3);
+
+/// Renders the image of a [`Renderer`] with Markov chains, whose samples are splatted into
+/// images which add up to the whole image
+pub struct Mlt<'a> {
+    renderer: &'a Renderer,
+    options: MltOptions,
+    /// Average luminance of the image
+    brightness: f32,
+    /// Seeds of the bootstrap paths, in proportion to whose luminance chains start from them
+    starts: Vec<(u64, f32)>,
+    /// Sum of the luminance of the bootstrap paths
+    total: f32,
+}
+
+impl<'a> Mlt<'a> {
+    /// Trace the bootstrap paths
+    pub fn new(renderer: &'a Renderer, options: MltOptions) -> Self {
+        let mut total = 0.;
+        let starts: Vec<(u64, f32)> = (0..u64::from(options.bootstrap_samples))
+            .map(|seed| {
+                let (_, color) = renderer.sample_path(&mut PrimarySampleSpace::new(seed, &options));
+                total += luminance(renderer, color);
+                (seed, total)
+            })
+            .collect();
+        Self {
+            renderer,
+            options,
+            brightness: total / starts.len().max(1) as f32,
+            starts,
+            total,
+        }
+    }
+
+    pub fn chains(&self) -> u32 {
+        self.options.chains
+    }
+
+    /// Run chain number `chain` of [`Mlt::chains`], adding its share of `total_mutations` to
+    /// `image`, which has a color for every pixel
+    pub fn run_chain(&self, chain: u32, total_mutations: u64, image: &mut [Vec3]) {
+        if self.total <= 0. || total_mutations == 0 {
+            return;
+        }
+        let chains = u64::from(self.options.chains);
+        let mutations =
+            total_mutations / chains + u64::from(u64::from(chain) < total_mutations % chains);
+        let mut rng = XorShiftRng::seed_from_u64(u64::from(chain));
+        // Start from a bootstrap path chosen in proportion to its luminance
+        let target = rng.gen::<f32>() * self.total;
+        let start = self.starts.partition_point(|&(_, sum)| sum <= target);
+        let (seed, _) = self.starts[start.min(self.starts.len() - 1)];
+        let mut space = PrimarySampleSpace::new(seed, &self.options);
+
+        // Every path adds its color over its luminance times the brightness of the image, so
+        // that pixels end up with their share of the brightness
+        let width = self.renderer.width();
+        let scale = self.brightness * image.len() as f32 / total_mutations as f32;
+        let mut splat = |((x, y), color): PathSample, weight: f32| {
+            let f = luminance(self.renderer, color);
+            if weight > 0. && f > 0. {
+                image[y * width + x] += color * (weight * scale / f);
+            }
+        };
+        let mut current = self.renderer.sample_path(&mut space);
+        for _ in 0..mutations {
+            space.start_iteration();
+            let proposed = self.renderer.sample_path(&mut space);
+            let (f_current, f_proposed) = (
+                luminance(self.renderer, current.1),
+                luminance(self.renderer, proposed.1),
+            );
+            let accept = if f_current > 0. {
+                (f_proposed / f_current).min(1.)
+            } else {
+                1.
+            };
+            splat(proposed, accept);
+            splat(current, 1. - accept);
+            if rng.gen::<f32>() < accept {
+                current = proposed;
+                space.accept();
+            } else {
+                space.reject();
+            }
+        }
+    }
+}
+
+/// Sum the images of chains, which have a color for every pixel, into linear RGB
+pub fn resolve(images: impl IntoIterator<Item = Vec<Vec3>>) -> Vec<f32> {
+    let mut sum: Vec<Vec3> = Vec::new();
+    for image in images {
+        if sum.is_empty() {
+            sum = image;
+        } else {
+            for (sum, color) in sum.iter_mut().zip(image) {
+                *sum += color;
+            }
+        }
+    }
+    let mut linear = Vec::with_capacity(sum.len() * COLOR_CHANNELS);
+    for color in sum {
+        linear.extend_from_slice(&[color.x, color.y, color.z]);
+    }
+    linear
+}
+
+/// Luminance of a path, which the chains sample in proportion to
+fn luminance(renderer: &Renderer, color: Vec3) -> f32 {
+    let luminance = renderer.working_space().luminance(color);
+    if luminance.is_finite() {
+        luminance.max(0.)
+    } else {
+        0.
+    }
+}
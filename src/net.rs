@@ -0,0 +1,192 @@
+//! Distributed rendering: a coordinator hands out tiles to workers over TCP.
+//!
+//! Every connection starts with the coordinator sending the serialized [`Job`]. After that the
+//! coordinator sends tile numbers and the worker answers each with the rendered samples, until
+//! the coordinator sends [`DONE`]. Workers open one connection per rendering thread, and
+//! reconnect after each job in case the coordinator has more frames to render.
+//!
+//! Path guides aren't sent over the network or merged. Every connection trains a guide of its
+//! own with one thread before rendering tiles, so tiles from different connections are sampled
+//! by different guides, each having learned only from its own training render.
+
+use anyhow::{anyhow, Context, Result};
+use rt::{
+    render::{Frame, Renderer},
+    scene::Scene,
+};
+use serde::{Deserialize, Serialize};
+use std::{
+    convert::TryFrom,
+    io::{prelude::*, BufReader, BufWriter},
+    net::TcpStream,
+    time::{Duration, Instant},
+};
+
+const DONE: u64 = u64::MAX;
+/// Largest job which workers accept, which is far more than scenes take unless they have
+/// meshes inline
+const MAX_JOB_LEN: usize = 1 << 30;
+/// Time for a worker to set up the job and render its first tile, such as building the BVH and
+/// training the path guide, before it's given up on
+const SETUP_TIMEOUT: Duration = Duration::from_secs(600);
+/// Least time that a worker is given for a tile, after which it gets several times as long as
+/// the slowest tile so far
+const MIN_TILE_TIMEOUT: Duration = Duration::from_secs(30);
+/// Time for sending a message to a worker
+const WRITE_TIMEOUT: Duration = Duration::from_secs(30);
+
+#[derive(Serialize, Deserialize)]
+pub struct Job {
+    pub scene: Scene,
+    pub frame: u32,
+    pub width: usize,
+    pub height: usize,
+    pub samples_per_pixel: u32,
+}
+
+fn write_message(write: &mut impl Write, data: &[u8]) -> Result<()> {
+    write.write_all(&u64::try_from(data.len())?.to_le_bytes())?;
+    write.write_all(data)?;
+    write.flush()?;
+    Ok(())
+}
+
+fn read_u64(read: &mut impl Read) -> Result<u64> {
+    let mut bytes = [0u8; 8];
+    read.read_exact(&mut bytes)?;
+    Ok(u64::from_le_bytes(bytes))
+}
+
+/// Read a message of at most `max_len` bytes, so that a broken peer can't make us allocate
+/// arbitrary amounts of memory
+fn read_message(read: &mut impl Read, max_len: usize) -> Result<Vec<u8>> {
+    let len = usize::try_from(read_u64(read)?)?;
+    if len > max_len {
+        return Err(anyhow!(
+            "Message of {} bytes is longer than {} bytes",
+            len,
+            max_len
+        ));
+    }
+    let mut data = vec![0u8; len];
+    read.read_exact(&mut data)?;
+    Ok(data)
+}
+
+/// Feed tiles of the frame to a connected worker until all of them are finished or cancelled.
+/// Tiles which were in flight when the connection failed or the worker stopped answering in
+/// time are returned to the queue.
+pub fn serve_worker(stream: TcpStream, job: &str, frame: &Frame<'_>) -> Result<()> {
+    stream.set_nodelay(true)?;
+    stream.set_write_timeout(Some(WRITE_TIMEOUT))?;
+    stream.set_read_timeout(Some(SETUP_TIMEOUT))?;
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut writer = BufWriter::new(stream);
+    write_message(&mut writer, job.as_bytes())?;
+
+    // Slowest tile after the first one, which includes the setup
+    let (mut first, mut slowest): (bool, Option<Duration>) = (true, None);
+    loop {
+        let i = loop {
+            if frame.stopped() {
+                writer.write_all(&DONE.to_le_bytes())?;
+                writer.flush()?;
+                return Ok(());
+            }
+            if let Some(i) = frame.next_tile() {
+                break i;
+            }
+            std::thread::sleep(Duration::from_millis(10));
+        };
+
+        let len = frame
+            .tile_data_len(i)
+            .ok_or_else(|| anyhow!("Frame has no tile {}", i))?;
+        if let Some(slowest) = slowest {
+            reader
+                .get_ref()
+                .set_read_timeout(Some((slowest * 8).max(MIN_TILE_TIMEOUT)))?;
+        }
+        let started = Instant::now();
+        let result = writer
+            .write_all(&u64::try_from(i)?.to_le_bytes())
+            .map_err(anyhow::Error::from)
+            .and_then(|_| writer.flush().map_err(anyhow::Error::from))
+            .and_then(|_| {
+                read_message(&mut reader, len)
+                    .with_context(|| format!("Worker didn't send tile {}", i))
+            })
+            .and_then(|data| frame.publish(i, &data));
+        if let Err(e) = result {
+            frame.return_tile(i);
+            return Err(e);
+        }
+        if !first {
+            slowest = slowest.max(Some(started.elapsed()));
+        }
+        first = false;
+    }
+}
+
+/// Connect to a coordinator with `nthreads` connections and render until it goes away
+pub fn work(address: &str, nthreads: usize) -> Result<()> {
+    crossbeam_utils::thread::scope(|s| {
+        let workers: Vec<_> = (0..nthreads)
+            .map(|_| {
+                s.spawn(|_| -> Result<()> {
+                    work_connection(address, true)?;
+                    // Failing to get another job means that the coordinator has finished
+                    while work_connection(address, false).is_ok() {}
+                    Ok(())
+                })
+            })
+            .collect();
+        workers
+            .into_iter()
+            .map(|w| {
+                w.join()
+                    .unwrap_or_else(|_| Err(anyhow!("Worker thread panicked")))
+            })
+            .collect::<Result<Vec<_>>>()
+    })
+    .map_err(|_| anyhow!("A rendering thread encountered an irrecoverable error"))??;
+    Ok(())
+}
+
+fn work_connection(address: &str, first: bool) -> Result<()> {
+    let stream = TcpStream::connect(address)
+        .with_context(|| format!("Cannot connect to coordinator {}", address))?;
+    stream.set_nodelay(true)?;
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut writer = BufWriter::new(stream);
+
+    let job: Job = ron::de::from_bytes(&read_message(&mut reader, MAX_JOB_LEN)?)
+        .context("Cannot deserialize job from coordinator")?;
+    if first {
+        eprintln!("Rendering frame {} for {}", job.frame, address);
+    }
+    let mut renderer = Renderer::new(
+        &job.scene,
+        job.frame,
+        job.width,
+        job.height,
+        job.samples_per_pixel,
+    )?;
+    // Guides aren't sent over the network, so every connection trains one of its own, which
+    // the tiles of the other connections don't benefit from
+    renderer.train_guide(1)?;
+    let tiles = renderer.tiles();
+    let mut data = Vec::new();
+
+    loop {
+        let i = read_u64(&mut reader)?;
+        if i == DONE {
+            return Ok(());
+        }
+        let tile = tiles
+            .get(usize::try_from(i)?)
+            .ok_or_else(|| anyhow!("Coordinator sent invalid tile {}", i))?;
+        renderer.accumulate_tile(tile, 0, &mut data, &mut |_| {});
+        write_message(&mut writer, &data)?;
+    }
+}
@@ -0,0 +1,39 @@
+//! Scheduling priority of the process, for rendering in the background
+
+use anyhow::Result;
+
+/// Make this process and the threads it starts afterwards yield to others, such as a desktop
+#[cfg(unix)]
+pub fn lower() -> Result<()> {
+    // On Linux the niceness is per thread, and inherited by threads created afterwards
+    // SAFETY: setpriority has no memory safety requirements
+    if unsafe { libc::setpriority(libc::PRIO_PROCESS, 0, 19) } != 0 {
+        return Err(std::io::Error::last_os_error().into());
+    }
+    Ok(())
+}
+
+/// Make this process and the threads it starts afterwards yield to others, such as a desktop
+#[cfg(windows)]
+pub fn lower() -> Result<()> {
+    use std::ffi::c_void;
+
+    const IDLE_PRIORITY_CLASS: u32 = 0x40;
+    #[link(name = "kernel32")]
+    extern "system" {
+        fn GetCurrentProcess() -> *mut c_void;
+        fn SetPriorityClass(process: *mut c_void, priority_class: u32) -> i32;
+    }
+    // SAFETY: the pseudo handle of the current process is always valid
+    if unsafe { SetPriorityClass(GetCurrentProcess(), IDLE_PRIORITY_CLASS) } == 0 {
+        return Err(std::io::Error::last_os_error().into());
+    }
+    Ok(())
+}
+
+#[cfg(not(any(unix, windows)))]
+pub fn lower() -> Result<()> {
+    Err(anyhow::anyhow!(
+        "Lowering the priority is not supported on this platform"
+    ))
+}
@@ -0,0 +1,106 @@
+//! Time spent in hot paths, collected when the `profile` feature is enabled.
+//!
+//! Scopes are entered with [`profile_scope!`], which compiles to nothing without the feature.
+//! The totals of all threads are written as folded stacks, one line per call path with its
+//! exclusive time in microseconds, which flamegraph tools such as `inferno-flamegraph` read.
+
+/// Measure the time until the end of the enclosing block
+#[cfg(feature = "profile")]
+macro_rules! profile_scope {
+    ($name:expr) => {
+        let _scope = $crate::profile::Scope::new($name);
+    };
+}
+
+#[cfg(not(feature = "profile"))]
+macro_rules! profile_scope {
+    ($name:expr) => {};
+}
+
+#[cfg(feature = "profile")]
+pub use self::enabled::*;
+
+#[cfg(feature = "profile")]
+mod enabled {
+    use anyhow::Result;
+    use parking_lot::Mutex;
+    use std::{
+        cell::RefCell,
+        collections::HashMap,
+        io::Write,
+        time::{Duration, Instant},
+    };
+
+    /// Exclusive time of each call path, merged from all threads
+    static TOTALS: Mutex<Option<HashMap<Vec<&'static str>, Duration>>> = Mutex::new(None);
+
+    #[derive(Default)]
+    struct Thread {
+        /// Names of the scopes which the thread is in
+        path: Vec<&'static str>,
+        /// When each scope was entered and how long was spent in its inner scopes
+        frames: Vec<(Instant, Duration)>,
+        totals: HashMap<Vec<&'static str>, Duration>,
+    }
+
+    thread_local! {
+        static THREAD: RefCell<Thread> = RefCell::default();
+    }
+
+    pub struct Scope(());
+
+    impl Scope {
+        pub fn new(name: &'static str) -> Self {
+            THREAD.with(|thread| {
+                let mut thread = thread.borrow_mut();
+                thread.path.push(name);
+                thread.frames.push((Instant::now(), Duration::default()));
+            });
+            Scope(())
+        }
+    }
+
+    impl Drop for Scope {
+        fn drop(&mut self) {
+            THREAD.with(|thread| {
+                let thread = &mut *thread.borrow_mut();
+                let (started, inner) = thread.frames.pop().expect("Scopes are nested");
+                let elapsed = started.elapsed();
+                match thread.totals.get_mut(thread.path.as_slice()) {
+                    Some(total) => *total += elapsed - inner,
+                    None => {
+                        thread.totals.insert(thread.path.clone(), elapsed - inner);
+                    }
+                }
+                thread.path.pop();
+
+                match thread.frames.last_mut() {
+                    Some((_, parent_inner)) => *parent_inner += elapsed,
+                    // Merge when leaving the outermost scope, because threads may exit any time
+                    None => {
+                        let mut totals = TOTALS.lock();
+                        let totals = totals.get_or_insert_with(HashMap::new);
+                        for (path, time) in thread.totals.drain() {
+                            *totals.entry(path).or_default() += time;
+                        }
+                    }
+                }
+            });
+        }
+    }
+
+    /// Write the time spent in every call path so far as folded stacks
+    pub fn write_folded(mut write: impl Write) -> Result<()> {
+        let totals = TOTALS.lock();
+        let mut lines: Vec<_> = totals
+            .iter()
+            .flatten()
+            .map(|(path, time)| (path.join(";"), time.as_micros()))
+            .collect();
+        lines.sort();
+        for (path, micros) in lines {
+            writeln!(write, "{} {}", path, micros)?;
+        }
+        Ok(())
+    }
+}
@@ -1,20 +1,314 @@
+use crate::world::volume::Volume;
+use serde::{Deserialize, Serialize};
 use ultraviolet::Vec3;
 
+/// Purpose of a ray, which decides the objects it can hit
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RayKind {
+    /// Traced from the camera
+    Camera,
+    /// Testing whether a point is in shadow
+    Shadow,
+    /// Scattered diffusely from a surface
+    Diffuse,
+    /// Reflected or refracted in a mirror-like direction
+    Specular,
+}
+
+/// Number of scattering events on the path before a ray, by kind
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Depth {
+    pub diffuse: u16,
+    /// Mirror-like and glossy reflections, and scattering in media
+    pub specular: u16,
+    /// Refractions through the surfaces of dielectrics
+    pub transmission: u16,
+}
+
+impl Depth {
+    pub fn total(&self) -> u32 {
+        u32::from(self.diffuse) + u32::from(self.specular) + u32::from(self.transmission)
+    }
+}
+
+/// Most bounces of each kind on a path, after which it is cut off, for spending rays on the
+/// bounces which matter the most in a scene. Unlimited kinds are only limited by the depth of
+/// the path.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BounceLimits {
+    #[serde(default)]
+    pub diffuse: Option<u16>,
+    /// Of mirror-like and glossy reflections
+    #[serde(default)]
+    pub glossy: Option<u16>,
+    #[serde(default)]
+    pub transmission: Option<u16>,
+}
+
+impl BounceLimits {
+    pub fn is_unlimited(&self) -> bool {
+        *self == Self::default()
+    }
+
+    /// Whether a path may continue with a ray at `depth`
+    pub fn allow(&self, depth: Depth) -> bool {
+        let within = |limit: Option<u16>, bounces: u16| limit.is_none_or(|limit| bounces <= limit);
+        within(self.diffuse, depth.diffuse)
+            && within(self.glossy, depth.specular)
+            && within(self.transmission, depth.transmission)
+    }
+}
+
+/// Roughening of mirror-like bounces late on a path, which blurs caustics seen in diffuse
+/// surfaces and in mirrors after several bounces a little, in exchange for far fewer fireflies
+/// from paths that go from a diffuse surface through mirrors or glass to a light
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Regularization {
+    /// Least roughness of bounces from mirrors and glass, from 0 to 1, on paths which have
+    /// bounced off a diffuse surface or bounced at least `bounces` times
+    #[serde(default = "Regularization::default_roughness")]
+    pub roughness: f32,
+    #[serde(default = "Regularization::default_bounces")]
+    pub bounces: u32,
+}
+
+impl Regularization {
+    fn default_roughness() -> f32 {
+        0.2
+    }
+
+    fn default_bounces() -> u32 {
+        3
+    }
+}
+
+impl Default for Regularization {
+    fn default() -> Self {
+        Self {
+            roughness: Self::default_roughness(),
+            bounces: Self::default_bounces(),
+        }
+    }
+}
+
+/// Most dielectric objects that a ray is tracked to be inside of at once
+const MAX_MEDIA: usize = 4;
+
+/// Dielectric object that a ray is inside of
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+struct Medium {
+    object: u32,
+    priority: u32,
+    refraction: f32,
+    volume: Option<Volume>,
+}
+
+/// Dielectric objects that a ray is inside of, for nested dielectrics as in Schmidt and Budge,
+/// "Simple Nested Dielectrics in Ray Traced Images". Where objects overlap, the one with the
+/// highest priority, or the latest entered of those, is the medium.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Media {
+    media: [Medium; MAX_MEDIA],
+    len: u8,
+}
+
+impl Media {
+    fn iter(&self) -> std::slice::Iter<'_, Medium> {
+        self.media[..usize::from(self.len)].iter()
+    }
+
+    /// Index of the medium, skipping `object`
+    fn top(&self, skip: Option<u32>) -> Option<usize> {
+        let mut top: Option<usize> = None;
+        for (i, medium) in self.iter().enumerate() {
+            if Some(medium.object) != skip
+                && top.is_none_or(|top| medium.priority >= self.media[top].priority)
+            {
+                top = Some(i);
+            }
+        }
+        top
+    }
+
+    /// Refractive index of the medium, or that of air outside of every object
+    pub fn refraction(&self) -> f32 {
+        self.top(None).map_or(1., |i| self.media[i].refraction)
+    }
+
+    /// Participating medium filling the medium, if any
+    pub fn volume(&self) -> Option<Volume> {
+        self.top(None).and_then(|i| self.media[i].volume)
+    }
+
+    /// Refractive index of the medium after leaving the current one
+    pub fn outer_refraction(&self) -> f32 {
+        let skip = self.top(None).map(|i| self.media[i].object);
+        self.top(skip).map_or(1., |i| self.media[i].refraction)
+    }
+
+    /// Whether crossing the boundary of `object` changes the medium, instead of the boundary
+    /// being inside of an object with a higher priority
+    pub fn is_boundary(&self, object: u32, priority: u32, entering: bool) -> bool {
+        match self.top(None).map(|i| self.media[i]) {
+            None => true,
+            Some(top) if entering => priority >= top.priority,
+            Some(top) => top.object == object || self.iter().all(|m| m.object != object),
+        }
+    }
+
+    /// After entering `object`. Objects past the most that can be tracked are ignored.
+    pub fn entered(
+        mut self,
+        object: u32,
+        priority: u32,
+        refraction: f32,
+        volume: Option<Volume>,
+    ) -> Self {
+        if usize::from(self.len) < MAX_MEDIA {
+            self.media[usize::from(self.len)] = Medium {
+                object,
+                priority,
+                refraction,
+                volume,
+            };
+            self.len += 1;
+        }
+        self
+    }
+
+    /// After leaving `object`
+    pub fn left(mut self, object: u32) -> Self {
+        if let Some(i) = self.iter().rposition(|medium| medium.object == object) {
+            self.media.copy_within(i + 1..usize::from(self.len), i);
+            self.len -= 1;
+        }
+        self
+    }
+}
+
+/// Rays through the neighbouring pixels along x and y, following a camera ray through mirrors
+/// and refractions to find how much of a texture it covers, as in Igehy, "Tracing Ray
+/// Differentials"
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Differentials {
+    pub origins: [Vec3; 2],
+    pub directions: [Vec3; 2],
+}
+
+/// Sign bits of the components of `direction`
+fn sign_bits(direction: Vec3) -> u8 {
+    u8::from(direction.x.is_sign_negative())
+        | u8::from(direction.y.is_sign_negative()) << 1
+        | u8::from(direction.z.is_sign_negative()) << 2
+}
+
+#[derive(Clone)]
 pub struct Ray {
     origin: Vec3,
     direction: Vec3,
+    /// Reciprocal of the direction, for testing many boxes in [`crate::world::aabb::Aabb::hit`]
+    inv_direction: Vec3,
+    /// Bit per axis, set where the direction is negative
+    sign: u8,
     time: f32,
+    kind: RayKind,
+    depth: Depth,
+    media: Media,
+    differentials: Option<Differentials>,
+    regularization: Option<Regularization>,
 }
 
 impl Ray {
+    /// Camera ray
     pub fn new(origin: Vec3, direction: Vec3, time: f32) -> Self {
+        let direction = direction.normalized();
         Self {
             origin,
-            direction: direction.normalized(),
+            direction,
+            inv_direction: Vec3::one() / direction,
+            sign: sign_bits(direction),
             time,
+            kind: RayKind::Camera,
+            depth: Depth::default(),
+            media: Media::default(),
+            differentials: None,
+            regularization: None,
+        }
+    }
+
+    /// Ray of `kind` continuing the path of this one from `origin`, at the same time. It has no
+    /// differentials unless they are given with [`Ray::with_differentials`].
+    pub fn scattered(&self, origin: Vec3, direction: Vec3, kind: RayKind) -> Self {
+        let mut depth = self.depth;
+        match kind {
+            RayKind::Diffuse => depth.diffuse = depth.diffuse.saturating_add(1),
+            RayKind::Specular => depth.specular = depth.specular.saturating_add(1),
+            RayKind::Camera | RayKind::Shadow => {}
+        }
+        let direction = direction.normalized();
+        Self {
+            origin,
+            direction,
+            inv_direction: Vec3::one() / direction,
+            sign: sign_bits(direction),
+            time: self.time,
+            kind,
+            depth,
+            media: self.media,
+            differentials: None,
+            regularization: self.regularization,
+        }
+    }
+
+    /// This ray continuing from `origin` past a boundary which doesn't scatter it, in `media`
+    pub fn continued(&self, origin: Vec3, media: Media) -> Self {
+        Self {
+            origin,
+            media,
+            ..*self
+        }
+    }
+
+    /// This ray in `media` instead
+    pub fn with_media(self, media: Media) -> Self {
+        Self { media, ..self }
+    }
+
+    /// This specular ray, which was refracted through a surface, counted as a transmission in
+    /// its depth instead
+    pub fn transmitted(self) -> Self {
+        let mut depth = self.depth;
+        if self.kind == RayKind::Specular && depth.specular > 0 {
+            depth.specular -= 1;
+            depth.transmission = depth.transmission.saturating_add(1);
+        }
+        Self { depth, ..self }
+    }
+
+    /// This ray along `direction` instead, at the same depth and without differentials
+    pub fn with_direction(self, direction: Vec3) -> Self {
+        let direction = direction.normalized();
+        Self {
+            direction,
+            inv_direction: Vec3::one() / direction,
+            sign: sign_bits(direction),
+            differentials: None,
+            ..self
+        }
+    }
+
+    pub fn with_differentials(self, differentials: Option<Differentials>) -> Self {
+        Self {
+            differentials,
+            ..self
         }
     }
 
+    /// This ray as one of `kind` instead, which changes which objects it can hit
+    pub fn with_kind(self, kind: RayKind) -> Self {
+        Self { kind, ..self }
+    }
+
     pub fn origin(&self) -> Vec3 {
         self.origin
     }
@@ -23,10 +317,67 @@ impl Ray {
         self.direction
     }
 
+    pub fn inv_direction(&self) -> Vec3 {
+        self.inv_direction
+    }
+
+    /// Whether the direction is negative along `axis`, including negative zero
+    pub fn is_negative(&self, axis: usize) -> bool {
+        self.sign >> axis & 1 == 1
+    }
+
+    /// 1 where the direction is negative along `axis`, otherwise 0
+    pub fn sign(&self, axis: usize) -> usize {
+        usize::from(self.sign >> axis & 1)
+    }
+
     pub fn time(&self) -> f32 {
         self.time
     }
 
+    pub fn kind(&self) -> RayKind {
+        self.kind
+    }
+
+    pub fn depth(&self) -> Depth {
+        self.depth
+    }
+
+    pub fn media(&self) -> Media {
+        self.media
+    }
+
+    /// Whether this ray was reflected or refracted in a mirror-like direction after a path has
+    /// bounced off a diffuse surface, so that a light which it hits makes a caustic on that
+    /// surface
+    pub fn is_caustic(&self) -> bool {
+        self.kind == RayKind::Specular && self.depth.diffuse > 0
+    }
+
+    /// This ray and the rays scattered from it regularized by `regularization`
+    pub fn with_regularization(self, regularization: Option<Regularization>) -> Self {
+        Self {
+            regularization,
+            ..self
+        }
+    }
+
+    /// Least roughness of a mirror-like bounce of this ray, from 0 to 1
+    pub fn roughness_floor(&self) -> f32 {
+        match self.regularization {
+            Some(regularization)
+                if self.depth.diffuse > 0 || self.depth.total() >= regularization.bounces =>
+            {
+                regularization.roughness
+            }
+            _ => 0.,
+        }
+    }
+
+    pub fn differentials(&self) -> Option<Differentials> {
+        self.differentials
+    }
+
     pub fn at(&self, t: f32) -> Vec3 {
         self.origin + t * self.direction
     }
@@ -0,0 +1,1677 @@
+use crate::{
+    camera::Camera,
+    color::{
+        average, f16_to_f32, f32_to_f16, resolve, ColorSpace, Display, OutputColor, COLOR_CHANNELS,
+    },
+    filter::SplatImage,
+    fog::DepthFog,
+    guiding::Guide,
+    lut::Lut,
+    ray::{BounceLimits, Depth, RayKind, Regularization},
+    sampler::{pixel_seed, RngKind, Sampler, SamplerKind},
+    scene::Scene,
+    world::{
+        bvh::{BvhStats, MAX_PACKET_SIZE},
+        material::{Material, Scatter},
+        surface::HitRecord,
+        volume::Interaction,
+        Intersection, World,
+    },
+    Ray,
+};
+use anyhow::{anyhow, Result};
+use parking_lot::Mutex;
+use rand::prelude::*;
+use rand_chacha::ChaCha8Rng;
+use rand_pcg::Pcg32;
+use rand_xorshift::XorShiftRng;
+use serde::{Deserialize, Serialize};
+use std::{
+    cell::Cell,
+    collections::HashSet,
+    convert::TryInto,
+    f32::consts::PI,
+    ops::Sub,
+    panic::{self, AssertUnwindSafe},
+    str::FromStr,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
+        mpsc::{self, Receiver, Sender},
+        Arc, OnceLock,
+    },
+};
+use ultraviolet::{Vec2, Vec3};
+
+/// Most bounces of a path in biased renders, which cut paths off there
+pub const MAX_DEPTH: u32 = 64;
+/// Most bounces of a path in unbiased renders, which end paths by Russian roulette long before
+/// this. Fewer than 1e-11 of the paths that survive the roulette reach it, which is below the
+/// precision of the image.
+const UNBIASED_MAX_DEPTH: u32 = 256;
+/// Bounces after which unbiased renders start playing Russian roulette with paths
+const ROULETTE_BOUNCES: u32 = 3;
+/// Highest chance of a path surviving Russian roulette, so that paths through clear glass and
+/// mirrors end too
+const MAX_SURVIVAL: f32 = 0.9;
+/// Width and height of a unit of work handed to a rendering thread
+pub const TILE_SIZE: usize = 64;
+/// Number of times rendering a row of a tile is tried before the tile is given up on
+const TILE_ATTEMPTS: u32 = 2;
+/// Number of camera rays traced together
+const PACKET_SIZE: usize = MAX_PACKET_SIZE;
+/// Samples taken before the noise of a pixel is estimated, so that a few lucky samples don't
+/// stop it early
+const MIN_ADAPTIVE_SAMPLES: u32 = 16;
+
+/// Part of the image, by how the paths through it begin. All components add up to the whole
+/// image, so they can be rebalanced in compositing.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Component {
+    /// Camera rays which hit nothing
+    Background,
+    /// Diffuse reflection of light which arrives without further bounces
+    DirectDiffuse,
+    /// Diffuse reflection of light which has bounced more
+    IndirectDiffuse,
+    /// Mirror-like reflection
+    Specular,
+    /// Refraction into transparent objects
+    Transmission,
+    /// Light emitted by objects which camera rays hit
+    Emission,
+}
+
+/// Every [`Component`], in the order of their images
+pub const COMPONENTS: [Component; 6] = [
+    Component::Background,
+    Component::DirectDiffuse,
+    Component::IndirectDiffuse,
+    Component::Specular,
+    Component::Transmission,
+    Component::Emission,
+];
+
+impl Component {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Background => "background",
+            Self::DirectDiffuse => "direct_diffuse",
+            Self::IndirectDiffuse => "indirect_diffuse",
+            Self::Specular => "specular",
+            Self::Transmission => "transmission",
+            Self::Emission => "emission",
+        }
+    }
+}
+
+/// Algorithm which estimates the light arriving at each pixel
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Integrator {
+    /// Paths traced independently for every pixel
+    #[default]
+    Path,
+    /// Paths mutated by Markov chains in primary sample space, see [`crate::mlt`]
+    Pssmlt,
+}
+
+impl FromStr for Integrator {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "path" => Ok(Self::Path),
+            "pssmlt" => Ok(Self::Pssmlt),
+            _ => Err(anyhow!("Unknown integrator {}", s)),
+        }
+    }
+}
+
+/// Image rendered in addition to the 8bpp image, with linear float channels. Passes other
+/// than the beauty pass are kept in half precision.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Pass {
+    /// The whole image
+    Beauty,
+    Component(Component),
+    /// Light which reaches the camera after at most one scattering event
+    Direct,
+    /// Light which has scattered more, which adds up to the whole image with [`Pass::Direct`]
+    Indirect,
+    /// Reflectance of the surfaces seen by the camera, for denoising
+    Albedo,
+    /// Normals of the surfaces seen by the camera, facing it
+    Normal,
+    /// Distance to the surfaces seen by the camera, infinite where nothing was hit
+    Depth,
+    /// Variance of the mean color of each pixel
+    Variance,
+    /// Fraction of the light of each pixel replaced by the depth fog of the scene, see
+    /// [`DepthFog::factor`]
+    Fog,
+}
+
+impl Pass {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Beauty => "beauty",
+            Self::Component(component) => component.name(),
+            Self::Direct => "direct",
+            Self::Indirect => "indirect",
+            Self::Albedo => "albedo",
+            Self::Normal => "normal",
+            Self::Depth => "depth",
+            Self::Variance => "variance",
+            Self::Fog => "fog",
+        }
+    }
+
+    /// Names of the channels of each pixel, following OpenEXR conventions
+    pub fn channel_names(&self) -> &'static [&'static str] {
+        match self {
+            Self::Normal => &["X", "Y", "Z"],
+            Self::Depth => &["Z"],
+            Self::Fog => &["Y"],
+            _ => &["R", "G", "B"],
+        }
+    }
+
+    pub fn channels(&self) -> usize {
+        self.channel_names().len()
+    }
+
+    /// Index of the pass in the values traced for a pixel
+    fn slot(&self) -> usize {
+        match self {
+            Self::Beauty => 0,
+            Self::Component(component) => 1 + *component as usize,
+            Self::Direct => 1 + COMPONENTS.len(),
+            Self::Indirect => 2 + COMPONENTS.len(),
+            Self::Albedo => 3 + COMPONENTS.len(),
+            Self::Normal => 4 + COMPONENTS.len(),
+            Self::Depth => 5 + COMPONENTS.len(),
+            Self::Variance => 6 + COMPONENTS.len(),
+            Self::Fog => 7 + COMPONENTS.len(),
+        }
+    }
+}
+
+/// Values traced for a pixel, one for each kind of [`Pass`]
+const SLOTS: usize = 8 + COMPONENTS.len();
+
+/// Length of the data of a tile of `pixels` pixels, see [`Renderer::accumulate_tile`]
+fn tile_len(passes: &[Pass], pixels: usize) -> usize {
+    let floats: usize = passes.iter().map(Pass::channels).sum();
+    pixels * (COLOR_CHANNELS * 4 + 4 + floats * 4)
+}
+
+/// Little-endian 32-bit values
+fn words(bytes: &[u8]) -> impl Iterator<Item = [u8; 4]> + '_ {
+    bytes
+        .chunks_exact(4)
+        .map(|bytes| bytes.try_into().expect("Chunks are 4 bytes"))
+}
+
+/// Running mean and sum of squared differences from it, from Welford's online algorithm
+#[derive(Default)]
+struct Welford {
+    n: u32,
+    mean: Vec3,
+    m2: Vec3,
+}
+
+impl Welford {
+    fn add(&mut self, x: Vec3) {
+        self.n += 1;
+        let delta = x - self.mean;
+        self.mean += delta / self.n as f32;
+        self.m2 += delta * (x - self.mean);
+    }
+
+    /// Estimated variance of the mean of the samples
+    fn variance_of_mean(&self) -> Vec3 {
+        if self.n > 1 {
+            let n = self.n as f32;
+            self.m2 / (n * (n - 1.))
+        } else {
+            Vec3::zero()
+        }
+    }
+}
+
+/// Numbers of rays traced, see [`ray_counts`]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct RayCounts {
+    /// Rays from the camera
+    pub primary: u64,
+    /// Rays scattered by surfaces and media, and continued through boundaries of media
+    pub secondary: u64,
+    /// Rays towards sampled lights
+    pub shadow: u64,
+}
+
+impl RayCounts {
+    pub fn total(&self) -> u64 {
+        self.primary + self.secondary + self.shadow
+    }
+}
+
+impl Sub for RayCounts {
+    type Output = Self;
+
+    fn sub(self, other: Self) -> Self {
+        Self {
+            primary: self.primary - other.primary,
+            secondary: self.secondary - other.secondary,
+            shadow: self.shadow - other.shadow,
+        }
+    }
+}
+
+thread_local! {
+    /// Rays traced by this thread which haven't been added to [`RAYS`] yet
+    static THREAD_RAYS: Cell<RayCounts> = const {
+        Cell::new(RayCounts {
+            primary: 0,
+            secondary: 0,
+            shadow: 0,
+        })
+    };
+}
+
+/// Primary, secondary and shadow rays traced by all threads
+static RAYS: [AtomicU64; 3] = [AtomicU64::new(0), AtomicU64::new(0), AtomicU64::new(0)];
+
+fn count_rays(count: impl FnOnce(&mut RayCounts)) {
+    THREAD_RAYS.with(|rays| {
+        let mut counts = rays.get();
+        count(&mut counts);
+        rays.set(counts);
+    });
+}
+
+/// Rays traced by all threads since the program started. Rays are counted when the pixel
+/// that they were traced for is finished, so that the threads don't contend for the totals.
+pub fn ray_counts() -> RayCounts {
+    let [primary, secondary, shadow] = &RAYS;
+    RayCounts {
+        primary: primary.load(Ordering::Relaxed),
+        secondary: secondary.load(Ordering::Relaxed),
+        shadow: shadow.load(Ordering::Relaxed),
+    }
+}
+
+/// Add the rays traced by this thread to the totals of [`ray_counts`]
+fn finish_ray_counts() {
+    let counts = THREAD_RAYS.with(|rays| rays.take());
+    let [primary, secondary, shadow] = &RAYS;
+    primary.fetch_add(counts.primary, Ordering::Relaxed);
+    secondary.fetch_add(counts.secondary, Ordering::Relaxed);
+    shadow.fetch_add(counts.shadow, Ordering::Relaxed);
+}
+
+/// Color of light arriving along `r` from where no object was hit, leaving out light which
+/// was already sampled at the diffuse surface that `r` was scattered from
+fn background(r: &Ray, world: &World) -> Vec3 {
+    world.background(r.direction(), r.kind() != RayKind::Diffuse, r.is_caustic())
+}
+
+/// Light from a sampled light arriving at the diffuse surface which `r` was scattered from,
+/// with geometric normal `normal` and shading normal `shading_normal`, to be multiplied by the
+/// attenuation of the scattering. `visible` is called with the sampled light and the object
+/// which shadows it, if they are objects.
+fn direct_light<R: Rng>(
+    r: &Ray,
+    normal: Vec3,
+    shading_normal: Vec3,
+    world: &World,
+    sampler: &mut Sampler<R>,
+    visible: &mut impl FnMut(u32),
+) -> Vec3 {
+    if r.kind() != RayKind::Diffuse || !world.has_sampled_lights() {
+        return Vec3::zero();
+    }
+    let sample = match world.sample_light(r.origin(), r.time(), sampler.next_2d()) {
+        Some(sample) => sample,
+        None => return Vec3::zero(),
+    };
+    // Light from below the geometric surface would leak through it
+    let cos_theta = sample.direction.dot(shading_normal);
+    if cos_theta <= 0. || sample.direction.dot(normal) <= 0. {
+        return Vec3::zero();
+    }
+    let shadow = r.scattered(r.origin(), sample.direction, RayKind::Shadow);
+    count_rays(|counts| counts.shadow += 1);
+    // Lights which are objects may be hit just short of the sampled point
+    if let Some(occluder) = world.occluder(&shadow, 0.001..sample.distance * 0.999) {
+        visible(occluder);
+        return Vec3::zero();
+    }
+    if let Some(object) = sample.object {
+        visible(object);
+    }
+    // Lambertian reflectance over the density of the cosine-weighted scattered rays
+    sample.weight * cos_theta / PI
+}
+
+/// Light emitted towards `r` by the object at `intersection`, leaving out light which was
+/// already sampled at the diffuse surface that `r` was scattered from, and caustics of objects
+/// which don't cast them
+fn emitted(r: &Ray, intersection: &Intersection) -> Vec3 {
+    let caustic = r.is_caustic() && !intersection.material.casts_caustics();
+    if r.kind() == RayKind::Diffuse && intersection.sampled || caustic {
+        Vec3::zero()
+    } else {
+        intersection.material.emitted(&intersection.hit)
+    }
+}
+
+/// Scatter `r` from a Lambertian surface at `hit` with `albedo`, in directions chosen by `guide`
+fn guided_scatter<R: Rng>(
+    guide: &Guide,
+    sampler: &mut Sampler<R>,
+    r: Ray,
+    hit: &HitRecord,
+    albedo: Vec3,
+) -> Option<(Vec3, Ray)> {
+    let u = Vec2::new(sampler.gen(), sampler.gen());
+    let direction = guide.sample(hit.position, &hit.frame, u, sampler.gen());
+    let cos_theta = direction.dot(hit.frame.normal);
+    if cos_theta <= 0. {
+        return None;
+    }
+    let pdf = guide.pdf(hit.position, hit.frame.normal, direction);
+    Some((
+        albedo * cos_theta / PI / pdf,
+        r.scattered(hit.position, direction, RayKind::Diffuse),
+    ))
+}
+
+/// Record `color` arriving at `position` on a Lambertian surface with shading normal `normal`
+/// from `direction`, which it was scattered towards, in `guide` if it is learning
+fn learn(guide: Option<&Guide>, position: Vec3, normal: Vec3, direction: Vec3, color: Vec3) {
+    if let Some(guide) = guide.filter(|guide| guide.is_learning()) {
+        let pdf = guide.pdf(position, normal, direction);
+        guide.record(
+            position,
+            direction,
+            (color.x + color.y + color.z) / 3. / pdf,
+        );
+    }
+}
+
+/// Scatter `r` at `intersection`, calling `visible` with the object. Bounces from Lambertian
+/// surfaces are sampled by `guide` if there is one.
+fn scatter<R: Rng>(
+    r: Ray,
+    intersection: Intersection,
+    sampler: &mut Sampler<R>,
+    guide: Option<&Guide>,
+    visible: &mut impl FnMut(u32),
+) -> Option<(Vec3, Ray)> {
+    profile_scope!("scatter");
+    visible(intersection.object);
+    sampler.start_bounce(r.depth().total());
+    let (object, priority) = (intersection.object, intersection.priority);
+    let (entering, normal) = (intersection.hit.front_facing, intersection.hit.normal);
+    let (refraction, volume) = (
+        intersection.material.refraction(),
+        intersection.material.volume(),
+    );
+    let (att, r) = match (guide, intersection.material) {
+        (Some(guide), Material::Lambertian(_)) => guided_scatter(
+            guide,
+            sampler,
+            r,
+            &intersection.hit,
+            intersection.material.albedo(),
+        )?,
+        (_, material) => material.scatter(sampler, r, intersection.hit)?,
+    };
+    // A shading normal can reflect rays below the geometric surface, where they would hit its
+    // back side, so they are mirrored back above it. Refracted rays are kept on their side by
+    // the material, and cross into media only when they cross the geometric surface.
+    let r = match refraction {
+        None if r.direction().dot(normal) < 0. => {
+            let direction = r.direction();
+            r.with_direction(direction - 2. * direction.dot(normal) * normal)
+        }
+        _ => r,
+    };
+    // Rays refracted by dielectrics enter or leave them as media
+    let media = match refraction {
+        Some(refraction) if r.direction().dot(normal) < 0. => {
+            if entering {
+                r.media().entered(object, priority, refraction, volume)
+            } else {
+                r.media().left(object)
+            }
+        }
+        _ => return Some((att, r)),
+    };
+    Some((att, r.with_media(media).transmitted()))
+}
+
+/// Shading normal of a Lambertian surface at `intersection`, where bounces are guided
+fn lambertian_normal(intersection: &Intersection) -> Option<Vec3> {
+    match intersection.material {
+        Material::Lambertian(_) => Some(intersection.hit.frame.normal),
+        _ => None,
+    }
+}
+
+/// Nearest hit of `r` which changes the medium that it is in, passing through the boundaries of
+/// dielectrics inside of ones with a higher priority. Also returns the ray which reaches the hit.
+fn nearest_hit(world: &World, mut r: Ray) -> (Option<Intersection<'_>>, Ray) {
+    loop {
+        count_rays(|counts| counts.secondary += 1);
+        let intersection = match world.traverse(&r, 0.001) {
+            Some(intersection) => intersection,
+            None => return (None, r),
+        };
+        let (object, priority) = (intersection.object, intersection.priority);
+        let entering = intersection.hit.front_facing;
+        match intersection.material.refraction() {
+            Some(refraction) if !r.media().is_boundary(object, priority, entering) => {
+                let media = if entering {
+                    let volume = intersection.material.volume();
+                    r.media().entered(object, priority, refraction, volume)
+                } else {
+                    r.media().left(object)
+                };
+                r = r.continued(intersection.hit.position, media);
+            }
+            _ => return (Some(intersection), r),
+        }
+    }
+}
+
+/// How paths are ended
+#[derive(Clone, Copy, Debug)]
+struct Termination {
+    /// Whether paths are cut off at [`MAX_DEPTH`] instead of being ended by Russian roulette,
+    /// see [`Scene::biased`]
+    biased: bool,
+    limits: BounceLimits,
+}
+
+impl Termination {
+    /// Most bounces of a path
+    fn max_depth(&self) -> u32 {
+        if self.biased {
+            MAX_DEPTH
+        } else {
+            UNBIASED_MAX_DEPTH
+        }
+    }
+
+    /// Chance of continuing the path of `r`, which was scattered with attenuation `att`. Paths
+    /// are cut off when they go past the bounce limits. In unbiased renders they are ended by
+    /// Russian roulette once they have bounced a few times, and are more likely to end when
+    /// little light is carried along them.
+    fn survival(&self, r: &Ray, att: Vec3) -> f32 {
+        if !self.limits.allow(r.depth()) {
+            0.
+        } else if self.biased || r.depth().total() <= ROULETTE_BOUNCES {
+            1.
+        } else {
+            att.component_max().min(MAX_SURVIVAL)
+        }
+    }
+}
+
+/// Color of light arriving along `r`, calling `visible` with every object that the path hits,
+/// samples light from or is shadowed by. Paths are cut off after `depth` more bounces, or ended
+/// earlier by `termination`. Also returns the depth of the last ray of the path.
+fn ray_color<R: Rng>(
+    r: Ray,
+    world: &World,
+    sampler: &mut Sampler<R>,
+    guide: Option<&Guide>,
+    termination: Termination,
+    depth: u32,
+    visible: &mut impl FnMut(u32),
+) -> (Vec3, Depth) {
+    if depth == 0 {
+        return (Vec3::zero(), r.depth());
+    }
+
+    let (hit, r) = nearest_hit(world, r);
+    // Light which reaches the hit through the medium that the ray is in
+    let mut transmitted = Vec3::one();
+    if let Some(volume) = r.media().volume() {
+        let max_distance = hit.as_ref().map_or(f32::INFINITY, |i| i.hit.t);
+        match volume.interact(max_distance, sampler.next_2d()) {
+            Interaction::Scattered(distance, weight) => {
+                // Lights aren't sampled in media, so the ray counts them like a specular one
+                let direction = volume.scatter(r.direction(), sampler.next_2d());
+                let r = r.scattered(r.at(distance), direction, RayKind::Specular);
+                let (color, end) =
+                    ray_color(r, world, sampler, guide, termination, depth - 1, visible);
+                return (weight * color, end);
+            }
+            Interaction::Passed(weight) => transmitted = weight,
+        }
+    }
+    let (color, end) = match hit {
+        Some(intersection) => {
+            let end = r.depth();
+            let (normal, shading_normal) = (intersection.hit.normal, intersection.hit.frame.normal);
+            let lambertian = lambertian_normal(&intersection);
+            let emitted = emitted(&r, &intersection);
+            match scatter(r, intersection, sampler, guide, visible) {
+                Some((att, r)) => {
+                    let survival = termination.survival(&r, att);
+                    if survival == 0. {
+                        return (transmitted * emitted, end);
+                    }
+                    let direct = direct_light(&r, normal, shading_normal, world, sampler, visible);
+                    if survival < 1. && sampler.gen::<f32>() >= survival {
+                        return (transmitted * (emitted + att * direct), end);
+                    }
+                    let (position, direction) = (r.origin(), r.direction());
+                    let (color, end) =
+                        ray_color(r, world, sampler, guide, termination, depth - 1, visible);
+                    if let Some(normal) = lambertian {
+                        learn(guide, position, normal, direction, color);
+                    }
+                    (emitted + att * (direct + color / survival), end)
+                }
+                None => (emitted, end),
+            }
+        }
+        None => (background(&r, world), r.depth()),
+    };
+    (transmitted * color, end)
+}
+
+/// Color of light arriving along camera ray `r`, which has already been traced to `hit`, the
+/// component that it belongs to and whether it is indirect light. The elements are the light
+/// from the background or emitted by the object that was hit, the light sampled at the first
+/// hit, and the light arriving along the rest of the path, which is traced like by
+/// [`ray_color`].
+fn shade<R: Rng>(
+    r: Ray,
+    hit: Option<Intersection>,
+    world: &World,
+    sampler: &mut Sampler<R>,
+    guide: Option<&Guide>,
+    termination: Termination,
+    visible: &mut impl FnMut(u32),
+) -> [(Vec3, Component, bool); 3] {
+    // Absorbed paths add nothing to any component
+    let nothing = (Vec3::zero(), Component::Background, false);
+    let intersection = match hit {
+        Some(intersection) => intersection,
+        None => {
+            return [
+                (background(&r, world), Component::Background, false),
+                nothing,
+                nothing,
+            ]
+        }
+    };
+    let (normal, shading_normal) = (intersection.hit.normal, intersection.hit.frame.normal);
+    let lambertian = lambertian_normal(&intersection);
+    let emitted = (emitted(&r, &intersection), Component::Emission, false);
+    match scatter(r, intersection, sampler, guide, visible) {
+        Some((_, r)) if !termination.limits.allow(r.depth()) => [emitted, nothing, nothing],
+        Some((att, r)) => {
+            let kind = r.kind();
+            let transmitted = r.direction().dot(normal) < 0.;
+            let direct = att * direct_light(&r, normal, shading_normal, world, sampler, visible);
+            let (position, direction) = (r.origin(), r.direction());
+            let depth = termination.max_depth() - 1;
+            let (color, end) = ray_color(r, world, sampler, guide, termination, depth, visible);
+            if let Some(normal) = lambertian {
+                learn(guide, position, normal, direction, color);
+            }
+            let indirect = end.total() > 1;
+            let component = match kind {
+                RayKind::Diffuse if indirect => Component::IndirectDiffuse,
+                RayKind::Diffuse => Component::DirectDiffuse,
+                _ if transmitted => Component::Transmission,
+                _ => Component::Specular,
+            };
+            [
+                emitted,
+                (direct, Component::DirectDiffuse, false),
+                (att * color, component, indirect),
+            ]
+        }
+        None => [emitted, nothing, nothing],
+    }
+}
+
+/// Rectangle of the image, `y` growing downwards from the top row
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Tile {
+    pub x: usize,
+    pub y: usize,
+    pub width: usize,
+    pub height: usize,
+}
+
+impl Tile {
+    pub fn pixel_count(&self) -> usize {
+        self.width * self.height
+    }
+}
+
+pub struct Renderer {
+    world: World,
+    camera: Camera,
+    width: usize,
+    height: usize,
+    samples_per_pixel: u32,
+    /// Paths continued from the first hit of every camera ray
+    light_samples: u32,
+    sampler: SamplerKind,
+    rng: RngKind,
+    passes: Vec<Pass>,
+    fog: Option<DepthFog>,
+    noise_threshold: Option<f32>,
+    /// Factor from radiance to the image
+    exposure: f32,
+    working_space: ColorSpace,
+    display: Display,
+    guide: Option<Guide>,
+    regularization: Option<Regularization>,
+    max_radiance: Option<f32>,
+    termination: Termination,
+    /// Of the random numbers of every pixel, see [`pixel_seed`]
+    seed: u64,
+}
+
+impl Renderer {
+    pub fn new(
+        scene: &Scene,
+        frame: u32,
+        width: usize,
+        height: usize,
+        samples_per_pixel: u32,
+    ) -> Result<Self> {
+        if !scene.biased && scene.has_biased_options() {
+            return Err(anyhow!(
+                "The scene has options which bias the image, which has to be allowed"
+            ));
+        }
+        let world = scene.world(frame)?;
+        Ok(Self {
+            guide: scene
+                .guiding
+                .map(|options| Guide::new(world.bounds(), options)),
+            world,
+            camera: scene.camera(width as f32 / height as f32, frame)?,
+            width,
+            height,
+            samples_per_pixel,
+            light_samples: scene.light_samples.max(1),
+            sampler: scene.sampler,
+            rng: scene.rng,
+            passes: scene.passes.clone(),
+            fog: scene.fog,
+            noise_threshold: scene.noise_threshold,
+            exposure: scene
+                .camera
+                .exposure
+                .map_or(1., |exposure| exposure.scale()),
+            working_space: scene.working_space,
+            display: match &scene.display_lut {
+                Some(path) => Display::new(scene.working_space).with_lut(Lut::open(path)?),
+                None => Display::new(scene.working_space),
+            },
+            regularization: scene.regularization,
+            max_radiance: scene.max_radiance,
+            termination: Termination {
+                biased: scene.biased,
+                limits: scene.bounce_limits,
+            },
+            seed: u64::from(frame),
+        })
+    }
+
+    /// Ray from the camera through the pixel whose lower left corner is at `uv` and the fraction
+    /// of its light in each channel which reaches the image, like [`Camera::sample_ray`],
+    /// regularized like the scene
+    fn camera_ray(
+        &self,
+        sampler: &mut Sampler<impl Rng>,
+        uv: Vec2,
+        pixel_size: Vec2,
+    ) -> (Ray, Vec3) {
+        let (r, weight) = self.camera.sample_ray(sampler, uv, pixel_size);
+        (r.with_regularization(self.regularization), weight)
+    }
+
+    /// `color`, which is the light brought to a pixel by a path after its first bounce, darkened
+    /// so that no channel is brighter than the maximum radiance of the scene
+    fn clamped(&self, color: Vec3) -> Vec3 {
+        match self.max_radiance {
+            Some(max) if color.component_max() > max => color * (max / color.component_max()),
+            _ => color,
+        }
+    }
+
+    /// Whether speedups which bias the image are allowed, see [`Scene::biased`]
+    pub fn biased(&self) -> bool {
+        self.termination.biased
+    }
+
+    /// Look through the camera of `scene`, such as one from [`Scene::seen_by`], keeping the
+    /// world and the path guide. The scene must have the objects of the one the renderer was
+    /// made with, and a shutter which is open during its shutter.
+    pub fn set_camera(&mut self, scene: &Scene, frame: u32) -> Result<()> {
+        self.camera = scene.camera(self.width as f32 / self.height as f32, frame)?;
+        self.exposure = scene
+            .camera
+            .exposure
+            .map_or(1., |exposure| exposure.scale());
+        Ok(())
+    }
+
+    /// Render images of `width` by `height` pixels, keeping the world, the path guide and the
+    /// camera, whose aspect ratio should stay about the same
+    pub fn set_resolution(&mut self, width: usize, height: usize) {
+        self.width = width;
+        self.height = height;
+    }
+
+    pub fn width(&self) -> usize {
+        self.width
+    }
+
+    /// Space of the colors of the image and its passes
+    pub fn working_space(&self) -> ColorSpace {
+        self.working_space
+    }
+
+    /// How the image is encoded to 8bpp RGB
+    pub fn display(&self) -> &Display {
+        &self.display
+    }
+
+    pub fn height(&self) -> usize {
+        self.height
+    }
+
+    pub fn samples_per_pixel(&self) -> u32 {
+        self.samples_per_pixel
+    }
+
+    pub fn bvh_stats(&self) -> &BvhStats {
+        self.world.bvh_stats()
+    }
+
+    /// Images rendered in addition to the whole image
+    pub fn passes(&self) -> &[Pass] {
+        &self.passes
+    }
+
+    /// Learn where light comes from for path guiding, if the scene has it, by rendering the
+    /// image with 1, 2, 4 and so on samples per pixel on `nthreads` threads and throwing it
+    /// away. Without this, bounces aren't guided.
+    pub fn train_guide(&mut self, nthreads: usize) -> Result<()> {
+        let iterations = match &self.guide {
+            Some(guide) => guide.options().training_iterations,
+            None => return Ok(()),
+        };
+        let samples_per_pixel = self.samples_per_pixel;
+        let noise_threshold = self.noise_threshold.take();
+        let passes = std::mem::take(&mut self.passes);
+        let seed = self.seed;
+        let result = (0..iterations).try_for_each(|iteration| {
+            self.samples_per_pixel = 1 << iteration.min(16);
+            self.seed = seed ^ u64::from(iteration + 1) << 48;
+            let frame = Frame::new(self, &(), CancellationToken::new());
+            #[cfg(feature = "threads")]
+            crossbeam_utils::thread::scope(|s| {
+                let threads: Vec<_> = (0..nthreads.max(1))
+                    .map(|_| {
+                        let (frame, renderer) = (&frame, &*self);
+                        s.spawn(move |_| frame.work(renderer))
+                    })
+                    .collect();
+                threads.into_iter().try_for_each(|thread| {
+                    thread
+                        .join()
+                        .unwrap_or_else(|_| Err(anyhow!("Rendering thread panicked")))
+                })
+            })
+            .map_err(|_| anyhow!("A rendering thread encountered an irrecoverable error"))??;
+            #[cfg(not(feature = "threads"))]
+            {
+                let _ = nthreads;
+                frame.work(self)?;
+            }
+            if let Some(guide) = &mut self.guide {
+                guide.refine();
+            }
+            Ok(())
+        });
+        self.samples_per_pixel = samples_per_pixel;
+        self.noise_threshold = noise_threshold;
+        self.seed = seed;
+        self.passes = passes;
+        if let Some(guide) = &mut self.guide {
+            guide.finish_learning();
+        }
+        result
+    }
+
+    /// Divide the image into tiles, row by row from the top
+    pub fn tiles(&self) -> Vec<Tile> {
+        let mut tiles = Vec::new();
+        for y in (0..self.height).step_by(TILE_SIZE) {
+            for x in (0..self.width).step_by(TILE_SIZE) {
+                tiles.push(Tile {
+                    x,
+                    y,
+                    width: TILE_SIZE.min(self.width - x),
+                    height: TILE_SIZE.min(self.height - y),
+                });
+            }
+        }
+        tiles
+    }
+
+    /// Trace one path through a pixel chosen by the first random numbers from `rng`, which all
+    /// of the random numbers of the path come from. Returns the pixel, counted from the top
+    /// left, and the color of the path.
+    pub fn sample_path<R: Rng>(&self, rng: &mut R) -> ((usize, usize), Vec3) {
+        let mut sampler = Sampler::new(rng, SamplerKind::Random, 1);
+        sampler.start_sample(0);
+        let p = sampler.next_2d();
+        let x = ((p.x * self.width as f32) as usize).min(self.width - 1);
+        let y = ((p.y * self.height as f32) as usize).min(self.height - 1);
+        let xy = Vec2::new(x as f32, (self.height - 1 - y) as f32);
+        let wh = Vec2::new(self.width as f32, self.height as f32);
+        let pixel_size = Vec2::one() / (wh - Vec2::one());
+        let (r, weight) = self.camera_ray(&mut sampler, xy * pixel_size, pixel_size);
+        let hit = self.world.traverse(&r, 0.001);
+        let [emitted, direct, rest] = shade(
+            r,
+            hit,
+            &self.world,
+            &mut sampler,
+            self.guide.as_ref(),
+            self.termination,
+            &mut |_| {},
+        );
+        let color = (emitted.0 + direct.0) * self.exposure + self.clamped(rest.0 * self.exposure);
+        ((x, y), color * weight)
+    }
+
+    /// Splat the samples of the pixels of row `y`, counted from the top, into `image` where in
+    /// the pixels they were taken, instead of averaging them in their pixels
+    pub fn splat_row(&self, y: usize, image: &mut SplatImage) {
+        for x in 0..self.width {
+            let seed = pixel_seed(self.seed, x, y);
+            match self.rng {
+                RngKind::XorShift => {
+                    self.splat_samples(&mut XorShiftRng::seed_from_u64(seed), x, y, image)
+                }
+                RngKind::Pcg32 => self.splat_samples(&mut Pcg32::seed_from_u64(seed), x, y, image),
+                RngKind::ChaCha8 => {
+                    self.splat_samples(&mut ChaCha8Rng::seed_from_u64(seed), x, y, image)
+                }
+            }
+        }
+        finish_ray_counts();
+    }
+
+    fn splat_samples<R: Rng>(&self, rng: &mut R, x: usize, y: usize, image: &mut SplatImage) {
+        let wh = Vec2::new(self.width as f32, self.height as f32);
+        let pixel_size = Vec2::one() / (wh - Vec2::one());
+        let xy = Vec2::new(x as f32, (self.height - 1 - y) as f32);
+        let mut sampler =
+            Sampler::new(rng, self.sampler, self.samples_per_pixel).with_splits(self.light_samples);
+        for sample in 0..self.samples_per_pixel {
+            sampler.start_sample(sample);
+            let offset = sampler.next_2d();
+            let (r, weight) =
+                self.camera
+                    .ray_through(&mut sampler, (xy + offset) * pixel_size, pixel_size);
+            let r = r.with_regularization(self.regularization);
+            count_rays(|counts| counts.primary += 1);
+            let hit = self.world.traverse(&r, 0.001);
+            let mut color = Vec3::zero();
+            for split in 0..self.light_samples {
+                sampler.start_split(split);
+                let [emitted, direct, rest] = shade(
+                    r.clone(),
+                    hit.clone(),
+                    &self.world,
+                    &mut sampler,
+                    self.guide.as_ref(),
+                    self.termination,
+                    &mut |_| {},
+                );
+                color +=
+                    (emitted.0 + direct.0) * self.exposure + self.clamped(rest.0 * self.exposure);
+            }
+            // The viewport grows upwards and the image downwards
+            let position = Vec2::new(x as f32 + offset.x, (y + 1) as f32 - offset.y);
+            image.splat(position, color * weight / self.light_samples as f32);
+        }
+    }
+
+    /// Render a pixel, `y` growing downwards from the top row of the image
+    pub fn render_pixel(&self, x: usize, y: usize) -> OutputColor {
+        let (_, [color, ..]) = self.trace_pixel(self.seed, x, y, &mut |_| {});
+        self.display.encode(color)
+    }
+
+    /// Shade row `y` of the image, counted from the top, as 8bpp RGB by how directly the
+    /// surfaces seen through the pixels face the camera, with one camera ray per pixel and no
+    /// lighting, for checking the geometry of a scene quickly
+    pub fn geometry_row<R: Rng>(&self, rng: &mut R, y: usize) -> Vec<u8> {
+        let wh = Vec2::new(self.width as f32, self.height as f32);
+        let pixel_size = Vec2::one() / (wh - Vec2::one());
+        let mut sampler = Sampler::new(rng, SamplerKind::Random, 1);
+        let rays: Vec<Ray> = (0..self.width)
+            .map(|x| {
+                sampler.start_sample(0);
+                let xy = Vec2::new(x as f32, (self.height - 1 - y) as f32);
+                self.camera_ray(&mut sampler, xy * pixel_size, pixel_size).0
+            })
+            .collect();
+        rays.chunks(PACKET_SIZE)
+            .flat_map(|packet| {
+                let hits = self.world.traverse_packet(packet, 0.001);
+                packet
+                    .iter()
+                    .zip(hits)
+                    .map(|(r, hit)| {
+                        let facing = hit.map_or(0., |Intersection { hit, .. }| {
+                            hit.normal.dot(r.direction().normalized()).abs()
+                        });
+                        self.display.encode(Vec3::broadcast(facing))
+                    })
+                    .collect::<Vec<_>>()
+            })
+            .flatten()
+            .collect()
+    }
+
+    /// Number of samples taken of a pixel and their average for each kind of [`Pass`], calling
+    /// `visible` with the index of every object in the scene that the paths hit. The random
+    /// numbers of the pixel depend only on `seed` and its coordinates, see [`pixel_seed`].
+    fn trace_pixel(
+        &self,
+        seed: u64,
+        x: usize,
+        y: usize,
+        visible: &mut impl FnMut(u32),
+    ) -> (u32, [Vec3; SLOTS]) {
+        let seed = pixel_seed(seed, x, y);
+        let pixel = match self.rng {
+            RngKind::XorShift => {
+                self.trace_samples(&mut XorShiftRng::seed_from_u64(seed), x, y, visible)
+            }
+            RngKind::Pcg32 => self.trace_samples(&mut Pcg32::seed_from_u64(seed), x, y, visible),
+            RngKind::ChaCha8 => {
+                self.trace_samples(&mut ChaCha8Rng::seed_from_u64(seed), x, y, visible)
+            }
+        };
+        finish_ray_counts();
+        pixel
+    }
+
+    fn trace_samples<R: Rng>(
+        &self,
+        rng: &mut R,
+        x: usize,
+        y: usize,
+        visible: &mut impl FnMut(u32),
+    ) -> (u32, [Vec3; SLOTS]) {
+        // Calculate pixel coordinates
+        let xy = Vec2::new(x as f32, (self.height - 1 - y) as f32);
+
+        // Accumulate color from rays. Samples of a pixel are coherent, so their camera rays are
+        // traced as packets.
+        let wh = Vec2::new(self.width as f32, self.height as f32);
+        let mut colors = [Vec3::zero(); SLOTS];
+        let mut stats = Welford::default();
+        let mut sampler =
+            Sampler::new(rng, self.sampler, self.samples_per_pixel).with_splits(self.light_samples);
+        let mut rays = Vec::with_capacity(PACKET_SIZE);
+        let mut weights = Vec::with_capacity(PACKET_SIZE);
+        for first in (0..self.samples_per_pixel).step_by(PACKET_SIZE) {
+            let samples = first..(first + PACKET_SIZE as u32).min(self.samples_per_pixel);
+            for sample in samples.clone() {
+                sampler.start_sample(sample);
+                // Ray through viewport in right handed space
+                let pixel_size = Vec2::one() / (wh - Vec2::one());
+                let (r, weight) = self.camera_ray(&mut sampler, xy * pixel_size, pixel_size);
+                rays.push(r);
+                weights.push(weight);
+            }
+            count_rays(|counts| counts.primary += rays.len() as u64);
+            let hits = self.world.traverse_packet(&rays, 0.001);
+            for (((r, hit), weight), sample) in
+                rays.drain(..).zip(hits).zip(weights.drain(..)).zip(samples)
+            {
+                sampler.start_sample(sample);
+                let depth = match &hit {
+                    Some(Intersection { hit, material, .. }) => {
+                        colors[Pass::Albedo.slot()] += material.albedo();
+                        colors[Pass::Normal.slot()] += hit.normal;
+                        hit.t
+                    }
+                    None => f32::INFINITY,
+                };
+                colors[Pass::Depth.slot()] += Vec3::broadcast(depth);
+                // Of each sample, so that edges against the sky aren't fogged like the sky
+                if let Some(fog) = &self.fog {
+                    colors[Pass::Fog.slot()] += Vec3::broadcast(fog.factor(depth));
+                }
+                let mut sample_color = Vec3::zero();
+                // Paths from the first hit share the weight of the sample
+                let weight = weight / self.light_samples as f32;
+                for split in 0..self.light_samples {
+                    sampler.start_split(split);
+                    for (i, &(color, component, indirect)) in shade(
+                        r.clone(),
+                        hit.clone(),
+                        &self.world,
+                        &mut sampler,
+                        self.guide.as_ref(),
+                        self.termination,
+                        visible,
+                    )
+                    .iter()
+                    .enumerate()
+                    {
+                        // Only the rest of the path is clamped
+                        let color = match i {
+                            2 => self.clamped(color * self.exposure),
+                            _ => color * self.exposure,
+                        } * weight;
+                        sample_color += color;
+                        colors[Pass::Component(component).slot()] += color;
+                        let light = if indirect {
+                            Pass::Indirect
+                        } else {
+                            Pass::Direct
+                        };
+                        colors[light.slot()] += color;
+                    }
+                }
+                colors[0] += sample_color;
+                stats.add(sample_color);
+            }
+
+            if let Some(threshold) = self.noise_threshold {
+                // Within 95% confidence
+                let error = 1.96 * stats.variance_of_mean().map(f32::sqrt);
+                if stats.n >= MIN_ADAPTIVE_SAMPLES && error.component_max() < threshold {
+                    break;
+                }
+            }
+        }
+
+        // Average samples
+        let n = stats.n as f32;
+        let mut colors = colors.map(|color| color / n);
+        colors[0] = stats.mean;
+        colors[Pass::Variance.slot()] = stats.variance_of_mean();
+        (stats.n, colors)
+    }
+
+    /// Render a rectangle of the image, replacing the contents of `out` with 8bpp RGB data.
+    /// Parts of the tile which extend past the edges of the image are left out.
+    pub fn render_tile(&self, tile: &Tile, out: &mut Vec<u8>) {
+        profile_scope!("render_tile");
+        out.clear();
+        for y in tile.y..(tile.y + tile.height).min(self.height) {
+            for x in tile.x..(tile.x + tile.width).min(self.width) {
+                out.extend_from_slice(&self.render_pixel(x, y));
+            }
+        }
+    }
+
+    /// Render a rectangle of the image for a [`Frame`], replacing the contents of `out` with the
+    /// sum of the samples of each pixel as linear RGB, followed by the number of samples of
+    /// each pixel, followed by the images of [`Renderer::passes`] with [`Pass::channels`]
+    /// values per pixel. All values are little-endian, sums and passes as 32-bit floats and
+    /// numbers of samples as 32-bit integers. `visible` is called with the index of every
+    /// object in the scene that the paths hit. Rendering a tile again with a different
+    /// `attempt` takes different random numbers.
+    pub fn accumulate_tile(
+        &self,
+        tile: &Tile,
+        attempt: u32,
+        out: &mut Vec<u8>,
+        visible: &mut impl FnMut(u32),
+    ) {
+        profile_scope!("render_tile");
+        let seed = self.seed.wrapping_add(u64::from(attempt) << 32);
+        let (xs, ys) = (
+            tile.x..(tile.x + tile.width).min(self.width),
+            tile.y..(tile.y + tile.height).min(self.height),
+        );
+        let pixel_count = xs.len() * ys.len();
+        out.clear();
+        out.resize(tile_len(&self.passes, pixel_count), 0);
+        let (sums, rest) = out.split_at_mut(pixel_count * COLOR_CHANNELS * 4);
+        let (counts, mut floats) = rest.split_at_mut(pixel_count * 4);
+        let mut pass_data: Vec<&mut [u8]> = Vec::with_capacity(self.passes.len());
+        for pass in &self.passes {
+            let (data, rest) = floats.split_at_mut(pixel_count * pass.channels() * 4);
+            pass_data.push(data);
+            floats = rest;
+        }
+        let write = |data: &mut [u8], i: usize, value: Vec3, channels: usize| {
+            for (c, bytes) in data[i * channels * 4..][..channels * 4]
+                .chunks_exact_mut(4)
+                .enumerate()
+            {
+                bytes.copy_from_slice(&value[c].to_le_bytes());
+            }
+        };
+        let mut i = 0;
+        for y in ys {
+            for x in xs.clone() {
+                let (samples, values) = self.trace_pixel(seed, x, y, visible);
+                write(sums, i, values[0] * samples as f32, COLOR_CHANNELS);
+                counts[i * 4..][..4].copy_from_slice(&samples.to_le_bytes());
+                for (pass, data) in self.passes.iter().zip(&mut pass_data) {
+                    write(data, i, values[pass.slot()], pass.channels());
+                }
+                i += 1;
+            }
+        }
+    }
+}
+
+/// A tile that has just been rendered
+pub struct TileCompleted<'a> {
+    pub tile: Tile,
+    /// 8bpp RGB data, row by row from the top
+    pub pixels: &'a [u8],
+    pub tiles_done: usize,
+    pub tiles_total: usize,
+}
+
+/// Observer of a render in progress, called from the rendering threads as tiles are finished
+pub trait RenderProgress: Sync {
+    fn tile_completed(&self, completed: &TileCompleted);
+}
+
+impl RenderProgress for () {
+    fn tile_completed(&self, _: &TileCompleted) {}
+}
+
+impl<F: Fn(&TileCompleted) + Sync> RenderProgress for F {
+    fn tile_completed(&self, completed: &TileCompleted) {
+        self(completed)
+    }
+}
+
+/// Owned version of [`TileCompleted`] for sending to other threads
+pub struct TileUpdate {
+    pub tile: Tile,
+    pub pixels: Vec<u8>,
+    pub tiles_done: usize,
+    pub tiles_total: usize,
+}
+
+/// Progress observer which sends tiles to a channel, see [`channel`]
+pub struct ChannelProgress(Mutex<Sender<TileUpdate>>);
+
+impl RenderProgress for ChannelProgress {
+    fn tile_completed(&self, completed: &TileCompleted) {
+        // Nobody listening is not an error for the render
+        let _ = self.0.lock().send(TileUpdate {
+            tile: completed.tile,
+            pixels: completed.pixels.to_vec(),
+            tiles_done: completed.tiles_done,
+            tiles_total: completed.tiles_total,
+        });
+    }
+}
+
+/// Create a progress observer which forwards every completed tile to the returned receiver
+pub fn channel() -> (ChannelProgress, Receiver<TileUpdate>) {
+    let (sender, receiver) = mpsc::channel();
+    (ChannelProgress(Mutex::new(sender)), receiver)
+}
+
+/// Shared flag for stopping a render early. Clones refer to the same flag.
+#[derive(Clone, Default)]
+pub struct CancellationToken(Arc<AtomicBool>);
+
+impl CancellationToken {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Ask rendering threads to stop after the tiles they are currently working on
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+/// Data of a finished tile of a [`Frame`], owned by the thread which rendered it until it is
+/// finished, and only read after that
+struct TileBuffers {
+    /// Linear RGB sums of samples
+    color: Vec<f32>,
+    samples: Vec<u32>,
+    passes: Vec<PassImage>,
+    /// Objects seen by the paths of the tile, unknown for tiles rendered elsewhere
+    visible: Option<Vec<u32>>,
+}
+
+/// Values of a pass for every pixel of a tile, in half precision except for [`Pass::Beauty`]
+enum PassImage {
+    Full(Vec<f32>),
+    Half(Vec<u16>),
+}
+
+impl PassImage {
+    fn new(pass: Pass, values: impl Iterator<Item = f32>) -> Self {
+        match pass {
+            Pass::Beauty => Self::Full(values.collect()),
+            _ => Self::Half(values.map(f32_to_f16).collect()),
+        }
+    }
+
+    fn get(&self, i: usize) -> f32 {
+        match self {
+            Self::Full(values) => values[i],
+            Self::Half(values) => f16_to_f32(values[i]),
+        }
+    }
+}
+
+/// Data of a rendered row like that of a tile one row high, and the objects seen in it
+type RowData = (Vec<u8>, Vec<u32>);
+
+/// Tile being rendered, whose rows are taken one at a time by the thread which took the tile
+/// and by threads which have no tiles left to take
+struct TileRows {
+    /// Number of the next row to take
+    next: AtomicUsize,
+    done: AtomicUsize,
+    /// Data of each row, which is missing for rows whose rendering panicked every time
+    rows: Vec<Mutex<Option<RowData>>>,
+}
+
+/// Image being rendered, shared between the threads that render its tiles. Finished tiles are
+/// kept apart and only merged into whole images when those are asked for, so that threads
+/// never wait for each other while rendering.
+pub struct Frame<'a> {
+    width: usize,
+    height: usize,
+    passes: Vec<Pass>,
+    display: Display,
+    tiles: Vec<Tile>,
+    /// Buffers of each tile, set once when it is finished
+    finished: Vec<OnceLock<TileBuffers>>,
+    /// Rows of each tile rendered here, set when it is started
+    rows: Vec<OnceLock<TileRows>>,
+    /// Number of the next tile to take, in order from the top
+    next_tile: AtomicUsize,
+    /// Tiles given back after being taken, which are taken again after the others
+    returned: Mutex<Vec<usize>>,
+    tiles_done: AtomicUsize,
+    /// Tiles whose rendering panicked every time, which are magenta in the image
+    failed: Mutex<Vec<usize>>,
+    progress: &'a dyn RenderProgress,
+    cancel: CancellationToken,
+}
+
+impl<'a> Frame<'a> {
+    pub fn new(
+        renderer: &Renderer,
+        progress: &'a dyn RenderProgress,
+        cancel: CancellationToken,
+    ) -> Self {
+        let tiles = renderer.tiles();
+        Self {
+            width: renderer.width,
+            height: renderer.height,
+            passes: renderer.passes().to_vec(),
+            display: renderer.display.clone(),
+            finished: tiles.iter().map(|_| OnceLock::new()).collect(),
+            rows: tiles.iter().map(|_| OnceLock::new()).collect(),
+            tiles,
+            next_tile: AtomicUsize::new(0),
+            returned: Mutex::new(Vec::new()),
+            tiles_done: AtomicUsize::new(0),
+            failed: Mutex::new(Vec::new()),
+            progress,
+            cancel,
+        }
+    }
+
+    pub fn width(&self) -> usize {
+        self.width
+    }
+
+    pub fn height(&self) -> usize {
+        self.height
+    }
+
+    pub fn tile(&self, i: usize) -> Option<Tile> {
+        self.tiles.get(i).copied()
+    }
+
+    pub fn tiles_total(&self) -> usize {
+        self.tiles.len()
+    }
+
+    pub fn tiles_done(&self) -> usize {
+        self.tiles_done.load(Ordering::Relaxed)
+    }
+
+    pub fn finished(&self) -> bool {
+        self.tiles_done() == self.tiles_total()
+    }
+
+    /// True when all tiles are finished or the render has been cancelled
+    pub fn stopped(&self) -> bool {
+        self.finished() || self.cancel.is_cancelled()
+    }
+
+    /// Take the number of a tile which nobody is working on yet
+    pub fn next_tile(&self) -> Option<usize> {
+        loop {
+            let i = self.next_tile.fetch_add(1, Ordering::Relaxed);
+            match self.finished.get(i) {
+                // Reused tiles are finished before anyone takes them
+                Some(finished) if finished.get().is_some() => continue,
+                Some(_) => return Some(i),
+                None => break,
+            }
+        }
+        self.returned.lock().pop()
+    }
+
+    /// Give back a tile that could not be rendered, so that someone else renders it
+    pub fn return_tile(&self, i: usize) {
+        self.returned.lock().push(i);
+    }
+
+    /// Length of the data of tile number `i`, see [`Renderer::accumulate_tile`]
+    pub fn tile_data_len(&self, i: usize) -> Option<usize> {
+        self.tile(i)
+            .map(|tile| tile_len(&self.passes, tile.pixel_count()))
+    }
+
+    /// Add the data of finished tile number `i`, see [`Renderer::accumulate_tile`]. Every
+    /// tile is finished once.
+    pub fn publish(&self, i: usize, data: &[u8]) -> Result<()> {
+        self.finish(i, data, None)
+    }
+
+    fn finish(&self, i: usize, data: &[u8], visible: Option<Vec<u32>>) -> Result<()> {
+        let tile = match self.tile(i) {
+            Some(tile) if data.len() == tile_len(&self.passes, tile.pixel_count()) => tile,
+            _ => return Err(anyhow!("Tile {} has wrong size {}", i, data.len())),
+        };
+        let (color, rest) = data.split_at(tile.pixel_count() * COLOR_CHANNELS * 4);
+        let (samples, mut floats) = rest.split_at(tile.pixel_count() * 4);
+        let color: Vec<f32> = words(color).map(f32::from_le_bytes).collect();
+        let samples: Vec<u32> = words(samples).map(u32::from_le_bytes).collect();
+        let pixels = resolve(&color, &samples, &self.display);
+        let passes = self
+            .passes
+            .iter()
+            .map(|&pass| {
+                let (data, rest) = floats.split_at(tile.pixel_count() * pass.channels() * 4);
+                floats = rest;
+                PassImage::new(pass, words(data).map(f32::from_le_bytes))
+            })
+            .collect();
+        self.finished[i]
+            .set(TileBuffers {
+                color,
+                samples,
+                passes,
+                visible,
+            })
+            .map_err(|_| anyhow!("Tile {} is already finished", i))?;
+
+        let tiles_done = self.tiles_done.fetch_add(1, Ordering::Relaxed) + 1;
+        self.progress.tile_completed(&TileCompleted {
+            tile,
+            pixels: &pixels,
+            tiles_done,
+            tiles_total: self.tiles_total(),
+        });
+        Ok(())
+    }
+
+    /// Finish tile number `i` with data from an earlier render instead of rendering it, given
+    /// the objects that were visible in it. Tiles are reused before rendering starts.
+    pub fn reuse(&self, i: usize, data: &[u8], visible: Vec<u32>) -> Result<()> {
+        if i < self.next_tile.load(Ordering::Relaxed) {
+            return Err(anyhow!("Tile {} is not waiting to be rendered", i));
+        }
+        self.finish(i, data, Some(visible))
+    }
+
+    /// Objects which were seen in each tile, see [`Renderer::accumulate_tile`]
+    pub fn visible_objects(&self) -> Vec<Option<Vec<u32>>> {
+        self.finished
+            .iter()
+            .map(|finished| finished.get().and_then(|buffers| buffers.visible.clone()))
+            .collect()
+    }
+
+    /// Render tiles until all of them are finished or the render is cancelled. Tiles are
+    /// rendered one row at a time, and threads which run out of tiles to take help with the
+    /// rows of tiles which others have started, so that slow tiles at the end don't keep the
+    /// other threads idle. Tiles can be returned to the queue by failing remote workers, so
+    /// this waits for other threads instead of returning early. A row whose rendering panics
+    /// is tried again, and its tile is finished in magenta if it fails every time, see
+    /// [`Frame::failed_tiles`]. Tiles which can't be finished cancel the render for every
+    /// thread and return the error.
+    pub fn work(&self, renderer: &Renderer) -> Result<()> {
+        let mut visible = HashSet::new();
+        while !self.stopped() {
+            match self.next_tile().or_else(|| self.started_tile()) {
+                Some(i) => {
+                    if let Err(e) = self.render_rows(i, renderer, &mut visible) {
+                        self.cancel.cancel();
+                        return Err(e);
+                    }
+                }
+                None => std::thread::sleep(std::time::Duration::from_millis(10)),
+            }
+        }
+        Ok(())
+    }
+
+    /// Number of a tile which has been started and has rows that nobody is rendering yet
+    fn started_tile(&self) -> Option<usize> {
+        self.rows.iter().position(|rows| {
+            rows.get()
+                .is_some_and(|rows| rows.next.load(Ordering::Relaxed) < rows.rows.len())
+        })
+    }
+
+    /// Render rows of tile number `i` until there are none left to take, finishing the tile
+    /// if the last row is rendered here
+    fn render_rows(&self, i: usize, renderer: &Renderer, visible: &mut HashSet<u32>) -> Result<()> {
+        let tile = self.tiles[i];
+        let rows = self.rows[i].get_or_init(|| TileRows {
+            next: AtomicUsize::new(0),
+            done: AtomicUsize::new(0),
+            rows: (0..tile.height).map(|_| Mutex::new(None)).collect(),
+        });
+        loop {
+            let row = rows.next.fetch_add(1, Ordering::Relaxed);
+            if row >= tile.height {
+                return Ok(());
+            }
+            let row_tile = Tile {
+                y: tile.y + row,
+                height: 1,
+                ..tile
+            };
+            let mut data = Vec::with_capacity(tile_len(&self.passes, tile.width));
+            let rendered = (0..TILE_ATTEMPTS).any(|attempt| {
+                visible.clear();
+                panic::catch_unwind(AssertUnwindSafe(|| {
+                    renderer.accumulate_tile(&row_tile, attempt, &mut data, &mut |object| {
+                        visible.insert(object);
+                    })
+                }))
+                .is_ok()
+            });
+            if rendered {
+                *rows.rows[row].lock() = Some((data, visible.iter().copied().collect()));
+            }
+            if rows.done.fetch_add(1, Ordering::AcqRel) + 1 == tile.height {
+                return self.finish_rows(i, rows);
+            }
+        }
+    }
+
+    /// Finish tile number `i` with the data of all of its rows
+    fn finish_rows(&self, i: usize, rows: &TileRows) -> Result<()> {
+        let tile = &self.tiles[i];
+        let rows: Vec<_> = rows.rows.iter().map(|row| row.lock().take()).collect();
+        let (data, visible) = match rows.into_iter().collect::<Option<Vec<_>>>() {
+            Some(rows) => {
+                // Each row has its sums, numbers of samples and passes one after another
+                let sections = [COLOR_CHANNELS, 1]
+                    .iter()
+                    .copied()
+                    .chain(self.passes.iter().map(Pass::channels));
+                let mut data = Vec::with_capacity(tile_len(&self.passes, tile.pixel_count()));
+                let mut offset = 0;
+                for channels in sections {
+                    let len = tile.width * channels * 4;
+                    for (row, _) in &rows {
+                        data.extend_from_slice(&row[offset..][..len]);
+                    }
+                    offset += len;
+                }
+                let mut visible: Vec<u32> = rows.into_iter().flat_map(|(_, v)| v).collect();
+                visible.sort_unstable();
+                visible.dedup();
+                (data, Some(visible))
+            }
+            None => {
+                self.failed.lock().push(i);
+                let mut data = Vec::new();
+                self.failed_tile_data(tile, &mut data);
+                (data, None)
+            }
+        };
+        self.finish(i, &data, visible)
+    }
+
+    /// Replace the contents of `out` with magenta data of `tile` in the format of
+    /// [`Renderer::accumulate_tile`], with empty passes
+    fn failed_tile_data(&self, tile: &Tile, out: &mut Vec<u8>) {
+        let pixels = tile.pixel_count();
+        out.clear();
+        for _ in 0..pixels {
+            out.extend([1f32, 0., 1.].iter().flat_map(|c| c.to_le_bytes()));
+        }
+        for _ in 0..pixels {
+            out.extend(1u32.to_le_bytes());
+        }
+        out.resize(tile_len(&self.passes, pixels), 0);
+    }
+
+    /// Numbers of the tiles which couldn't be rendered because rendering them panicked
+    pub fn failed_tiles(&self) -> Vec<usize> {
+        self.failed.lock().clone()
+    }
+
+    /// Merge the finished tiles into a whole image with `channels` values per pixel, given
+    /// the value at an index into the buffers of a tile. Pixels of unfinished tiles are zero.
+    fn merge<T: Copy + Default>(
+        &self,
+        channels: usize,
+        value: impl Fn(&TileBuffers, usize) -> T,
+    ) -> Vec<T> {
+        let mut image = vec![T::default(); self.width * self.height * channels];
+        for (tile, finished) in self.tiles.iter().zip(&self.finished) {
+            if let Some(buffers) = finished.get() {
+                let row_len = tile.width * channels;
+                for row in 0..tile.height {
+                    let offset = ((tile.y + row) * self.width + tile.x) * channels;
+                    for (i, pixel) in image[offset..][..row_len].iter_mut().enumerate() {
+                        *pixel = value(buffers, row * row_len + i);
+                    }
+                }
+            }
+        }
+        image
+    }
+
+    /// Resolve the image to 8bpp RGB for viewing, which is black where tiles haven't been
+    /// finished yet
+    pub fn image(&self) -> Vec<u8> {
+        let (color, samples) = self.accumulation();
+        resolve(&color, &samples, &self.display)
+    }
+
+    /// Resolve the image to linear RGB floats in [`Renderer::working_space`]
+    pub fn linear_image(&self) -> Vec<f32> {
+        let (color, samples) = self.accumulation();
+        average(&color, &samples)
+    }
+
+    /// Sums of the samples of each pixel as linear RGB and the number of samples of each pixel
+    pub fn accumulation(&self) -> (Vec<f32>, Vec<u32>) {
+        (
+            self.merge(COLOR_CHANNELS, |buffers, i| buffers.color[i]),
+            self.merge(1, |buffers, i| buffers.samples[i]),
+        )
+    }
+
+    pub fn into_image(self) -> Vec<u8> {
+        self.image()
+    }
+
+    /// The image of each of [`Renderer::passes`], with [`Pass::channels`] values per pixel.
+    /// Passes other than [`Pass::Beauty`] are kept in half precision while rendering.
+    pub fn pass_images(&self) -> Vec<(Pass, Vec<f32>)> {
+        self.passes
+            .iter()
+            .enumerate()
+            .map(|(p, &pass)| {
+                (
+                    pass,
+                    self.merge(pass.channels(), |buffers, i| buffers.passes[p].get(i)),
+                )
+            })
+            .collect()
+    }
+}
+
+/// Render a whole image using `nthreads` threads, reporting finished tiles to `progress`.
+/// If `cancel` is triggered, the partially rendered image is returned.
+#[cfg(feature = "threads")]
+pub fn render(
+    renderer: &Renderer,
+    nthreads: usize,
+    progress: &dyn RenderProgress,
+    cancel: CancellationToken,
+) -> Result<Vec<u8>> {
+    let frame = Frame::new(renderer, progress, cancel);
+    crossbeam_utils::thread::scope(|s| {
+        let threads: Vec<_> = (0..nthreads)
+            .map(|_| s.spawn(|_| frame.work(renderer)))
+            .collect();
+        threads.into_iter().try_for_each(|thread| {
+            thread
+                .join()
+                .unwrap_or_else(|_| Err(anyhow!("Rendering thread panicked")))
+        })
+    })
+    .map_err(|_| anyhow!("A rendering thread encountered an irrecoverable error"))??;
+    Ok(frame.into_image())
+}
@@ -0,0 +1,469 @@
+//! Mapping points of the unit square to directions and points, with the probability densities
+//! of the results. Directions are in a local frame with the z axis as the normal.
+
+use std::f32::consts::{FRAC_1_PI, FRAC_PI_2, FRAC_PI_4, PI, TAU};
+use ultraviolet::{Vec2, Vec3};
+
+/// Concentric mapping of the square onto the unit disc, which keeps stratified samples
+/// stratified
+pub fn concentric_disc(u: Vec2) -> Vec2 {
+    let v = u * 2. - Vec2::one();
+    if v.x == 0. && v.y == 0. {
+        return v;
+    }
+    let (r, theta) = if v.x.abs() > v.y.abs() {
+        (v.x, FRAC_PI_4 * (v.y / v.x))
+    } else {
+        (v.y, FRAC_PI_2 - FRAC_PI_4 * (v.x / v.y))
+    };
+    r * Vec2::new(theta.cos(), theta.sin())
+}
+
+/// Uniformly on the unit sphere
+pub fn uniform_sphere(u: Vec2) -> Vec3 {
+    let phi = u.x * TAU;
+    let z = u.y * 2. - 1.; // Equal to cos theta
+    let sin_theta = (1. - z.powi(2)).sqrt();
+    Vec3::new(sin_theta * phi.cos(), sin_theta * phi.sin(), z)
+}
+
+pub fn uniform_sphere_pdf() -> f32 {
+    1. / (4. * PI)
+}
+
+/// On the hemisphere around z, in proportion to the cosine of the angle to it
+pub fn cosine_hemisphere(u: Vec2) -> Vec3 {
+    // Malley's method projects the disc up
+    let d = concentric_disc(u);
+    let z = (1. - d.mag_sq()).max(0.).sqrt();
+    Vec3::new(d.x, d.y, z)
+}
+
+pub fn cosine_hemisphere_pdf(cos_theta: f32) -> f32 {
+    cos_theta.max(0.) * FRAC_1_PI
+}
+
+/// Uniformly in the cone of directions within `angle_radians` of z
+pub fn uniform_cone(u: Vec2, angle_radians: f32) -> Vec3 {
+    // 1 - cos is precise for small angles
+    let one_minus_cos = u.x * 2. * (angle_radians / 2.).sin().powi(2);
+    let cos_theta = 1. - one_minus_cos;
+    let sin_theta = (one_minus_cos * (2. - one_minus_cos)).sqrt();
+    let phi = TAU * u.y;
+    Vec3::new(sin_theta * phi.cos(), sin_theta * phi.sin(), cos_theta)
+}
+
+/// Infinite when the cone has no width
+pub fn uniform_cone_pdf(angle_radians: f32) -> f32 {
+    1. / (TAU * 2. * (angle_radians / 2.).sin().powi(2))
+}
+
+/// Barycentric coordinates of a point uniformly on a triangle, whose density is one over its
+/// area
+pub fn uniform_triangle(u: Vec2) -> [f32; 3] {
+    let s = u.x.sqrt();
+    let (b0, b1) = (1. - s, u.y * s);
+    [b0, b1, 1. - b0 - b1]
+}
+
+/// Two independent values of the standard normal distribution, by the Box-Muller transform
+pub fn standard_normal(u: Vec2) -> Vec2 {
+    // One minus u is never zero
+    let r = (-2. * (1. - u.x).ln()).sqrt();
+    let phi = u.y * TAU;
+    r * Vec2::new(phi.cos(), phi.sin())
+}
+
+/// Direction scattered by the Henyey-Greenstein phase function from a ray travelling along z,
+/// forwards for positive `g` and backwards for negative
+pub fn henyey_greenstein(u: Vec2, g: f32) -> Vec3 {
+    let cos_theta = if g.abs() < 1e-3 {
+        1. - 2. * u.x
+    } else {
+        let t = (1. - g * g) / (1. - g + 2. * g * u.x);
+        ((1. + g * g - t * t) / (2. * g)).clamp(-1., 1.)
+    };
+    let sin_theta = (1. - cos_theta.powi(2)).max(0.).sqrt();
+    let phi = TAU * u.y;
+    Vec3::new(sin_theta * phi.cos(), sin_theta * phi.sin(), cos_theta)
+}
+
+/// Of [`henyey_greenstein`] scattering by an angle whose cosine is `cos_theta`, which is also
+/// the value of the phase function
+pub fn henyey_greenstein_pdf(cos_theta: f32, g: f32) -> f32 {
+    let denominator = 1. + g * g - 2. * g * cos_theta;
+    (1. - g * g) / (4. * PI * denominator * denominator.sqrt())
+}
+
+/// Distribution of GGX microfacet normals, with roughness `alpha_x` and `alpha_y` along x and y
+pub fn ggx_d(h: Vec3, alpha_x: f32, alpha_y: f32) -> f32 {
+    if h.z <= 0. {
+        return 0.;
+    }
+    let t = (h.x / alpha_x).powi(2) + (h.y / alpha_y).powi(2) + h.z.powi(2);
+    1. / (PI * alpha_x * alpha_y * t * t)
+}
+
+/// Smith masking of the microfacets seen from `w`, in the upper hemisphere
+pub fn ggx_g1(w: Vec3, alpha_x: f32, alpha_y: f32) -> f32 {
+    if w.z <= 0. {
+        return 0.;
+    }
+    let tan_sq = ((w.x * alpha_x).powi(2) + (w.y * alpha_y).powi(2)) / w.z.powi(2);
+    2. / (1. + (1. + tan_sq).sqrt())
+}
+
+/// Microfacet normal of the GGX distribution visible from `wo` in the upper hemisphere, from
+/// Heitz, "Sampling the GGX Distribution of Visible Normals"
+pub fn ggx_vndf(wo: Vec3, alpha_x: f32, alpha_y: f32, u: Vec2) -> Vec3 {
+    // To the hemisphere configuration
+    let v = Vec3::new(alpha_x * wo.x, alpha_y * wo.y, wo.z).normalized();
+    let len_sq = v.x.powi(2) + v.y.powi(2);
+    let t1 = if len_sq > 0. {
+        Vec3::new(-v.y, v.x, 0.) / len_sq.sqrt()
+    } else {
+        Vec3::unit_x()
+    };
+    let t2 = v.cross(t1);
+
+    // Uniformly on the projected area, which is half a disc and half an ellipse
+    let r = u.x.sqrt();
+    let phi = TAU * u.y;
+    let p1 = r * phi.cos();
+    let s = 0.5 * (1. + v.z);
+    let p2 = (1. - s) * (1. - p1.powi(2)).sqrt() + s * r * phi.sin();
+    let n = t1 * p1 + t2 * p2 + v * (1. - p1.powi(2) - p2.powi(2)).max(0.).sqrt();
+
+    // Back to the ellipsoid configuration
+    Vec3::new(alpha_x * n.x, alpha_y * n.y, n.z.max(0.)).normalized()
+}
+
+/// Of [`ggx_vndf`] choosing `h`
+pub fn ggx_vndf_pdf(wo: Vec3, h: Vec3, alpha_x: f32, alpha_y: f32) -> f32 {
+    if wo.z <= 0. {
+        return 0.;
+    }
+    ggx_g1(wo, alpha_x, alpha_y) * wo.dot(h).max(0.) * ggx_d(h, alpha_x, alpha_y) / wo.z
+}
+
+/// Orthonormal basis, for moving directions between world space and a local frame where the
+/// normal is z
+#[derive(Clone, Copy, Debug)]
+pub struct Onb {
+    pub tangent: Vec3,
+    pub bitangent: Vec3,
+    pub normal: Vec3,
+}
+
+impl Onb {
+    /// Around unit vector `normal` with some tangent, from Duff et al., "Building an Orthonormal
+    /// Basis, Revisited"
+    pub fn from_normal(normal: Vec3) -> Self {
+        let n = normal;
+        let sign = 1f32.copysign(n.z);
+        let a = -1. / (sign + n.z);
+        let b = n.x * n.y * a;
+        Self {
+            tangent: Vec3::new(1. + sign * n.x * n.x * a, sign * b, -sign * n.x),
+            bitangent: Vec3::new(b, sign + n.y * n.y * a, -n.y),
+            normal,
+        }
+    }
+
+    /// Around unit vector `normal` with the tangent towards `tangent`, or some tangent if it is
+    /// parallel to the normal
+    pub fn from_normal_tangent(normal: Vec3, tangent: Vec3) -> Self {
+        let tangent = tangent - normal * normal.dot(tangent);
+        if tangent.mag_sq() < 1e-12 {
+            return Self::from_normal(normal);
+        }
+        let tangent = tangent.normalized();
+        Self {
+            tangent,
+            bitangent: normal.cross(tangent),
+            normal,
+        }
+    }
+
+    /// Turned counter-clockwise around the normal by `angle_radians`
+    pub fn rotated(&self, angle_radians: f32) -> Self {
+        let (sin, cos) = angle_radians.sin_cos();
+        Self {
+            tangent: self.tangent * cos + self.bitangent * sin,
+            bitangent: self.bitangent * cos - self.tangent * sin,
+            normal: self.normal,
+        }
+    }
+
+    pub fn to_world(&self, v: Vec3) -> Vec3 {
+        self.tangent * v.x + self.bitangent * v.y + self.normal * v.z
+    }
+
+    pub fn to_local(&self, v: Vec3) -> Vec3 {
+        Vec3::new(
+            v.dot(self.tangent),
+            v.dot(self.bitangent),
+            v.dot(self.normal),
+        )
+    }
+}
+
+/// Piecewise constant distribution on `[0, 1)`
+pub struct Distribution1D {
+    weights: Vec<f32>,
+    /// Normalized, with one more element than `weights`
+    cdf: Vec<f32>,
+    /// Of the piecewise constant function
+    integral: f32,
+}
+
+impl Distribution1D {
+    pub fn new(weights: Vec<f32>) -> Self {
+        let n = weights.len() as f32;
+        let mut cdf = Vec::with_capacity(weights.len() + 1);
+        cdf.push(0.);
+        for (i, weight) in weights.iter().enumerate() {
+            cdf.push(cdf[i] + weight.max(0.) / n);
+        }
+        let integral = cdf[weights.len()];
+        for (i, value) in cdf.iter_mut().enumerate() {
+            *value = if integral > 0. {
+                *value / integral
+            } else {
+                // Uniform when there is nothing to sample by
+                i as f32 / n
+            };
+        }
+        Self {
+            weights,
+            cdf,
+            integral,
+        }
+    }
+
+    /// Point chosen with `u` in `[0, 1)`, its probability density and the piece it is in
+    pub fn sample(&self, u: f32) -> (f32, f32, usize) {
+        let n = self.weights.len();
+        let i = (self.cdf.partition_point(|&c| c <= u) - 1).min(n - 1);
+        let width = self.cdf[i + 1] - self.cdf[i];
+        let offset = if width > 0. {
+            (u - self.cdf[i]) / width
+        } else {
+            0.
+        };
+        let pdf = if self.integral > 0. {
+            self.weights[i].max(0.) / self.integral
+        } else {
+            1.
+        };
+        (
+            ((i as f32 + offset) / n as f32).min(1. - f32::EPSILON),
+            pdf,
+            i,
+        )
+    }
+}
+
+/// Piecewise constant distribution on the unit square, sampled by choosing a row from the
+/// marginal distribution and a point on it from the conditional distribution of the row
+pub struct Distribution2D {
+    conditional: Vec<Distribution1D>,
+    marginal: Distribution1D,
+}
+
+impl Distribution2D {
+    /// `weights` has `height` rows of `width` elements
+    pub fn new(weights: Vec<f32>, width: usize, height: usize) -> Self {
+        let conditional: Vec<_> = weights
+            .chunks(width)
+            .take(height)
+            .map(|row| Distribution1D::new(row.to_vec()))
+            .collect();
+        let marginal = Distribution1D::new(conditional.iter().map(|row| row.integral).collect());
+        Self {
+            conditional,
+            marginal,
+        }
+    }
+
+    /// Point chosen with `u` in the unit square and its probability density
+    pub fn sample(&self, u: Vec2) -> (Vec2, f32) {
+        let (y, pdf_y, row) = self.marginal.sample(u.y);
+        let (x, pdf_x, _) = self.conditional[row].sample(u.x);
+        (Vec2::new(x, y), pdf_x * pdf_y)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Midpoints of an n by n grid over the unit square
+    fn grid(n: usize) -> impl Iterator<Item = Vec2> {
+        (0..n * n).map(move |i| {
+            Vec2::new(
+                ((i % n) as f32 + 0.5) / n as f32,
+                ((i / n) as f32 + 0.5) / n as f32,
+            )
+        })
+    }
+
+    fn mean(values: impl Iterator<Item = f32>) -> f32 {
+        let (sum, count) = values.fold((0f64, 0), |(sum, count), v| (sum + v as f64, count + 1));
+        (sum / count as f64) as f32
+    }
+
+    fn assert_close(a: f32, b: f32, tolerance: f32) {
+        assert!((a - b).abs() <= tolerance, "{} is not close to {}", a, b);
+    }
+
+    #[test]
+    fn concentric_disc_covers_disc_uniformly() {
+        assert!(grid(64).all(|u| concentric_disc(u).mag() <= 1. + 1e-6));
+        // Mean distance from the center of the unit disc is 2/3
+        assert_close(
+            mean(grid(256).map(|u| concentric_disc(u).mag())),
+            2. / 3.,
+            1e-3,
+        );
+    }
+
+    #[test]
+    fn uniform_sphere_integrates_solid_angle() {
+        assert!(grid(64).all(|u| (uniform_sphere(u).mag() - 1.).abs() < 1e-5));
+        assert_close(mean(grid(256).map(|u| uniform_sphere(u).z)), 0., 1e-3);
+        assert_close(1. / uniform_sphere_pdf(), 4. * PI, 1e-5);
+    }
+
+    #[test]
+    fn cosine_hemisphere_matches_pdf() {
+        let directions: Vec<_> = grid(256).map(cosine_hemisphere).collect();
+        assert!(directions
+            .iter()
+            .all(|d| d.z >= 0. && (d.mag() - 1.).abs() < 1e-5));
+        // Integral of cos² over the hemisphere is 2π / 3
+        let integral = mean(
+            directions
+                .iter()
+                .map(|d| d.z.powi(2) / cosine_hemisphere_pdf(d.z)),
+        );
+        assert_close(integral, TAU / 3., 1e-3);
+        // Mean cosine is 2/3 under cosine weighting
+        assert_close(mean(directions.iter().map(|d| d.z)), 2. / 3., 1e-3);
+    }
+
+    #[test]
+    fn uniform_cone_stays_in_cone() {
+        let angle = 0.3f32;
+        let directions: Vec<_> = grid(128).map(|u| uniform_cone(u, angle)).collect();
+        assert!(directions
+            .iter()
+            .all(|d| d.z >= angle.cos() - 1e-6 && (d.mag() - 1.).abs() < 1e-5));
+        // Uniform in cos theta between cos angle and 1
+        assert_close(
+            mean(directions.iter().map(|d| d.z)),
+            (1. + angle.cos()) / 2.,
+            1e-5,
+        );
+        assert_close(1. / uniform_cone_pdf(angle), TAU * (1. - angle.cos()), 1e-5);
+        assert_eq!(uniform_cone(Vec2::new(0.7, 0.2), 0.), Vec3::unit_z());
+    }
+
+    #[test]
+    fn uniform_triangle_has_centroid_mean() {
+        let points: Vec<_> = grid(128).map(uniform_triangle).collect();
+        assert!(points
+            .iter()
+            .all(|b| b.iter().all(|&b| b >= -1e-6) && (b.iter().sum::<f32>() - 1.).abs() < 1e-5));
+        for i in 0..3 {
+            assert_close(mean(points.iter().map(|b| b[i])), 1. / 3., 1e-3);
+        }
+    }
+
+    #[test]
+    fn henyey_greenstein_matches_pdf() {
+        for &g in &[-0.5, 0., 0.3, 0.8] {
+            let directions: Vec<_> = grid(256).map(|u| henyey_greenstein(u, g)).collect();
+            assert!(directions.iter().all(|d| (d.mag() - 1.).abs() < 1e-4));
+            // Mean cosine of the phase function is g
+            assert_close(mean(directions.iter().map(|d| d.z)), g, 2e-3);
+            // Normalized over the sphere
+            let integral = mean(
+                grid(512)
+                    .map(|u| henyey_greenstein_pdf(uniform_sphere(u).z, g) / uniform_sphere_pdf()),
+            );
+            assert_close(integral, 1., 0.01);
+        }
+    }
+
+    #[test]
+    fn ggx_d_is_normalized() {
+        // Projected microfacet area equals the macrosurface area, estimated with cosine
+        // weighted directions
+        for &(alpha_x, alpha_y) in &[(0.5, 0.5), (0.3, 0.8), (1., 1.)] {
+            let integral = mean(grid(512).map(|u| {
+                let h = cosine_hemisphere(u);
+                ggx_d(h, alpha_x, alpha_y) * h.z / cosine_hemisphere_pdf(h.z).max(1e-6)
+            }));
+            assert_close(integral, 1., 0.02);
+        }
+    }
+
+    #[test]
+    fn ggx_vndf_matches_pdf() {
+        // Uniformly on the upper hemisphere, with a density of 1 / 2π
+        let hemisphere = |u| {
+            let d = uniform_sphere(u);
+            Vec3::new(d.x, d.y, d.z.abs())
+        };
+        for &(alpha_x, alpha_y) in &[(0.5, 0.5), (0.2, 0.6), (0.9, 0.4)] {
+            for &wo in &[
+                Vec3::unit_z(),
+                Vec3::new(0.6, 0., 0.8),
+                Vec3::new(-0.3, 0.7, 0.5).normalized(),
+            ] {
+                let pdf = |h| ggx_vndf_pdf(wo, h, alpha_x, alpha_y);
+                let integral = mean(grid(512).map(|u| pdf(hemisphere(u)) * TAU));
+                assert_close(integral, 1., 0.02);
+
+                // Projected area of the microfacets facing wo, estimated with both
+                let area = |h: Vec3| {
+                    if wo.dot(h) > 0. {
+                        ggx_d(h, alpha_x, alpha_y) * h.z
+                    } else {
+                        0.
+                    }
+                };
+                let uniform = mean(grid(512).map(|u| area(hemisphere(u)) * TAU));
+                let importance = mean(grid(256).map(|u| {
+                    let h = ggx_vndf(wo, alpha_x, alpha_y, u);
+                    assert!(h.z >= 0. && (h.mag() - 1.).abs() < 1e-4);
+                    match pdf(h) {
+                        pdf if pdf > 0. => area(h) / pdf,
+                        _ => 0.,
+                    }
+                }));
+                assert_close(importance, uniform, 0.02);
+            }
+        }
+    }
+
+    #[test]
+    fn onb_is_orthonormal() {
+        let tangent = Vec3::new(0.3, -0.2, 0.9);
+        for u in grid(16) {
+            let n = uniform_sphere(u);
+            for onb in [Onb::from_normal(n), Onb::from_normal_tangent(n, tangent)] {
+                assert_close(onb.tangent.mag(), 1., 1e-5);
+                assert_close(onb.bitangent.mag(), 1., 1e-5);
+                assert_close(onb.tangent.dot(onb.bitangent), 0., 1e-5);
+                assert_close(onb.tangent.dot(n), 0., 1e-5);
+                assert_close(onb.bitangent.dot(n), 0., 1e-5);
+                // Right-handed, and back and forth is the identity
+                assert_close(onb.tangent.cross(onb.bitangent).dot(n), 1., 1e-5);
+                let v = Vec3::new(0.1, 0.5, -0.7);
+                assert_close((onb.to_world(onb.to_local(v)) - v).mag(), 0., 1e-5);
+            }
+        }
+    }
+}
@@ -0,0 +1,1689 @@
+use crate::{
+    camera::Camera,
+    camera_path::CameraPath,
+    color::{blackbody, ColorSpace},
+    filter::PixelFilter,
+    flare::{LensFlare, Streaks},
+    fog::DepthFog,
+    grain::Grain,
+    guiding::GuidingOptions,
+    ies::IesProfile,
+    image::{Image, Rows},
+    ray::{BounceLimits, Regularization},
+    render::{Integrator, Pass},
+    sampler::{RngKind, SamplerKind},
+    texture::{Texture, TextureCache, TextureCacheOptions},
+    world::{
+        bvh::BvhOptions,
+        environment::{self, Environment, EnvironmentMap},
+        light::{Light, PointLight, Sun},
+        material::{
+            AnisotropicMetal, Clearcoat, Cutout, Dielectric, Emissive, Lambertian, Material, Metal,
+        },
+        physics::PhysicsFrame,
+        surface::{Sphere, Surface, Surfaces, Triangle},
+        volume::Volume,
+        MaterialHandle, Object, SurfaceHandle, Visibility, World,
+    },
+};
+use anyhow::{anyhow, Context, Result};
+use rand::prelude::*;
+use serde::{de::Error as _, Deserialize, Deserializer, Serialize};
+use std::{
+    collections::HashMap,
+    convert::TryFrom,
+    fs,
+    ops::Range,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+use ultraviolet::{Lerp, Vec2, Vec3};
+
+/// Serializable description of everything needed to render an image
+#[derive(Clone, PartialEq, Serialize, Deserialize)]
+pub struct Scene {
+    pub camera: CameraSpec,
+    /// Other views of the scene, each rendered to an image of its own with the same BVH
+    #[serde(default)]
+    pub cameras: Vec<NamedCamera>,
+    pub surfaces: Vec<SurfaceSpec>,
+    pub materials: Vec<MaterialSpec>,
+    /// RON files mapping names to materials, relative to the working directory, where
+    /// [`MaterialSpec::Named`] materials are looked up before the built-in presets. Later
+    /// files take precedence.
+    #[serde(default)]
+    pub material_libraries: Vec<PathBuf>,
+    pub objects: Vec<ObjectSpec>,
+    #[serde(default)]
+    pub bvh: BvhOptions,
+    #[serde(default)]
+    pub sampler: SamplerKind,
+    /// Paths traced on from the first hit of every sample, which are averaged, so that noise
+    /// from lighting can be reduced without more samples over the pixels and the lens
+    #[serde(default = "Scene::default_light_samples")]
+    pub light_samples: u32,
+    /// Reconstruction filter of pixels
+    #[serde(default)]
+    pub filter: PixelFilter,
+    /// Splat every sample into all of the pixels under the filter, weighted by it, instead of
+    /// placing the samples of a pixel by the filter. Splatted images are rendered whole,
+    /// without passes.
+    #[serde(default)]
+    pub splat: bool,
+    #[serde(default)]
+    pub rng: RngKind,
+    #[serde(default)]
+    pub integrator: Integrator,
+    /// Images to render in addition to the whole image
+    #[serde(default)]
+    pub passes: Vec<Pass>,
+    /// Fog blended into the image by the distance to what the camera sees
+    #[serde(default)]
+    pub fog: Option<DepthFog>,
+    /// Stop sampling a pixel when the 95% confidence interval of its mean color is narrower
+    /// than this on every channel, instead of always taking all samples
+    #[serde(default)]
+    pub noise_threshold: Option<f32>,
+    #[serde(default)]
+    pub environment: EnvironmentSpec,
+    #[serde(default)]
+    pub lights: Vec<LightSpec>,
+    /// Space of the colors in the scene and the rendered image. Images read from files are
+    /// converted to it from sRGB, and PNG files are written in sRGB.
+    #[serde(default)]
+    pub working_space: ColorSpace,
+    /// Lookup table in the .cube format from the working space to 8-bit output, such as a
+    /// display and view transform baked from an OpenColorIO config with `ociobakelut`, as
+    /// configs are not read directly. By default colors are converted to sRGB with a gamma of 2.
+    #[serde(default)]
+    pub display_lut: Option<PathBuf>,
+    /// Learn where light comes from before rendering and sample bounces from diffuse surfaces
+    /// towards it, which helps with scenes that are lit mostly indirectly. In distributed
+    /// renders every worker connection learns a guide of its own, which isn't shared.
+    #[serde(default)]
+    pub guiding: Option<GuidingOptions>,
+    /// Keep the textures of materials on disk and only as much of them in memory as fits in a
+    /// budget, instead of all of them in memory
+    #[serde(default)]
+    pub texture_cache: Option<TextureCacheOptions>,
+    /// Quantize the bounds of BVH nodes and the vertices of triangles, which takes about half
+    /// the memory and is a little slower and less accurate
+    #[serde(default)]
+    pub compact_memory: bool,
+    /// Roughen mirror-like bounces late on paths, which trades a little blur in caustics for
+    /// fewer fireflies. Only in biased renders.
+    #[serde(default)]
+    pub regularization: Option<Regularization>,
+    /// Largest value of any channel of the light which a path brings to a pixel after its
+    /// first bounce, in the units of the image, which keeps out fireflies at the cost of dimming
+    /// bright indirect light. Only in biased renders.
+    #[serde(default)]
+    pub max_radiance: Option<f32>,
+    /// Most diffuse, glossy and transmission bounces of paths. Only in biased renders.
+    #[serde(default)]
+    pub bounce_limits: BounceLimits,
+    /// Allow speedups which make the image converge to something slightly different from the
+    /// true image: regularization, clamping to the maximum radiance, bounce limits, and cutting
+    /// paths off at [`crate::render::MAX_DEPTH`] bounces instead of ending them by Russian
+    /// roulette. Without this the renderer is unbiased.
+    #[serde(default)]
+    pub biased: bool,
+}
+
+/// Parameters of [`Scene::random_with`]
+#[derive(Clone, Debug)]
+pub struct RandomOptions {
+    /// Number of small spheres along each side of the grid
+    pub grid_size: u32,
+    /// Relative chances of a small sphere being diffuse, metal or glass
+    pub diffuse_weight: f32,
+    pub metal_weight: f32,
+    pub glass_weight: f32,
+    /// Smallest and largest radius of the small spheres
+    pub radius: (f32, f32),
+    /// Whether the diffuse spheres move up during the shutter time
+    pub motion: bool,
+}
+
+impl Default for RandomOptions {
+    fn default() -> Self {
+        Self {
+            grid_size: 23,
+            diffuse_weight: 80.,
+            metal_weight: 15.,
+            glass_weight: 6.,
+            radius: (0.2, 0.2),
+            motion: true,
+        }
+    }
+}
+
+#[derive(Clone, PartialEq, Serialize, Deserialize)]
+pub struct CameraSpec {
+    pub look_from: [f32; 3],
+    pub look_at: [f32; 3],
+    #[serde(default = "CameraSpec::default_up")]
+    pub up: [f32; 3],
+    pub vertical_fov_degrees: f32,
+    #[serde(default)]
+    pub aperture: f32,
+    pub focus_distance: f32,
+    #[serde(default = "CameraSpec::default_shutter_time")]
+    pub shutter_time: (f32, f32),
+    /// Fraction of the shutter time that each row is exposed for, with rows exposed from the
+    /// top down like by a CMOS sensor, instead of all at once
+    #[serde(default)]
+    pub rolling_shutter: Option<f32>,
+    /// Radiance is shown as it is without an exposure
+    #[serde(default)]
+    pub exposure: Option<Exposure>,
+    /// Animated camera which places this one and gives its field of view instead
+    #[serde(default)]
+    pub path: Option<CameraPathSpec>,
+    /// Focus distance by frame instead of the one above
+    #[serde(default)]
+    pub focus_distance_keys: Keyframes,
+    /// Aperture by frame instead of the one above
+    #[serde(default)]
+    pub aperture_keys: Keyframes,
+    /// Name of an object which is kept in focus, by focusing at the distance of its position
+    /// in the middle of the shutter time instead of the focus distance
+    #[serde(default)]
+    pub focus_object: Option<String>,
+    /// Grayscale image of the aperture filling the square around the lens, relative to the
+    /// working directory, which gives out of focus highlights its shape instead of a disc.
+    /// Light passes in proportion to the first channel.
+    #[serde(default)]
+    pub aperture_texture: Option<PathBuf>,
+    /// How much the barrel of the lens clips the aperture towards the edges of the image, which
+    /// darkens them and gives out of focus highlights there the shape of a cat's eye. From 0
+    /// for none, in radii of the aperture in the corners.
+    #[serde(default)]
+    pub cat_eye: f32,
+    /// Shift of the lens parallel to the image, in widths and heights of the image, which
+    /// moves the view without converging vertical lines like turning the camera does
+    #[serde(default)]
+    pub shift: [f32; 2],
+    /// Tilt of the plane in focus around the vertical and horizontal axes of the image, which
+    /// brings a receding ground in focus, or with a wide aperture makes a scene look like a
+    /// miniature. Positive angles move the plane away towards the right and the top.
+    #[serde(default)]
+    pub tilt_degrees: [f32; 2],
+    /// Squeeze factor of an anamorphic lens, like 1.33 or 2, which makes out of focus
+    /// highlights that many times as tall as they are wide. From 1 for a spherical lens.
+    #[serde(default = "CameraSpec::default_anamorphic_squeeze")]
+    pub anamorphic_squeeze: f32,
+    /// Horizontal streaks of light through highlights brighter than white, added to the image
+    /// after rendering
+    #[serde(default)]
+    pub flare_streaks: Option<Streaks>,
+    /// How much more blue is magnified than green and green than red, towards the edges of
+    /// the image, which fringes edges there. From 0 for none, like 0.005.
+    #[serde(default)]
+    pub lateral_aberration: f32,
+    /// How much farther blue is focused than green and green than red, relative to the focus
+    /// distance, which fringes out of focus edges. From 0 for none, like 0.01.
+    #[serde(default)]
+    pub longitudinal_aberration: f32,
+    /// Ghosts and diffraction spikes of highlights, added to the image after rendering
+    #[serde(default)]
+    pub lens_flare: Option<LensFlare>,
+    /// Noise of a sensor or grain of film, added to the image after rendering
+    #[serde(default)]
+    pub grain: Option<Grain>,
+}
+
+/// Values at frames, in ascending order, which are interpolated linearly between them and
+/// held before the first and after the last. Keys which aren't in order, or which share a
+/// frame, are rejected when a scene is read.
+#[derive(Clone, Debug, Default, PartialEq, Serialize)]
+pub struct Keyframes(pub Vec<(u32, f32)>);
+
+impl<'de> Deserialize<'de> for Keyframes {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        #[derive(Deserialize)]
+        #[serde(rename = "Keyframes")]
+        struct Keys(Vec<(u32, f32)>);
+
+        let Keys(keys) = Keys::deserialize(deserializer)?;
+        match keys.windows(2).find(|pair| pair[0].0 >= pair[1].0) {
+            Some(pair) => Err(D::Error::custom(format!(
+                "Keyframe at frame {} follows one at frame {}, but frames must increase",
+                pair[1].0, pair[0].0
+            ))),
+            None => Ok(Self(keys)),
+        }
+    }
+}
+
+impl Keyframes {
+    /// Value at `frame`, or `None` without keyframes
+    pub fn at(&self, frame: u32) -> Option<f32> {
+        let next = self.0.partition_point(|&(key, _)| key <= frame);
+        match (self.0.get(next.wrapping_sub(1)), self.0.get(next)) {
+            (Some(&(start, a)), Some(&(end, b))) => {
+                let t = (frame - start) as f32 / (end as f32 - start as f32);
+                Some(a + (b - a) * t)
+            }
+            (Some(&(_, value)), None) | (None, Some(&(_, value))) => Some(value),
+            (None, None) => None,
+        }
+    }
+}
+
+/// Camera node in a glTF file, sampled at the start of each frame
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct CameraPathSpec {
+    /// .gltf or .glb file, relative to the working directory
+    pub file: PathBuf,
+    /// Of the camera node, or the first camera node if there is none
+    #[serde(default)]
+    pub node: Option<String>,
+    #[serde(default = "CameraPathSpec::default_frames_per_second")]
+    pub frames_per_second: f32,
+    /// Frame at the start of the animation, like the start frame in Blender
+    #[serde(default = "CameraPathSpec::default_first_frame")]
+    pub first_frame: u32,
+}
+
+impl CameraPathSpec {
+    fn default_frames_per_second() -> f32 {
+        24.
+    }
+
+    fn default_first_frame() -> u32 {
+        1
+    }
+}
+
+/// Brightness of the image like with the settings of a real camera, for scenes lit with
+/// physical units
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub enum Exposure {
+    /// Exposure value at ISO 100, such as 15 for a sunny day and -2 for moonlight
+    Ev100(f32),
+    Settings {
+        iso: f32,
+        f_number: f32,
+        shutter_seconds: f32,
+    },
+}
+
+impl Exposure {
+    pub fn ev100(&self) -> f32 {
+        match *self {
+            Self::Ev100(ev100) => ev100,
+            Self::Settings {
+                iso,
+                f_number,
+                shutter_seconds,
+            } => (f_number * f_number / shutter_seconds * 100. / iso).log2(),
+        }
+    }
+
+    /// Factor from radiance to the image, where white is the luminance which saturates the
+    /// sensor, 1.2 × 2^EV100 cd/m² by the saturation-based speed of ISO 12232
+    pub fn scale(&self) -> f32 {
+        683. / (1.2 * self.ev100().exp2())
+    }
+}
+
+/// Camera which is chosen by its name, which also names its image
+#[derive(Clone, PartialEq, Serialize, Deserialize)]
+pub struct NamedCamera {
+    pub name: String,
+    pub camera: CameraSpec,
+}
+
+/// Name of [`Scene::camera`] among [`Scene::cameras`]
+pub const MAIN_CAMERA: &str = "main";
+
+impl CameraSpec {
+    /// Time is measured in frames, so the shutter opens `frame` units after the first frame
+    pub fn shutter(&self, frame: u32) -> Range<f32> {
+        let time = frame as f32;
+        time + self.shutter_time.0..time + self.shutter_time.1
+    }
+
+    /// Pinhole camera at `look_from` looking at the origin
+    fn towards_origin(look_from: [f32; 3], vertical_fov_degrees: f32) -> Self {
+        Self {
+            look_from,
+            vertical_fov_degrees,
+            focus_distance: Vec3::from(look_from).mag(),
+            ..Self::default()
+        }
+    }
+
+    fn default_up() -> [f32; 3] {
+        [0., 1., 0.]
+    }
+
+    fn default_shutter_time() -> (f32, f32) {
+        (0., 1.)
+    }
+
+    fn default_anamorphic_squeeze() -> f32 {
+        1.
+    }
+}
+
+/// Pinhole camera at (0, 0, 1) looking at the origin with a 90 degree field of view
+impl Default for CameraSpec {
+    fn default() -> Self {
+        Self {
+            look_from: [0., 0., 1.],
+            look_at: [0.; 3],
+            up: Self::default_up(),
+            vertical_fov_degrees: 90.,
+            aperture: 0.,
+            focus_distance: 1.,
+            shutter_time: Self::default_shutter_time(),
+            rolling_shutter: None,
+            exposure: None,
+            path: None,
+            focus_distance_keys: Keyframes::default(),
+            aperture_keys: Keyframes::default(),
+            focus_object: None,
+            aperture_texture: None,
+            cat_eye: 0.,
+            shift: [0.; 2],
+            tilt_degrees: [0.; 2],
+            anamorphic_squeeze: Self::default_anamorphic_squeeze(),
+            flare_streaks: None,
+            lateral_aberration: 0.,
+            longitudinal_aberration: 0.,
+            lens_flare: None,
+            grain: None,
+        }
+    }
+}
+
+/// Placement of a surface and a material, which can be shared by many objects
+#[derive(Clone, PartialEq, Serialize, Deserialize)]
+pub struct ObjectSpec {
+    /// For referring to the object, such as with [`CameraSpec::focus_object`]
+    #[serde(default)]
+    pub name: Option<String>,
+    /// Index into [`Scene::surfaces`]
+    pub surface: usize,
+    /// Index into [`Scene::materials`]
+    pub material: usize,
+    pub position: [f32; 3],
+    /// Distance traveled during one frame
+    #[serde(default)]
+    pub velocity: [f32; 3],
+    #[serde(default)]
+    pub visibility: Visibility,
+    /// Where dielectric objects overlap, such as a liquid and its glass, the one with the
+    /// higher priority is the medium
+    #[serde(default)]
+    pub priority: u32,
+}
+
+#[derive(Clone, PartialEq, Serialize, Deserialize)]
+pub enum SurfaceSpec {
+    Sphere {
+        radius: f32,
+    },
+    /// Vertices relative to the object's position, counter-clockwise when seen from the front
+    Triangle {
+        vertices: [[f32; 3]; 3],
+        /// Texture coordinates at the vertices
+        #[serde(default = "SurfaceSpec::default_uvs")]
+        uvs: [[f32; 2]; 3],
+    },
+}
+
+impl SurfaceSpec {
+    fn default_uvs() -> [[f32; 2]; 3] {
+        [[0., 0.], [1., 0.], [0., 1.]]
+    }
+}
+
+/// Light arriving from where no object was hit
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub enum EnvironmentSpec {
+    /// Blue to white gradient from the horizon up
+    #[default]
+    Gradient,
+    /// Equirectangular Radiance HDR or OpenEXR image with the top row up, relative to the
+    /// working directory. Diffuse surfaces sample it in proportion to its brightness.
+    Map {
+        path: PathBuf,
+        #[serde(default = "EnvironmentSpec::default_intensity")]
+        intensity: f32,
+        /// Counter-clockwise around the vertical axis when seen from above
+        #[serde(default)]
+        rotation_degrees: f32,
+    },
+    /// Built-in photo studio with softboxes in front of and behind the -z axis, which is
+    /// sampled like a map
+    Studio {
+        #[serde(default = "EnvironmentSpec::default_intensity")]
+        intensity: f32,
+        /// Counter-clockwise around the vertical axis when seen from above
+        #[serde(default)]
+        rotation_degrees: f32,
+    },
+}
+
+impl EnvironmentSpec {
+    fn default_intensity() -> f32 {
+        1.
+    }
+
+    fn build(&self, space: ColorSpace) -> Result<Environment> {
+        Ok(match self {
+            Self::Gradient => Environment::Gradient,
+            Self::Map {
+                path,
+                intensity,
+                rotation_degrees,
+            } => {
+                let image = Image::open(path)?;
+                if image.pixels.is_empty() {
+                    return Err(anyhow!("Environment map {} is empty", path.display()));
+                }
+                let image = converted(image, space);
+                Environment::Map(EnvironmentMap::new(
+                    image,
+                    *intensity,
+                    rotation_degrees.to_radians(),
+                ))
+            }
+            Self::Studio {
+                intensity,
+                rotation_degrees,
+            } => Environment::Map(EnvironmentMap::new(
+                converted(environment::studio(), space),
+                *intensity,
+                rotation_degrees.to_radians(),
+            )),
+        })
+    }
+}
+
+/// Light which is sampled explicitly at diffuse surfaces
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub enum LightSpec {
+    /// Distant light from a disk in the sky, which casts softer shadows further away from the
+    /// objects casting them
+    Sun {
+        /// Towards the sun
+        direction: [f32; 3],
+        /// Half of the angle covered by the disk, about 0.27° for the real sun. Zero gives
+        /// sharp shadows.
+        #[serde(default = "LightSpec::default_sun_radius")]
+        angular_radius_degrees: f32,
+        #[serde(default = "LightSpec::default_color")]
+        color: [f32; 3],
+        /// Tints the color with that of a black body at this temperature, such as 2700 K for a
+        /// tungsten bulb
+        #[serde(default)]
+        temperature_kelvin: Option<f32>,
+        /// On a surface facing the sun
+        irradiance: Irradiance,
+        /// Whether the sun is seen in mirror-like bounces after diffuse ones, such as through
+        /// glass on a table. Leaving these caustics out removes most of the noise of such scenes,
+        /// which path tracing resolves slowly. Only in biased renders.
+        #[serde(default = "LightSpec::default_caustics")]
+        caustics: bool,
+    },
+    /// Light from a point, equally in every direction unless it has a profile, which is
+    /// oriented straight down
+    Point {
+        position: [f32; 3],
+        #[serde(default = "LightSpec::default_color")]
+        color: [f32; 3],
+        /// Tints the color with that of a black body at this temperature, such as 2700 K for a
+        /// tungsten bulb
+        #[serde(default)]
+        temperature_kelvin: Option<f32>,
+        /// In the brightest direction. Can be left out when there is a profile, whose
+        /// intensities are then used as they are.
+        #[serde(default)]
+        intensity: Option<Intensity>,
+        /// IES file, relative to the working directory
+        #[serde(default)]
+        profile: Option<PathBuf>,
+    },
+    /// Light from a point in a cone, which also points the profile if there is one
+    Spot {
+        position: [f32; 3],
+        direction: [f32; 3],
+        /// From the direction to the edge of the cone
+        cone_angle_degrees: f32,
+        /// Width of the edge, inside of the cone, over which the light fades out
+        #[serde(default)]
+        blend_degrees: f32,
+        #[serde(default = "LightSpec::default_color")]
+        color: [f32; 3],
+        /// Tints the color with that of a black body at this temperature, such as 2700 K for a
+        /// tungsten bulb
+        #[serde(default)]
+        temperature_kelvin: Option<f32>,
+        #[serde(default)]
+        intensity: Option<Intensity>,
+        #[serde(default)]
+        profile: Option<PathBuf>,
+    },
+}
+
+/// Light emitted by a light, either per unit solid angle in its brightest direction or in
+/// total
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub enum Intensity {
+    /// Radiometric, in the units of the image
+    WattsPerSteradian(f32),
+    /// Photometric, converted like [`Irradiance::Lux`]
+    Candela(f32),
+    /// Radiometric power
+    Watts(f32),
+    /// Photometric power, converted like [`Irradiance::Lux`]
+    Lumens(f32),
+}
+
+impl Intensity {
+    /// Radiant intensity of light of `color` in its brightest direction, when it emits
+    /// `relative_flux` watts per unit of intensity
+    fn of(&self, color: Vec3, relative_flux: f32, space: ColorSpace) -> Vec3 {
+        color
+            * match *self {
+                Self::WattsPerSteradian(intensity) => intensity,
+                Self::Candela(intensity) => from_photometric(intensity, color, space),
+                Self::Watts(flux) => flux / relative_flux,
+                Self::Lumens(flux) => from_photometric(flux, color, space) / relative_flux,
+            }
+    }
+}
+
+/// Radiometric quantity of light of `color` in `space` from photometric `value`
+fn from_photometric(value: f32, color: Vec3, space: ColorSpace) -> f32 {
+    value / (683. * space.luminance(color)).max(f32::MIN_POSITIVE)
+}
+
+/// Light received by a surface
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub enum Irradiance {
+    /// Radiometric, in the units of the image, with 1 W/m²/sr of radiance being 1
+    WattsPerSquareMeter(f32),
+    /// Photometric, converted with the luminous efficacy of 683 lm/W so that the luminance of
+    /// the light's color times the irradiance is the illuminance
+    Lux(f32),
+}
+
+impl Irradiance {
+    fn of(&self, color: Vec3, space: ColorSpace) -> Vec3 {
+        match *self {
+            Self::WattsPerSquareMeter(irradiance) => color * irradiance,
+            Self::Lux(illuminance) => color * from_photometric(illuminance, color, space),
+        }
+    }
+}
+
+impl LightSpec {
+    fn default_sun_radius() -> f32 {
+        0.27
+    }
+
+    fn default_color() -> [f32; 3] {
+        [1., 1., 1.]
+    }
+
+    fn default_caustics() -> bool {
+        true
+    }
+
+    fn build(&self, space: ColorSpace) -> Result<Light> {
+        match self {
+            Self::Sun {
+                direction,
+                angular_radius_degrees,
+                color,
+                temperature_kelvin,
+                irradiance,
+                caustics,
+            } => {
+                let direction = Vec3::from(*direction);
+                if direction.mag_sq() == 0. {
+                    return Err(anyhow!("Sun has no direction"));
+                }
+                let sun = Sun::new(
+                    direction,
+                    angular_radius_degrees.to_radians(),
+                    irradiance.of(tinted(*color, *temperature_kelvin, space), space),
+                );
+                Ok(Light::Sun(if *caustics {
+                    sun
+                } else {
+                    sun.without_caustics()
+                }))
+            }
+            Self::Point {
+                position,
+                color,
+                temperature_kelvin,
+                intensity,
+                profile,
+            } => Ok(Light::Point(point_light(
+                PointLight::new((*position).into()),
+                tinted(*color, *temperature_kelvin, space),
+                *intensity,
+                profile,
+                space,
+            )?)),
+            Self::Spot {
+                position,
+                direction,
+                cone_angle_degrees,
+                blend_degrees,
+                color,
+                temperature_kelvin,
+                intensity,
+                profile,
+            } => {
+                let direction = Vec3::from(*direction);
+                if direction.mag_sq() == 0. {
+                    return Err(anyhow!("Spot light has no direction"));
+                }
+                let light = PointLight::new((*position).into())
+                    .pointing(direction)
+                    .with_cone(cone_angle_degrees.to_radians(), blend_degrees.to_radians());
+                Ok(Light::Point(point_light(
+                    light,
+                    tinted(*color, *temperature_kelvin, space),
+                    *intensity,
+                    profile,
+                    space,
+                )?))
+            }
+        }
+    }
+}
+
+/// `color` in `space` tinted by a black body at `temperature_kelvin`
+fn tinted(color: [f32; 3], temperature_kelvin: Option<f32>, space: ColorSpace) -> Vec3 {
+    let color = Vec3::from(color);
+    temperature_kelvin.map_or(color, |kelvin| color * space.from_srgb(blackbody(kelvin)))
+}
+
+/// Give `light` its profile and intensity
+fn point_light(
+    light: PointLight,
+    color: Vec3,
+    intensity: Option<Intensity>,
+    profile: &Option<PathBuf>,
+    space: ColorSpace,
+) -> Result<PointLight> {
+    let profile = profile.as_deref().map(IesProfile::open).transpose()?;
+    let intensity = match (intensity, &profile) {
+        (Some(intensity), _) => intensity,
+        (None, Some(profile)) => Intensity::Candela(profile.max_candela()),
+        (None, None) => return Err(anyhow!("Light without a profile needs an intensity")),
+    };
+    let light = match profile {
+        Some(profile) => light.with_profile(profile),
+        None => light,
+    };
+    let intensity = intensity.of(color, light.relative_flux(), space);
+    Ok(light.with_intensity(intensity))
+}
+
+#[derive(Clone, PartialEq, Serialize, Deserialize)]
+pub enum MaterialSpec {
+    Lambertian {
+        albedo: [f32; 3],
+    },
+    Metal {
+        albedo: [f32; 3],
+        fuzz: f32,
+    },
+    /// Metal with GGX microfacets, which may be rougher along one direction for a brushed look
+    AnisotropicMetal {
+        /// Reflectance at normal incidence
+        albedo: [f32; 3],
+        /// From 0 to 1 along the direction in which the first texture coordinate grows and
+        /// across it
+        roughness: [f32; 2],
+        /// Of the direction of the roughness, counter-clockwise around the normal
+        #[serde(default)]
+        rotation_degrees: f32,
+        /// Image whose first channel adds turns of rotation, relative to the working directory
+        #[serde(default)]
+        rotation_texture: Option<PathBuf>,
+    },
+    Dielectric {
+        refraction: f32,
+        /// From 0 for clear to 1 for frosted
+        #[serde(default)]
+        roughness: f32,
+        /// Participating medium filling the inside. With a refractive index of 1 the surface is
+        /// an invisible boundary, such as for smoke.
+        #[serde(default)]
+        volume: Option<VolumeSpec>,
+    },
+    /// Emits light from the front side and reflects nothing. Triangles with it are sampled as
+    /// lights.
+    Emissive {
+        radiance: [f32; 3],
+        /// Tints the radiance with the color of a black body at this temperature
+        #[serde(default)]
+        temperature_kelvin: Option<f32>,
+        /// Radiance HDR, PNG or OpenEXR image multiplying the radiance, relative to the working
+        /// directory
+        #[serde(default)]
+        texture: Option<PathBuf>,
+        /// Whether the material is seen in mirror-like bounces after diffuse ones, like
+        /// [`LightSpec::Sun::caustics`]. Only in biased renders.
+        #[serde(default = "LightSpec::default_caustics")]
+        caustics: bool,
+    },
+    /// Thin dielectric layer over another material, like lacquer or car paint
+    Clearcoat {
+        base: Box<MaterialSpec>,
+        /// Of the coat, from 0 to 1
+        #[serde(default)]
+        roughness: f32,
+        #[serde(default = "MaterialSpec::default_coat_refraction")]
+        refraction: f32,
+    },
+    /// Another material with holes in it, which shadows and reflections see through too
+    Cutout {
+        base: Box<MaterialSpec>,
+        /// Image whose first channel is the opacity of the base, from 0 where rays pass through
+        /// to 1, relative to the working directory. Like other PNG textures, 8-bit values are
+        /// squared.
+        alpha: PathBuf,
+    },
+    /// Material from [`Scene::material_libraries`] or a built-in preset, by its name. The
+    /// presets are `gold`, `silver`, `copper`, `aluminium`, `chrome`, `plaster`, `water`,
+    /// `glass` and `glass-` followed by a refractive index, like `glass-1.5`.
+    Named(String),
+}
+
+/// Homogeneous participating medium, with coefficients per unit of distance
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub struct VolumeSpec {
+    /// Light absorbed, which tints what is seen through the medium
+    #[serde(default)]
+    pub absorption: [f32; 3],
+    /// Light scattered to other directions, which makes the medium cloudy
+    #[serde(default)]
+    pub scattering: [f32; 3],
+    /// From -1 for scattering backwards to 1 for forwards
+    #[serde(default)]
+    pub asymmetry: f32,
+}
+
+impl Scene {
+    fn default_light_samples() -> u32 {
+        1
+    }
+
+    /// Scene with nothing in it, seen by `camera`
+    pub fn new(camera: CameraSpec) -> Self {
+        Self {
+            camera,
+            cameras: Vec::new(),
+            surfaces: Vec::new(),
+            materials: Vec::new(),
+            material_libraries: Vec::new(),
+            objects: Vec::new(),
+            bvh: BvhOptions::default(),
+            sampler: SamplerKind::default(),
+            light_samples: Self::default_light_samples(),
+            filter: PixelFilter::default(),
+            splat: false,
+            rng: RngKind::default(),
+            integrator: Integrator::default(),
+            passes: Vec::new(),
+            fog: None,
+            noise_threshold: None,
+            environment: EnvironmentSpec::default(),
+            lights: Vec::new(),
+            working_space: ColorSpace::default(),
+            display_lut: None,
+            guiding: None,
+            texture_cache: None,
+            compact_memory: false,
+            regularization: None,
+            max_radiance: None,
+            bounce_limits: BounceLimits::default(),
+            biased: false,
+        }
+    }
+
+    /// Read a scene from a RON file. A line of only `include "other.ron"` in it is replaced by
+    /// the contents of that file, relative to the including file, so that a large scene can be
+    /// split into files of geometry, materials and lighting.
+    pub fn open(path: &Path) -> Result<Self> {
+        let text = included(path, &mut Vec::new())?;
+        ron::de::from_str(&text).with_context(|| format!("Cannot parse {}", path.display()))
+    }
+
+    /// The scene on the cover of Ray Tracing in One Weekend, with spheres moving up
+    pub fn random(rng: &mut impl Rng) -> Self {
+        Self::random_with(rng, &RandomOptions::default())
+    }
+
+    /// Grid of small spheres of random materials around three big ones, see [`RandomOptions`]
+    pub fn random_with(rng: &mut impl Rng, options: &RandomOptions) -> Self {
+        let mut scene = Self::new(CameraSpec {
+            look_from: [13., 2., 3.],
+            look_at: [0., 0., 0.],
+            vertical_fov_degrees: 20.,
+            aperture: 0.1,
+            focus_distance: 10.,
+            ..CameraSpec::default()
+        });
+
+        let ground = scene.add_surface(SurfaceSpec::Sphere { radius: 1000. });
+        let (min_radius, max_radius) = options.radius;
+        let small = scene.add_surface(SurfaceSpec::Sphere { radius: min_radius });
+        let big = scene.add_surface(SurfaceSpec::Sphere { radius: 1. });
+        let glass = scene.add_material(MaterialSpec::Dielectric {
+            refraction: 1.5,
+            roughness: 0.,
+            volume: None,
+        });
+
+        let ground_material = scene.add_material(MaterialSpec::Lambertian {
+            albedo: [0.5, 0.5, 0.5],
+        });
+        scene.add_object(ground, ground_material, Vec3::new(0., -1000., 0.));
+
+        let total_weight = options.diffuse_weight + options.metal_weight + options.glass_weight;
+        let first = -(options.grid_size as i32 / 2);
+        let last = first + options.grid_size as i32 - 1;
+        for a in first..=last {
+            for b in first..=last {
+                let (surface, radius) = if min_radius < max_radius {
+                    let radius = rng.gen_range(min_radius..max_radius);
+                    (scene.add_surface(SurfaceSpec::Sphere { radius }), radius)
+                } else {
+                    (small, min_radius)
+                };
+                let center = Vec3::new(
+                    a as f32 + rng.gen_range(0f32..0.9),
+                    radius,
+                    b as f32 + rng.gen_range(0f32..0.9),
+                );
+
+                // The middle of one of 101 equal parts of the total weight
+                let choice = (rng.gen_range(0..=100) as f32 + 0.5) / 101. * total_weight;
+                let (velocity, material) = if choice < options.diffuse_weight {
+                    let rise = rng.gen_range(0f32..0.5);
+                    (
+                        Vec3::unit_y() * if options.motion { rise } else { 0. },
+                        scene.add_material(MaterialSpec::Lambertian {
+                            albedo: (Vec3::from(rng.gen::<[f32; 3]>())
+                                * Vec3::from(rng.gen::<[f32; 3]>()))
+                            .into(),
+                        }),
+                    )
+                } else if choice < options.diffuse_weight + options.metal_weight {
+                    (
+                        Vec3::zero(),
+                        scene.add_material(MaterialSpec::Metal {
+                            albedo: Vec3::from(rng.gen::<[f32; 3]>())
+                                .lerp(Vec3::one(), 0.4)
+                                .into(),
+                            fuzz: rng.gen_range(0.0..0.2),
+                        }),
+                    )
+                } else {
+                    (Vec3::zero(), glass)
+                };
+
+                scene.objects.push(ObjectSpec {
+                    name: None,
+                    surface,
+                    material,
+                    position: center.into(),
+                    velocity: velocity.into(),
+                    visibility: Visibility::default(),
+                    priority: 0,
+                });
+            }
+        }
+
+        scene.add_object(big, glass, Vec3::new(0., 1., 0.));
+        let diffuse = scene.add_material(MaterialSpec::Lambertian {
+            albedo: [0.4, 0.2, 0.1],
+        });
+        scene.add_object(big, diffuse, Vec3::new(-4., 1., 0.));
+        let metal = scene.add_material(MaterialSpec::Metal {
+            albedo: [0.7, 0.6, 0.5],
+            fuzz: 0.,
+        });
+        scene.add_object(big, metal, Vec3::new(4., 1., 0.));
+
+        scene
+    }
+
+    /// Ball of `material` on a gray floor in the studio environment, next to a small gray and
+    /// a small chrome ball which show the lighting
+    pub fn material_ball(material: MaterialSpec) -> Self {
+        let mut scene = Self::new(CameraSpec {
+            look_from: [0., 1.6, 6.],
+            look_at: [0., 0.8, 0.],
+            vertical_fov_degrees: 25.,
+            aperture: 0.,
+            focus_distance: 6.,
+            ..CameraSpec::default()
+        });
+        scene.environment = EnvironmentSpec::Studio {
+            intensity: 1.,
+            rotation_degrees: 0.,
+        };
+
+        let floor = scene.add_surface(SurfaceSpec::Sphere { radius: 1000. });
+        let gray = scene.add_material(MaterialSpec::Lambertian {
+            albedo: [0.18, 0.18, 0.18],
+        });
+        scene.add_object(floor, gray, Vec3::new(0., -1000., 0.));
+
+        let ball = scene.add_surface(SurfaceSpec::Sphere { radius: 0.8 });
+        let material = scene.add_material(material);
+        scene.add_object(ball, material, Vec3::new(0., 0.8, 0.));
+
+        let reference = scene.add_surface(SurfaceSpec::Sphere { radius: 0.3 });
+        let chrome = scene.add_material(MaterialSpec::Metal {
+            albedo: [0.9, 0.9, 0.9],
+            fuzz: 0.,
+        });
+        scene.add_object(reference, gray, Vec3::new(-1.5, 0.3, 0.5));
+        scene.add_object(reference, chrome, Vec3::new(1.5, 0.3, 0.5));
+        scene
+    }
+
+    /// Eric Haines' sphereflake of a sphere with nine spheres a third of its size on it,
+    /// recursively to `depth`, for (9^(`depth` + 1) - 1) / 8 spheres
+    pub fn sphere_flake(depth: u32) -> Self {
+        let mut scene = Self::new(CameraSpec::towards_origin([4., 2.6, 4.], 40.));
+        let floor = scene.add_surface(SurfaceSpec::Sphere { radius: 1000. });
+        let gray = scene.add_material(MaterialSpec::Lambertian {
+            albedo: [0.5, 0.5, 0.5],
+        });
+        scene.add_object(floor, gray, Vec3::new(0., -1001., 0.));
+        let metal = scene.add_material(MaterialSpec::Metal {
+            albedo: [0.8, 0.7, 0.6],
+            fuzz: 0.05,
+        });
+        let surfaces: Vec<usize> = (0..=depth)
+            .map(|level| {
+                scene.add_surface(SurfaceSpec::Sphere {
+                    radius: 3f32.powi(-(level as i32)),
+                })
+            })
+            .collect();
+        scene.add_flake(&surfaces, metal, Vec3::zero(), Vec3::unit_y());
+        scene
+    }
+
+    /// Sphere of `surfaces[0]` at `center` with smaller ones on the side towards `axis`
+    fn add_flake(&mut self, surfaces: &[usize], material: usize, center: Vec3, axis: Vec3) {
+        self.add_object(surfaces[0], material, center);
+        if surfaces.len() < 2 {
+            return;
+        }
+        let radius = match self.surfaces[surfaces[0]] {
+            SurfaceSpec::Sphere { radius } => radius,
+            SurfaceSpec::Triangle { .. } => unreachable!("Flakes are made of spheres"),
+        };
+        let side = if axis.x.abs() < 0.9 {
+            Vec3::unit_x()
+        } else {
+            Vec3::unit_y()
+        };
+        let (u, v) = (
+            axis.cross(side).normalized(),
+            axis.cross(axis.cross(side)).normalized(),
+        );
+        // Six around the equator and three above them
+        let directions = (0..6)
+            .map(|i| (i as f32 * 60f32, 0f32))
+            .chain((0..3).map(|i| (30. + i as f32 * 120., 60.)));
+        for (azimuth, elevation) in directions {
+            let (azimuth, elevation) = (azimuth.to_radians(), elevation.to_radians());
+            let direction =
+                (u * azimuth.cos() + v * azimuth.sin()) * elevation.cos() + axis * elevation.sin();
+            self.add_flake(
+                &surfaces[1..],
+                material,
+                center + direction * radius * 4. / 3.,
+                direction,
+            );
+        }
+    }
+
+    /// Menger sponge of cubes removed from a cube of side 2 recursively to `level`, for
+    /// 12 × 20^`level` triangles
+    pub fn menger_sponge(level: u32) -> Self {
+        let mut scene = Self::new(CameraSpec::towards_origin([3., 2.2, 3.6], 45.));
+        let floor = scene.add_surface(SurfaceSpec::Sphere { radius: 1000. });
+        let gray = scene.add_material(MaterialSpec::Lambertian {
+            albedo: [0.5, 0.5, 0.5],
+        });
+        scene.add_object(floor, gray, Vec3::new(0., -1001., 0.));
+        let white = scene.add_material(MaterialSpec::Lambertian {
+            albedo: [0.8, 0.8, 0.8],
+        });
+
+        let mut cubes = vec![(Vec3::zero(), 1f32)];
+        for _ in 0..level {
+            cubes = cubes
+                .into_iter()
+                .flat_map(|(center, half)| {
+                    let offsets = (-1..=1)
+                        .flat_map(|x| (-1..=1).flat_map(move |y| (-1..=1).map(move |z| [x, y, z])));
+                    // Keep the sub-cubes at the corners and edges
+                    offsets
+                        .filter(|offset| offset.iter().filter(|&&o| o == 0).count() < 2)
+                        .map(move |offset| {
+                            let half = half / 3.;
+                            (
+                                center + Vec3::from(offset.map(|o| o as f32)) * half * 2.,
+                                half,
+                            )
+                        })
+                })
+                .collect();
+        }
+
+        let mut positions: Vec<[f32; 3]> = Vec::with_capacity(cubes.len() * 8);
+        let mut indices = Vec::with_capacity(cubes.len() * 12);
+        for (center, half) in cubes {
+            let first = positions.len() as u32;
+            // Corners with the bits of their index telling which side they are on along x, y, z
+            positions.extend((0..8).map(|corner: u32| {
+                let side = |bit: u32| if corner >> bit & 1 == 0 { -half } else { half };
+                <[f32; 3]>::from(center + Vec3::new(side(0), side(1), side(2)))
+            }));
+            // Two triangles with outward normals for each face
+            const FACES: [[u32; 4]; 6] = [
+                [0, 4, 6, 2],
+                [1, 3, 7, 5],
+                [0, 1, 5, 4],
+                [2, 6, 7, 3],
+                [0, 2, 3, 1],
+                [4, 5, 7, 6],
+            ];
+            for [a, b, c, d] in FACES {
+                indices.push([first + a, first + b, first + c]);
+                indices.push([first + a, first + c, first + d]);
+            }
+        }
+        scene
+            .add_mesh(&positions, &indices, white)
+            .expect("Cube indices are valid");
+        scene
+    }
+
+    /// `count` randomly placed and oriented triangles in a cube of side 2, about as big as the
+    /// space between them
+    pub fn triangle_soup(rng: &mut impl Rng, count: usize) -> Self {
+        let mut scene = Self::new(CameraSpec::towards_origin([3., 2.2, 3.6], 45.));
+        let material = scene.add_material(MaterialSpec::Lambertian {
+            albedo: [0.6, 0.6, 0.6],
+        });
+        let size = 2. / (count.max(1) as f32).cbrt();
+        for _ in 0..count {
+            let center = Vec3::from(rng.gen::<[f32; 3]>()) * 2. - Vec3::one();
+            let vertices = [(); 3].map(|_| {
+                let offset = Vec3::from(rng.gen::<[f32; 3]>()) - Vec3::broadcast(0.5);
+                (center + offset * size).into()
+            });
+            let surface = scene.add_surface(SurfaceSpec::Triangle {
+                vertices,
+                uvs: SurfaceSpec::default_uvs(),
+            });
+            scene.add_object(surface, material, Vec3::zero());
+        }
+        scene
+    }
+
+    /// Returns the index of the new surface
+    pub fn add_surface(&mut self, surface: SurfaceSpec) -> usize {
+        self.surfaces.push(surface);
+        self.surfaces.len() - 1
+    }
+
+    /// Returns the index of the new material
+    pub fn add_material(&mut self, material: MaterialSpec) -> usize {
+        self.materials.push(material);
+        self.materials.len() - 1
+    }
+
+    /// Add a stationary object
+    pub fn add_object(&mut self, surface: usize, material: usize, position: Vec3) {
+        self.objects.push(ObjectSpec {
+            name: None,
+            surface,
+            material,
+            position: position.into(),
+            velocity: [0.; 3],
+            visibility: Visibility::default(),
+            priority: 0,
+        });
+    }
+
+    /// Add an indexed triangle mesh as one object per triangle, all sharing `material`
+    pub fn add_mesh(
+        &mut self,
+        positions: &[[f32; 3]],
+        indices: &[[u32; 3]],
+        material: usize,
+    ) -> Result<()> {
+        for triangle in indices {
+            let mut vertices = [[0.; 3]; 3];
+            for (vertex, &index) in vertices.iter_mut().zip(triangle) {
+                *vertex = *positions
+                    .get(index as usize)
+                    .ok_or_else(|| anyhow!("Vertex index {} out of bounds", index))?;
+            }
+            let surface = self.add_surface(SurfaceSpec::Triangle {
+                vertices,
+                uvs: SurfaceSpec::default_uvs(),
+            });
+            self.add_object(surface, material, Vec3::zero());
+        }
+        Ok(())
+    }
+
+    pub fn world(&self, frame: u32) -> Result<World> {
+        if ![2, 4, 8].contains(&self.bvh.width) {
+            return Err(anyhow!("BVH width must be 2, 4 or 8"));
+        }
+        let handle = |index: usize, len: usize, what: &str| {
+            u32::try_from(index)
+                .ok()
+                .filter(|_| index < len)
+                .ok_or_else(|| anyhow!("Object refers to nonexistent {} {}", what, index))
+        };
+        let objects = self
+            .objects
+            .iter()
+            .map(|object| {
+                let position = Vec3::from(object.position);
+                Ok(Object {
+                    surface: SurfaceHandle(handle(object.surface, self.surfaces.len(), "surface")?),
+                    material: MaterialHandle(handle(
+                        object.material,
+                        self.materials.len(),
+                        "material",
+                    )?),
+                    physics: PhysicsFrame {
+                        position: position..position + Vec3::from(object.velocity),
+                    },
+                    visibility: object.visibility,
+                    priority: object.priority,
+                })
+            })
+            .collect::<Result<_>>()?;
+        let cache = self.texture_cache.as_ref().map(TextureCache::new);
+        let surfaces: Vec<Surface> = self.surfaces.iter().map(SurfaceSpec::build).collect();
+        Ok(World::new(
+            if self.compact_memory {
+                Surfaces::quantized(surfaces)
+            } else {
+                surfaces.into()
+            },
+            self.resolved_materials()?
+                .iter()
+                .map(|material| material.build(self.working_space, cache.as_ref()))
+                .collect::<Result<_>>()?,
+            objects,
+            self.shutter(frame),
+            &BvhOptions {
+                compact: self.compact_memory,
+                ..self.bvh.clone()
+            },
+            self.environment.build(self.working_space)?,
+            self.lights
+                .iter()
+                .map(|light| light.build(self.working_space))
+                .collect::<Result<_>>()?,
+        ))
+    }
+
+    /// The materials with names looked up in the material libraries and the presets
+    fn resolved_materials(&self) -> Result<Vec<MaterialSpec>> {
+        let libraries = self
+            .material_libraries
+            .iter()
+            .map(|path| {
+                let bytes =
+                    fs::read(path).with_context(|| format!("Cannot read {}", path.display()))?;
+                ron::de::from_bytes(&bytes)
+                    .with_context(|| format!("Cannot parse {}", path.display()))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        self.materials
+            .iter()
+            .map(|material| material.resolved(&libraries, self.working_space, &mut Vec::new()))
+            .collect()
+    }
+
+    /// Replace named materials with the ones they refer to, so that the scene no longer
+    /// depends on its material libraries, such as when it is sent to another machine
+    pub fn resolve_materials(&mut self) -> Result<()> {
+        self.materials = self.resolved_materials()?;
+        self.material_libraries.clear();
+        Ok(())
+    }
+
+    /// Time during which the shutter of any of the cameras is open, see
+    /// [`CameraSpec::shutter`]
+    pub fn shutter(&self, frame: u32) -> Range<f32> {
+        self.cameras
+            .iter()
+            .map(|named| named.camera.shutter(frame))
+            .fold(self.camera.shutter(frame), |a, b| {
+                a.start.min(b.start)..a.end.max(b.end)
+            })
+    }
+
+    pub fn camera(&self, aspect_ratio: f32, frame: u32) -> Result<Camera> {
+        let spec = &self.camera;
+        let (mut look_from, mut look_at, mut up) =
+            (spec.look_from.into(), spec.look_at.into(), spec.up.into());
+        let mut vertical_fov_degrees = spec.vertical_fov_degrees;
+        if let Some(path) = &spec.path {
+            let camera_path = CameraPath::open(&path.file, path.node.as_deref())?;
+            let time = (frame as f32 - path.first_frame as f32) / path.frames_per_second;
+            let transform = camera_path.transform(time);
+            look_from = transform.transform_point3(Vec3::zero());
+            look_at = look_from - transform.transform_vec3(Vec3::unit_z());
+            up = transform.transform_vec3(Vec3::unit_y());
+            if let Some(fov) = camera_path.vertical_fov() {
+                vertical_fov_degrees = fov.to_degrees();
+            }
+        }
+        let shutter = spec.shutter(frame);
+        let focus_distance = match &spec.focus_object {
+            Some(name) => {
+                let object = self
+                    .objects
+                    .iter()
+                    .find(|object| object.name.as_ref() == Some(name))
+                    .ok_or_else(|| anyhow!("There is no object called {}", name))?;
+                let time = (shutter.start + shutter.end) / 2.;
+                let position = Vec3::from(object.position) + Vec3::from(object.velocity) * time;
+                // Along the view direction, as the plane in focus faces the camera
+                let distance = (position - look_from).dot((look_at - look_from).normalized());
+                if distance <= 0. {
+                    return Err(anyhow!("Object {} is behind the camera", name));
+                }
+                distance
+            }
+            None => spec
+                .focus_distance_keys
+                .at(frame)
+                .unwrap_or(spec.focus_distance),
+        };
+        let camera = Camera::new(
+            look_from,
+            look_at,
+            up,
+            vertical_fov_degrees,
+            aspect_ratio,
+            spec.aperture_keys.at(frame).unwrap_or(spec.aperture),
+            focus_distance,
+            shutter,
+        );
+        let camera = match spec.rolling_shutter {
+            Some(exposure) if exposure.is_nan() || exposure < 0. => {
+                return Err(anyhow!(
+                    "Rolling shutter exposure must be from 0 to 1, not {}",
+                    exposure
+                ))
+            }
+            Some(exposure) => camera.with_rolling_shutter(exposure),
+            None => camera,
+        };
+        let camera = match &spec.aperture_texture {
+            Some(path) => camera.with_aperture(&texture(path)?),
+            None => camera,
+        };
+        Ok(camera
+            .with_cat_eye(spec.cat_eye)
+            .with_shift(spec.shift.into())
+            .with_tilt(spec.tilt_degrees.into())
+            .with_anamorphic_squeeze(spec.anamorphic_squeeze)
+            .with_chromatic_aberration(spec.lateral_aberration, spec.longitudinal_aberration)
+            .with_filter(self.filter))
+    }
+
+    /// Whether the scene has options which bias the image, which [`Scene::biased`] has to allow
+    pub fn has_biased_options(&self) -> bool {
+        self.regularization.is_some()
+            || self.max_radiance.is_some()
+            || !self.bounce_limits.is_unlimited()
+            || self.lights.iter().any(|light| {
+                matches!(
+                    light,
+                    LightSpec::Sun {
+                        caustics: false,
+                        ..
+                    }
+                )
+            })
+            || !self.materials.iter().all(MaterialSpec::casts_caustics)
+    }
+
+    /// Names of the cameras, starting with [`MAIN_CAMERA`]
+    pub fn camera_names(&self) -> Result<Vec<&str>> {
+        let mut names = vec![MAIN_CAMERA];
+        for named in &self.cameras {
+            if names.contains(&named.name.as_str()) {
+                return Err(anyhow!(
+                    "There is more than one camera called {}",
+                    named.name
+                ));
+            }
+            names.push(&named.name);
+        }
+        Ok(names)
+    }
+
+    /// The scene as seen by the camera called `name`, which is its only camera
+    pub fn seen_by(&self, name: &str) -> Result<Self> {
+        let camera = if name == MAIN_CAMERA {
+            &self.camera
+        } else {
+            self.cameras
+                .iter()
+                .find(|named| named.name == name)
+                .map(|named| &named.camera)
+                .ok_or_else(|| anyhow!("There is no camera called {}", name))?
+        };
+        Ok(Self {
+            camera: camera.clone(),
+            cameras: Vec::new(),
+            ..self.clone()
+        })
+    }
+}
+
+impl SurfaceSpec {
+    fn build(&self) -> Surface {
+        match *self {
+            Self::Sphere { radius } => Surface::Sphere(Sphere::new(radius)),
+            Self::Triangle { vertices, uvs } => Surface::Triangle(
+                Triangle::new(vertices.map(Vec3::from)).with_uvs(uvs.map(Vec2::from)),
+            ),
+        }
+    }
+}
+
+/// Text of the file at `path` with its includes replaced, see [`Scene::open`]. `including`
+/// are the files whose includes are being replaced already, which must not be included again.
+fn included(path: &Path, including: &mut Vec<PathBuf>) -> Result<String> {
+    let canonical = path
+        .canonicalize()
+        .with_context(|| format!("Cannot read {}", path.display()))?;
+    if including.contains(&canonical) {
+        return Err(anyhow!("{} includes itself", path.display()));
+    }
+    let text =
+        fs::read_to_string(path).with_context(|| format!("Cannot read {}", path.display()))?;
+    including.push(canonical);
+    let mut result = String::with_capacity(text.len());
+    for line in text.lines() {
+        let include = line
+            .trim()
+            .strip_prefix("include")
+            .map(str::trim_start)
+            .and_then(|rest| rest.strip_prefix('"')?.strip_suffix('"'));
+        match include {
+            Some(other) => {
+                let other = path.parent().unwrap_or(Path::new("")).join(other);
+                result.push_str(&included(&other, including)?);
+            }
+            None => result.push_str(line),
+        }
+        result.push('\n');
+    }
+    including.pop();
+    Ok(result)
+}
+
+/// Image for texturing materials, which must not be empty
+fn texture(path: &Path) -> Result<Image> {
+    let image = Image::open(path)?;
+    if image.pixels.is_empty() {
+        return Err(anyhow!("Texture {} is empty", path.display()));
+    }
+    Ok(image)
+}
+
+/// Texture of the image at `path` like [`texture`], with its pixels converted from linear sRGB
+/// to `space` if there is one. Images kept in `cache` are read into it a row at a time, and
+/// others are kept in memory.
+fn cached(
+    path: &Path,
+    space: Option<ColorSpace>,
+    cache: Option<&Arc<TextureCache>>,
+) -> Result<Texture> {
+    let convert = move |pixel| match space {
+        Some(space) => space.from_srgb(pixel),
+        None => pixel,
+    };
+    let cache = match cache {
+        Some(cache) => cache,
+        None => {
+            let mut image = texture(path)?;
+            image
+                .pixels
+                .iter_mut()
+                .for_each(|pixel| *pixel = convert(*pixel));
+            return Ok(image.into());
+        }
+    };
+    let rows = Rows::open(path)?;
+    if rows.width == 0 || rows.height == 0 {
+        return Err(anyhow!("Texture {} is empty", path.display()));
+    }
+    let (width, height) = (rows.width, rows.height);
+    let rows = rows.map(|row| Ok(row?.into_iter().map(convert).collect()));
+    cache
+        .insert(width, height, rows)
+        .with_context(|| format!("Cannot cache {}", path.display()))
+}
+
+/// `image` of linear sRGB colors converted to `space`
+fn converted(mut image: Image, space: ColorSpace) -> Image {
+    for pixel in &mut image.pixels {
+        *pixel = space.from_srgb(*pixel);
+    }
+    image
+}
+
+impl MaterialSpec {
+    fn default_coat_refraction() -> f32 {
+        1.5
+    }
+
+    /// Whether light emitted by the material is seen by caustic rays, see
+    /// [`crate::ray::Ray::is_caustic`]
+    fn casts_caustics(&self) -> bool {
+        match self {
+            Self::Emissive { caustics, .. } => *caustics,
+            Self::Clearcoat { base, .. } | Self::Cutout { base, .. } => base.casts_caustics(),
+            _ => true,
+        }
+    }
+
+    /// Built-in material called `name`, with its colors converted to `space`
+    pub fn preset(name: &str, space: ColorSpace) -> Option<Self> {
+        let color = |srgb: [f32; 3]| space.from_srgb(srgb.into()).into();
+        let metal = |albedo| Self::AnisotropicMetal {
+            albedo: color(albedo),
+            roughness: [0.2; 2],
+            rotation_degrees: 0.,
+            rotation_texture: None,
+        };
+        let dielectric = |refraction| Self::Dielectric {
+            refraction,
+            roughness: 0.,
+            volume: None,
+        };
+        // Reflectances of metals at normal incidence from measured refractive indices
+        Some(match name {
+            "gold" => metal([1., 0.782, 0.344]),
+            "silver" => metal([0.972, 0.960, 0.915]),
+            "copper" => metal([0.955, 0.638, 0.538]),
+            "aluminium" | "aluminum" => metal([0.913, 0.922, 0.924]),
+            "chrome" => Self::Metal {
+                albedo: color([0.550, 0.556, 0.554]),
+                fuzz: 0.,
+            },
+            "plaster" => Self::Lambertian {
+                albedo: color([0.8, 0.8, 0.78]),
+            },
+            "water" => dielectric(1.333),
+            "glass" => dielectric(1.5),
+            _ => dielectric(name.strip_prefix("glass-")?.parse().ok()?),
+        })
+    }
+
+    /// This material with names in it looked up in `libraries` from the last, and then in the
+    /// presets. `names` are the ones being looked up already, which must not be looked up
+    /// again.
+    fn resolved(
+        &self,
+        libraries: &[HashMap<String, MaterialSpec>],
+        space: ColorSpace,
+        names: &mut Vec<String>,
+    ) -> Result<Self> {
+        Ok(match self {
+            Self::Named(name) => {
+                if names.contains(name) {
+                    return Err(anyhow!("Material {} is defined in terms of itself", name));
+                }
+                let material = libraries
+                    .iter()
+                    .rev()
+                    .find_map(|library| library.get(name).cloned())
+                    .or_else(|| Self::preset(name, space))
+                    .ok_or_else(|| anyhow!("No material is named {}", name))?;
+                names.push(name.clone());
+                let material = material.resolved(libraries, space, names)?;
+                names.pop();
+                material
+            }
+            Self::Clearcoat {
+                base,
+                roughness,
+                refraction,
+            } => Self::Clearcoat {
+                base: Box::new(base.resolved(libraries, space, names)?),
+                roughness: *roughness,
+                refraction: *refraction,
+            },
+            Self::Cutout { base, alpha } => Self::Cutout {
+                base: Box::new(base.resolved(libraries, space, names)?),
+                alpha: alpha.clone(),
+            },
+            material => material.clone(),
+        })
+    }
+
+    /// The material with its textures kept in `cache`, if there is one
+    fn build(&self, space: ColorSpace, cache: Option<&Arc<TextureCache>>) -> Result<Material> {
+        Ok(match *self {
+            Self::Lambertian { albedo } => Material::Lambertian(Lambertian::new(albedo.into())),
+            Self::Metal { albedo, fuzz } => Material::Metal(Metal::new(albedo.into(), fuzz)),
+            Self::AnisotropicMetal {
+                albedo,
+                roughness,
+                rotation_degrees,
+                ref rotation_texture,
+            } => {
+                let metal = AnisotropicMetal::new(
+                    albedo.into(),
+                    roughness.into(),
+                    rotation_degrees.to_radians(),
+                );
+                Material::AnisotropicMetal(match rotation_texture {
+                    Some(path) => metal.with_rotation_texture(cached(path, None, cache)?),
+                    None => metal,
+                })
+            }
+            Self::Dielectric {
+                refraction,
+                roughness,
+                volume,
+            } => {
+                let dielectric = Dielectric::new(refraction).with_roughness(roughness);
+                Material::Dielectric(match volume {
+                    Some(volume) => dielectric.with_volume(Volume::new(
+                        volume.absorption.into(),
+                        volume.scattering.into(),
+                        volume.asymmetry,
+                    )),
+                    None => dielectric,
+                })
+            }
+            Self::Emissive {
+                radiance,
+                temperature_kelvin,
+                ref texture,
+                caustics,
+            } => {
+                let emissive = Emissive::new(tinted(radiance, temperature_kelvin, space));
+                let emissive = if caustics {
+                    emissive
+                } else {
+                    emissive.without_caustics()
+                };
+                Material::Emissive(match texture {
+                    Some(path) => emissive.with_texture(cached(path, Some(space), cache)?),
+                    None => emissive,
+                })
+            }
+            Self::Clearcoat {
+                ref base,
+                roughness,
+                refraction,
+            } => Material::Clearcoat(Clearcoat::new(
+                base.build(space, cache)?,
+                roughness,
+                refraction,
+            )),
+            Self::Cutout {
+                ref base,
+                ref alpha,
+            } => Material::Cutout(Cutout::new(
+                base.build(space, cache)?,
+                cached(alpha, None, cache)?,
+            )),
+            Self::Named(ref name) => return Err(anyhow!("Material {} is not resolved", name)),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keyframes_are_interpolated_in_order() {
+        let keys: Keyframes = ron::from_str("([(1, 0.0), (5, 2.0), (9, 1.0)])").unwrap();
+        assert_eq!(keys.at(0), Some(0.));
+        assert_eq!(keys.at(3), Some(1.));
+        assert_eq!(keys.at(7), Some(1.5));
+        assert_eq!(keys.at(10), Some(1.));
+        assert_eq!(Keyframes::default().at(3), None);
+    }
+
+    #[test]
+    fn keyframes_out_of_order_are_rejected() {
+        assert!(ron::from_str::<Keyframes>("([(5, 0.0), (1, 2.0)])").is_err());
+        assert!(ron::from_str::<Keyframes>("([(1, 0.0), (1, 2.0)])").is_err());
+        // Written with its name like other newtypes
+        assert!(ron::from_str::<Keyframes>("Keyframes([(1, 0.0), (2, 2.0)])").is_ok());
+    }
+}
@@ -0,0 +1,232 @@
+//! Parameters of a scene set from the command line, and renders with one varied between them
+
+use anyhow::{anyhow, Result};
+use rt::scene::{CameraSpec, MaterialSpec, Scene};
+use std::str::FromStr;
+
+/// What is varied
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Parameter {
+    /// Of a material, or the fuzz of a metal
+    Roughness,
+    /// Index of refraction of a material
+    Refraction,
+    /// Of a metal
+    Fuzz,
+    /// Per pixel, rounded
+    Samples,
+    /// Vertical field of view of every camera, in degrees
+    Fov,
+    /// Of every camera
+    Aperture,
+    /// Of every camera
+    FocusDistance,
+}
+
+impl FromStr for Parameter {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "roughness" => Ok(Self::Roughness),
+            "refraction" | "ior" => Ok(Self::Refraction),
+            "fuzz" => Ok(Self::Fuzz),
+            "samples" => Ok(Self::Samples),
+            "fov" => Ok(Self::Fov),
+            "aperture" => Ok(Self::Aperture),
+            "focus_distance" => Ok(Self::FocusDistance),
+            _ => Err(anyhow!("Unknown parameter {}", s)),
+        }
+    }
+}
+
+impl Parameter {
+    /// Whether the parameter belongs to a material, which then has to be chosen
+    pub fn of_material(&self) -> bool {
+        matches!(self, Self::Roughness | Self::Refraction | Self::Fuzz)
+    }
+
+    /// Whether the parameter belongs to a camera, which can be chosen
+    pub fn of_camera(&self) -> bool {
+        matches!(self, Self::Fov | Self::Aperture | Self::FocusDistance)
+    }
+
+    /// Set the parameter of `scene` or its material at index `material` to `value`
+    pub fn apply(
+        &self,
+        scene: &mut Scene,
+        material: Option<usize>,
+        samples_per_pixel: &mut u32,
+        value: f32,
+    ) -> Result<()> {
+        if *self == Self::Samples {
+            *samples_per_pixel = value.round().max(1.) as u32;
+        }
+        if self.of_camera() {
+            std::iter::once(&mut scene.camera)
+                .chain(scene.cameras.iter_mut().map(|n| &mut n.camera))
+                .for_each(|camera| set_camera(*self, camera, value));
+        }
+        if !self.of_material() {
+            return Ok(());
+        }
+        let index = material.ok_or_else(|| anyhow!("Choose a material to vary"))?;
+        let material = scene
+            .materials
+            .get_mut(index)
+            .ok_or_else(|| anyhow!("There is no material {}", index))?;
+        if set_material(*self, material, value) {
+            Ok(())
+        } else {
+            Err(anyhow!("Material {} has no {:?} to vary", index, self))
+        }
+    }
+}
+
+fn set_camera(parameter: Parameter, camera: &mut CameraSpec, value: f32) {
+    match parameter {
+        Parameter::Fov => camera.vertical_fov_degrees = value,
+        Parameter::Aperture => camera.aperture = value,
+        Parameter::FocusDistance => camera.focus_distance = value,
+        _ => (),
+    }
+}
+
+/// Returns whether `material` has `parameter`
+fn set_material(parameter: Parameter, material: &mut MaterialSpec, value: f32) -> bool {
+    match (parameter, material) {
+        (Parameter::Roughness, MaterialSpec::Metal { fuzz, .. })
+        | (Parameter::Fuzz, MaterialSpec::Metal { fuzz, .. }) => *fuzz = value,
+        (Parameter::Roughness, MaterialSpec::AnisotropicMetal { roughness, .. }) => {
+            *roughness = [value; 2]
+        }
+        (Parameter::Roughness, MaterialSpec::Dielectric { roughness, .. })
+        | (Parameter::Roughness, MaterialSpec::Clearcoat { roughness, .. }) => *roughness = value,
+        (Parameter::Refraction, MaterialSpec::Dielectric { refraction, .. })
+        | (Parameter::Refraction, MaterialSpec::Clearcoat { refraction, .. }) => {
+            *refraction = value
+        }
+        (parameter, MaterialSpec::Cutout { base, .. }) => {
+            return set_material(parameter, base, value)
+        }
+        _ => return false,
+    }
+    true
+}
+
+/// Parameter set to a value, written like `samples=256`, `camera.fov=35` or
+/// `materials.2.roughness=0.5`, which can be any [`Parameter`] that can be swept. Parameters of
+/// cameras are set on every camera, or on one like `camera.1.fov=35`, where 0 is the main camera
+/// and the others are numbered from 1 in the order of the other views of the scene.
+#[derive(Clone, Copy, Debug)]
+pub struct Override {
+    pub parameter: Parameter,
+    /// Index of the material whose parameter is set
+    pub material: Option<usize>,
+    /// Index of the camera whose parameter is set, or every camera
+    pub camera: Option<usize>,
+    pub value: f32,
+}
+
+impl FromStr for Override {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let (key, value) = s
+            .split_once('=')
+            .ok_or_else(|| anyhow!("Override {} is not like parameter=value", s))?;
+        let (material, name) = match key.strip_prefix("materials.") {
+            Some(rest) => {
+                let (index, name) = rest
+                    .split_once('.')
+                    .ok_or_else(|| anyhow!("Override {} has no parameter of the material", s))?;
+                (Some(index.parse()?), name)
+            }
+            None => (None, key.strip_prefix("camera.").unwrap_or(key)),
+        };
+        let (camera, name) = match name.split_once('.') {
+            Some((index, name)) if key.starts_with("camera.") => (Some(index.parse()?), name),
+            _ => (None, name),
+        };
+        let parameter: Parameter = name.parse()?;
+        if parameter.of_material() != material.is_some() {
+            return Err(anyhow!(
+                "Set parameters of materials like materials.0.{} and others without the material",
+                name
+            ));
+        }
+        if camera.is_some() && !parameter.of_camera() {
+            return Err(anyhow!("{} is not a parameter of a camera", name));
+        }
+        Ok(Self {
+            parameter,
+            material,
+            camera,
+            value: value.parse()?,
+        })
+    }
+}
+
+impl Override {
+    pub fn apply(&self, scene: &mut Scene, samples_per_pixel: &mut u32) -> Result<()> {
+        let camera = match self.camera {
+            Some(0) => &mut scene.camera,
+            Some(index) => {
+                &mut scene
+                    .cameras
+                    .get_mut(index - 1)
+                    .ok_or_else(|| anyhow!("There is no camera {}", index))?
+                    .camera
+            }
+            None => {
+                return self
+                    .parameter
+                    .apply(scene, self.material, samples_per_pixel, self.value)
+            }
+        };
+        set_camera(self.parameter, camera, self.value);
+        Ok(())
+    }
+}
+
+/// Evenly spaced values of a parameter, written like `roughness=0:1:5`
+#[derive(Clone, Copy, Debug)]
+pub struct Sweep {
+    pub parameter: Parameter,
+    pub start: f32,
+    pub end: f32,
+    /// Number of values, including both ends
+    pub steps: u32,
+}
+
+impl FromStr for Sweep {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let (parameter, range) = s
+            .split_once('=')
+            .ok_or_else(|| anyhow!("Sweep {} is not like parameter=start:end:steps", s))?;
+        let mut range = range.splitn(3, ':');
+        let (start, end, steps) = match (range.next(), range.next(), range.next()) {
+            (Some(start), Some(end), Some(steps)) => (start, end, steps),
+            _ => return Err(anyhow!("Sweep {} is not like parameter=start:end:steps", s)),
+        };
+        let steps = steps.parse()?;
+        if steps == 0 {
+            return Err(anyhow!("Sweep needs at least one step"));
+        }
+        Ok(Self {
+            parameter: parameter.parse()?,
+            start: start.parse()?,
+            end: end.parse()?,
+            steps,
+        })
+    }
+}
+
+impl Sweep {
+    pub fn values(&self) -> impl Iterator<Item = f32> + '_ {
+        let last = (self.steps - 1).max(1) as f32;
+        (0..self.steps).map(move |i| self.start + (self.end - self.start) * i as f32 / last)
+    }
+}
@@ -0,0 +1,207 @@
+use anyhow::{anyhow, Result};
+use rt::color::COLOR_CHANNELS;
+use std::{convert::TryFrom, io::Write, str::FromStr};
+
+#[derive(Clone, Copy)]
+pub enum Protocol {
+    Sixel,
+    Kitty,
+    Iterm,
+}
+
+impl FromStr for Protocol {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "sixel" => Ok(Self::Sixel),
+            "kitty" => Ok(Self::Kitty),
+            "iterm" => Ok(Self::Iterm),
+            _ => Err(anyhow!("Unknown terminal graphics protocol {}", s)),
+        }
+    }
+}
+
+impl Protocol {
+    /// Guess the best supported protocol from the environment, falling back to sixel
+    pub fn detect() -> Self {
+        let var = |key| std::env::var(key).unwrap_or_default();
+        if !var("KITTY_WINDOW_ID").is_empty() || var("TERM") == "xterm-kitty" {
+            Self::Kitty
+        } else if var("TERM_PROGRAM") == "iTerm.app" {
+            Self::Iterm
+        } else {
+            Self::Sixel
+        }
+    }
+
+    pub fn write(
+        self,
+        mut write: impl Write,
+        width: usize,
+        height: usize,
+        rgb8_data: &[u8],
+    ) -> Result<()> {
+        // Draw over the previous preview instead of scrolling the terminal
+        write!(write, "\x1b[H")?;
+        match self {
+            Self::Sixel => write_sixel(&mut write, width, height, rgb8_data)?,
+            Self::Kitty => write_kitty(&mut write, width, height, rgb8_data)?,
+            Self::Iterm => write_iterm(&mut write, width, height, rgb8_data)?,
+        }
+        writeln!(write)?;
+        write.flush()?;
+        Ok(())
+    }
+}
+
+/// Nearest neighbor downscale so that the result is at most `max_width` pixels wide
+pub fn downscale(
+    width: usize,
+    height: usize,
+    rgb8_data: &[u8],
+    max_width: usize,
+) -> (usize, usize, Vec<u8>) {
+    if width <= max_width {
+        return (width, height, rgb8_data.to_vec());
+    }
+
+    let out_width = max_width;
+    let out_height = (height * max_width / width).max(1);
+    let mut out = Vec::with_capacity(out_width * out_height * COLOR_CHANNELS);
+    for y in 0..out_height {
+        for x in 0..out_width {
+            let pixel = (y * height / out_height) * width + x * width / out_width;
+            out.extend_from_slice(&rgb8_data[pixel * COLOR_CHANNELS..][..COLOR_CHANNELS]);
+        }
+    }
+
+    (out_width, out_height, out)
+}
+
+fn base64(data: &[u8]) -> String {
+    const TABLE: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut out = String::with_capacity(data.len().div_ceil(3) * 4);
+    for group in data.chunks(3) {
+        let b = [
+            group[0],
+            *group.get(1).unwrap_or(&0),
+            *group.get(2).unwrap_or(&0),
+        ];
+        let n = u32::from(b[0]) << 16 | u32::from(b[1]) << 8 | u32::from(b[2]);
+        for i in 0..4 {
+            if i <= group.len() {
+                out.push(TABLE[(n >> (18 - 6 * i) & 63) as usize] as char);
+            } else {
+                out.push('=');
+            }
+        }
+    }
+    out
+}
+
+fn write_kitty(write: &mut impl Write, width: usize, height: usize, rgb8: &[u8]) -> Result<()> {
+    // Payload has to be sent in chunks of at most 4096 bytes
+    let payload = base64(rgb8);
+    let mut chunks = payload.as_bytes().chunks(4096).peekable();
+    let mut first = true;
+    while let Some(chunk) = chunks.next() {
+        let more = chunks.peek().is_some() as u8;
+        if first {
+            write!(write, "\x1b_Ga=T,f=24,s={},v={},m={};", width, height, more)?;
+            first = false;
+        } else {
+            write!(write, "\x1b_Gm={};", more)?;
+        }
+        write.write_all(chunk)?;
+        write!(write, "\x1b\\")?;
+    }
+    Ok(())
+}
+
+fn write_iterm(write: &mut impl Write, width: usize, height: usize, rgb8: &[u8]) -> Result<()> {
+    let mut png_data = Vec::new();
+    rt::write_png(&mut png_data, width, height, rgb8)?;
+    write!(
+        write,
+        "\x1b]1337;File=inline=1;size={};width={}px;height={}px:{}\x07",
+        png_data.len(),
+        width,
+        height,
+        base64(&png_data)
+    )?;
+    Ok(())
+}
+
+fn write_sixel(write: &mut impl Write, width: usize, height: usize, rgb8: &[u8]) -> Result<()> {
+    // Quantize to a 6x6x6 color cube
+    const LEVELS: usize = 6;
+    let index = |c: u8| usize::from(c) * LEVELS / 256;
+    let palette_index: Vec<usize> = rgb8
+        .chunks(COLOR_CHANNELS)
+        .map(|c| (index(c[0]) * LEVELS + index(c[1])) * LEVELS + index(c[2]))
+        .collect();
+
+    // Enter sixel mode with square pixels and declare raster attributes
+    write!(write, "\x1bPq\"1;1;{};{}", width, height)?;
+    for i in 0..LEVELS.pow(3) {
+        let percent = |level| level * 100 / (LEVELS - 1);
+        let (r, g, b) = (i / LEVELS / LEVELS, i / LEVELS % LEVELS, i % LEVELS);
+        write!(
+            write,
+            "#{};2;{};{};{}",
+            i,
+            percent(r),
+            percent(g),
+            percent(b)
+        )?;
+    }
+
+    // Each sixel covers a column of 6 pixels, so proceed in bands of 6 rows
+    let mut used = vec![false; LEVELS.pow(3)];
+    for band in (0..height).step_by(6) {
+        let rows = 6.min(height - band);
+        used.iter_mut().for_each(|u| *u = false);
+        for y in band..band + rows {
+            for &i in &palette_index[y * width..][..width] {
+                used[i] = true;
+            }
+        }
+
+        for color in (0..used.len()).filter(|&c| used[c]) {
+            write!(write, "#{}", color)?;
+            let mut run = (0u8, 0usize);
+            for x in 0..width {
+                let mut bits = 0u8;
+                for row in 0..rows {
+                    if palette_index[(band + row) * width + x] == color {
+                        bits |= 1 << row;
+                    }
+                }
+                if bits == run.0 {
+                    run.1 += 1;
+                } else {
+                    write_sixel_run(write, run)?;
+                    run = (bits, 1);
+                }
+            }
+            write_sixel_run(write, run)?;
+            // Carriage return to overlay the next color on the same band
+            write!(write, "$")?;
+        }
+        write!(write, "-")?;
+    }
+
+    write!(write, "\x1b\\")?;
+    Ok(())
+}
+
+fn write_sixel_run(write: &mut impl Write, (bits, count): (u8, usize)) -> Result<()> {
+    let c = char::from(63 + bits);
+    match count {
+        0 => {}
+        1..=3 => write!(write, "{}", c.to_string().repeat(count))?,
+        _ => write!(write, "!{}{}", u32::try_from(count)?, c)?,
+    }
+    Ok(())
+}
@@ -0,0 +1,522 @@
+//! Textures of materials in half precision with mip levels, kept in memory or on disk in tiles
+//! which are read as they are needed
+
+use crate::{
+    color::{from_half, to_half},
+    image::Image,
+};
+use anyhow::{anyhow, Context, Result};
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{BTreeMap, HashMap},
+    convert::TryInto,
+    fs::{self, File, OpenOptions},
+    io::{self, BufWriter, Seek, SeekFrom, Write},
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+};
+use ultraviolet::{Vec2, Vec3};
+
+#[cfg(not(unix))]
+use std::io::Read;
+
+/// Width and height of the pieces that textures on disk are read in
+const TILE_SIZE: usize = 64;
+const TILE_BYTES: usize = TILE_SIZE * TILE_SIZE * 3 * 2;
+
+/// Limit on the memory used for the textures of materials, beyond which the least recently
+/// used parts are dropped and read again from disk when needed
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct TextureCacheOptions {
+    pub megabytes: usize,
+    /// Where the textures are kept while rendering, by default the temporary directory of
+    /// the system
+    #[serde(default)]
+    pub directory: Option<PathBuf>,
+}
+
+/// Image which is sampled at texture coordinates
+pub enum Texture {
+    Resident(HalfImage),
+    Cached(CachedTexture),
+}
+
+/// Image with half precision pixels at each mip level
+pub struct HalfImage {
+    levels: Vec<Level>,
+    average: Vec3,
+}
+
+/// Half precision pixels of a mip level, top row first
+struct Level {
+    width: usize,
+    height: usize,
+    pixels: Vec<[u16; 3]>,
+}
+
+impl From<Image> for Texture {
+    fn from(image: Image) -> Self {
+        Self::Resident(HalfImage {
+            levels: mip_levels(&image)
+                .into_iter()
+                .map(|(width, height, pixels)| Level {
+                    width,
+                    height,
+                    pixels: pixels.into_iter().map(to_half).collect(),
+                })
+                .collect(),
+            average: image.average(),
+        })
+    }
+}
+
+/// Width, height and pixels of `image` followed by those of images half as large down to one
+/// pixel, each pixel averaging the pixels it covers in the previous level
+fn mip_levels(image: &Image) -> Vec<(usize, usize, Vec<Vec3>)> {
+    let mut levels = vec![(image.width, image.height, image.pixels.clone())];
+    loop {
+        let (width, height, pixels) = levels.last().expect("There is a level");
+        if *width == 1 && *height == 1 {
+            return levels;
+        }
+        let (next_width, next_height) = (width.div_ceil(2), height.div_ceil(2));
+        let mut next = Vec::with_capacity(next_width * next_height);
+        for y in 0..next_height {
+            for x in 0..next_width {
+                // Odd edges repeat their last pixel
+                let pixel = |dx: usize, dy: usize| {
+                    pixels[(y * 2 + dy).min(height - 1) * width + (x * 2 + dx).min(width - 1)]
+                };
+                next.push((pixel(0, 0) + pixel(1, 0) + pixel(0, 1) + pixel(1, 1)) * 0.25);
+            }
+        }
+        levels.push((next_width, next_height, next));
+    }
+}
+
+/// Mip level out of `levels` where a pixel covers the change of the texture coordinates
+/// `duv` from one pixel of the image to the next, for a texture of `width` and `height`
+fn mip_level(duv: [Vec2; 2], width: usize, height: usize, levels: usize) -> usize {
+    let size = Vec2::new(width as f32, height as f32);
+    let footprint = (duv[0] * size).mag().max((duv[1] * size).mag());
+    if footprint > 1. {
+        (footprint.log2().round() as usize).min(levels - 1)
+    } else {
+        0
+    }
+}
+
+/// Column and row of the nearest pixel to texture coordinates `uv`, like [`Image::sample`]
+fn nearest(uv: Vec2, width: usize, height: usize) -> (usize, usize) {
+    let (u, v) = (uv.x.rem_euclid(1.), 1. - uv.y.rem_euclid(1.));
+    let x = ((u * width as f32) as usize).min(width - 1);
+    let y = ((v * height as f32) as usize).min(height - 1);
+    (x, y)
+}
+
+impl Texture {
+    /// Nearest pixel to texture coordinates `uv`, like [`Image::sample`], at the mip level
+    /// where a pixel covers `duv`, the change of the texture coordinates from one pixel of the
+    /// image to the next along x and y. Zero samples the full resolution.
+    pub fn sample(&self, uv: Vec2, duv: [Vec2; 2]) -> Vec3 {
+        match self {
+            Self::Resident(image) => {
+                let base = &image.levels[0];
+                let level =
+                    &image.levels[mip_level(duv, base.width, base.height, image.levels.len())];
+                let (x, y) = nearest(uv, level.width, level.height);
+                from_half(level.pixels[y * level.width + x])
+            }
+            Self::Cached(texture) => texture.sample(uv, duv),
+        }
+    }
+
+    /// Mean of the pixels, at full precision
+    pub fn average(&self) -> Vec3 {
+        match self {
+            Self::Resident(image) => image.average,
+            Self::Cached(texture) => texture.average,
+        }
+    }
+}
+
+/// Tiles of textures read from disk, of which the least recently used are dropped to stay
+/// within a memory budget
+pub struct TextureCache {
+    directory: PathBuf,
+    /// Number of tiles which fit in the budget of each shard
+    capacity: usize,
+    next_id: AtomicUsize,
+    /// Tiles are spread over shards with a lock of their own, so that threads sampling
+    /// different tiles seldom wait for each other
+    shards: Vec<Mutex<Tiles>>,
+}
+
+/// Number of independently locked parts of a [`TextureCache`]
+const SHARDS: usize = 16;
+
+/// Texture and tile number
+type TileKey = (usize, usize);
+/// Half precision pixels of a tile, row by row
+type TilePixels = Arc<Vec<[u16; 3]>>;
+
+#[derive(Default)]
+struct Tiles {
+    /// Pixels of tiles by texture and tile number, and when they were last used
+    resident: HashMap<TileKey, (TilePixels, u64)>,
+    /// Tiles by when they were last used
+    uses: BTreeMap<u64, TileKey>,
+    clock: u64,
+}
+
+impl Tiles {
+    /// Pixels of the tile `key` if it is in memory, marking it used
+    fn get(&mut self, key: TileKey) -> Option<TilePixels> {
+        self.clock += 1;
+        let (pixels, used) = self.resident.get_mut(&key)?;
+        self.uses.remove(used);
+        *used = self.clock;
+        self.uses.insert(self.clock, key);
+        Some(Arc::clone(pixels))
+    }
+
+    /// Keep `pixels` of the tile `key`, dropping the least recently used tile if there are
+    /// already `capacity` tiles
+    fn insert(&mut self, key: TileKey, pixels: TilePixels, capacity: usize) {
+        if self.resident.len() >= capacity {
+            if let Some((_, oldest)) = self.uses.pop_first() {
+                self.resident.remove(&oldest);
+            }
+        }
+        self.clock += 1;
+        self.resident.insert(key, (pixels, self.clock));
+        self.uses.insert(self.clock, key);
+    }
+}
+
+impl TextureCache {
+    pub fn new(options: &TextureCacheOptions) -> Arc<Self> {
+        let capacity = options.megabytes * 1024 * 1024 / TILE_BYTES;
+        Arc::new(Self {
+            directory: options.directory.clone().unwrap_or_else(std::env::temp_dir),
+            capacity: (capacity / SHARDS).max(1),
+            next_id: AtomicUsize::new(0),
+            shards: (0..SHARDS).map(|_| Mutex::new(Tiles::default())).collect(),
+        })
+    }
+
+    /// Write the image of `width` by `height` pixels whose `rows` are read one at a time, top
+    /// row first, to disk in tiles, and return a texture which reads them through this cache.
+    /// Only a band of rows of each mip level is in memory at a time.
+    pub fn insert(
+        self: &Arc<Self>,
+        width: usize,
+        height: usize,
+        rows: impl Iterator<Item = Result<Vec<Vec3>>>,
+    ) -> Result<Texture> {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let path = self
+            .directory
+            .join(format!("rt-texture-{}-{}.tiles", std::process::id(), id));
+        let mut writer = BufWriter::new(
+            OpenOptions::new()
+                .read(true)
+                .write(true)
+                .create(true)
+                .truncate(true)
+                .open(&path)
+                .with_context(|| format!("Cannot create texture cache file {}", path.display()))?,
+        );
+        // Levels follow each other in the file
+        let mut levels: Vec<LevelWriter> = Vec::new();
+        let (mut level_width, mut level_height, mut first_tile) = (width, height, 0);
+        loop {
+            let level = CachedLevel {
+                width: level_width,
+                height: level_height,
+                tiles_x: level_width.div_ceil(TILE_SIZE),
+                first_tile,
+            };
+            first_tile += level.tiles_x * level_height.div_ceil(TILE_SIZE);
+            levels.push(LevelWriter::new(level));
+            if level_width == 1 && level_height == 1 {
+                break;
+            }
+            (level_width, level_height) = (level_width.div_ceil(2), level_height.div_ceil(2));
+        }
+
+        let mut sum = Vec3::zero();
+        let mut count = 0;
+        for row in rows {
+            let row = row?;
+            if row.len() != width || count == height {
+                return Err(anyhow!("Image has rows of the wrong size or too many rows"));
+            }
+            sum = row.iter().fold(sum, |sum, &pixel| sum + pixel);
+            count += 1;
+            // Each row is written to its level and completes every other row of the next
+            let mut row = Some(row);
+            for level in &mut levels {
+                row = match row {
+                    Some(row) => level.push(row, &mut writer)?,
+                    None => break,
+                };
+            }
+        }
+        if count != height || height == 0 {
+            return Err(anyhow!("Image has too few rows"));
+        }
+        let file = writer.into_inner().map_err(|e| e.into_error())?;
+        Ok(Texture::Cached(CachedTexture {
+            cache: Arc::clone(self),
+            id,
+            levels: levels.into_iter().map(|level| level.level).collect(),
+            average: sum / (width * height) as f32,
+            file: SharedFile::new(file),
+            path,
+        }))
+    }
+
+    /// Pixels of tile number `tile` of `texture`, read from its file if they aren't in memory
+    fn tile(&self, texture: &CachedTexture, tile: usize) -> io::Result<TilePixels> {
+        let key = (texture.id, tile);
+        let shard = &self.shards[(texture.id + tile) % SHARDS];
+        if let Some(pixels) = shard.lock().get(key) {
+            return Ok(pixels);
+        }
+        // Other threads use the cache while the tile is read
+        let pixels = Arc::new(texture.read_tile(tile)?);
+        let tiles = &mut *shard.lock();
+        match tiles.get(key) {
+            // Read by another thread in the meantime
+            Some(pixels) => Ok(pixels),
+            None => {
+                tiles.insert(key, Arc::clone(&pixels), self.capacity);
+                Ok(pixels)
+            }
+        }
+    }
+
+    /// Drop the tiles of the texture `id`
+    fn remove(&self, id: usize) {
+        for shard in &self.shards {
+            let Tiles { resident, uses, .. } = &mut *shard.lock();
+            resident.retain(|&(texture, _), (_, used)| {
+                if texture == id {
+                    uses.remove(used);
+                }
+                texture != id
+            });
+        }
+    }
+}
+
+/// Mip level of a texture being written to disk, with the rows of its current band of tiles
+struct LevelWriter {
+    level: CachedLevel,
+    band: Vec<Vec3>,
+    /// Number of rows received
+    rows: usize,
+    /// Row waiting for the next one to be averaged with into a row of the next level
+    pending: Option<Vec<Vec3>>,
+}
+
+impl LevelWriter {
+    fn new(level: CachedLevel) -> Self {
+        Self {
+            band: Vec::with_capacity(level.width * TILE_SIZE),
+            level,
+            rows: 0,
+            pending: None,
+        }
+    }
+
+    /// Add the next row, writing the tiles of the band when it is complete. Returns the next
+    /// row of the level half as large when it can be made, each pixel averaging the pixels
+    /// it covers like in [`mip_levels`].
+    fn push(
+        &mut self,
+        row: Vec<Vec3>,
+        writer: &mut BufWriter<File>,
+    ) -> io::Result<Option<Vec<Vec3>>> {
+        let CachedLevel {
+            width,
+            height,
+            tiles_x,
+            first_tile,
+        } = self.level;
+        self.band.extend_from_slice(&row);
+        self.rows += 1;
+        if self.rows.is_multiple_of(TILE_SIZE) || self.rows == height {
+            let tile_y = (self.rows - 1) / TILE_SIZE;
+            let band_height = self.band.len() / width;
+            writer.seek(SeekFrom::Start(
+                ((first_tile + tile_y * tiles_x) * TILE_BYTES) as u64,
+            ))?;
+            // Tiles are padded past the edges of the image to keep them the same size
+            for x0 in (0..tiles_x).map(|tile| tile * TILE_SIZE) {
+                for y in 0..TILE_SIZE {
+                    for x in x0..x0 + TILE_SIZE {
+                        let pixel = if x < width && y < band_height {
+                            self.band[y * width + x]
+                        } else {
+                            Vec3::zero()
+                        };
+                        for channel in to_half(pixel) {
+                            writer.write_all(&channel.to_le_bytes())?;
+                        }
+                    }
+                }
+            }
+            self.band.clear();
+        }
+
+        if width == 1 && height == 1 {
+            return Ok(None);
+        }
+        // Odd edges repeat their last pixel
+        let (above, below) = match self.pending.take() {
+            Some(above) => (above, row),
+            None if self.rows == height => (row.clone(), row),
+            None => {
+                self.pending = Some(row);
+                return Ok(None);
+            }
+        };
+        let pixel = |x: usize| {
+            let x1 = (x + 1).min(width - 1);
+            (above[x] + above[x1] + below[x] + below[x1]) * 0.25
+        };
+        Ok(Some((0..width.div_ceil(2)).map(|x| pixel(x * 2)).collect()))
+    }
+}
+
+/// File which threads read from at any offset at the same time
+struct SharedFile {
+    #[cfg(unix)]
+    file: File,
+    #[cfg(not(unix))]
+    file: Mutex<File>,
+}
+
+impl SharedFile {
+    fn new(file: File) -> Self {
+        Self {
+            #[cfg(unix)]
+            file,
+            #[cfg(not(unix))]
+            file: Mutex::new(file),
+        }
+    }
+
+    #[cfg(unix)]
+    fn read_exact_at(&self, bytes: &mut [u8], offset: u64) -> io::Result<()> {
+        std::os::unix::fs::FileExt::read_exact_at(&self.file, bytes, offset)
+    }
+
+    #[cfg(not(unix))]
+    fn read_exact_at(&self, bytes: &mut [u8], offset: u64) -> io::Result<()> {
+        let mut file = self.file.lock();
+        file.seek(SeekFrom::Start(offset))?;
+        file.read_exact(bytes)
+    }
+}
+
+/// Texture whose pixels are in a file, see [`TextureCache::insert`]. Tiles which can't be read
+/// from the file, such as when it was deleted while rendering, are sampled as the average of
+/// the texture.
+pub struct CachedTexture {
+    cache: Arc<TextureCache>,
+    id: usize,
+    levels: Vec<CachedLevel>,
+    average: Vec3,
+    file: SharedFile,
+    path: PathBuf,
+}
+
+/// Mip level of a [`CachedTexture`]
+#[derive(Clone, Copy)]
+struct CachedLevel {
+    width: usize,
+    height: usize,
+    tiles_x: usize,
+    /// Number of the first tile of the level in the file
+    first_tile: usize,
+}
+
+impl CachedTexture {
+    fn sample(&self, uv: Vec2, duv: [Vec2; 2]) -> Vec3 {
+        let base = &self.levels[0];
+        let level = &self.levels[mip_level(duv, base.width, base.height, self.levels.len())];
+        let (x, y) = nearest(uv, level.width, level.height);
+        let tile = level.first_tile + y / TILE_SIZE * level.tiles_x + x / TILE_SIZE;
+        match self.cache.tile(self, tile) {
+            Ok(pixels) => from_half(pixels[y % TILE_SIZE * TILE_SIZE + x % TILE_SIZE]),
+            Err(_) => self.average,
+        }
+    }
+
+    fn read_tile(&self, tile: usize) -> io::Result<Vec<[u16; 3]>> {
+        let mut bytes = vec![0; TILE_BYTES];
+        self.file
+            .read_exact_at(&mut bytes, (tile * TILE_BYTES) as u64)?;
+        Ok(bytes
+            .chunks_exact(6)
+            .map(|pixel| {
+                let channel = |i: usize| {
+                    u16::from_le_bytes(pixel[i * 2..i * 2 + 2].try_into().expect("2 bytes"))
+                };
+                [channel(0), channel(1), channel(2)]
+            })
+            .collect())
+    }
+}
+
+impl Drop for CachedTexture {
+    fn drop(&mut self) {
+        self.cache.remove(self.id);
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cached_textures_match_resident_ones() {
+        // Odd sizes which span several tiles, so that edges are repeated and tiles padded
+        let (width, height) = (TILE_SIZE * 2 + 7, TILE_SIZE + 3);
+        let pixels = (0..width * height)
+            .map(|i| Vec3::new((i % width) as f32, (i / width) as f32, (i % 13) as f32) * 0.01)
+            .collect();
+        let image = Image {
+            width,
+            height,
+            pixels,
+        };
+        let rows: Vec<_> = image
+            .pixels
+            .chunks(width)
+            .map(|row| Ok(row.to_vec()))
+            .collect();
+        let cache = TextureCache::new(&TextureCacheOptions {
+            megabytes: 1,
+            directory: None,
+        });
+        let cached = cache.insert(width, height, rows.into_iter()).unwrap();
+        let resident = Texture::from(image);
+        assert_eq!(cached.average(), resident.average());
+        for level in 0..9 {
+            let duv = [Vec2::new((1 << level) as f32 / width as f32, 0.); 2];
+            for i in 0..997 {
+                let uv = Vec2::new(i as f32 / 997., (i * 7 % 997) as f32 / 997.);
+                assert_eq!(cached.sample(uv, duv), resident.sample(uv, duv));
+            }
+        }
+    }
+}
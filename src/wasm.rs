@@ -0,0 +1,77 @@
+//! C ABI entry points for running the renderer in a browser, see `examples/wasm`.
+//! Memory for the output buffers is managed with [`rt_alloc`] and [`rt_free`].
+
+use crate::{
+    color::COLOR_CHANNELS,
+    render::{Renderer, Tile},
+    scene::Scene,
+};
+use rand::prelude::*;
+use rand_xorshift::XorShiftRng;
+use std::cell::RefCell;
+
+thread_local! {
+    static STATE: RefCell<Option<Renderer>> = const { RefCell::new(None) };
+}
+
+#[no_mangle]
+pub extern "C" fn rt_alloc(len: usize) -> *mut u8 {
+    let mut buffer = Vec::<u8>::with_capacity(len);
+    let ptr = buffer.as_mut_ptr();
+    std::mem::forget(buffer);
+    ptr
+}
+
+/// # Safety
+///
+/// `ptr` and `len` must come from a previous call to [`rt_alloc`].
+#[no_mangle]
+pub unsafe extern "C" fn rt_free(ptr: *mut u8, len: usize) {
+    drop(Vec::from_raw_parts(ptr, 0, len));
+}
+
+/// Generate the random scene from `seed` and prepare to render it
+#[no_mangle]
+pub extern "C" fn init(seed: u32, width: u32, height: u32, samples_per_pixel: u32) {
+    let scene = Scene::random(&mut XorShiftRng::seed_from_u64(seed.into()));
+    let renderer = Renderer::new(
+        &scene,
+        0,
+        width as usize,
+        height as usize,
+        samples_per_pixel,
+    )
+    .expect("Random scene is valid");
+    STATE.with(|state| {
+        *state.borrow_mut() = Some(renderer);
+    });
+}
+
+/// Render a rectangle of the image as 8bpp RGB into `out`, which must have room for
+/// `width * height * 3` bytes. Returns the number of bytes written, which is smaller when the
+/// rectangle extends past the edges of the image, or 0 if [`init`] hasn't been called.
+///
+/// # Safety
+///
+/// `out` must be valid for writes of `width * height * 3` bytes.
+#[no_mangle]
+pub unsafe extern "C" fn render_tile(x: u32, y: u32, width: u32, height: u32, out: *mut u8) -> u32 {
+    STATE.with(|state| {
+        let state = state.borrow();
+        let renderer = match state.as_ref() {
+            Some(renderer) => renderer,
+            None => return 0,
+        };
+
+        let mut tile = Vec::with_capacity((width * height) as usize * COLOR_CHANNELS);
+        let tile_rect = Tile {
+            x: x as usize,
+            y: y as usize,
+            width: width as usize,
+            height: height as usize,
+        };
+        renderer.render_tile(&tile_rect, &mut tile);
+        std::ptr::copy_nonoverlapping(tile.as_ptr(), out, tile.len());
+        tile.len() as u32
+    })
+}
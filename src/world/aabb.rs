@@ -2,47 +2,78 @@ use crate::Ray;
 use std::ops::Range;
 use ultraviolet::Vec3;
 
-pub struct Aabb(Range<Vec3>);
+#[derive(Clone, Copy, Debug)]
+pub struct Aabb {
+    pub min: Vec3,
+    pub max: Vec3,
+}
 
 impl Aabb {
     pub fn new(range: Range<Vec3>) -> Self {
-        Self(range)
+        Self {
+            min: range.start,
+            max: range.end,
+        }
+    }
+
+    /// Box which contains nothing, the identity of [`Aabb::union`]
+    pub fn empty() -> Self {
+        Self {
+            min: Vec3::broadcast(f32::INFINITY),
+            max: Vec3::broadcast(f32::NEG_INFINITY),
+        }
     }
 
     pub fn surrounding(ranges: Range<Range<Vec3>>) -> Self {
-        Self(
-            ranges.start.start.min_by_component(ranges.end.start)
-                ..ranges.start.end.max_by_component(ranges.end.end),
-        )
+        Self::new(ranges.start).union(&Self::new(ranges.end))
     }
 
-    pub fn hit(&self, ray: &Ray, t_range: Range<f32>) -> bool {
-        let mut t_min = t_range.start;
-        let mut t_max = t_range.end;
-
-        for a in 0..3 {
-            // Extract vector components
-            let inv_direction = 1. / ray.direction().as_slice()[a];
-            let start = self.0.start.as_slice()[a];
-            let end = self.0.end.as_slice()[a];
-            let origin = ray.origin().as_slice()[a];
-
-            // Compute t-intevals for each component of ray and AABB vectors
-            let mut t0 = (start - origin) * inv_direction;
-            let mut t1 = (end - origin) * inv_direction;
-
-            // Sort
-            if inv_direction < 0. {
-                std::mem::swap(&mut t0, &mut t1);
-            }
-            t_min = t0.max(t_min);
-            t_max = t1.min(t_max);
-
-            if t_max <= t_min {
-                return false; // No overlap in t-interval
-            }
+    pub fn union(&self, other: &Self) -> Self {
+        Self {
+            min: self.min.min_by_component(other.min),
+            max: self.max.max_by_component(other.max),
+        }
+    }
+
+    pub fn grow(&self, point: Vec3) -> Self {
+        Self {
+            min: self.min.min_by_component(point),
+            max: self.max.max_by_component(point),
         }
+    }
+
+    pub fn centroid(&self) -> Vec3 {
+        (self.min + self.max) * 0.5
+    }
+
+    /// Surface area, zero for empty boxes
+    pub fn area(&self) -> f32 {
+        let d = (self.max - self.min).max_by_component(Vec3::zero());
+        2. * (d.x * d.y + d.y * d.z + d.z * d.x)
+    }
+
+    /// Slab test with the reciprocal direction and signs precomputed in `ray`. The near and far
+    /// planes on each axis are picked by the sign instead of comparing both distances.
+    pub fn hit(&self, ray: &Ray, t_range: Range<f32>) -> bool {
+        let bounds = [self.min, self.max];
+        let (origin, inv_direction) = (ray.origin(), ray.inv_direction());
+        let near = Vec3::new(
+            bounds[ray.sign(0)].x,
+            bounds[ray.sign(1)].y,
+            bounds[ray.sign(2)].z,
+        );
+        let far = Vec3::new(
+            bounds[1 - ray.sign(0)].x,
+            bounds[1 - ray.sign(1)].y,
+            bounds[1 - ray.sign(2)].z,
+        );
+        let t_near = (near - origin) * inv_direction;
+        let t_far = (far - origin) * inv_direction;
 
-        true
+        // Overlap of the intervals on all axes, which is a single point for boxes which are flat
+        // on an axis, like those of triangles in an axis plane
+        let t_min = t_near.component_max().max(t_range.start);
+        let t_max = t_far.component_min().min(t_range.end);
+        t_min <= t_max
     }
 }
@@ -0,0 +1,708 @@
+//! Bounding volume hierarchy built with a binned surface area heuristic
+
+mod cache;
+mod compact;
+mod wide;
+
+use self::{compact::CompactNode, wide::WideNode};
+use super::aabb::Aabb;
+use crate::Ray;
+use serde::{Deserialize, Serialize};
+use std::{ops::Range, path::PathBuf, time::Duration};
+use ultraviolet::{f32x4, f32x8, Vec3};
+
+/// Relative cost of testing a ray against the bounds of a node
+const TRAVERSAL_COST: f32 = 1.;
+/// Relative cost of testing a ray against a primitive
+const INTERSECTION_COST: f32 = 1.;
+/// Deeper subtrees are split at the median so that traversal fits in a fixed size stack
+const MAX_SAH_DEPTH: usize = 32;
+const STACK_SIZE: usize = 64;
+/// Largest number of rays traced together by [`Bvh::traverse_packet`]
+pub const MAX_PACKET_SIZE: usize = 8;
+/// Subtrees with fewer primitives than this are not worth a thread of their own
+#[cfg(feature = "threads")]
+const PARALLEL_BUILD_THRESHOLD: usize = 4096;
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct BvhOptions {
+    /// Number of candidate split positions per axis
+    pub bins: usize,
+    /// Nodes with more primitives than this are always split
+    pub max_leaf_size: usize,
+    /// Children per node when tracing rays, 2, 4 or 8. Wider nodes are tested with SIMD.
+    pub width: usize,
+    /// Directory for storing built hierarchies, which are reused for identical geometry. The
+    /// meshes themselves aren't cached, so scenes are still read and their bounds computed.
+    #[serde(skip)]
+    pub cache: Option<PathBuf>,
+    /// Trace rays through nodes with quantized bounds, which take 12 instead of 32 bytes.
+    /// Overrides `width`, and packets of rays are traced one ray at a time.
+    #[serde(skip)]
+    pub compact: bool,
+}
+
+impl Default for BvhOptions {
+    fn default() -> Self {
+        Self {
+            bins: 16,
+            max_leaf_size: 4,
+            width: 2,
+            cache: None,
+            compact: false,
+        }
+    }
+}
+
+impl BvhOptions {
+    /// Nodes with more primitives than this are always split, so that their count fits in a
+    /// node
+    fn leaf_size_limit(&self) -> usize {
+        let limit = if self.compact {
+            compact::MAX_LEAF_SIZE
+        } else {
+            u16::MAX.into()
+        };
+        self.max_leaf_size.clamp(1, limit)
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct BvhStats {
+    pub nodes: usize,
+    pub leaves: usize,
+    /// Memory taken by the nodes, including those of the wide layout
+    pub bytes: usize,
+    /// Expected cost of tracing a ray, relative to intersecting one primitive
+    pub sah_cost: f32,
+    /// Time spent building or loading the hierarchy
+    pub build_time: Duration,
+    /// Whether the hierarchy was loaded from [`BvhOptions::cache`]
+    pub cached: bool,
+}
+
+/// Nodes are stored depth first, so the first child of an interior node directly follows it.
+/// At 32 bytes, two nodes fit in a cache line.
+#[derive(Clone, Copy)]
+struct Node {
+    bounds: Aabb,
+    /// Index of the first primitive of a leaf, or of the second child of an interior node
+    offset: u32,
+    /// Number of primitives, zero for interior nodes
+    count: u16,
+    /// Axis along which an interior node was split
+    axis: u16,
+}
+
+const _: () = assert!(std::mem::size_of::<Node>() == 32);
+
+impl Node {
+    fn leaf(bounds: Aabb, primitives: Range<usize>) -> Self {
+        Self {
+            bounds,
+            offset: primitives.start as u32,
+            count: primitives.len() as u16,
+            axis: 0,
+        }
+    }
+
+    fn interior(bounds: Aabb, second_child: u32, axis: usize) -> Self {
+        Self {
+            bounds,
+            offset: second_child,
+            count: 0,
+            axis: axis as u16,
+        }
+    }
+
+    fn is_leaf(&self) -> bool {
+        self.count > 0
+    }
+
+    fn primitives(&self) -> Range<usize> {
+        self.offset as usize..self.offset as usize + self.count as usize
+    }
+}
+
+struct Item {
+    bounds: Aabb,
+    centroid: Vec3,
+    index: usize,
+}
+
+/// Nodes used for traversal
+enum Layout {
+    Binary,
+    Wide4(Vec<WideNode<f32x4, 4>>),
+    Wide8(Vec<WideNode<f32x8, 8>>),
+    /// Bounds of the root with the nodes, which replace the binary ones
+    Compact(Aabb, Vec<CompactNode>),
+}
+
+pub struct Bvh {
+    nodes: Vec<Node>,
+    layout: Layout,
+    stats: BvhStats,
+}
+
+impl Bvh {
+    /// Build a hierarchy over primitives with the given bounds. Returns the order in which the
+    /// primitives have to be stored, because leaves refer to consecutive primitives.
+    pub fn build(bounds: &[Aabb], options: &BvhOptions) -> (Self, Vec<usize>) {
+        let cache_path = options
+            .cache
+            .as_ref()
+            .map(|dir| dir.join(format!("{:016x}.bvh", cache::key(bounds, options))));
+        if let Some(path) = &cache_path {
+            // A missing or invalid cache file just means that the hierarchy has to be built
+            if let (Ok((nodes, order)), load_time) = timed(|| cache::read(path, bounds.len())) {
+                return (Self::new(nodes, options, load_time, true), order);
+            }
+        }
+
+        let ((nodes, order), build_time) = timed(|| {
+            let mut items: Vec<Item> = bounds
+                .iter()
+                .enumerate()
+                .map(|(index, bounds)| Item {
+                    bounds: *bounds,
+                    centroid: bounds.centroid(),
+                    index,
+                })
+                .collect();
+            let mut nodes = Vec::new();
+            if !items.is_empty() {
+                #[cfg(feature = "threads")]
+                let threads = num_cpus::get();
+                #[cfg(not(feature = "threads"))]
+                let threads = 1;
+                build_node(&mut items, 0, 0, options, &mut nodes, threads);
+            }
+            (
+                nodes,
+                items.iter().map(|item| item.index).collect::<Vec<_>>(),
+            )
+        });
+
+        if let Some(path) = &cache_path {
+            // Caching is an optimization, so failing to write is not an error
+            let _ = cache::write(path, &nodes, &order);
+        }
+        (Self::new(nodes, options, build_time, false), order)
+    }
+
+    fn new(nodes: Vec<Node>, options: &BvhOptions, build_time: Duration, cached: bool) -> Self {
+        let layout = match options.width {
+            _ if options.compact => {
+                let (root, nodes) = compact::compress(&nodes);
+                Layout::Compact(root, nodes)
+            }
+            4 => Layout::Wide4(wide::collapse(&nodes)),
+            8 => Layout::Wide8(wide::collapse(&nodes)),
+            _ => Layout::Binary,
+        };
+        let mut bvh = Self {
+            nodes,
+            layout,
+            stats: BvhStats::default(),
+        };
+        bvh.stats = BvhStats {
+            nodes: bvh.nodes.len(),
+            leaves: bvh.nodes.iter().filter(|node| node.is_leaf()).count(),
+            bytes: match &bvh.layout {
+                // The binary nodes are dropped once the compact ones are made
+                Layout::Compact(_, nodes) => std::mem::size_of_val(nodes.as_slice()),
+                layout => {
+                    std::mem::size_of_val(bvh.nodes.as_slice())
+                        + match layout {
+                            Layout::Wide4(nodes) => std::mem::size_of_val(nodes.as_slice()),
+                            Layout::Wide8(nodes) => std::mem::size_of_val(nodes.as_slice()),
+                            _ => 0,
+                        }
+                }
+            },
+            sah_cost: bvh.sah_cost(),
+            build_time,
+            cached,
+        };
+        if let Layout::Compact(..) = bvh.layout {
+            bvh.nodes = Vec::new();
+        }
+        bvh
+    }
+
+    pub fn stats(&self) -> &BvhStats {
+        &self.stats
+    }
+
+    fn sah_cost(&self) -> f32 {
+        let root_area = match self.nodes.first() {
+            Some(root) => root.bounds.area().max(f32::MIN_POSITIVE),
+            None => return 0.,
+        };
+        self.nodes
+            .iter()
+            .map(|node| {
+                let cost = if node.is_leaf() {
+                    INTERSECTION_COST * node.count as f32
+                } else {
+                    TRAVERSAL_COST
+                };
+                cost * node.bounds.area() / root_area
+            })
+            .sum()
+    }
+
+    /// Call `leaf` with the primitives of every leaf that the ray hits before `t_range.end`.
+    /// `leaf` gets the current maximum distance and returns a new one, which is lower when a
+    /// primitive was hit, or `f32::NEG_INFINITY` to stop. Nearer children are visited first, so
+    /// that farther ones can be culled.
+    pub fn traverse(
+        &self,
+        r: &Ray,
+        t_range: Range<f32>,
+        leaf: impl FnMut(Range<usize>, f32) -> f32,
+    ) {
+        match &self.layout {
+            Layout::Binary => self.traverse_binary(r, t_range, leaf),
+            Layout::Wide4(nodes) => wide::traverse(nodes, r, t_range, leaf),
+            Layout::Wide8(nodes) => wide::traverse(nodes, r, t_range, leaf),
+            Layout::Compact(root, nodes) => compact::traverse(root, nodes, r, t_range, leaf),
+        }
+    }
+
+    fn traverse_binary(
+        &self,
+        r: &Ray,
+        t_range: Range<f32>,
+        mut leaf: impl FnMut(Range<usize>, f32) -> f32,
+    ) {
+        if self.nodes.is_empty() {
+            return;
+        }
+
+        let mut t_max = t_range.end;
+        let mut stack = [0u32; STACK_SIZE];
+        let mut len = 0;
+        let mut index = 0;
+        loop {
+            let node = &self.nodes[index as usize];
+            if node.bounds.hit(r, t_range.start..t_max) {
+                if node.is_leaf() {
+                    t_max = leaf(node.primitives(), t_max);
+                    if t_max == f32::NEG_INFINITY {
+                        return;
+                    }
+                } else {
+                    // The first child is on the lower side of the split axis
+                    let (near, far) = if r.is_negative(node.axis as usize) {
+                        (node.offset, index + 1)
+                    } else {
+                        (index + 1, node.offset)
+                    };
+                    stack[len] = far;
+                    len += 1;
+                    index = near;
+                    continue;
+                }
+            }
+            if len == 0 {
+                break;
+            }
+            len -= 1;
+            index = stack[len];
+        }
+    }
+
+    /// Same as [`Bvh::traverse`] for up to [`MAX_PACKET_SIZE`] rays, which visit nodes
+    /// together as long as any of them hits, testing bounds for all rays at once with SIMD.
+    /// `t_max` has the maximum distance of each ray, and `leaf` also gets the index of the ray.
+    /// Packets always use the binary hierarchy, which is faster for them than wide ones.
+    pub fn traverse_packet(
+        &self,
+        rays: &[Ray],
+        t_min: f32,
+        t_max: &mut [f32],
+        mut leaf: impl FnMut(Range<usize>, usize, f32) -> f32,
+    ) {
+        assert!(rays.len() <= MAX_PACKET_SIZE && rays.len() == t_max.len());
+        if let Layout::Compact(root, nodes) = &self.layout {
+            // There is no binary hierarchy to trace packets through
+            for (i, r) in rays.iter().enumerate() {
+                let t_end = t_max[i];
+                compact::traverse(root, nodes, r, t_min..t_end, |primitives, t| {
+                    t_max[i] = leaf(primitives, i, t);
+                    t_max[i]
+                });
+            }
+            return;
+        }
+        if self.nodes.is_empty() || rays.is_empty() {
+            return;
+        }
+
+        // Rays as lanes, unused lanes never hit anything
+        let mut origins = [[0.; MAX_PACKET_SIZE]; 3];
+        let mut inv_directions = [[0.; MAX_PACKET_SIZE]; 3];
+        let mut t_max_lanes = [f32::NEG_INFINITY; MAX_PACKET_SIZE];
+        for (i, r) in rays.iter().enumerate() {
+            let inv_direction = r.inv_direction();
+            for axis in 0..3 {
+                origins[axis][i] = r.origin().as_slice()[axis];
+                inv_directions[axis][i] = inv_direction.as_slice()[axis];
+            }
+            t_max_lanes[i] = t_max[i];
+        }
+        let origins = origins.map(f32x8::from);
+        let inv_directions = inv_directions.map(f32x8::from);
+        let t_min = f32x8::splat(t_min);
+        let mut t_max_lanes = f32x8::from(t_max_lanes);
+
+        // Coherent rays mostly agree on the order in which to visit children
+        let direction = rays[0].direction();
+        let negative = [direction.x < 0., direction.y < 0., direction.z < 0.];
+        // Node indices with a mask of the rays which hit their parent
+        let mut stack = [(0u32, 0u32); STACK_SIZE];
+        let mut len = 0;
+        let (mut index, mut active) = (0, u32::MAX);
+        loop {
+            let node = &self.nodes[index as usize];
+            let mut near = t_min;
+            let mut far = t_max_lanes;
+            for axis in 0..3 {
+                let t0 = (f32x8::splat(node.bounds.min.as_slice()[axis]) - origins[axis])
+                    * inv_directions[axis];
+                let t1 = (f32x8::splat(node.bounds.max.as_slice()[axis]) - origins[axis])
+                    * inv_directions[axis];
+                near = near.max(t0.min(t1));
+                far = far.min(t0.max(t1));
+            }
+            let mut hits = wide::Lanes::less_equal(near, far) & active;
+            if hits != 0 {
+                if node.is_leaf() {
+                    while hits != 0 {
+                        let i = hits.trailing_zeros() as usize;
+                        hits &= hits - 1;
+                        t_max[i] = leaf(node.primitives(), i, t_max[i]);
+                    }
+                    let mut lanes = [f32::NEG_INFINITY; MAX_PACKET_SIZE];
+                    lanes[..t_max.len()].copy_from_slice(t_max);
+                    t_max_lanes = f32x8::from(lanes);
+                } else {
+                    let (near, far) = if negative[node.axis as usize] {
+                        (node.offset, index + 1)
+                    } else {
+                        (index + 1, node.offset)
+                    };
+                    stack[len] = (far, hits);
+                    len += 1;
+                    index = near;
+                    active = hits;
+                    continue;
+                }
+            }
+            if len == 0 {
+                break;
+            }
+            len -= 1;
+            (index, active) = stack[len];
+        }
+    }
+}
+
+/// Append the subtree of `items` to `nodes`, returning the index of its root.
+/// `first` is the position of `items` in the whole primitive array, and up to `threads`
+/// threads are used for building large subtrees.
+#[cfg_attr(not(feature = "threads"), allow(clippy::only_used_in_recursion))]
+fn build_node(
+    items: &mut [Item],
+    first: usize,
+    depth: usize,
+    options: &BvhOptions,
+    nodes: &mut Vec<Node>,
+    threads: usize,
+) -> u32 {
+    let bounds = items
+        .iter()
+        .fold(Aabb::empty(), |bounds, item| bounds.union(&item.bounds));
+    let index = nodes.len();
+    nodes.push(Node::leaf(bounds, first..first + items.len()));
+
+    if let Some((mid, axis)) = split(items, &bounds, depth, options) {
+        #[cfg(feature = "threads")]
+        let parallel = threads > 1 && items.len() >= PARALLEL_BUILD_THRESHOLD;
+        let (left, right) = items.split_at_mut(mid);
+        #[cfg(feature = "threads")]
+        let [left, right] = if parallel {
+            build_children_parallel([left, right], first, depth, options, nodes, threads)
+        } else {
+            [
+                build_node(left, first, depth + 1, options, nodes, 1),
+                build_node(right, first + mid, depth + 1, options, nodes, 1),
+            ]
+        };
+        #[cfg(not(feature = "threads"))]
+        let [left, right] = [
+            build_node(left, first, depth + 1, options, nodes, threads),
+            build_node(right, first + mid, depth + 1, options, nodes, threads),
+        ];
+        debug_assert_eq!(left as usize, index + 1);
+        nodes[index] = Node::interior(bounds, right, axis);
+    }
+    index as u32
+}
+
+/// Build the second subtree in another thread and append it after the first one
+#[cfg(feature = "threads")]
+fn build_children_parallel(
+    [left, right]: [&mut [Item]; 2],
+    first: usize,
+    depth: usize,
+    options: &BvhOptions,
+    nodes: &mut Vec<Node>,
+    threads: usize,
+) -> [u32; 2] {
+    let mid = left.len();
+    let mut right_nodes = Vec::new();
+    let right_threads = threads / 2;
+    let (left, right) = crossbeam_utils::thread::scope(|s| {
+        let right = s.spawn(|_| {
+            build_node(
+                right,
+                first + mid,
+                depth + 1,
+                options,
+                &mut right_nodes,
+                right_threads,
+            )
+        });
+        let left = build_node(
+            left,
+            first,
+            depth + 1,
+            options,
+            nodes,
+            threads - right_threads,
+        );
+        (left, right.join().expect("BVH build thread panicked"))
+    })
+    .expect("BVH build thread panicked");
+
+    // The second subtree was built with indices starting from zero
+    let offset = nodes.len() as u32;
+    nodes.extend(right_nodes.into_iter().map(|node| {
+        if node.is_leaf() {
+            node
+        } else {
+            Node {
+                offset: node.offset + offset,
+                ..node
+            }
+        }
+    }));
+    [left, right + offset]
+}
+
+/// Partition `items` into two halves if it is cheaper than making a leaf, returning the
+/// number of items in the first half and the axis along which they were split
+fn split(
+    items: &mut [Item],
+    bounds: &Aabb,
+    depth: usize,
+    options: &BvhOptions,
+) -> Option<(usize, usize)> {
+    let count = items.len();
+    if count <= 1 {
+        return None;
+    }
+    // Leaf sizes have to fit in a node
+    let must_split = count > options.leaf_size_limit();
+    let median = |items: &mut [Item], axis: usize| {
+        items.select_nth_unstable_by(count / 2, |a, b| {
+            a.centroid.as_slice()[axis].total_cmp(&b.centroid.as_slice()[axis])
+        });
+        Some((count / 2, axis))
+    };
+
+    let centroid_bounds = items
+        .iter()
+        .fold(Aabb::empty(), |bounds, item| bounds.grow(item.centroid));
+    let extent = centroid_bounds.max - centroid_bounds.min;
+    let widest_axis = (0..3)
+        .max_by(|&a, &b| extent.as_slice()[a].total_cmp(&extent.as_slice()[b]))
+        .unwrap();
+    if depth >= MAX_SAH_DEPTH {
+        return if must_split {
+            median(items, widest_axis)
+        } else {
+            None
+        };
+    }
+
+    let bins = options.bins.max(2);
+    let bin_of = |item: &Item, axis: usize| {
+        let offset = item.centroid.as_slice()[axis] - centroid_bounds.min.as_slice()[axis];
+        ((offset / extent.as_slice()[axis] * bins as f32) as usize).min(bins - 1)
+    };
+    let parent_area = bounds.area().max(f32::MIN_POSITIVE);
+
+    // Lowest cost split as (cost, axis, first bin of the second half)
+    let mut best: Option<(f32, usize, usize)> = None;
+    for axis in (0..3).filter(|&axis| extent.as_slice()[axis] > 0.) {
+        let mut bin_bounds = vec![Aabb::empty(); bins];
+        let mut bin_counts = vec![0usize; bins];
+        for item in items.iter() {
+            let bin = bin_of(item, axis);
+            bin_bounds[bin] = bin_bounds[bin].union(&item.bounds);
+            bin_counts[bin] += 1;
+        }
+
+        // Sweep from the right to get the area and count of every possible right half
+        let mut right = vec![(0., 0); bins];
+        let (mut acc_bounds, mut acc_count) = (Aabb::empty(), 0);
+        for bin in (1..bins).rev() {
+            acc_bounds = acc_bounds.union(&bin_bounds[bin]);
+            acc_count += bin_counts[bin];
+            right[bin] = (acc_bounds.area(), acc_count);
+        }
+
+        let (mut acc_bounds, mut acc_count) = (Aabb::empty(), 0);
+        for bin in 1..bins {
+            acc_bounds = acc_bounds.union(&bin_bounds[bin - 1]);
+            acc_count += bin_counts[bin - 1];
+            let (right_area, right_count) = right[bin];
+            if acc_count == 0 || right_count == 0 {
+                continue;
+            }
+            let cost = TRAVERSAL_COST
+                + INTERSECTION_COST
+                    * (acc_bounds.area() * acc_count as f32 + right_area * right_count as f32)
+                    / parent_area;
+            if best.is_none_or(|(best_cost, ..)| cost < best_cost) {
+                best = Some((cost, axis, bin));
+            }
+        }
+    }
+
+    match best {
+        Some((cost, axis, bin)) if must_split || cost < INTERSECTION_COST * count as f32 => {
+            Some((partition(items, |item| bin_of(item, axis) < bin), axis))
+        }
+        // All centroids are in the same place, so any split is as good as another
+        None if must_split => median(items, widest_axis),
+        _ => None,
+    }
+}
+
+/// Move items for which `predicate` is true to the front, returning how many there are
+fn partition(items: &mut [Item], predicate: impl Fn(&Item) -> bool) -> usize {
+    let mut mid = 0;
+    for i in 0..items.len() {
+        if predicate(&items[i]) {
+            items.swap(i, mid);
+            mid += 1;
+        }
+    }
+    mid
+}
+
+/// Measure how long `f` takes. `Instant` is not available on wasm32-unknown-unknown.
+#[cfg(not(target_arch = "wasm32"))]
+fn timed<T>(f: impl FnOnce() -> T) -> (T, Duration) {
+    let started = std::time::Instant::now();
+    let result = f();
+    (result, started.elapsed())
+}
+
+#[cfg(target_arch = "wasm32")]
+fn timed<T>(f: impl FnOnce() -> T) -> (T, Duration) {
+    (f(), Duration::default())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Bounds of a floor of triangles lying in the plane y = 0, which are flat on that axis
+    fn floor() -> Vec<Aabb> {
+        (0..64)
+            .map(|i| {
+                let corner = Vec3::new((i % 8) as f32, 0., (i / 8) as f32);
+                Aabb::new(corner..corner + Vec3::new(1., 0., 1.))
+            })
+            .collect()
+    }
+
+    fn layouts() -> Vec<BvhOptions> {
+        let mut layouts: Vec<BvhOptions> = [2, 4, 8]
+            .iter()
+            .map(|&width| BvhOptions {
+                width,
+                max_leaf_size: 1,
+                ..BvhOptions::default()
+            })
+            .collect();
+        layouts.push(BvhOptions {
+            compact: true,
+            max_leaf_size: 1,
+            ..BvhOptions::default()
+        });
+        layouts
+    }
+
+    #[test]
+    fn flat_bounds_are_hit() {
+        let bounds = floor();
+        let ray = Ray::new(Vec3::new(3.3, 2., 4.2), Vec3::new(0.1, -1., 0.2), 0.);
+        for options in layouts() {
+            let (bvh, order) = Bvh::build(&bounds, &options);
+            let mut leaves = 0;
+            bvh.traverse(&ray, 0.001..f32::INFINITY, |primitives, t_max| {
+                leaves += primitives.len();
+                t_max
+            });
+            assert!(leaves > 0, "Missed with width {}", options.width);
+
+            let mut t_max = [f32::INFINITY];
+            let mut hit = Vec::new();
+            bvh.traverse_packet(
+                std::slice::from_ref(&ray),
+                0.001,
+                &mut t_max,
+                |primitives, _, t| {
+                    hit.extend(primitives.map(|p| order[p]));
+                    t
+                },
+            );
+            // The ray reaches the floor at (3.5, 0, 4.6)
+            assert!(
+                hit.contains(&35),
+                "Packet missed with width {}",
+                options.width
+            );
+        }
+    }
+
+    /// Chain of `depth` interior nodes, each with a leaf as its second child
+    fn chain(depth: usize) -> Vec<Node> {
+        let bounds = Aabb::new(Vec3::zero()..Vec3::one());
+        let mut nodes: Vec<Node> = (0..depth)
+            .map(|i| Node::interior(bounds, (depth + 1 + i) as u32, 0))
+            .collect();
+        nodes.extend((0..=depth).map(|i| Node::leaf(bounds, i..i + 1)));
+        nodes
+    }
+
+    #[test]
+    fn cached_hierarchies_fit_the_stack() {
+        let path = std::env::temp_dir().join(format!("rt_deep_{}.bvh", std::process::id()));
+        for (depth, fits) in [(STACK_SIZE, true), (STACK_SIZE + 1, false)] {
+            let order: Vec<usize> = (0..=depth).collect();
+            cache::write(&path, &chain(depth), &order).unwrap();
+            let read = cache::read(&path, depth + 1);
+            assert_eq!(read.is_ok(), fits, "Read {} deep hierarchy", depth);
+        }
+        let _ = std::fs::remove_file(path);
+    }
+}
@@ -0,0 +1,165 @@
+//! Binary BVH files, named after a hash of the geometry and build options.
+//!
+//! Only the hierarchy is cached. Scenes are still read and the bounds of their primitives are
+//! computed on every run, because the key is hashed from those bounds, and not from a source
+//! file: there is no loader of glTF or OBJ meshes whose results could be cached, since meshes
+//! are read from the scene file like any other surfaces.
+//!
+//! A file contains a magic number and a format version, the nodes in their in-memory layout,
+//! and the order of the primitives, all little-endian.
+
+use super::{BvhOptions, Node, STACK_SIZE};
+use crate::world::aabb::Aabb;
+use anyhow::{anyhow, Result};
+use std::{
+    convert::TryFrom,
+    fs::{self, File},
+    io::{prelude::*, BufReader, BufWriter},
+    path::Path,
+};
+use ultraviolet::Vec3;
+
+const MAGIC: &[u8; 8] = b"rt-bvh\0\0";
+/// Increment when the file format or the build algorithm changes
+const VERSION: u32 = 2;
+
+/// FNV-1a, which is stable across platforms and compiler versions unlike `DefaultHasher`
+struct Fnv(u64);
+
+impl Fnv {
+    fn write(&mut self, bytes: &[u8]) {
+        for &byte in bytes {
+            self.0 = (self.0 ^ u64::from(byte)).wrapping_mul(0x100_0000_01b3);
+        }
+    }
+}
+
+/// Hash of everything that affects the built hierarchy
+pub fn key(bounds: &[Aabb], options: &BvhOptions) -> u64 {
+    let mut hash = Fnv(0xcbf2_9ce4_8422_2325);
+    hash.write(&VERSION.to_le_bytes());
+    hash.write(&(options.bins as u64).to_le_bytes());
+    hash.write(&(options.leaf_size_limit() as u64).to_le_bytes());
+    hash.write(&(bounds.len() as u64).to_le_bytes());
+    for aabb in bounds {
+        for v in aabb.min.as_slice().iter().chain(aabb.max.as_slice()) {
+            hash.write(&v.to_le_bytes());
+        }
+    }
+    hash.0
+}
+
+fn read_u32(read: &mut impl Read) -> Result<u32> {
+    let mut bytes = [0u8; 4];
+    read.read_exact(&mut bytes)?;
+    Ok(u32::from_le_bytes(bytes))
+}
+
+fn read_u64(read: &mut impl Read) -> Result<u64> {
+    let mut bytes = [0u8; 8];
+    read.read_exact(&mut bytes)?;
+    Ok(u64::from_le_bytes(bytes))
+}
+
+fn read_vec3(read: &mut impl Read) -> Result<Vec3> {
+    let mut v = [0f32; 3];
+    for component in &mut v {
+        *component = f32::from_bits(read_u32(read)?);
+    }
+    Ok(v.into())
+}
+
+/// Read a hierarchy over `primitives` primitives, checking that it can be traversed safely
+pub fn read(path: &Path, primitives: usize) -> Result<(Vec<Node>, Vec<usize>)> {
+    let mut read = BufReader::new(File::open(path)?);
+    let mut magic = [0u8; 8];
+    read.read_exact(&mut magic)?;
+    if &magic != MAGIC || read_u32(&mut read)? != VERSION {
+        return Err(anyhow!("{} is not a compatible BVH file", path.display()));
+    }
+
+    let node_count = usize::try_from(read_u64(&mut read)?)?;
+    // Leaves have primitives, so there are fewer than twice as many nodes
+    if node_count > primitives * 2 {
+        return Err(anyhow!("{} has too many nodes", path.display()));
+    }
+    let mut nodes = Vec::with_capacity(node_count);
+    // Number of interior ancestors of each node, which traversal keeps at most as many
+    // siblings of on its stack
+    let mut depths = vec![0; node_count];
+    for index in 0..node_count {
+        let bounds = Aabb::new(read_vec3(&mut read)?..read_vec3(&mut read)?);
+        let offset = read_u32(&mut read)?;
+        let count_axis = read_u32(&mut read)?;
+        let node = Node {
+            bounds,
+            offset,
+            count: count_axis as u16,
+            axis: (count_axis >> 16) as u16,
+        };
+        // Children always come after their parent, which rules out cycles
+        let valid = if node.is_leaf() {
+            node.primitives().end <= primitives
+        } else {
+            index + 1 < offset as usize && (offset as usize) < node_count && node.axis < 3
+        };
+        if !valid {
+            return Err(anyhow!("Invalid node in {}", path.display()));
+        }
+        if !node.is_leaf() {
+            let depth = depths[index] + 1;
+            if depth > STACK_SIZE {
+                return Err(anyhow!("{} is too deep to traverse", path.display()));
+            }
+            for child in [index + 1, node.offset as usize] {
+                depths[child] = depths[child].max(depth);
+            }
+        }
+        nodes.push(node);
+    }
+
+    if usize::try_from(read_u64(&mut read)?)? != primitives {
+        return Err(anyhow!("{} has wrong number of primitives", path.display()));
+    }
+    let mut seen = vec![false; primitives];
+    let mut order = Vec::with_capacity(primitives);
+    for _ in 0..primitives {
+        let i = read_u32(&mut read)? as usize;
+        match seen.get_mut(i) {
+            Some(seen) if !*seen => *seen = true,
+            _ => return Err(anyhow!("Invalid primitive order in {}", path.display())),
+        }
+        order.push(i);
+    }
+    Ok((nodes, order))
+}
+
+/// Write to a temporary file first, so that concurrent renders never read a partial file
+pub fn write(path: &Path, nodes: &[Node], order: &[usize]) -> Result<()> {
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    let temporary = path.with_extension(format!("tmp{}", std::process::id()));
+    {
+        let mut write = BufWriter::new(File::create(&temporary)?);
+        write.write_all(MAGIC)?;
+        write.write_all(&VERSION.to_le_bytes())?;
+        write.write_all(&(nodes.len() as u64).to_le_bytes())?;
+        for node in nodes {
+            let bounds = &node.bounds;
+            for v in bounds.min.as_slice().iter().chain(bounds.max.as_slice()) {
+                write.write_all(&v.to_le_bytes())?;
+            }
+            write.write_all(&node.offset.to_le_bytes())?;
+            let count_axis = u32::from(node.count) | u32::from(node.axis) << 16;
+            write.write_all(&count_axis.to_le_bytes())?;
+        }
+        write.write_all(&(order.len() as u64).to_le_bytes())?;
+        for &i in order {
+            write.write_all(&u32::try_from(i)?.to_le_bytes())?;
+        }
+        write.flush()?;
+    }
+    fs::rename(&temporary, path)?;
+    Ok(())
+}
@@ -0,0 +1,217 @@
+//! Hierarchy with 4 or 8 children per node, collapsed from the binary one after it is built.
+//! The bounds of all children of a node are stored as SIMD lanes and tested at once.
+
+use super::{Node, STACK_SIZE};
+use crate::Ray;
+use std::ops::{Mul, Range, Sub};
+use ultraviolet::{f32x4, f32x8};
+use wide::CmpLe;
+
+/// SIMD vector with a lane for each child of a node
+pub trait Lanes<const N: usize>:
+    Copy + From<[f32; N]> + Into<[f32; N]> + Sub<Output = Self> + Mul<Output = Self>
+{
+    fn splat(v: f32) -> Self;
+    fn min(self, other: Self) -> Self;
+    fn max(self, other: Self) -> Self;
+    /// Bit mask of the lanes in which `self` is less than or equal to `other`
+    fn less_equal(self, other: Self) -> u32;
+}
+
+impl Lanes<4> for f32x4 {
+    fn splat(v: f32) -> Self {
+        f32x4::splat(v)
+    }
+
+    fn min(self, other: Self) -> Self {
+        self.min(other)
+    }
+
+    fn max(self, other: Self) -> Self {
+        self.max(other)
+    }
+
+    fn less_equal(self, other: Self) -> u32 {
+        self.cmp_le(other).move_mask() as u32
+    }
+}
+
+impl Lanes<8> for f32x8 {
+    fn splat(v: f32) -> Self {
+        f32x8::splat(v)
+    }
+
+    fn min(self, other: Self) -> Self {
+        self.min(other)
+    }
+
+    fn max(self, other: Self) -> Self {
+        self.max(other)
+    }
+
+    fn less_equal(self, other: Self) -> u32 {
+        self.cmp_le(other).move_mask() as u32
+    }
+}
+
+/// Unused lanes have bounds at positive infinity on every axis, which no ray can hit
+pub struct WideNode<S, const N: usize> {
+    /// Lower bounds of the children on each axis
+    min: [S; 3],
+    /// Upper bounds of the children on each axis
+    max: [S; 3],
+    /// Index of the node of an interior child, or of the first primitive of a leaf
+    offsets: [u32; N],
+    /// Number of primitives, zero for interior children
+    counts: [u32; N],
+}
+
+/// Convert a binary hierarchy, making each wide node out of up to `N` binary subtrees
+pub fn collapse<S: Lanes<N>, const N: usize>(binary: &[Node]) -> Vec<WideNode<S, N>> {
+    let mut nodes = Vec::new();
+    if !binary.is_empty() {
+        collapse_node(binary, 0, &mut nodes);
+    }
+    nodes
+}
+
+/// Append the wide node for the binary subtree at `index`, returning its index
+fn collapse_node<S: Lanes<N>, const N: usize>(
+    binary: &[Node],
+    index: usize,
+    nodes: &mut Vec<WideNode<S, N>>,
+) -> u32 {
+    let node = &binary[index];
+    let mut children = if node.is_leaf() {
+        vec![index]
+    } else {
+        vec![index + 1, node.offset as usize]
+    };
+    // Replacing the largest interior children with their own children minimizes the
+    // expected number of bounds tests
+    while children.len() < N {
+        let largest = children
+            .iter()
+            .enumerate()
+            .filter(|(_, &child)| !binary[child].is_leaf())
+            .max_by(|(_, &a), (_, &b)| binary[a].bounds.area().total_cmp(&binary[b].bounds.area()))
+            .map(|(i, _)| i);
+        match largest {
+            Some(i) => {
+                let child = children[i];
+                children[i] = child + 1;
+                children.push(binary[child].offset as usize);
+            }
+            None => break,
+        }
+    }
+
+    let wide_index = nodes.len();
+    let mut min = [[f32::INFINITY; N]; 3];
+    let mut max = [[f32::INFINITY; N]; 3];
+    let mut offsets = [0; N];
+    let mut counts = [0; N];
+    nodes.push(WideNode {
+        min: min.map(S::from),
+        max: max.map(S::from),
+        offsets,
+        counts,
+    });
+    for (lane, &child) in children.iter().enumerate() {
+        let child_node = &binary[child];
+        for axis in 0..3 {
+            min[axis][lane] = child_node.bounds.min.as_slice()[axis];
+            max[axis][lane] = child_node.bounds.max.as_slice()[axis];
+        }
+        if child_node.is_leaf() {
+            offsets[lane] = child_node.offset;
+            counts[lane] = child_node.count.into();
+        } else {
+            offsets[lane] = collapse_node(binary, child, nodes);
+        }
+    }
+    nodes[wide_index] = WideNode {
+        min: min.map(S::from),
+        max: max.map(S::from),
+        offsets,
+        counts,
+    };
+    wide_index as u32
+}
+
+/// Same as [`super::Bvh::traverse`]
+pub fn traverse<S: Lanes<N>, const N: usize>(
+    nodes: &[WideNode<S, N>],
+    r: &Ray,
+    t_range: Range<f32>,
+    mut leaf: impl FnMut(Range<usize>, f32) -> f32,
+) {
+    if nodes.is_empty() {
+        return;
+    }
+
+    let (origin, inv_direction) = (r.origin(), r.inv_direction());
+    let origin = [origin.x, origin.y, origin.z].map(S::splat);
+    let inv_direction = [inv_direction.x, inv_direction.y, inv_direction.z].map(S::splat);
+    let t_min = S::splat(t_range.start);
+    let mut t_max = t_range.end;
+    // Children to visit as (distance, node index * 8 + lane). Every level of the hierarchy
+    // adds at most N - 1 entries, and binary hierarchies are at most STACK_SIZE deep.
+    let mut stack = [(0f32, 0u32); STACK_SIZE * 8];
+    let mut len = 0;
+    let mut index = 0;
+    loop {
+        let node = &nodes[index];
+        let mut near = t_min;
+        let mut far = S::splat(t_max);
+        for axis in 0..3 {
+            let t0 = (node.min[axis] - origin[axis]) * inv_direction[axis];
+            let t1 = (node.max[axis] - origin[axis]) * inv_direction[axis];
+            near = near.max(t0.min(t1));
+            far = far.min(t0.max(t1));
+        }
+        let mut mask = near.less_equal(far);
+        let near: [f32; N] = near.into();
+
+        // Push hit children farthest first, so that the nearest one is visited next
+        let mut hits = [(0f32, 0u32); N];
+        let mut count = 0;
+        while mask != 0 {
+            let lane = mask.trailing_zeros();
+            mask &= mask - 1;
+            let hit = (near[lane as usize], index as u32 * 8 + lane);
+            let mut i = count;
+            while i > 0 && hits[i - 1].0 < hit.0 {
+                hits[i] = hits[i - 1];
+                i -= 1;
+            }
+            hits[i] = hit;
+            count += 1;
+        }
+        stack[len..len + count].copy_from_slice(&hits[..count]);
+        len += count;
+
+        // Visit leaves until reaching an interior child
+        loop {
+            if len == 0 {
+                return;
+            }
+            len -= 1;
+            let (distance, child) = stack[len];
+            if distance >= t_max {
+                continue;
+            }
+            let parent = &nodes[child as usize / 8];
+            let lane = child as usize % 8;
+            let (offset, count) = (parent.offsets[lane] as usize, parent.counts[lane] as usize);
+            if count == 0 {
+                index = offset;
+                break;
+            }
+            t_max = leaf(offset..offset + count, t_max);
+            if t_max == f32::NEG_INFINITY {
+                return;
+            }
+        }
+    }
+}
@@ -0,0 +1,136 @@
+//! Light arriving from infinitely far away, where rays don't hit any object
+
+use super::light::LightSample;
+use crate::{color::luminance, image::Image, sampling::Distribution2D};
+use std::f32::consts::{PI, TAU};
+use ultraviolet::{Lerp, Rotor3, Vec2, Vec3};
+
+pub enum Environment {
+    /// Blue to white gradient from the horizon up
+    Gradient,
+    Map(EnvironmentMap),
+}
+
+impl Environment {
+    pub fn radiance(&self, direction: Vec3) -> Vec3 {
+        match self {
+            Self::Gradient => {
+                // From 0 to 1 when down to up
+                let t = 0.5 * (direction.y + 1.);
+                Vec3::one().lerp(Vec3::new(0.5, 0.7, 1.), t)
+            }
+            Self::Map(map) => map.radiance(direction),
+        }
+    }
+
+    /// Whether light from the environment is sampled with [`Environment::sample`] at diffuse
+    /// surfaces instead of being found by scattered rays
+    pub fn is_sampled(&self) -> bool {
+        matches!(self, Self::Map(_))
+    }
+
+    /// Choose a direction towards the environment in proportion to its brightness, with `u` in
+    /// the unit square. `None` if the environment isn't sampled.
+    pub fn sample(&self, u: Vec2) -> Option<LightSample> {
+        match self {
+            Self::Gradient => None,
+            Self::Map(map) => map.sample(u),
+        }
+    }
+}
+
+/// Equirectangular image of the environment, with the top row up
+pub struct EnvironmentMap {
+    image: Image,
+    intensity: f32,
+    /// Of the image around the vertical axis
+    rotation: Rotor3,
+    /// Over the image, in proportion to the light arriving from each pixel
+    distribution: Distribution2D,
+}
+
+impl EnvironmentMap {
+    /// `image` must not be empty. `rotation_radians` is counter-clockwise around the vertical
+    /// axis when seen from above.
+    pub fn new(image: Image, intensity: f32, rotation_radians: f32) -> Self {
+        let (width, height) = (image.width, image.height);
+        // Rows near the poles cover less of the sphere
+        let weights = (0..height)
+            .flat_map(|y| {
+                let sin_theta = (PI * (y as f32 + 0.5) / height as f32).sin();
+                let image = &image;
+                (0..width).map(move |x| luminance(image.pixel(x, y)) * sin_theta)
+            })
+            .collect();
+        Self {
+            distribution: Distribution2D::new(weights, width, height),
+            image,
+            intensity,
+            // From x towards -z is counter-clockwise when seen from above
+            rotation: Rotor3::from_rotation_xz(-rotation_radians),
+        }
+    }
+
+    fn radiance(&self, direction: Vec3) -> Vec3 {
+        let d = self.rotation.reversed() * direction;
+        let u = (d.z.atan2(d.x) / TAU).rem_euclid(1.);
+        let v = d.y.clamp(-1., 1.).acos() / PI;
+        let x = ((u * self.image.width as f32) as usize).min(self.image.width - 1);
+        let y = ((v * self.image.height as f32) as usize).min(self.image.height - 1);
+        self.image.pixel(x, y) * self.intensity
+    }
+
+    fn sample(&self, u: Vec2) -> Option<LightSample> {
+        let (uv, pdf) = self.distribution.sample(u);
+        let (phi, theta) = (uv.x * TAU, uv.y * PI);
+        let sin_theta = theta.sin();
+        if pdf == 0. || sin_theta == 0. {
+            return None;
+        }
+        let d = Vec3::new(sin_theta * phi.cos(), theta.cos(), sin_theta * phi.sin());
+        let direction = self.rotation * d;
+        // The image is stretched over 2π by π radians
+        let pdf = pdf / (2. * PI * PI * sin_theta);
+        Some(LightSample {
+            direction,
+            distance: f32::INFINITY,
+            weight: self.radiance(direction) / pdf,
+            object: None,
+        })
+    }
+}
+
+/// Equirectangular image of a photo studio for looking at materials, with round softboxes as
+/// the key, fill and rim lights around the front of the -z axis, and a dim backdrop
+pub fn studio() -> Image {
+    let (width, height) = (512, 256);
+    // Directions from the subject, angular radii in degrees and radiances
+    let softboxes = [
+        (Vec3::new(-1., 1.2, 1.), 15f32, 6.),
+        (Vec3::new(1.2, 0.3, 1.), 20., 1.5),
+        (Vec3::new(0.4, 0.8, -1.), 8., 5.),
+        (Vec3::unit_y(), 30., 1.),
+    ];
+    let pixels = (0..width * height)
+        .map(|i| {
+            let phi = ((i % width) as f32 + 0.5) / width as f32 * TAU;
+            let theta = ((i / width) as f32 + 0.5) / height as f32 * PI;
+            let d = Vec3::new(
+                theta.sin() * phi.cos(),
+                theta.cos(),
+                theta.sin() * phi.sin(),
+            );
+            let backdrop = if d.y > 0. { 0.06 } else { 0.03 };
+            let radiance = softboxes
+                .iter()
+                .find(|(center, radius, _)| d.dot(center.normalized()) > radius.to_radians().cos())
+                .map_or(backdrop, |&(_, _, radiance)| radiance);
+            Vec3::broadcast(radiance)
+        })
+        .collect();
+    Image {
+        width,
+        height,
+        pixels,
+    }
+}
@@ -0,0 +1,379 @@
+//! Lights which are sampled explicitly at diffuse surfaces
+
+use super::{material::Emissive, physics::PhysicsFrame};
+use crate::{
+    color::luminance,
+    ies::IesProfile,
+    sampling::{uniform_cone, uniform_triangle, Onb},
+};
+use std::f32::consts::{PI, TAU};
+use ultraviolet::{Vec2, Vec3};
+
+/// Light arriving at a point from a direction chosen by a light
+pub struct LightSample {
+    pub direction: Vec3,
+    /// To the light, infinite for distant lights
+    pub distance: f32,
+    /// Radiance over the probability density of choosing the direction per unit solid angle.
+    /// For lights which can only be sampled, such as points, the irradiance on a surface
+    /// facing the light.
+    pub weight: Vec3,
+    /// Index of the object in the scene which emitted the light, for lights which are objects
+    pub object: Option<u32>,
+}
+
+pub enum Light {
+    Sun(Sun),
+    Point(PointLight),
+    Mesh(MeshLight),
+}
+
+impl Light {
+    /// Choose a direction towards the light from `position` at `time`, with `u` in the unit
+    /// square
+    pub fn sample(&self, position: Vec3, time: f32, u: Vec2) -> Option<LightSample> {
+        match self {
+            Self::Sun(sun) => Some(sun.sample(u)),
+            Self::Point(point) => point.sample(position),
+            Self::Mesh(mesh) => mesh.sample(position, time, u),
+        }
+    }
+
+    /// Light arriving along `direction` from a light which rays can hit without hitting any
+    /// object. Lights without caustics aren't seen by `caustic` rays, see
+    /// [`crate::Ray::is_caustic`].
+    pub fn radiance(&self, direction: Vec3, caustic: bool) -> Vec3 {
+        match self {
+            Self::Sun(sun) if sun.caustics || !caustic => sun.radiance(direction),
+            Self::Sun(_) | Self::Point(_) | Self::Mesh(_) => Vec3::zero(),
+        }
+    }
+}
+
+/// Distant light from a disk in the sky
+pub struct Sun {
+    /// Towards the center of the disk
+    direction: Vec3,
+    /// Angular radius
+    radius: f32,
+    /// Irradiance on a surface facing the sun
+    irradiance: Vec3,
+    caustics: bool,
+}
+
+impl Sun {
+    pub fn new(direction: Vec3, angular_radius_radians: f32, irradiance: Vec3) -> Self {
+        Self {
+            direction: direction.normalized(),
+            radius: angular_radius_radians,
+            irradiance,
+            caustics: true,
+        }
+    }
+
+    /// Leave out light which reaches diffuse surfaces through mirror-like bounces, see
+    /// [`crate::Ray::is_caustic`]
+    pub fn without_caustics(self) -> Self {
+        Self {
+            caustics: false,
+            ..self
+        }
+    }
+
+    fn sample(&self, u: Vec2) -> LightSample {
+        let d = uniform_cone(u, self.radius);
+        LightSample {
+            direction: Onb::from_normal(self.direction).to_world(d),
+            distance: f32::INFINITY,
+            // Radiance from the disk over the uniform density is the irradiance times
+            // 2π (1 - cos r) / (π sin² r), which also works when the radius is zero
+            weight: self.irradiance * 2. / (1. + self.radius.cos()),
+            object: None,
+        }
+    }
+
+    fn radiance(&self, direction: Vec3) -> Vec3 {
+        if self.radius > 0. && direction.dot(self.direction) >= self.radius.cos() {
+            // The disk lights a surface facing it by π sin² r times its radiance
+            self.irradiance / (PI * self.radius.sin().powi(2))
+        } else {
+            Vec3::zero()
+        }
+    }
+}
+
+/// Light from a point, which may shine in a cone or follow an [`IesProfile`]
+pub struct PointLight {
+    position: Vec3,
+    /// Radiant intensity in the brightest direction
+    intensity: Vec3,
+    /// Straight down for the profile and the center of the cone
+    axis: Vec3,
+    /// Where the horizontal angle of the profile is zero
+    tangent: Vec3,
+    bitangent: Vec3,
+    /// Cosines of the angles at which the light begins to fade and is gone
+    cone: Option<(f32, f32)>,
+    /// With its intensities relative to the brightest one
+    profile: Option<(IesProfile, f32)>,
+}
+
+impl PointLight {
+    /// Light shining equally in every direction, with unit intensity
+    pub fn new(position: Vec3) -> Self {
+        let axis = -Vec3::unit_y();
+        Self {
+            position,
+            intensity: Vec3::one(),
+            axis,
+            tangent: Vec3::unit_x(),
+            bitangent: axis.cross(Vec3::unit_x()),
+            cone: None,
+            profile: None,
+        }
+    }
+
+    /// Point `axis`, the straight down direction of profiles, elsewhere. The horizontal angles
+    /// of a profile are then measured from some direction perpendicular to it.
+    pub fn pointing(self, axis: Vec3) -> Self {
+        let axis = axis.normalized();
+        let Onb {
+            tangent, bitangent, ..
+        } = Onb::from_normal(axis);
+        Self {
+            axis,
+            tangent,
+            bitangent,
+            ..self
+        }
+    }
+
+    /// Limit the light to a cone around the axis of `angle_radians` from it, fading out over
+    /// `blend_radians` inside its edge
+    pub fn with_cone(self, angle_radians: f32, blend_radians: f32) -> Self {
+        let inner = (angle_radians - blend_radians).max(0.);
+        Self {
+            cone: Some((inner.cos(), angle_radians.cos())),
+            ..self
+        }
+    }
+
+    /// Radiant intensity in the brightest direction
+    pub fn with_intensity(self, intensity: Vec3) -> Self {
+        Self { intensity, ..self }
+    }
+
+    /// Follow the distribution of `profile`, with the intensity of the light in its brightest
+    /// direction
+    pub fn with_profile(self, profile: IesProfile) -> Self {
+        let max = profile.max_candela();
+        Self {
+            profile: Some((profile, max)),
+            ..self
+        }
+    }
+
+    /// Radiant flux emitted per unit of intensity in the brightest direction, which is 4π
+    /// without a cone or a profile
+    pub fn relative_flux(&self) -> f32 {
+        // Midpoint rule, with many rows to resolve narrow cones
+        const ROWS: usize = 4096;
+        const COLUMNS: usize = 64;
+        let (d_theta, d_phi) = (PI / ROWS as f32, TAU / COLUMNS as f32);
+        let mut flux = 0.;
+        for row in 0..ROWS {
+            let theta = (row as f32 + 0.5) * d_theta;
+            let mut sum = 0.;
+            for column in 0..COLUMNS {
+                let phi = (column as f32 + 0.5) * d_phi;
+                let direction = (self.tangent * phi.cos() + self.bitangent * phi.sin())
+                    * theta.sin()
+                    + self.axis * theta.cos();
+                sum += self.relative_intensity(direction);
+            }
+            flux += sum * theta.sin() * d_theta * d_phi;
+        }
+        flux
+    }
+
+    /// Fraction of the intensity in the brightest direction towards `direction` away from the
+    /// light
+    fn relative_intensity(&self, direction: Vec3) -> f32 {
+        let cos_theta = direction.dot(self.axis);
+        let mut relative = 1.;
+        if let Some((inner, outer)) = self.cone {
+            if cos_theta <= outer {
+                return 0.;
+            }
+            if cos_theta < inner {
+                let t = (cos_theta - outer) / (inner - outer);
+                relative *= t * t * (3. - 2. * t);
+            }
+        }
+        if let Some((profile, max)) = &self.profile {
+            let vertical = cos_theta.clamp(-1., 1.).acos().to_degrees();
+            let horizontal = direction
+                .dot(self.bitangent)
+                .atan2(direction.dot(self.tangent))
+                .to_degrees();
+            if *max > 0. {
+                relative *= profile.candela(vertical, horizontal) / max;
+            }
+        }
+        relative
+    }
+
+    fn sample(&self, position: Vec3) -> Option<LightSample> {
+        let to_light = self.position - position;
+        let distance_sq = to_light.mag_sq();
+        if distance_sq == 0. {
+            return None;
+        }
+        let distance = distance_sq.sqrt();
+        let direction = to_light / distance;
+        Some(LightSample {
+            direction,
+            distance,
+            weight: self.intensity * self.relative_intensity(-direction) / distance_sq,
+            object: None,
+        })
+    }
+}
+
+/// Triangle of an object which emits light from its front side
+pub struct EmissiveTriangle {
+    /// Relative to the position of the object
+    pub vertices: [Vec3; 3],
+    /// Texture coordinates at the vertices
+    pub uvs: [Vec2; 3],
+    pub physics: PhysicsFrame,
+    pub emissive: Emissive,
+    /// Index of the object in the scene
+    pub object: u32,
+}
+
+/// Emissive triangles, which are chosen in proportion to the light they emit
+pub struct MeshLight {
+    triangles: Vec<EmissiveTriangle>,
+    alias: AliasTable,
+}
+
+impl MeshLight {
+    pub fn new(triangles: Vec<EmissiveTriangle>) -> Self {
+        let weights: Vec<f32> = triangles
+            .iter()
+            .map(|triangle| {
+                let [a, b, c] = triangle.vertices;
+                let area = (b - a).cross(c - a).mag() / 2.;
+                area * luminance(triangle.emissive.average_radiance())
+            })
+            .collect();
+        Self {
+            alias: AliasTable::new(&weights),
+            triangles,
+        }
+    }
+
+    fn sample(&self, position: Vec3, time: f32, u: Vec2) -> Option<LightSample> {
+        let (i, probability, u_x) = self.alias.sample(u.x)?;
+        let triangle = &self.triangles[i];
+        let offset = triangle.physics.position(time);
+        let [a, b, c] = triangle.vertices.map(|vertex| vertex + offset);
+        let cross = (b - a).cross(c - a);
+        let area = cross.mag() / 2.;
+
+        let [b0, b1, b2] = uniform_triangle(Vec2::new(u_x, u.y));
+        let point = a * b0 + b * b1 + c * b2;
+        let [uv0, uv1, uv2] = triangle.uvs;
+        let uv = uv0 * b0 + uv1 * b1 + uv2 * b2;
+
+        let to_light = point - position;
+        let distance_sq = to_light.mag_sq();
+        let distance = distance_sq.sqrt();
+        let direction = to_light / distance;
+        // Only the front, counter-clockwise side emits
+        let cos_light = -direction.dot(cross / (2. * area));
+        if cos_light <= 0. || area == 0. || distance == 0. {
+            return None;
+        }
+        Some(LightSample {
+            direction,
+            distance,
+            // Density per unit area converted to per unit solid angle
+            weight: triangle.emissive.radiance(uv, [Vec2::zero(); 2]) * cos_light * area
+                / (probability * distance_sq),
+            object: Some(triangle.object),
+        })
+    }
+}
+
+/// Discrete distribution which is sampled in constant time, from Vose, "A Linear Algorithm for
+/// Generating Random Numbers with a Given Distribution"
+struct AliasTable {
+    /// Of each element
+    probabilities: Vec<f32>,
+    /// Probability of keeping each bucket's own element instead of its alias
+    thresholds: Vec<f32>,
+    aliases: Vec<u32>,
+}
+
+impl AliasTable {
+    fn new(weights: &[f32]) -> Self {
+        let n = weights.len();
+        let total: f32 = weights.iter().map(|weight| weight.max(0.)).sum();
+        let probabilities: Vec<f32> = weights
+            .iter()
+            .map(|weight| {
+                if total > 0. {
+                    weight.max(0.) / total
+                } else {
+                    1. / n as f32
+                }
+            })
+            .collect();
+        let mut thresholds: Vec<f32> = probabilities.iter().map(|p| p * n as f32).collect();
+        let mut aliases: Vec<u32> = (0..n as u32).collect();
+        let (mut small, mut large): (Vec<usize>, Vec<usize>) =
+            (0..n).partition(|&i| thresholds[i] < 1.);
+        while let (Some(&less), Some(&more)) = (small.last(), large.last()) {
+            small.pop();
+            aliases[less] = more as u32;
+            thresholds[more] -= 1. - thresholds[less];
+            if thresholds[more] < 1. {
+                large.pop();
+                small.push(more);
+            }
+        }
+        // The rest are only off by rounding
+        for i in small.into_iter().chain(large) {
+            thresholds[i] = 1.;
+        }
+        Self {
+            probabilities,
+            thresholds,
+            aliases,
+        }
+    }
+
+    /// Element chosen with `u` in `[0, 1)`, its probability and a new number in `[0, 1)` made
+    /// from what was left of `u`. `None` if there are no elements.
+    fn sample(&self, u: f32) -> Option<(usize, f32, f32)> {
+        let n = self.thresholds.len();
+        if n == 0 {
+            return None;
+        }
+        let scaled = u * n as f32;
+        let bucket = (scaled as usize).min(n - 1);
+        let coin = scaled - bucket as f32;
+        let threshold = self.thresholds[bucket];
+        let (i, u) = if coin < threshold {
+            (bucket, coin / threshold)
+        } else {
+            (
+                self.aliases[bucket] as usize,
+                (coin - threshold) / (1. - threshold),
+            )
+        };
+        Some((i, self.probabilities[i], u.min(1. - f32::EPSILON)))
+    }
+}
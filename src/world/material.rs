@@ -1,17 +1,115 @@
-use super::HitRecord;
-use crate::Ray;
+use super::{volume::Volume, HitRecord};
+use crate::{
+    ray::RayKind,
+    sampling::{
+        cosine_hemisphere, cosine_hemisphere_pdf, ggx_g1, ggx_vndf, ggx_vndf_pdf, uniform_sphere,
+        Onb,
+    },
+    texture::Texture,
+    Ray,
+};
 use rand::prelude::*;
-use ultraviolet::Vec3;
+use std::{f32::consts::TAU, sync::Arc};
+use ultraviolet::{Vec2, Vec3};
 
 pub trait Scatter<R: Rng>: Send + Sync {
     fn scatter(&self, rng: &mut R, r: Ray, hit: HitRecord) -> Option<(Vec3, Ray)>;
 }
 
+/// Any of the supported materials, dispatched without a virtual call
+pub enum Material {
+    Lambertian(Lambertian),
+    Metal(Metal),
+    AnisotropicMetal(AnisotropicMetal),
+    Dielectric(Dielectric),
+    Emissive(Emissive),
+    Clearcoat(Clearcoat),
+    Cutout(Cutout),
+}
+
+impl Material {
+    /// Fraction of light which is scattered, for albedo passes
+    pub fn albedo(&self) -> Vec3 {
+        match self {
+            Self::Lambertian(lambertian) => lambertian.albedo,
+            Self::Metal(metal) => metal.albedo,
+            Self::AnisotropicMetal(metal) => metal.albedo,
+            Self::Dielectric(_) => Vec3::one(),
+            Self::Emissive(_) => Vec3::zero(),
+            Self::Clearcoat(clearcoat) => clearcoat.base.albedo(),
+            Self::Cutout(cutout) => cutout.base.albedo(),
+        }
+    }
+
+    /// Radiance emitted from `hit` towards where the ray came from
+    pub fn emitted(&self, hit: &HitRecord) -> Vec3 {
+        match self {
+            Self::Emissive(emissive) if hit.front_facing => emissive.radiance(hit.uv, hit.duv),
+            Self::Clearcoat(clearcoat) => clearcoat.base.emitted(hit),
+            Self::Cutout(cutout) => cutout.base.emitted(hit),
+            _ => Vec3::zero(),
+        }
+    }
+
+    /// Whether light emitted by the material is seen by caustic rays, see [`Ray::is_caustic`]
+    pub fn casts_caustics(&self) -> bool {
+        match self {
+            Self::Emissive(emissive) => emissive.caustics,
+            Self::Clearcoat(clearcoat) => clearcoat.base.casts_caustics(),
+            Self::Cutout(cutout) => cutout.base.casts_caustics(),
+            _ => true,
+        }
+    }
+
+    /// Refractive index of a dielectric, which is a medium for rays inside of it
+    pub fn refraction(&self) -> Option<f32> {
+        match self {
+            Self::Dielectric(dielectric) => Some(dielectric.refraction),
+            Self::Cutout(cutout) => cutout.base.refraction(),
+            _ => None,
+        }
+    }
+
+    /// Participating medium inside of a dielectric
+    pub fn volume(&self) -> Option<Volume> {
+        match self {
+            Self::Dielectric(dielectric) => dielectric.volume,
+            Self::Cutout(cutout) => cutout.base.volume(),
+            _ => None,
+        }
+    }
+
+    /// Probability density per unit solid angle of scattering a ray arriving along `incoming`
+    /// towards `direction` at `hit`, or `None` if the material has no density, such as for
+    /// mirror-like reflection
+    pub fn pdf(&self, hit: &HitRecord, incoming: Vec3, direction: Vec3) -> Option<f32> {
+        match self {
+            Self::Lambertian(_) => Some(cosine_hemisphere_pdf(
+                hit.frame.to_local(direction.normalized()).z,
+            )),
+            Self::AnisotropicMetal(metal) => Some(metal.pdf(hit, incoming, direction)),
+            Self::Cutout(cutout) => cutout.base.pdf(hit, incoming, direction),
+            Self::Metal(_) | Self::Dielectric(_) | Self::Emissive(_) | Self::Clearcoat(_) => None,
+        }
+    }
+}
+
+impl<R: Rng> Scatter<R> for Material {
+    fn scatter(&self, rng: &mut R, r: Ray, hit: HitRecord) -> Option<(Vec3, Ray)> {
+        match self {
+            Self::Lambertian(lambertian) => lambertian.scatter(rng, r, hit),
+            Self::Metal(metal) => metal.scatter(rng, r, hit),
+            Self::AnisotropicMetal(metal) => metal.scatter(rng, r, hit),
+            Self::Dielectric(dielectric) => dielectric.scatter(rng, r, hit),
+            Self::Emissive(_) => None,
+            Self::Clearcoat(clearcoat) => clearcoat.scatter(rng, r, hit),
+            Self::Cutout(cutout) => cutout.base.scatter(rng, r, hit),
+        }
+    }
+}
+
 fn random_on_sphere(rng: &mut impl Rng) -> Vec3 {
-    let phi = rng.gen_range(0f32..std::f32::consts::TAU);
-    let z = rng.gen_range(-1f32..1.); // Equal to cos theta
-    let sin_theta = (1. - z.powi(2)).sqrt();
-    Vec3::new(sin_theta * phi.cos(), sin_theta * phi.sin(), z)
+    uniform_sphere(Vec2::new(rng.gen_range(0f32..1.), rng.gen_range(0f32..1.)))
 }
 
 pub struct Lambertian {
@@ -26,8 +124,14 @@ impl Lambertian {
 
 impl<R: Rng> Scatter<R> for Lambertian {
     fn scatter(&self, rng: &mut R, r: Ray, hit: HitRecord) -> Option<(Vec3, Ray)> {
-        let direction = hit.normal + random_on_sphere(rng);
-        Some((self.albedo, Ray::new(hit.position, direction, r.time())))
+        // In proportion to the cosine, which cancels out of the rendering equation
+        let direction = hit
+            .frame
+            .to_world(cosine_hemisphere(Vec2::new(rng.gen(), rng.gen())));
+        Some((
+            self.albedo,
+            r.scattered(hit.position, direction, RayKind::Diffuse),
+        ))
     }
 }
 
@@ -44,15 +148,178 @@ impl Metal {
 
 impl<R: Rng> Scatter<R> for Metal {
     fn scatter(&self, rng: &mut R, r: Ray, hit: HitRecord) -> Option<(Vec3, Ray)> {
-        let direction = r.direction().reflected(hit.normal) + self.fuzz * random_on_sphere(rng);
-        if direction.dot(hit.normal) > 0. {
-            Some((self.albedo, Ray::new(hit.position, direction, r.time())))
+        let d = hit.frame.to_local(r.direction());
+        let fuzz = self.fuzz.max(r.roughness_floor());
+        let direction = Vec3::new(d.x, d.y, -d.z) + fuzz * random_on_sphere(rng);
+        if direction.z > 0. {
+            // Fuzzy reflections blur the texture more than differentials would tell
+            let differentials = if fuzz == 0. {
+                hit.reflect_differentials(&r, hit.frame.normal)
+            } else {
+                None
+            };
+            Some((
+                self.albedo,
+                r.scattered(
+                    hit.position,
+                    hit.frame.to_world(direction),
+                    RayKind::Specular,
+                )
+                .with_differentials(differentials),
+            ))
         } else {
             None
         }
     }
 }
 
+/// Conductor with GGX microfacets which may be rougher along one direction, like brushed metal
+pub struct AnisotropicMetal {
+    /// Reflectance at normal incidence
+    albedo: Vec3,
+    /// GGX alpha along the tangent and the bitangent
+    alpha: Vec2,
+    /// Of the tangent counter-clockwise around the normal
+    rotation: f32,
+    /// Adds turns of rotation from its first channel
+    rotation_texture: Option<Texture>,
+}
+
+impl AnisotropicMetal {
+    /// `roughness` is along the tangent and the bitangent of surfaces, from 0 to 1
+    pub fn new(albedo: Vec3, roughness: Vec2, rotation_radians: f32) -> Self {
+        // Perceptually linear roughness, clamped away from a mirror which can't be sampled
+        let alpha = |roughness: f32| roughness.powi(2).max(1e-3);
+        Self {
+            albedo,
+            alpha: Vec2::new(alpha(roughness.x), alpha(roughness.y)),
+            rotation: rotation_radians,
+            rotation_texture: None,
+        }
+    }
+
+    /// Rotate the direction of the roughness by the first channel of `texture` in turns, which
+    /// must not be empty
+    pub fn with_rotation_texture(self, texture: impl Into<Texture>) -> Self {
+        Self {
+            rotation_texture: Some(texture.into()),
+            ..self
+        }
+    }
+
+    fn frame(&self, hit: &HitRecord) -> Onb {
+        let turns = self
+            .rotation_texture
+            .as_ref()
+            .map_or(0., |texture| texture.sample(hit.uv, hit.duv).x);
+        hit.frame.rotated(self.rotation + turns * TAU)
+    }
+
+    fn pdf(&self, hit: &HitRecord, incoming: Vec3, direction: Vec3) -> f32 {
+        let frame = self.frame(hit);
+        let wo = frame.to_local(-incoming.normalized());
+        let wi = frame.to_local(direction.normalized());
+        let h = (wo + wi).normalized();
+        if wi.z <= 0. || wo.dot(h) <= 0. {
+            return 0.;
+        }
+        // Reflection doubles the angle to the microfacet normal
+        ggx_vndf_pdf(wo, h, self.alpha.x, self.alpha.y) / (4. * wo.dot(h))
+    }
+}
+
+impl<R: Rng> Scatter<R> for AnisotropicMetal {
+    fn scatter(&self, rng: &mut R, r: Ray, hit: HitRecord) -> Option<(Vec3, Ray)> {
+        let frame = self.frame(&hit);
+        let wo = frame.to_local(-r.direction());
+        let floor = r.roughness_floor().powi(2);
+        let (alpha_x, alpha_y) = (self.alpha.x.max(floor), self.alpha.y.max(floor));
+        let h = ggx_vndf(wo, alpha_x, alpha_y, Vec2::new(rng.gen(), rng.gen()));
+        let wi = (-wo).reflected(h);
+        if wi.z <= 0. {
+            return None;
+        }
+        // With visible normals, only Fresnel and the masking of the reflection remain
+        let cos = wi.dot(h).max(0.);
+        let fresnel = self.albedo + (Vec3::one() - self.albedo) * (1. - cos).powi(5);
+        Some((
+            fresnel * ggx_g1(wi, alpha_x, alpha_y),
+            r.scattered(hit.position, frame.to_world(wi), RayKind::Specular),
+        ))
+    }
+}
+
+/// Thin dielectric layer over another material, like lacquer or the clear coat of car paint
+pub struct Clearcoat {
+    base: Box<Material>,
+    /// GGX alpha of the coat
+    alpha: f32,
+    refraction: f32,
+}
+
+impl Clearcoat {
+    /// `roughness` of the coat is from 0 to 1
+    pub fn new(base: Material, roughness: f32, refraction: f32) -> Self {
+        Self {
+            base: Box::new(base),
+            alpha: roughness.powi(2).max(1e-3),
+            refraction,
+        }
+    }
+}
+
+impl<R: Rng> Scatter<R> for Clearcoat {
+    fn scatter(&self, rng: &mut R, r: Ray, hit: HitRecord) -> Option<(Vec3, Ray)> {
+        // Reflect off the coat as often as it reflects towards the ray, and otherwise leave the
+        // ray to the base, ignoring the light lost inside the coat
+        let wo = hit.frame.to_local(-r.direction());
+        let ratio = 1. / self.refraction;
+        let probability = reflectance(wo.z.clamp(0., 1.), ratio);
+        if rng.gen::<f32>() >= probability {
+            return self.base.scatter(rng, r, hit);
+        }
+
+        let alpha = self.alpha.max(r.roughness_floor().powi(2));
+        let h = ggx_vndf(wo, alpha, alpha, Vec2::new(rng.gen(), rng.gen()));
+        let wi = (-wo).reflected(h);
+        if wi.z <= 0. {
+            return None;
+        }
+        let fresnel = reflectance(wi.dot(h).clamp(0., 1.), ratio);
+        Some((
+            Vec3::broadcast(fresnel * ggx_g1(wi, alpha, alpha) / probability),
+            r.scattered(hit.position, hit.frame.to_world(wi), RayKind::Specular),
+        ))
+    }
+}
+
+/// Another material with holes in it, like a leaf card or a chain-link fence. Rays pass through
+/// the holes while the world is traversed, so this is seen as the base material where it is hit.
+pub struct Cutout {
+    base: Box<Material>,
+    alpha: Texture,
+}
+
+impl Cutout {
+    /// The first channel of `alpha` is the probability that a ray hits the base material
+    pub fn new(base: Material, alpha: impl Into<Texture>) -> Self {
+        Self {
+            base: Box::new(base),
+            alpha: alpha.into(),
+        }
+    }
+
+    pub fn base(&self) -> &Material {
+        &self.base
+    }
+
+    /// Probability of hitting the base material at texture coordinates `uv`, over the footprint
+    /// `duv` as in [`Texture::sample`]
+    pub fn opacity(&self, uv: Vec2, duv: [Vec2; 2]) -> f32 {
+        self.alpha.sample(uv, duv).x
+    }
+}
+
 fn reflectance(cos_theta: f32, refraction_ratio: f32) -> f32 {
     // Schlick's approximation
     let r0 = ((1. - refraction_ratio) / (1. + refraction_ratio)).powi(2);
@@ -61,32 +328,150 @@ fn reflectance(cos_theta: f32, refraction_ratio: f32) -> f32 {
 
 pub struct Dielectric {
     refraction: f32,
+    /// GGX alpha, or zero for a smooth surface
+    alpha: f32,
+    volume: Option<Volume>,
 }
 
 impl Dielectric {
     pub fn new(refraction: f32) -> Self {
-        Self { refraction }
+        Self {
+            refraction,
+            alpha: 0.,
+            volume: None,
+        }
+    }
+
+    /// Fill the inside with `volume`. A refractive index of 1 makes the surface an invisible
+    /// boundary of it, such as for smoke.
+    pub fn with_volume(self, volume: Volume) -> Self {
+        Self {
+            volume: Some(volume),
+            ..self
+        }
+    }
+
+    /// Frost the surface with GGX microfacets, `roughness` from 0 to 1
+    pub fn with_roughness(self, roughness: f32) -> Self {
+        Self {
+            alpha: roughness.powi(2),
+            ..self
+        }
     }
 }
 
 impl<R: Rng> Scatter<R> for Dielectric {
     fn scatter(&self, rng: &mut R, r: Ray, hit: HitRecord) -> Option<(Vec3, Ray)> {
+        // From the medium the ray is in to the one on the other side
+        let media = r.media();
         let refraction_ratio = if hit.front_facing {
-            1. / self.refraction
+            media.refraction() / self.refraction
         } else {
-            self.refraction
+            self.refraction / media.outer_refraction()
         };
 
-        let cos_theta = (-r.direction()).dot(hit.normal);
+        let d = hit.frame.to_local(r.direction());
+        // A smooth surface is its own microfacet
+        let alpha = self.alpha.max(r.roughness_floor().powi(2));
+        let (h, alpha) = if alpha > 0. {
+            let alpha = alpha.max(1e-3);
+            (
+                ggx_vndf(-d, alpha, alpha, Vec2::new(rng.gen(), rng.gen())),
+                alpha,
+            )
+        } else {
+            (Vec3::unit_z(), 0.)
+        };
+        let cos_theta = -d.dot(h);
         let sin_theta = (1. - cos_theta.powi(2)).sqrt();
         let reflectance = reflectance(cos_theta, refraction_ratio);
 
         let direction = if refraction_ratio * sin_theta > 1. || rng.gen::<f32>() < reflectance {
-            r.direction().reflected(hit.normal)
+            d.reflected(h)
+        } else {
+            d.refracted(h, refraction_ratio)
+        };
+        // Rough microfacets may send the ray to the wrong side of the surface, and shadow it
+        let reflected = direction.z > 0.;
+        if alpha > 0. && reflected != (direction.dot(h) > 0.) {
+            return None;
+        }
+        let masking = if alpha > 0. {
+            let w = Vec3::new(direction.x, direction.y, direction.z.abs());
+            ggx_g1(w, alpha, alpha)
         } else {
-            r.direction().refracted(hit.normal, refraction_ratio)
+            1.
+        };
+
+        // A shading normal can refract the ray back to the side it came from, or reflect it
+        // through the surface, which would confuse the media it is in
+        let direction = hit.frame.to_world(direction);
+        if reflected != (direction.dot(hit.normal) > 0.) {
+            return None;
+        }
+        // Rough surfaces blur the texture more than differentials would tell
+        let differentials = match (alpha > 0., reflected) {
+            (true, _) => None,
+            (false, true) => hit.reflect_differentials(&r, hit.frame.normal),
+            (false, false) => hit.refract_differentials(&r, hit.frame.normal, refraction_ratio),
         };
 
-        Some((Vec3::one(), Ray::new(hit.position, direction, r.time())))
+        Some((
+            Vec3::broadcast(masking),
+            r.scattered(hit.position, direction, RayKind::Specular)
+                .with_differentials(differentials),
+        ))
+    }
+}
+
+/// Emits light from the front side of a surface and reflects nothing
+#[derive(Clone)]
+pub struct Emissive {
+    radiance: Vec3,
+    /// Multiplies the radiance, shared with the lights of emissive triangles
+    texture: Option<Arc<Texture>>,
+    caustics: bool,
+}
+
+impl Emissive {
+    pub fn new(radiance: Vec3) -> Self {
+        Self {
+            radiance,
+            texture: None,
+            caustics: true,
+        }
+    }
+
+    /// Leave out light which reaches diffuse surfaces through mirror-like bounces, see
+    /// [`Ray::is_caustic`]
+    pub fn without_caustics(self) -> Self {
+        Self {
+            caustics: false,
+            ..self
+        }
+    }
+
+    /// Modulate the radiance by `texture`, which must not be empty
+    pub fn with_texture(self, texture: impl Into<Texture>) -> Self {
+        Self {
+            texture: Some(Arc::new(texture.into())),
+            ..self
+        }
+    }
+
+    /// At texture coordinates `uv`, over the footprint `duv` as in [`Texture::sample`]
+    pub fn radiance(&self, uv: Vec2, duv: [Vec2; 2]) -> Vec3 {
+        match &self.texture {
+            Some(texture) => self.radiance * texture.sample(uv, duv),
+            None => self.radiance,
+        }
+    }
+
+    /// Over the whole texture
+    pub fn average_radiance(&self) -> Vec3 {
+        match &self.texture {
+            Some(texture) => self.radiance * texture.average(),
+            None => self.radiance,
+        }
     }
 }
@@ -1,116 +1,448 @@
 pub mod aabb;
+pub mod bvh;
+pub mod environment;
+pub mod light;
 pub mod material;
 pub mod physics;
 pub mod surface;
+pub mod volume;
 
-use crate::Ray;
+use crate::{ray::RayKind, sampler::mix, Ray};
 use aabb::Aabb;
-use material::{Dielectric, Lambertian, Metal, Scatter};
+use bvh::{Bvh, BvhOptions, BvhStats};
+use environment::Environment;
+use light::{EmissiveTriangle, Light, LightSample, MeshLight};
+use material::Material;
 use physics::PhysicsFrame;
-use rand::prelude::*;
-use surface::{Hit, HitRecord, Sphere};
-use ultraviolet::{Lerp, Vec3};
+use serde::{Deserialize, Serialize};
+use std::{borrow::Cow, ops::Range};
+use surface::{Hit, HitRecord, Surface, Surfaces};
+use ultraviolet::{Vec2, Vec3};
 
-pub struct Object<R: Rng> {
-    pub surface: Box<dyn Hit>,
-    pub material: Box<dyn Scatter<R>>,
+/// Index into the surface table of a [`World`]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct SurfaceHandle(pub u32);
+
+/// Index into the material table of a [`World`]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct MaterialHandle(pub u32);
+
+/// Kinds of rays which an object can be hit by
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Visibility {
+    pub camera: bool,
+    /// Casts shadows
+    pub shadow: bool,
+    /// Appears in reflections and refractions and lights other surfaces
+    pub indirect: bool,
+}
+
+impl Visibility {
+    pub fn contains(&self, kind: RayKind) -> bool {
+        match kind {
+            RayKind::Camera => self.camera,
+            RayKind::Shadow => self.shadow,
+            RayKind::Diffuse | RayKind::Specular => self.indirect,
+        }
+    }
+}
+
+impl Default for Visibility {
+    fn default() -> Self {
+        Self {
+            camera: true,
+            shadow: true,
+            indirect: true,
+        }
+    }
+}
+
+/// Placement of a surface and a material, which are shared through the world's tables
+pub struct Object {
+    pub surface: SurfaceHandle,
+    pub material: MaterialHandle,
     pub physics: PhysicsFrame,
+    pub visibility: Visibility,
+    /// Of the object as a medium where dielectric objects overlap, higher taking precedence
+    pub priority: u32,
 }
 
-pub struct World<R: Rng> {
-    objects: Vec<Object<R>>,
+/// Nearest hit of a ray
+#[derive(Clone)]
+pub struct Intersection<'a> {
+    pub hit: HitRecord,
+    pub material: &'a Material,
+    /// Index of the object in the order in which objects were given to [`World::new`]
+    pub object: u32,
+    /// Whether light emitted by the object is also sampled with [`World::sample_light`]
+    pub sampled: bool,
+    pub priority: u32,
 }
 
-impl<R: Rng> World<R> {
-    pub fn new(objects: Vec<Object<R>>) -> Self {
-        Self { objects }
-    }
-
-    pub fn random(rng: &mut impl Rng) -> Self {
-        let mut objects = vec![Object {
-            surface: Box::new(Sphere::new(1000.)),
-            material: Box::new(Lambertian::new(Vec3::one() * 0.5)),
-            physics: PhysicsFrame::stationary(Vec3::new(0., -1000., 0.)),
-        }];
-
-        for a in -11..=11 {
-            for b in -11..=11 {
-                let center = Vec3::new(
-                    a as f32 + rng.gen_range(0f32..0.9),
-                    0.2,
-                    b as f32 + rng.gen_range(0f32..0.9),
-                );
-
-                let (velocity, material) = match rng.gen_range(0..=100) {
-                    // Diffuse
-                    0..=79 => (
-                        Vec3::unit_y() * rng.gen_range(0f32..0.5),
-                        Box::new(Lambertian::new(
-                            Vec3::from(rng.gen::<[f32; 3]>()) * Vec3::from(rng.gen::<[f32; 3]>()),
-                        )) as Box<dyn Scatter<R>>,
-                    ),
-                    // Metal
-                    80..=94 => (
-                        Vec3::zero(),
-                        Box::new(Metal::new(
-                            Vec3::from(rng.gen::<[f32; 3]>()).lerp(Vec3::one(), 0.4),
-                            rng.gen_range(0.0..0.2),
-                        )) as Box<dyn Scatter<R>>,
-                    ),
-                    // Glass
-                    _ => (
-                        Vec3::zero(),
-                        Box::new(Dielectric::new(1.5)) as Box<dyn Scatter<R>>,
-                    ),
-                };
-
-                objects.push(Object {
-                    surface: Box::new(Sphere::new(0.2)),
-                    material,
-                    physics: PhysicsFrame {
-                        position: center..center + velocity,
-                    },
+pub struct World {
+    surfaces: Surfaces,
+    materials: Vec<Material>,
+    /// Objects in the BVH come first in leaf order, followed by unbounded objects
+    objects: Vec<Object>,
+    /// Original index of each object
+    ids: Vec<u32>,
+    /// Whether each object is part of a [`MeshLight`]
+    emitters: Vec<bool>,
+    bvh: Bvh,
+    bounded: usize,
+    /// Of the bounded objects
+    bounds: Aabb,
+    environment: Environment,
+    lights: Vec<Light>,
+}
+
+impl World {
+    /// Handles in `objects` must be valid indices into `surfaces` and `materials`.
+    /// The BVH is built to contain moving objects during `time`.
+    pub fn new(
+        surfaces: impl Into<Surfaces>,
+        materials: Vec<Material>,
+        objects: Vec<Object>,
+        time: Range<f32>,
+        bvh_options: &BvhOptions,
+        environment: Environment,
+        mut lights: Vec<Light>,
+    ) -> Self {
+        let surfaces = surfaces.into();
+        let (bounded, unbounded): (Vec<_>, Vec<_>) = objects
+            .into_iter()
+            .enumerate()
+            .map(|(id, object)| {
+                let bounds = surfaces
+                    .get(object.surface.0 as usize)
+                    .bounding_box(&object.physics, time.clone());
+                (id as u32, object, bounds)
+            })
+            .partition(|(_, _, bounds)| bounds.is_some());
+
+        let bounds: Vec<Aabb> = bounded
+            .iter()
+            .filter_map(|(_, _, bounds)| *bounds)
+            .collect();
+        let (bvh, order) = Bvh::build(&bounds, bvh_options);
+        let bounds = bounds.iter().fold(Aabb::empty(), |all, b| all.union(b));
+        let mut bounded: Vec<Option<(u32, Object)>> = bounded
+            .into_iter()
+            .map(|(id, object, _)| Some((id, object)))
+            .collect();
+        let (mut ids, mut objects): (Vec<u32>, Vec<Object>) = order
+            .into_iter()
+            .map(|i| bounded[i].take().expect("BVH order is a permutation"))
+            .unzip();
+        let bounded = objects.len();
+        for (id, object, _) in unbounded {
+            ids.push(id);
+            objects.push(object);
+        }
+
+        // Emissive triangles are sampled together as one light
+        let mut emitters = vec![false; objects.len()];
+        let mut triangles = Vec::new();
+        for ((object, emitter), &id) in objects.iter().zip(&mut emitters).zip(&ids) {
+            if let (Surface::Triangle(triangle), Material::Emissive(emissive)) = (
+                &*surfaces.get(object.surface.0 as usize),
+                &materials[object.material.0 as usize],
+            ) {
+                *emitter = true;
+                triangles.push(EmissiveTriangle {
+                    vertices: triangle.vertices(),
+                    uvs: triangle.uvs(),
+                    physics: object.physics.clone(),
+                    emissive: emissive.clone(),
+                    object: id,
                 });
             }
         }
+        if !triangles.is_empty() {
+            lights.push(Light::Mesh(MeshLight::new(triangles)));
+        }
+
+        Self {
+            surfaces,
+            materials,
+            objects,
+            ids,
+            emitters,
+            bvh,
+            bounded,
+            bounds,
+            environment,
+            lights,
+        }
+    }
+
+    pub fn surface(&self, handle: SurfaceHandle) -> Cow<'_, Surface> {
+        self.surfaces.get(handle.0 as usize)
+    }
+
+    /// Memory taken by the surface table
+    pub fn surface_bytes(&self) -> usize {
+        self.surfaces.bytes()
+    }
+
+    pub fn material(&self, handle: MaterialHandle) -> &Material {
+        &self.materials[handle.0 as usize]
+    }
+
+    pub fn bvh_stats(&self) -> &BvhStats {
+        self.bvh.stats()
+    }
+
+    /// Box containing the objects which have bounds during the shutter time of the world, or an
+    /// empty box if there are none
+    pub fn bounds(&self) -> Aabb {
+        self.bounds
+    }
+
+    pub fn environment(&self) -> &Environment {
+        &self.environment
+    }
+
+    /// Whether there is any light for [`World::sample_light`] to choose
+    pub fn has_sampled_lights(&self) -> bool {
+        !self.lights.is_empty() || self.environment.is_sampled()
+    }
+
+    /// Choose one of the lights and the environment if it is sampled, and a direction towards
+    /// it from `position` at `time`, with `u` in the unit square
+    pub fn sample_light(&self, position: Vec3, time: f32, u: Vec2) -> Option<LightSample> {
+        let count = self.lights.len() + usize::from(self.environment.is_sampled());
+        if count == 0 {
+            return None;
+        }
+        // The first dimension picks the light and is then stretched back to the unit interval
+        let scaled = u.x * count as f32;
+        let i = (scaled as usize).min(count - 1);
+        let u = Vec2::new((scaled - i as f32).min(1. - f32::EPSILON), u.y);
+        let sample = match self.lights.get(i) {
+            Some(light) => light.sample(position, time, u),
+            None => self.environment.sample(u),
+        };
+        sample.map(|sample| LightSample {
+            weight: sample.weight * count as f32,
+            ..sample
+        })
+    }
+
+    /// Light arriving along `direction` from where no object was hit. Light which
+    /// [`World::sample_light`] can choose is left out unless `include_sampled` is true, and
+    /// lights without caustics are left out for `caustic` rays.
+    pub fn background(&self, direction: Vec3, include_sampled: bool, caustic: bool) -> Vec3 {
+        let mut color = Vec3::zero();
+        if include_sampled || !self.environment.is_sampled() {
+            color += self.environment.radiance(direction);
+        }
+        if include_sampled {
+            for light in &self.lights {
+                color += light.radiance(direction, caustic);
+            }
+        }
+        color
+    }
 
-        objects.extend(vec![
-            Object {
-                surface: Box::new(Sphere::new(1.)),
-                material: Box::new(Dielectric::new(1.5)),
-                physics: PhysicsFrame::stationary(Vec3::new(0., 1., 0.)),
-            },
-            Object {
-                surface: Box::new(Sphere::new(1.)),
-                material: Box::new(Lambertian::new(Vec3::new(0.4, 0.2, 0.1))),
-                physics: PhysicsFrame::stationary(Vec3::new(-4., 1., 0.)),
-            },
-            Object {
-                surface: Box::new(Sphere::new(1.)),
-                material: Box::new(Metal::new(Vec3::new(0.7, 0.6, 0.5), 0.)),
-                physics: PhysicsFrame::stationary(Vec3::new(4., 1., 0.)),
-            },
-        ]);
-
-        Self::new(objects)
-    }
-
-    pub fn traverse(&self, r: &Ray, t_min: f32) -> Option<(HitRecord, &dyn Scatter<R>)> {
+    /// Nearest hit of `r` on objects visible to rays of its kind
+    pub fn traverse(&self, r: &Ray, t_min: f32) -> Option<Intersection<'_>> {
+        profile_scope!("traverse");
         let mut nearest_hit = None;
-        let mut nearest_t = f32::INFINITY;
+        let t_max = self.hit_objects(
+            self.bounded..self.objects.len(),
+            r,
+            t_min..f32::INFINITY,
+            &mut nearest_hit,
+        );
+        self.bvh.traverse(r, t_min..t_max, |objects, t_max| {
+            self.hit_objects(objects, r, t_min..t_max, &mut nearest_hit)
+        });
+        nearest_hit
+    }
+
+    /// Same as [`World::traverse`] for a packet of coherent rays, such as camera rays
+    pub fn traverse_packet(&self, rays: &[Ray], t_min: f32) -> Vec<Option<Intersection<'_>>> {
+        profile_scope!("traverse_packet");
+        let mut nearest_hits: Vec<_> = rays.iter().map(|_| None).collect();
+        let mut t_max: Vec<f32> = rays
+            .iter()
+            .zip(&mut nearest_hits)
+            .map(|(r, nearest_hit)| {
+                self.hit_objects(
+                    self.bounded..self.objects.len(),
+                    r,
+                    t_min..f32::INFINITY,
+                    nearest_hit,
+                )
+            })
+            .collect();
+        self.bvh
+            .traverse_packet(rays, t_min, &mut t_max, |objects, i, t_max| {
+                self.hit_objects(objects, &rays[i], t_min..t_max, &mut nearest_hits[i])
+            });
+        nearest_hits
+    }
+
+    /// Whether `r` hits any object visible to rays of its kind within `t_range`, stopping at
+    /// the first one found
+    pub fn occluded(&self, r: &Ray, t_range: Range<f32>) -> bool {
+        self.occluder(r, t_range).is_some()
+    }
+
+    /// Index in the scene of the first object found like by [`World::occluded`], if any
+    pub fn occluder(&self, r: &Ray, t_range: Range<f32>) -> Option<u32> {
+        profile_scope!("occluded");
+        let mut occluder =
+            self.occlude_objects(self.bounded..self.objects.len(), r, t_range.clone());
+        if occluder.is_none() {
+            self.bvh.traverse(r, t_range.clone(), |objects, t_max| {
+                occluder = self.occlude_objects(objects, r, t_range.start..t_max);
+                if occluder.is_some() {
+                    f32::NEG_INFINITY
+                } else {
+                    t_max
+                }
+            });
+        }
+        occluder.map(|i| self.ids[i])
+    }
 
-        for Object {
+    /// Same as [`World::occluded`] for a packet of rays, such as shadow rays from one point
+    pub fn occluded_packet(&self, rays: &[Ray], t_range: Range<f32>) -> Vec<bool> {
+        profile_scope!("occluded_packet");
+        let mut occluded: Vec<bool> = rays
+            .iter()
+            .map(|r| {
+                self.occlude_objects(self.bounded..self.objects.len(), r, t_range.clone())
+                    .is_some()
+            })
+            .collect();
+        // Rays which are already occluded don't hit any boxes
+        let mut t_max: Vec<f32> = occluded
+            .iter()
+            .map(|&occluded| {
+                if occluded {
+                    f32::NEG_INFINITY
+                } else {
+                    t_range.end
+                }
+            })
+            .collect();
+        self.bvh
+            .traverse_packet(rays, t_range.start, &mut t_max, |objects, i, t_max| {
+                occluded[i] = self
+                    .occlude_objects(objects, &rays[i], t_range.start..t_max)
+                    .is_some();
+                if occluded[i] {
+                    f32::NEG_INFINITY
+                } else {
+                    t_max
+                }
+            });
+        occluded
+    }
+
+    /// First of `objects` visible to `r` which is hit within `t_range`
+    fn occlude_objects(
+        &self,
+        objects: Range<usize>,
+        r: &Ray,
+        t_range: Range<f32>,
+    ) -> Option<usize> {
+        objects.into_iter().find(|&i| {
+            let Object {
+                surface,
+                material,
+                physics,
+                visibility,
+                ..
+            } = &self.objects[i];
+            if !visibility.contains(r.kind()) {
+                return false;
+            }
+            match self.material(*material) {
+                // Holes need the texture coordinates of the hit
+                Material::Cutout(_) => self.hit_object(i, r, t_range.clone()).is_some(),
+                _ => self.surface(*surface).occludes(r, t_range.clone(), physics),
+            }
+        })
+    }
+
+    /// Nearest hit of `r` on object `i` within `t_range` and its material, passing through
+    /// the holes of cutouts
+    fn hit_object(&self, i: usize, r: &Ray, t_range: Range<f32>) -> Option<(HitRecord, &Material)> {
+        let Object {
             surface,
             material,
             physics,
-        } in &self.objects
-        {
-            if let Some(hit) = surface.hit(r, t_min..nearest_t, physics) {
-                nearest_t = hit.t;
-                nearest_hit = Some((hit, material.as_ref()));
+            ..
+        } = &self.objects[i];
+        let mut t_min = t_range.start;
+        while let Some(hit) = self.surface(*surface).hit(r, t_min..t_range.end, physics) {
+            match self.material(*material) {
+                Material::Cutout(cutout) => {
+                    if cutout.opacity(hit.uv, hit.duv) <= cutout_threshold(r, self.ids[i], hit.t) {
+                        // Look for another hit on the surface past the hole
+                        t_min = f32::from_bits(hit.t.to_bits() + 1);
+                        continue;
+                    }
+                    return Some((hit, cutout.base()));
+                }
+                material => return Some((hit, material)),
             }
         }
+        None
+    }
 
-        nearest_hit
+    /// Replace `nearest_hit` with hits on `objects` visible to `r` nearer than `t_range.end`,
+    /// returning the distance to the nearest one
+    fn hit_objects<'a>(
+        &'a self,
+        objects: Range<usize>,
+        r: &Ray,
+        t_range: Range<f32>,
+        nearest_hit: &mut Option<Intersection<'a>>,
+    ) -> f32 {
+        let mut nearest_t = t_range.end;
+        for i in objects {
+            let object = &self.objects[i];
+            if !object.visibility.contains(r.kind()) {
+                continue;
+            }
+            if let Some((hit, material)) = self.hit_object(i, r, t_range.start..nearest_t) {
+                nearest_t = hit.t;
+                *nearest_hit = Some(Intersection {
+                    hit,
+                    material,
+                    object: self.ids[i],
+                    sampled: self.emitters[i],
+                    priority: object.priority,
+                });
+            }
+        }
+        nearest_t
     }
 }
+
+/// Uniform number in `[0, 1)` for deciding whether `r` passes through a cutout of `object` at
+/// distance `t`, which is the same every time the same hit is found
+fn cutout_threshold(r: &Ray, object: u32, t: f32) -> f32 {
+    let (origin, direction) = (r.origin(), r.direction());
+    let hash = [
+        origin.x,
+        origin.y,
+        origin.z,
+        direction.x,
+        direction.y,
+        direction.z,
+        t,
+    ]
+    .iter()
+    .fold(u64::from(object), |hash, value| {
+        mix(hash ^ u64::from(value.to_bits()))
+    });
+    // The top 24 bits, so that the result is never rounded up to 1
+    (hash >> 40) as f32 / (1 << 24) as f32
+}
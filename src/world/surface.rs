@@ -1,37 +1,190 @@
-use super::Aabb;
+use super::aabb::Aabb;
 use super::PhysicsFrame;
-use crate::Ray;
+use crate::{ray::Differentials, sampling::Onb, Ray};
+use std::borrow::Cow;
+use std::f32::consts::{PI, TAU};
 use std::ops::Range;
-use ultraviolet::Vec3;
+use ultraviolet::{Vec2, Vec3};
 
+#[derive(Clone)]
 pub struct HitRecord {
     pub position: Vec3,
+    /// Geometric normal, facing where the ray came from
     pub normal: Vec3,
+    /// Shading frame, in which materials scatter. Its normal is the geometric one unless it is
+    /// replaced with [`HitRecord::with_shading_normal`].
+    pub frame: Onb,
     pub t: f32,
     pub front_facing: bool,
+    /// Texture coordinates
+    pub uv: Vec2,
+    /// Offsets from `position` to where the differentials of the ray meet the plane of the
+    /// surface, zero if the ray has none
+    pub dp: [Vec3; 2],
+    /// Changes of the texture coordinates from one pixel to the next along x and y, zero if
+    /// the ray has no differentials
+    pub duv: [Vec2; 2],
 }
 
 impl HitRecord {
-    pub fn new(position: Vec3, outward_normal: Vec3, t: f32, r: &Ray) -> Self {
+    /// `tangent` is the direction in which `uv.x` grows, not necessarily perpendicular to the
+    /// normal
+    pub fn new(
+        position: Vec3,
+        outward_normal: Vec3,
+        tangent: Vec3,
+        t: f32,
+        uv: Vec2,
+        r: &Ray,
+    ) -> Self {
         let front_facing = r.direction().dot(outward_normal) < 0.;
+        let normal = if front_facing {
+            outward_normal
+        } else {
+            -outward_normal
+        };
         Self {
             position,
-            normal: if front_facing {
-                outward_normal
-            } else {
-                -outward_normal
-            },
+            normal,
+            frame: Onb::from_normal_tangent(normal, tangent),
             t,
             front_facing,
+            uv,
+            dp: [Vec3::zero(); 2],
+            duv: [Vec2::zero(); 2],
+        }
+    }
+
+    /// With the footprint of the differentials of `r`, if it has them, on a surface where the
+    /// position changes by `dpduv` with the texture coordinates
+    pub fn with_footprint(self, r: &Ray, dpduv: [Vec3; 2]) -> Self {
+        let differentials = match r.differentials() {
+            Some(differentials) => differentials,
+            None => return self,
+        };
+        let mut dp = [Vec3::zero(); 2];
+        for (dp, (origin, direction)) in dp
+            .iter_mut()
+            .zip(differentials.origins.iter().zip(&differentials.directions))
+        {
+            let cos = direction.dot(self.normal);
+            // Grazing differentials would reach out arbitrarily far
+            if cos.abs() < 1e-6 {
+                return self;
+            }
+            let t = (self.position - *origin).dot(self.normal) / cos;
+            *dp = *origin + *direction * t - self.position;
+        }
+
+        // Solve dp = dpdu * du + dpdv * dv on the two axes where the surface is widest
+        let n = self.normal;
+        let (a, b) = if n.x.abs() > n.y.abs() && n.x.abs() > n.z.abs() {
+            (1, 2)
+        } else if n.y.abs() > n.z.abs() {
+            (0, 2)
+        } else {
+            (0, 1)
+        };
+        let [dpdu, dpdv] = dpduv.map(|v| [v.as_slice()[a], v.as_slice()[b]]);
+        let determinant = dpdu[0] * dpdv[1] - dpdv[0] * dpdu[1];
+        let duv = if determinant.abs() > f32::MIN_POSITIVE {
+            dp.map(|dp| {
+                let dp = [dp.as_slice()[a], dp.as_slice()[b]];
+                Vec2::new(
+                    (dp[0] * dpdv[1] - dpdv[0] * dp[1]) / determinant,
+                    (dpdu[0] * dp[1] - dp[0] * dpdu[1]) / determinant,
+                )
+            })
+        } else {
+            [Vec2::zero(); 2]
+        };
+        Self { dp, duv, ..self }
+    }
+
+    /// Differentials of `r` after reflecting off a mirror at this hit with unit normal `normal`
+    pub fn reflect_differentials(&self, r: &Ray, normal: Vec3) -> Option<Differentials> {
+        let differentials = r.differentials()?;
+        Some(Differentials {
+            origins: self.dp.map(|dp| self.position + dp),
+            directions: differentials.directions.map(|d| d.reflected(normal)),
+        })
+    }
+
+    /// Differentials of `r` after refracting through a smooth surface at this hit, with unit
+    /// normal `normal` facing where the ray came from and a ratio of refractive indices `ratio`
+    pub fn refract_differentials(
+        &self,
+        r: &Ray,
+        normal: Vec3,
+        ratio: f32,
+    ) -> Option<Differentials> {
+        let differentials = r.differentials()?;
+        let directions = differentials.directions.map(|d| d.refracted(normal, ratio));
+        // Differentials which reflect totally don't tell how wide the refracted ray is
+        if directions.iter().any(|d| *d == Vec3::zero()) {
+            return None;
+        }
+        Some(Differentials {
+            origins: self.dp.map(|dp| self.position + dp),
+            directions,
+        })
+    }
+    /// Shade with unit vector `shading_normal`, such as one interpolated from the vertices of
+    /// a mesh, turned to the same side of the surface as the geometric normal
+    pub fn with_shading_normal(self, shading_normal: Vec3) -> Self {
+        let normal = if shading_normal.dot(self.normal) < 0. {
+            -shading_normal
+        } else {
+            shading_normal
+        };
+        Self {
+            frame: Onb::from_normal_tangent(normal, self.frame.tangent),
+            ..self
         }
     }
 }
 
 pub trait Hit: Send + Sync {
     fn hit(&self, r: &Ray, t_range: Range<f32>, physics: &PhysicsFrame) -> Option<HitRecord>;
-    fn bounding_box(&self, physics: &PhysicsFrame) -> Option<Aabb>;
+    /// Whether `r` hits the surface within `t_range`, without working out the hit record
+    fn occludes(&self, r: &Ray, t_range: Range<f32>, physics: &PhysicsFrame) -> bool {
+        self.hit(r, t_range, physics).is_some()
+    }
+    /// Box containing the surface during `time`, or `None` if it is unbounded
+    fn bounding_box(&self, physics: &PhysicsFrame, time: Range<f32>) -> Option<Aabb>;
+}
+
+/// Any of the supported surface types, dispatched without a virtual call
+#[derive(Clone)]
+pub enum Surface {
+    Sphere(Sphere),
+    Triangle(Triangle),
+}
+
+impl Hit for Surface {
+    fn hit(&self, r: &Ray, t_range: Range<f32>, physics: &PhysicsFrame) -> Option<HitRecord> {
+        match self {
+            Self::Sphere(sphere) => sphere.hit(r, t_range, physics),
+            Self::Triangle(triangle) => triangle.hit(r, t_range, physics),
+        }
+    }
+
+    fn occludes(&self, r: &Ray, t_range: Range<f32>, physics: &PhysicsFrame) -> bool {
+        match self {
+            Self::Sphere(sphere) => sphere.occludes(r, t_range, physics),
+            Self::Triangle(triangle) => triangle.occludes(r, t_range, physics),
+        }
+    }
+
+    fn bounding_box(&self, physics: &PhysicsFrame, time: Range<f32>) -> Option<Aabb> {
+        match self {
+            Self::Sphere(sphere) => sphere.bounding_box(physics, time),
+            Self::Triangle(triangle) => triangle.bounding_box(physics, time),
+        }
+    }
 }
 
+#[derive(Clone)]
 pub struct Sphere {
     radius: f32,
 }
@@ -40,11 +193,9 @@ impl Sphere {
     pub fn new(radius: f32) -> Self {
         Self { radius }
     }
-}
 
-impl Hit for Sphere {
-    fn hit(&self, r: &Ray, t_range: Range<f32>, physics: &PhysicsFrame) -> Option<HitRecord> {
-        let center = physics.position(r.time());
+    /// Distance to the nearest intersection of `r` within `t_range` with the sphere at `center`
+    fn root(&self, r: &Ray, t_range: Range<f32>, center: Vec3) -> Option<f32> {
         let oc = r.origin() - center;
         let a = r.direction().mag().powi(2);
         let half_b = oc.dot(r.direction());
@@ -64,18 +215,284 @@ impl Hit for Sphere {
                 return None;
             }
         }
+        Some(root)
+    }
+}
 
+impl Hit for Sphere {
+    fn hit(&self, r: &Ray, t_range: Range<f32>, physics: &PhysicsFrame) -> Option<HitRecord> {
+        let center = physics.position(r.time());
+        let root = self.root(r, t_range, center)?;
         let position = r.at(root);
         let outward_normal = (position - center) / self.radius;
-        Some(HitRecord::new(position, outward_normal, root, r))
+        // Like an equirectangular environment map seen from outside
+        let n = outward_normal;
+        let uv = Vec2::new(
+            (n.z.atan2(n.x) / TAU).rem_euclid(1.),
+            1. - n.y.clamp(-1., 1.).acos() / PI,
+        );
+        let tangent = Vec3::new(-n.z, 0., n.x);
+        // Derivatives of the position by the angles around and down from the pole
+        let sin_theta = (n.x * n.x + n.z * n.z).sqrt();
+        let dpdtheta = if sin_theta > 0. {
+            Vec3::new(n.y * n.x / sin_theta, -sin_theta, n.y * n.z / sin_theta)
+        } else {
+            Vec3::zero()
+        };
+        let dpduv = [tangent * TAU * self.radius, dpdtheta * -PI * self.radius];
+        Some(
+            HitRecord::new(position, outward_normal, tangent, root, uv, r).with_footprint(r, dpduv),
+        )
+    }
+
+    fn occludes(&self, r: &Ray, t_range: Range<f32>, physics: &PhysicsFrame) -> bool {
+        self.root(r, t_range, physics.position(r.time())).is_some()
     }
 
-    fn bounding_box(&self, physics: &PhysicsFrame) -> Option<Aabb> {
-        let pos0 = physics.position(0.);
-        let pos1 = physics.position(1.);
+    fn bounding_box(&self, physics: &PhysicsFrame, time: Range<f32>) -> Option<Aabb> {
+        let pos0 = physics.position(time.start);
+        let pos1 = physics.position(time.end);
         let radius = Vec3::broadcast(self.radius);
         Some(Aabb::surrounding(
             ((pos0 - radius)..(pos0 + radius))..((pos1 - radius)..(pos1 + radius)),
         ))
     }
 }
+
+#[derive(Clone)]
+pub struct Triangle {
+    vertices: [Vec3; 3],
+    uvs: [Vec2; 3],
+}
+
+impl Triangle {
+    /// Texture coordinates are (0, 0), (1, 0) and (0, 1) at the vertices
+    pub fn new(vertices: [Vec3; 3]) -> Self {
+        Self {
+            vertices,
+            uvs: [Vec2::zero(), Vec2::unit_x(), Vec2::unit_y()],
+        }
+    }
+
+    /// Texture coordinates at the vertices
+    pub fn with_uvs(self, uvs: [Vec2; 3]) -> Self {
+        Self { uvs, ..self }
+    }
+
+    /// Relative to the position of the object
+    pub fn vertices(&self) -> [Vec3; 3] {
+        self.vertices
+    }
+
+    pub fn uvs(&self) -> [Vec2; 3] {
+        self.uvs
+    }
+
+    /// Distance to the intersection of `r` within `t_range` and its barycentric coordinates,
+    /// with the triangle moved to `position`
+    fn intersect(&self, r: &Ray, t_range: Range<f32>, position: Vec3) -> Option<(f32, f32, f32)> {
+        // Möller-Trumbore intersection
+        let v0 = self.vertices[0] + position;
+        let edge1 = self.vertices[1] - self.vertices[0];
+        let edge2 = self.vertices[2] - self.vertices[0];
+        let p = r.direction().cross(edge2);
+        let determinant = edge1.dot(p);
+        if determinant.abs() < f32::EPSILON {
+            return None; // Parallel to the triangle
+        }
+
+        let inv_determinant = 1. / determinant;
+        let s = r.origin() - v0;
+        let u = s.dot(p) * inv_determinant;
+        if !(0. ..=1.).contains(&u) {
+            return None;
+        }
+        let q = s.cross(edge1);
+        let v = r.direction().dot(q) * inv_determinant;
+        if v < 0. || u + v > 1. {
+            return None;
+        }
+
+        let t = edge2.dot(q) * inv_determinant;
+        if t < t_range.start || t_range.end < t {
+            return None;
+        }
+        Some((t, u, v))
+    }
+}
+
+impl Hit for Triangle {
+    fn hit(&self, r: &Ray, t_range: Range<f32>, physics: &PhysicsFrame) -> Option<HitRecord> {
+        let (t, u, v) = self.intersect(r, t_range, physics.position(r.time()))?;
+        let edge1 = self.vertices[1] - self.vertices[0];
+        let edge2 = self.vertices[2] - self.vertices[0];
+
+        let outward_normal = edge1.cross(edge2).normalized();
+        let uv = self.uvs[0] * (1. - u - v) + self.uvs[1] * u + self.uvs[2] * v;
+        // Solve for how the position changes with the texture coordinates, along the first of
+        // which is the tangent
+        let (duv1, duv2) = (self.uvs[1] - self.uvs[0], self.uvs[2] - self.uvs[0]);
+        let uv_determinant = duv1.x * duv2.y - duv2.x * duv1.y;
+        let dpduv = if uv_determinant != 0. {
+            [
+                (edge1 * duv2.y - edge2 * duv1.y) / uv_determinant,
+                (edge2 * duv1.x - edge1 * duv2.x) / uv_determinant,
+            ]
+        } else {
+            [edge1, edge2]
+        };
+        Some(HitRecord::new(r.at(t), outward_normal, dpduv[0], t, uv, r).with_footprint(r, dpduv))
+    }
+
+    fn occludes(&self, r: &Ray, t_range: Range<f32>, physics: &PhysicsFrame) -> bool {
+        self.intersect(r, t_range, physics.position(r.time()))
+            .is_some()
+    }
+
+    fn bounding_box(&self, physics: &PhysicsFrame, time: Range<f32>) -> Option<Aabb> {
+        let min = self.vertices[0]
+            .min_by_component(self.vertices[1])
+            .min_by_component(self.vertices[2]);
+        let max = self.vertices[0]
+            .max_by_component(self.vertices[1])
+            .max_by_component(self.vertices[2]);
+        let pos0 = physics.position(time.start);
+        let pos1 = physics.position(time.end);
+        Some(Aabb::surrounding(
+            ((pos0 + min)..(pos0 + max))..((pos1 + min)..(pos1 + max)),
+        ))
+    }
+}
+
+/// Largest step of a vertex coordinate on the grid of [`Surfaces::quantized`], three of which
+/// fit in a `u64`
+const POSITION_STEPS: u32 = (1 << 21) - 1;
+const UV_STEPS: u32 = u16::MAX as u32;
+
+/// Surface table of a world, optionally with triangles stored on grids to save memory
+pub enum Surfaces {
+    Exact(Vec<Surface>),
+    Quantized {
+        surfaces: Vec<QuantizedSurface>,
+        /// Lowest vertex and the distance between vertex positions on the grid
+        origin: Vec3,
+        step: Vec3,
+        /// Same for texture coordinates
+        uv_origin: Vec2,
+        uv_step: Vec2,
+    },
+}
+
+/// Triangles have their vertex positions and texture coordinates as steps on grids
+pub enum QuantizedSurface {
+    Sphere(Sphere),
+    Triangle([u64; 3], [[u16; 2]; 3]),
+}
+
+impl From<Vec<Surface>> for Surfaces {
+    fn from(surfaces: Vec<Surface>) -> Self {
+        Self::Exact(surfaces)
+    }
+}
+
+impl Surfaces {
+    /// Move the vertices of triangles to a grid of 2^21 steps along each axis of their bounds,
+    /// and their texture coordinates to one of 2^16 steps, taking 40 instead of 64 bytes for
+    /// each surface. Triangles which share vertices stay connected, because the same
+    /// positions are rounded the same way.
+    pub fn quantized(surfaces: Vec<Surface>) -> Self {
+        let triangles = || {
+            surfaces.iter().filter_map(|surface| match surface {
+                Surface::Triangle(triangle) => Some(triangle),
+                Surface::Sphere(_) => None,
+            })
+        };
+        let bounds = triangles()
+            .flat_map(|triangle| triangle.vertices)
+            .fold(Aabb::empty(), |bounds, v| bounds.grow(v));
+        let (uv_min, uv_max) = triangles().flat_map(|triangle| triangle.uvs).fold(
+            (
+                Vec2::broadcast(f32::INFINITY),
+                Vec2::broadcast(f32::NEG_INFINITY),
+            ),
+            |(min, max), uv| (min.min_by_component(uv), max.max_by_component(uv)),
+        );
+        // Without triangles the bounds are empty and the steps are zero
+        let origin = bounds.min;
+        let step = (bounds.max - bounds.min).max_by_component(Vec3::zero()) / POSITION_STEPS as f32;
+        let uv_step = (uv_max - uv_min).max_by_component(Vec2::zero()) / UV_STEPS as f32;
+        let quantize = |v: f32, origin: f32, step: f32, steps: u32| {
+            if step > 0. {
+                ((v - origin) / step).round().clamp(0., steps as f32) as u32
+            } else {
+                0
+            }
+        };
+        let surfaces = surfaces
+            .into_iter()
+            .map(|surface| match surface {
+                Surface::Sphere(sphere) => QuantizedSurface::Sphere(sphere),
+                Surface::Triangle(triangle) => QuantizedSurface::Triangle(
+                    triangle.vertices.map(|v| {
+                        (0..3).fold(0, |packed, axis| {
+                            let q = quantize(
+                                v.as_slice()[axis],
+                                origin.as_slice()[axis],
+                                step.as_slice()[axis],
+                                POSITION_STEPS,
+                            );
+                            packed | u64::from(q) << (21 * axis)
+                        })
+                    }),
+                    triangle.uvs.map(|uv| {
+                        [
+                            quantize(uv.x, uv_min.x, uv_step.x, UV_STEPS) as u16,
+                            quantize(uv.y, uv_min.y, uv_step.y, UV_STEPS) as u16,
+                        ]
+                    }),
+                ),
+            })
+            .collect();
+        Self::Quantized {
+            surfaces,
+            origin,
+            step,
+            uv_origin: uv_min,
+            uv_step,
+        }
+    }
+
+    /// Surface at `index`, with quantized triangles restored from the grids
+    pub fn get(&self, index: usize) -> Cow<'_, Surface> {
+        match self {
+            Self::Exact(surfaces) => Cow::Borrowed(&surfaces[index]),
+            Self::Quantized {
+                surfaces,
+                origin,
+                step,
+                uv_origin,
+                uv_step,
+            } => Cow::Owned(match &surfaces[index] {
+                QuantizedSurface::Sphere(sphere) => Surface::Sphere(sphere.clone()),
+                QuantizedSurface::Triangle(positions, uvs) => Surface::Triangle(
+                    Triangle::new(positions.map(|packed| {
+                        let q =
+                            |axis: u32| (packed >> (21 * axis) & u64::from(POSITION_STEPS)) as f32;
+                        *origin + Vec3::new(q(0), q(1), q(2)) * *step
+                    }))
+                    .with_uvs(uvs.map(|[u, v]| {
+                        *uv_origin + Vec2::new(f32::from(u), f32::from(v)) * *uv_step
+                    })),
+                ),
+            }),
+        }
+    }
+
+    /// Memory taken by the table
+    pub fn bytes(&self) -> usize {
+        match self {
+            Self::Exact(surfaces) => std::mem::size_of_val(surfaces.as_slice()),
+            Self::Quantized { surfaces, .. } => std::mem::size_of_val(surfaces.as_slice()),
+        }
+    }
+}
@@ -0,0 +1,77 @@
+//! Participating media filling the inside of objects, such as colored glass, juice or smoke
+
+use crate::sampling::{henyey_greenstein, Onb};
+use ultraviolet::{Vec2, Vec3};
+
+/// Homogeneous medium, with coefficients per unit of distance
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Volume {
+    absorption: Vec3,
+    scattering: Vec3,
+    /// Henyey-Greenstein asymmetry, from -1 for backwards to 1 for forwards scattering
+    asymmetry: f32,
+}
+
+/// What happens to a ray travelling through a [`Volume`]
+pub enum Interaction {
+    /// Scattered at a distance, with the weight of the light arriving there
+    Scattered(f32, Vec3),
+    /// Reached the end of the distance, with the weight of the light arriving from there
+    Passed(Vec3),
+}
+
+impl Volume {
+    pub fn new(absorption: Vec3, scattering: Vec3, asymmetry: f32) -> Self {
+        Self {
+            absorption,
+            scattering,
+            asymmetry: asymmetry.clamp(-0.999, 0.999),
+        }
+    }
+
+    fn extinction(&self) -> Vec3 {
+        self.absorption + self.scattering
+    }
+
+    fn transmittance(&self, distance: f32) -> Vec3 {
+        let e = self.extinction() * -distance;
+        Vec3::new(e.x.exp(), e.y.exp(), e.z.exp())
+    }
+
+    /// Sample where a ray scatters before travelling `max_distance`, with `u` in the unit
+    /// square
+    pub fn interact(&self, max_distance: f32, u: Vec2) -> Interaction {
+        // From the extinction of a random channel, weighted by the density averaged over all
+        // of them
+        let channel = ((u.x * 3.) as usize).min(2);
+        let extinction = self.extinction().as_slice()[channel];
+        let distance = if extinction > 0. {
+            -(1. - u.y).ln() / extinction
+        } else {
+            f32::INFINITY
+        };
+        if distance < max_distance {
+            let transmittance = self.transmittance(distance);
+            let pdf = mean(self.extinction() * transmittance);
+            Interaction::Scattered(distance, self.scattering * transmittance / pdf)
+        } else {
+            let transmittance = self.transmittance(max_distance);
+            let probability = mean(transmittance);
+            Interaction::Passed(if probability > 0. {
+                transmittance / probability
+            } else {
+                Vec3::zero()
+            })
+        }
+    }
+
+    /// New direction for a ray travelling along unit vector `direction`, with `u` in the unit
+    /// square. The phase function is sampled exactly, so it doesn't weigh the light.
+    pub fn scatter(&self, direction: Vec3, u: Vec2) -> Vec3 {
+        Onb::from_normal(direction).to_world(henyey_greenstein(u, self.asymmetry))
+    }
+}
+
+fn mean(v: Vec3) -> f32 {
+    (v.x + v.y + v.z) / 3.
+}